@@ -16,4 +16,8 @@ pub enum Error {
     ChainMismatchTip(BlockHash, BlockHash),
     #[error("Cannot extend chain root {0} with the header {1}, parent doesn't match")]
     ChainMismatchRoot(BlockHash, BlockHash),
+    #[error("Main chain has a gap, expected parent at height {expected}, found at {found}")]
+    ChainGap { expected: u32, found: u32 },
+    #[error("Refusing to reorganize {depth} blocks of the main chain, the limit is exceeded")]
+    ReorgTooDeep { depth: u32 },
 }
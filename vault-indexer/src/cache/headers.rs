@@ -1,5 +1,6 @@
 use super::error::Error;
 use crate::db::{DatabaseHeaders, DatabaseMeta, HeaderRecord};
+use crate::ChainHeight;
 use bitcoin::{
     block::Header,
     hashes::Hash,
@@ -12,59 +13,200 @@ use bitcoin::{
 use core::{fmt::Display, iter::Iterator};
 use log::*;
 use rusqlite::Connection;
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+
+/// Outcome of applying new headers to the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainUpdate {
+    /// Nothing changed in the known chains (e.g. empty message or orphan headers)
+    NoOp,
+    /// The main chain was extended without forks
+    Extended { from: BlockHash, to: BlockHash },
+    /// Headers are stored as a fork with less work than the main chain
+    ForkStored,
+    /// The fork has more work and became the main chain. The `fork_height` is height of
+    /// the last mutual block of the old and the new chains.
+    Reorged {
+        old_tip: BlockHash,
+        new_tip: BlockHash,
+        fork_height: u32,
+    },
+}
+
+impl ChainUpdate {
+    /// Combine two consequent updates into one that describes the whole change
+    fn merge(self, next: ChainUpdate) -> ChainUpdate {
+        match (self, next) {
+            (prev, ChainUpdate::NoOp) => prev,
+            (ChainUpdate::NoOp | ChainUpdate::ForkStored, next) => next,
+            (prev, ChainUpdate::ForkStored) => prev,
+            (ChainUpdate::Extended { from, .. }, ChainUpdate::Extended { to, .. }) => {
+                ChainUpdate::Extended { from, to }
+            }
+            (
+                ChainUpdate::Reorged {
+                    old_tip,
+                    fork_height,
+                    ..
+                },
+                ChainUpdate::Extended { to, .. },
+            ) => ChainUpdate::Reorged {
+                old_tip,
+                new_tip: to,
+                fork_height,
+            },
+            (ChainUpdate::Extended { .. }, reorg @ ChainUpdate::Reorged { .. }) => reorg,
+            (
+                ChainUpdate::Reorged {
+                    old_tip,
+                    fork_height: first_height,
+                    ..
+                },
+                ChainUpdate::Reorged {
+                    new_tip,
+                    fork_height: second_height,
+                    ..
+                },
+            ) => ChainUpdate::Reorged {
+                old_tip,
+                new_tip,
+                fork_height: first_height.min(second_height),
+            },
+        }
+    }
+}
+
+/// How many blocks of the main chain a reorganization can disconnect by default
+pub const DEFAULT_MAX_REORG_DEPTH: u32 = 100;
+
+/// Connection to read the headers that were evicted from the memory
+struct HeadersWindow {
+    conn: Connection,
+    size: u32,
+}
 
 pub struct HeadersCache {
     headers: HashMap<BlockHash, HeaderRecord>,
     best_tip: BlockHash,
     height: u32,
+    // Height of the first element of main_chain, older ones are evicted
+    main_chain_start: u32,
     main_chain: Vec<BlockHash>,
     dirty: Vec<BlockHash>,
     orphans: HashMap<BlockHash, Header>,
+    max_reorg_depth: u32,
+    window: Option<HeadersWindow>,
 }
 
 impl HeadersCache {
     /// Load all headers from database
     pub fn load(conn: &Connection) -> Result<Self, Error> {
-        let mut headers = HashMap::new();
-        conn.load_block_headers(|record| {
-            headers.insert(record.header.block_hash(), record);
-        })?;
-        let best_tip = conn.get_main_tip()?;
         let mut cache = HeadersCache {
-            headers,
-            best_tip,
+            headers: HashMap::new(),
+            best_tip: conn.get_main_tip()?,
             height: 0,
+            main_chain_start: 0,
             main_chain: vec![],
             dirty: vec![],
             orphans: HashMap::new(),
+            max_reorg_depth: DEFAULT_MAX_REORG_DEPTH,
+            window: None,
         };
-        trace!("Loading main chain");
-        cache.fill_main_chain()?;
+        cache.reload(conn)?;
+        Ok(cache)
+    }
+
+    /// Keep in memory only the headers of the last `size` blocks of the main chain (and forks
+    /// of the same heights). Older headers are read from the database with the `backing`
+    /// connection on demand, which trades RAM for occasional database reads. The window is
+    /// never smaller than the max reorganization depth.
+    pub fn load_window(conn: &Connection, size: u32, backing: Connection) -> Result<Self, Error> {
+        let mut cache = HeadersCache {
+            headers: HashMap::new(),
+            best_tip: conn.get_main_tip()?,
+            height: 0,
+            main_chain_start: 0,
+            main_chain: vec![],
+            dirty: vec![],
+            orphans: HashMap::new(),
+            max_reorg_depth: DEFAULT_MAX_REORG_DEPTH,
+            window: Some(HeadersWindow {
+                conn: backing,
+                size,
+            }),
+        };
+        cache.reload(conn)?;
         Ok(cache)
     }
 
+    /// Drop the cached headers and load them from the database again, e.g. when other process
+    /// writes the database. Keeps the settings of the cache.
+    pub fn reload(&mut self, conn: &Connection) -> Result<(), Error> {
+        self.best_tip = conn.get_main_tip()?;
+        self.main_chain_start = match self.window_size() {
+            Some(size) => {
+                let tip_height = conn
+                    .load_block_header(self.best_tip)?
+                    .ok_or(Error::MissingHeader(self.best_tip))?
+                    .height;
+                tip_height.saturating_sub(size)
+            }
+            None => 0,
+        };
+        let mut headers = HashMap::new();
+        conn.load_block_headers_from(self.main_chain_start, |record| {
+            headers.insert(record.header.block_hash(), record);
+        })?;
+        self.headers = headers;
+        self.main_chain = vec![];
+        self.dirty = vec![];
+        self.orphans = HashMap::new();
+        trace!("Loading main chain");
+        self.fill_main_chain()
+    }
+
+    /// Limit how many blocks of the main chain a reorganization can disconnect. Deeper
+    /// reorganizations fail with [Error::ReorgTooDeep] and leave the chains untouched.
+    pub fn set_max_reorg_depth(&mut self, depth: u32) {
+        self.max_reorg_depth = depth;
+    }
+
+    /// How many blocks below the tip are kept in memory, missing if all headers are kept.
+    /// Reorganizations never touch blocks below the window.
+    fn window_size(&self) -> Option<u32> {
+        self.window
+            .as_ref()
+            .map(|window| window.size.max(self.max_reorg_depth))
+    }
+
     fn fill_main_chain(&mut self) -> Result<(), Error> {
         trace!("Loading the head: {}", self.best_tip);
-        let tip_record = self.get_header(self.best_tip)?.clone();
-        let empty_hash = BlockHash::from_byte_array([0u8; 32]);
+        let tip_record = self.get_header(self.best_tip)?;
         self.height = tip_record.height;
-        self.main_chain
-            .resize(tip_record.height as usize + 1, empty_hash);
+        // Placeholders are overwritten by the walk from the tip below
+        self.main_chain.resize(
+            (tip_record.height - self.main_chain_start) as usize + 1,
+            BlockHash::all_zeros(),
+        );
 
         let mut current_record = tip_record;
         loop {
             let curr_height = current_record.height;
-            self.main_chain[curr_height as usize] = current_record.header.block_hash();
-            if current_record.height == 0 {
+            self.main_chain[(curr_height - self.main_chain_start) as usize] =
+                current_record.header.block_hash();
+            if current_record.height == self.main_chain_start {
                 break;
             }
             // let prev_hash = current_record.header.prev_blockhash;
             // trace!("Loading previous block: {}", prev_hash);
-            current_record = self
-                .get_header(current_record.header.prev_blockhash)?
-                .clone();
-            assert_eq!(curr_height, current_record.height + 1);
+            current_record = self.get_header(current_record.header.prev_blockhash)?;
+            // Inconsistent database must not crash the process
+            if current_record.height != curr_height - 1 {
+                return Err(Error::ChainGap {
+                    expected: curr_height - 1,
+                    found: current_record.height,
+                });
+            }
         }
         Ok(())
     }
@@ -81,17 +223,98 @@ impl HeadersCache {
         }
         conn.set_best_tip(self.best_tip)?;
         self.dirty = vec![];
+        self.evict();
         Ok(())
     }
 
-    /// Query the header in the cache. Doesn't gurantee that the header in the main chain
-    pub fn get_header(&self, hash: BlockHash) -> Result<&HeaderRecord, Error> {
-        self.headers.get(&hash).ok_or(Error::MissingHeader(hash))
+    /// Forget headers below the window, they are stored already
+    fn evict(&mut self) {
+        let Some(size) = self.window_size() else {
+            return;
+        };
+        let start = self.height.saturating_sub(size);
+        if start <= self.main_chain_start {
+            return;
+        }
+        trace!("Evicting headers below height {start}");
+        self.main_chain
+            .drain(..(start - self.main_chain_start) as usize);
+        self.main_chain_start = start;
+        self.headers.retain(|_, record| record.height >= start);
+        trace!("Headers left in memory: {}", self.headers_in_memory());
+    }
+
+    /// Query the header in the cache or the database if it is evicted. Doesn't gurantee that
+    /// the header in the main chain
+    pub fn get_header(&self, hash: BlockHash) -> Result<HeaderRecord, Error> {
+        self.find_header(hash)?.ok_or(Error::MissingHeader(hash))
+    }
+
+    /// Same as [HeadersCache::get_header], but unknown headers aren't an error
+    pub fn find_header(&self, hash: BlockHash) -> Result<Option<HeaderRecord>, Error> {
+        if let Some(record) = self.headers.get(&hash) {
+            return Ok(Some(record.clone()));
+        }
+        match &self.window {
+            Some(window) => Ok(window.conn.load_block_header(hash)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Mutable access to the header, evicted one is loaded back to the memory
+    fn get_header_mut(&mut self, hash: BlockHash) -> Result<&mut HeaderRecord, Error> {
+        match self.headers.entry(hash) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let window = self.window.as_ref().ok_or(Error::MissingHeader(hash))?;
+                let record = window
+                    .conn
+                    .load_block_header(hash)?
+                    .ok_or(Error::MissingHeader(hash))?;
+                Ok(entry.insert(record))
+            }
+        }
     }
 
     /// Get the block hash that is in main chain in the given height
     pub fn get_blockhash_at(&self, height: u32) -> Option<BlockHash> {
-        self.main_chain.get(height as usize).cloned()
+        if height > self.height {
+            return None;
+        }
+        match height.checked_sub(self.main_chain_start) {
+            Some(i) => self.main_chain.get(i as usize).cloned(),
+            None => {
+                let window = self.window.as_ref()?;
+                window.conn.load_main_hash_at(height).unwrap_or_else(|e| {
+                    error!("Failed to load evicted header at height {height}: {e}");
+                    None
+                })
+            }
+        }
+    }
+
+    /// Remember the main chain block at the height, the height must be inside the window
+    fn set_main_hash(&mut self, height: u32, hash: BlockHash) -> Result<(), Error> {
+        let i = height
+            .checked_sub(self.main_chain_start)
+            .ok_or(Error::MissingHeaderHeight(height))?;
+        let slot = self
+            .main_chain
+            .get_mut(i as usize)
+            .ok_or(Error::MissingHeaderHeight(height))?;
+        *slot = hash;
+        Ok(())
+    }
+
+    /// Get the main chain header at the given height. Returns `None` for heights above the tip.
+    pub fn find_header_at(&self, height: u32) -> Result<Option<HeaderRecord>, Error> {
+        if height > self.height {
+            return Ok(None);
+        }
+        match self.get_blockhash_at(height) {
+            Some(hash) => self.find_header(hash),
+            None => Ok(None),
+        }
     }
 
     /// Get the Bitcoin core locator of current main chain.
@@ -120,60 +343,122 @@ impl HeadersCache {
         Ok(hashes)
     }
 
-    /// Construct a message to node to request next headers from head
-    pub fn make_get_headers(&self) -> Result<GetHeadersMessage, Error> {
-        let stop_hash = BlockHash::from_byte_array([0u8; 32]);
-        let locator_hashes = self.get_locator_main_chain()?;
+    /// Construct a message to node to request next headers from head. When the `stop` block
+    /// is given, the node doesn't send headers past it. If the stop block is already in the
+    /// main chain, the locator points to its parent, so the node replies with the stop header
+    /// only instead of the headers after our tip.
+    pub fn make_get_headers(&self, stop: Option<BlockHash>) -> Result<GetHeadersMessage, Error> {
+        let stop_record = match stop {
+            Some(hash) => self.find_header(hash)?,
+            None => None,
+        };
+        let locator_hashes = match stop_record {
+            Some(record) if record.in_longest => {
+                self.get_height_locator(record.height.saturating_sub(1))?
+            }
+            _ => self.get_locator_main_chain()?,
+        };
+        // The protocol encodes "no stop" as all-zero hash, it never leaks into the cache logic
+        let stop_hash = stop.unwrap_or_else(BlockHash::all_zeros);
         let headers_msg = GetHeadersMessage::new(locator_hashes, stop_hash);
         Ok(headers_msg)
     }
 
-    /// Construct a message to node to request next blocks from given height
-    pub fn make_get_blocks(&self, height: u32, amount: u32) -> Result<NetworkMessage, Error> {
+    /// Get hashes of main chain blocks starting from the given height. Heights above the main
+    /// chain tip are skipped.
+    pub fn get_blocks_range(&self, height: u32, amount: u32) -> Result<Vec<BlockHash>, Error> {
         let mut hashes = vec![];
-        for i in height..(height + amount).min(self.height + 1) {
+        let end = height
+            .saturating_add(amount)
+            .min(self.height.saturating_add(1));
+        for i in height..end {
             let hash = self
                 .get_blockhash_at(i)
                 .ok_or(Error::MissingHeaderHeight(i))?;
-            hashes.push(Inventory::Block(hash));
+            hashes.push(hash);
         }
-        Ok(NetworkMessage::GetData(hashes))
+        Ok(hashes)
+    }
+
+    /// Get hashes of the fork blocks from the `tip` down to the main chain (exclusive), e.g.
+    /// blocks of the old main chain that were disconnected by a reorganization
+    pub fn get_fork_branch(&self, tip: BlockHash) -> Result<Vec<BlockHash>, Error> {
+        if self.get_header(tip)?.in_longest {
+            return Ok(vec![]);
+        }
+        let chain = self.get_chain_until(tip, |r| r.in_longest)?;
+        Ok(chain.headers().map(|h| h.block_hash()).collect())
+    }
+
+    /// Tips of the chains that aren't the main one with their heights, ordered from the
+    /// highest. Only forks that are held in memory are listed, older ones can't be reorged to.
+    pub fn fork_tips(&self) -> Vec<(BlockHash, u32)> {
+        let parents: HashSet<BlockHash> = self
+            .headers
+            .values()
+            .map(|record| record.header.prev_blockhash)
+            .collect();
+        let mut tips: Vec<(BlockHash, u32)> = self
+            .headers
+            .iter()
+            .filter(|(hash, record)| !record.in_longest && !parents.contains(*hash))
+            .map(|(hash, record)| (*hash, record.height))
+            .collect();
+        tips.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        tips
+    }
+
+    /// Hashes of headers that are waiting for their unknown parent
+    pub fn orphan_hashes(&self) -> Vec<BlockHash> {
+        let mut hashes: Vec<BlockHash> = self.orphans.keys().copied().collect();
+        hashes.sort();
+        hashes
+    }
+
+    /// Amount of headers that are held in memory, including forks
+    pub fn headers_in_memory(&self) -> usize {
+        self.headers.len()
     }
 
     /// Get current main chain height
-    pub fn get_current_height(&self) -> u32 {
-        self.height
+    pub fn get_current_height(&self) -> ChainHeight {
+        ChainHeight(self.height)
     }
 
     /// Checks if the given header chain extends the longest chain and saves metadata.
     ///
     /// If the extended chain is not the longest, traverses back both the longest and current
     /// to find the common ancestor and compare the total work of the chains.
-    pub fn update_longest_chain(&mut self, headers: &[Header]) -> Result<(), Error> {
+    pub fn update_longest_chain(&mut self, headers: &[Header]) -> Result<ChainUpdate, Error> {
         let first_header = if let Some(header) = headers.first() {
             header
         } else {
-            return Ok(());
+            return Ok(ChainUpdate::NoOp);
         };
 
         // Check if we updates the tip (the optmistic scenario)
-        if self.best_tip == first_header.prev_blockhash {
+        let update = if self.best_tip == first_header.prev_blockhash {
             debug!("Extending the current main chain");
 
+            let old_tip = self.best_tip;
             let tip_record = self.get_header(self.best_tip)?;
             let extension_chain = HeaderChain::from_headers(tip_record.header, headers);
             self.store_active(extension_chain)?;
+            ChainUpdate::Extended {
+                from: old_tip,
+                to: self.best_tip,
+            }
         } else {
             debug!("Fork detected");
 
             // Check if we have the header in the cache at all (or we stash them in separate orphans house for a while)
             let new_tip_hash = first_header.prev_blockhash;
-            if !self.headers.contains_key(&new_tip_hash) {
+            if self.find_header(new_tip_hash)?.is_none() {
                 debug!("The new chain is orphan");
                 for header in headers {
                     self.orphans.insert(header.block_hash(), *header);
                 }
-                return Ok(());
+                return Ok(ChainUpdate::NoOp);
             }
 
             // Find the first shared ancestor of the current chain and the new one
@@ -187,22 +472,40 @@ impl HeadersCache {
                 r.header.block_hash() == new_chain.root_hash()
             })?;
             if new_chain.total_work() > main_chain.total_work() {
+                // The mutual ancestor isn't included, all headers are disconnected
+                let depth = main_chain.len() as u32;
+                if depth > self.max_reorg_depth {
+                    return Err(Error::ReorgTooDeep { depth });
+                }
                 debug!("Total work of new chain is greater, inactivating main chain");
+                let old_tip = self.best_tip;
+                let root_record = self.get_header(new_chain.root_hash())?;
+                let fork_height = if root_record.in_longest {
+                    root_record.height
+                } else {
+                    root_record.height.saturating_sub(1)
+                };
                 // Reorganization
                 // TODO: inactivate index in vault transactions
                 self.inactivate(&main_chain)?;
                 debug!("Activating new chain");
                 self.store_active(new_chain)?;
+                ChainUpdate::Reorged {
+                    old_tip,
+                    new_tip: self.best_tip,
+                    fork_height,
+                }
             } else {
                 debug!("Total work of current active chain is greater, storing fork");
                 // Just store fork
                 self.store_inactive(new_chain)?;
+                ChainUpdate::ForkStored
             }
-        }
+        };
 
         // Now we can retry orphans after new blocks arrived
-        self.process_orphans()?;
-        Ok(())
+        let orphans_update = self.process_orphans()?;
+        Ok(update.merge(orphans_update))
     }
 
     /// Collect all headers from given tip to first block (including) that turns the predicate to true
@@ -210,23 +513,20 @@ impl HeadersCache {
     where
         F: Fn(&HeaderRecord) -> bool,
     {
-        let mut current_record = self.headers.get(&tip).ok_or(Error::MissingHeader(tip))?;
+        let mut current_record = self.get_header(tip)?;
 
         let mut chain = HeaderChain::new(current_record.header);
-        if pred(current_record) {
+        if pred(&current_record) {
             return Ok(chain);
         }
 
         trace!("Made a starting chain {chain}");
         loop {
             let next_hash = current_record.header.prev_blockhash;
-            current_record = self
-                .headers
-                .get(&next_hash)
-                .ok_or(Error::MissingHeader(next_hash))?;
+            current_record = self.get_header(next_hash)?;
             trace!("Testing next record: {current_record:?}");
 
-            if pred(current_record) {
+            if pred(&current_record) {
                 break;
             }
 
@@ -235,41 +535,41 @@ impl HeadersCache {
         Ok(chain)
     }
 
-    /// Mark all the headers from given chain (except the root) as inactive
+    /// Mark all the headers from given chain as inactive, the parent of the chain root
+    /// becomes the tip
     fn inactivate(&mut self, chain: &HeaderChain) -> Result<(), Error> {
-        for header in chain.headers().skip(1) {
+        for header in chain.headers() {
             let hash = header.block_hash();
-            let header_record = self
-                .headers
-                .get_mut(&hash)
-                .ok_or(Error::MissingHeader(hash))?;
-            header_record.in_longest = false;
+            self.get_header_mut(hash)?.in_longest = false;
             self.dirty.push(hash);
         }
-        let root_record = self.get_header(chain.root_hash())?.clone();
-        self.best_tip = root_record.header.block_hash();
-        self.height = root_record.height;
-        self.main_chain.truncate(self.height as usize);
+        let root_record = self.get_header(chain.root_hash())?;
+        self.best_tip = root_record.header.prev_blockhash;
+        self.height = root_record.height - 1;
+        let keep = root_record
+            .height
+            .checked_sub(self.main_chain_start)
+            .ok_or(Error::MissingHeaderHeight(root_record.height))?;
+        self.main_chain.truncate(keep as usize);
         Ok(())
     }
 
     /// Store headers from the chain as main chain sequence
     fn store_active(&mut self, chain: HeaderChain) -> Result<(), Error> {
         trace!("Activation of chain: {chain}");
-        let root_hash = chain.root_hash();
-        let mut prev_record = self
-            .headers
-            .get(&root_hash)
-            .ok_or(Error::MissingHeader(root_hash))?
-            .clone();
+        let mut prev_record = self.get_header(chain.root_hash())?;
         let start_height = prev_record.height;
         let new_height = start_height + chain.len() as u32 - 1;
-        let zero_hash = BlockHash::from_byte_array([0u8; 32]);
-        self.main_chain.resize(new_height as usize + 1, zero_hash);
+        let new_len = new_height
+            .checked_sub(self.main_chain_start)
+            .ok_or(Error::MissingHeaderHeight(new_height))?;
+        // Placeholders are overwritten by the chain headers below
+        self.main_chain
+            .resize(new_len as usize + 1, BlockHash::all_zeros());
 
         for header in chain.headers() {
             let hash = header.block_hash();
-            if let Entry::Vacant(e) = self.headers.entry(hash) {
+            if self.find_header(hash)?.is_none() {
                 // insert new
                 let height = prev_record.height + 1;
                 let new_record = HeaderRecord {
@@ -277,21 +577,19 @@ impl HeadersCache {
                     height,
                     in_longest: true,
                 };
-                e.insert(new_record.clone());
-                self.main_chain[height as usize] = hash;
+                self.headers.insert(hash, new_record.clone());
+                self.set_main_hash(height, hash)?;
                 self.orphans.remove(&hash);
                 self.dirty.push(hash);
                 prev_record = new_record;
             } else {
                 // activate
-                let header_record = self
-                    .headers
-                    .get_mut(&hash)
-                    .ok_or(Error::MissingHeader(hash))?;
+                let header_record = self.get_header_mut(hash)?;
                 header_record.in_longest = true;
-                self.main_chain[header_record.height as usize] = hash;
+                let header_record = header_record.clone();
+                self.set_main_hash(header_record.height, hash)?;
                 self.dirty.push(hash);
-                prev_record = header_record.clone();
+                prev_record = header_record;
             }
         }
 
@@ -304,21 +602,16 @@ impl HeadersCache {
 
     /// Store theaders from the chain as not main sequence
     fn store_inactive(&mut self, chain: HeaderChain) -> Result<(), Error> {
-        let root_hash = chain.root_hash();
-        let mut prev_record = self
-            .headers
-            .get(&root_hash)
-            .ok_or(Error::MissingHeader(root_hash))?
-            .clone();
+        let mut prev_record = self.get_header(chain.root_hash())?;
         for header in chain.headers() {
             let hash = header.block_hash();
-            if let Entry::Vacant(e) = self.headers.entry(hash) {
+            if self.find_header(hash)?.is_none() {
                 let new_record = HeaderRecord {
                     header,
                     height: prev_record.height + 1,
                     in_longest: false,
                 };
-                e.insert(new_record.clone());
+                self.headers.insert(hash, new_record.clone());
                 self.orphans.remove(&hash);
                 self.dirty.push(hash);
                 prev_record = new_record;
@@ -328,22 +621,23 @@ impl HeadersCache {
     }
 
     /// Retry orphans headers and try to add them to the main graph
-    fn process_orphans(&mut self) -> Result<(), Error> {
+    fn process_orphans(&mut self) -> Result<ChainUpdate, Error> {
         let mut removed_orphans: Vec<BlockHash> = vec![];
         let mut adopted_oprhans = vec![];
         for orphan in self.orphans.values().cloned() {
-            if self.headers.contains_key(&orphan.prev_blockhash) {
+            if self.find_header(orphan.prev_blockhash)?.is_some() {
                 adopted_oprhans.push(orphan);
                 removed_orphans.push(orphan.block_hash());
             }
         }
+        let mut update = ChainUpdate::NoOp;
         for orphan in adopted_oprhans {
-            self.update_longest_chain(&[orphan])?;
+            update = update.merge(self.update_longest_chain(&[orphan])?);
         }
         for orphan in removed_orphans {
             self.orphans.remove(&orphan);
         }
-        Ok(())
+        Ok(update)
     }
 }
 
@@ -448,6 +742,11 @@ impl HeaderChain {
     }
 }
 
+/// Construct a message to node to request the given blocks
+pub fn make_get_data(hashes: &[BlockHash]) -> NetworkMessage {
+    NetworkMessage::GetData(hashes.iter().cloned().map(Inventory::Block).collect())
+}
+
 /// We sample block hashes exponentionally (^2) from the tip of the chain
 fn get_locator_heights(height: u32) -> Vec<u32> {
     let mut is = vec![];
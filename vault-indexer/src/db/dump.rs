@@ -0,0 +1,261 @@
+//! Logical dump of the database as newline delimited JSON. The first line is [DumpHeader]
+//! with the columns of each table, then each row is a [DumpRow] line and the last line
+//! is [DumpEnd]. BLOB values are hex encoded, the dump doesn't depend on the SQLite file
+//! format and survives schema migrations that only add columns.
+use super::error::Error;
+use super::metadata::DatabaseMeta;
+use crate::Network;
+use log::*;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{params_from_iter, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+/// Marker of the first line, tells the dump from other JSON files
+pub const DUMP_FORMAT: &str = "vault-indexer-dump";
+
+/// Version of the dump layout, bumped when old dumps can't be imported as is
+pub const DUMP_VERSION: u32 = 1;
+
+/// Tables in the order of import, referenced rows go first
+const DUMP_TABLES: &[&str] = &[
+    "metadata",
+    "headers",
+    "blocks",
+    "scanned_blocks",
+    "reorgs",
+    "vaults",
+    "transactions",
+    "transactions_runes",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpHeader {
+    pub format: String,
+    pub version: u32,
+    pub network: String,
+    pub tables: Vec<DumpTable>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpTable {
+    pub name: String,
+    pub columns: Vec<String>,
+}
+
+/// Values of the row in the order of [DumpTable::columns]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DumpRow {
+    pub table: String,
+    pub values: Vec<serde_json::Value>,
+}
+
+/// Last line of the dump, a truncated file doesn't have it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpEnd {
+    pub rows: u64,
+}
+
+/// Names and declared types of the table columns
+fn table_columns(conn: &Connection, table: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut statement = conn
+        .prepare_cached("SELECT name, type FROM pragma_table_info(?1) ORDER BY cid")
+        .map_err(Error::PrepareQuery)?;
+    let rows = statement
+        .query_map([table], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(Error::ExecuteQuery)?;
+    rows.map(|row| row.map_err(Error::FetchRow)).collect()
+}
+
+fn encode_value(value: ValueRef<'_>) -> serde_json::Value {
+    match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => f.into(),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into(),
+        ValueRef::Blob(blob) => hex::encode(blob).into(),
+    }
+}
+
+fn decode_value(value: serde_json::Value, declared: &str) -> Result<Value, Error> {
+    match value {
+        serde_json::Value::Null => Ok(Value::Null),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(i) => Ok(Value::Integer(i)),
+            None => number
+                .as_f64()
+                .map(Value::Real)
+                .ok_or_else(|| Error::DumpFormat(format!("unsupported number {number}"))),
+        },
+        serde_json::Value::String(s) if declared.starts_with("BLOB") => hex::decode(&s)
+            .map(Value::Blob)
+            .map_err(|e| Error::DumpFormat(format!("invalid hex {s:?}: {e}"))),
+        serde_json::Value::String(s) => Ok(Value::Text(s)),
+        other => Err(Error::DumpFormat(format!("unsupported value {other}"))),
+    }
+}
+
+fn write_line<W: Write, T: Serialize>(writer: &mut W, line: &T) -> Result<(), Error> {
+    serde_json::to_writer(&mut *writer, line).map_err(Error::DumpJson)?;
+    writer.write_all(b"\n").map_err(Error::DumpIo)
+}
+
+/// Write all indexed data to the writer, returns amount of written rows. The caller should
+/// hold the connection, so the dump is consistent.
+pub fn export_dump<W: Write>(conn: &Connection, writer: &mut W) -> Result<u64, Error> {
+    let mut tables = vec![];
+    for name in DUMP_TABLES {
+        let columns = table_columns(conn, name)?
+            .into_iter()
+            .map(|(column, _)| column)
+            .collect();
+        tables.push(DumpTable {
+            name: name.to_string(),
+            columns,
+        });
+    }
+    let header = DumpHeader {
+        format: DUMP_FORMAT.to_owned(),
+        version: DUMP_VERSION,
+        network: conn.get_network()?.to_string(),
+        tables,
+    };
+    write_line(writer, &header)?;
+
+    let mut rows = 0;
+    for table in header.tables.iter() {
+        trace!("Dumping table {}", table.name);
+        let query = format!("SELECT {} FROM {}", table.columns.join(", "), table.name);
+        let mut statement = conn.prepare(&query).map_err(Error::PrepareQuery)?;
+        let mut table_rows = statement.query([]).map_err(Error::ExecuteQuery)?;
+        while let Some(row) = table_rows.next().map_err(Error::FetchRow)? {
+            let values = (0..table.columns.len())
+                .map(|i| row.get_ref(i).map(encode_value))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(Error::FetchRow)?;
+            write_line(
+                writer,
+                &DumpRow {
+                    table: table.name.clone(),
+                    values,
+                },
+            )?;
+            rows += 1;
+        }
+    }
+    write_line(writer, &DumpEnd { rows })?;
+    writer.flush().map_err(Error::DumpIo)?;
+    Ok(rows)
+}
+
+/// Statement to insert rows of the dumped table and declared types of its columns
+struct ImportTable {
+    query: String,
+    types: Vec<String>,
+}
+
+/// Replace all indexed data with the rows of the dump, returns amount of imported rows.
+/// The network of the dump must match the database. Everything is imported in single
+/// transaction, so a broken dump leaves the database untouched.
+pub fn import_dump<R: BufRead>(conn: &mut Connection, reader: R) -> Result<u64, Error> {
+    let network = conn.get_network()?;
+    let mut lines = reader.lines();
+    let mut next_line = || -> Result<String, Error> {
+        lines
+            .next()
+            .ok_or_else(|| Error::DumpFormat("unexpected end of the dump".to_owned()))?
+            .map_err(Error::DumpIo)
+    };
+
+    let header: DumpHeader = serde_json::from_str(&next_line()?).map_err(Error::DumpJson)?;
+    if header.format != DUMP_FORMAT {
+        return Err(Error::DumpFormat(format!(
+            "unknown format {}",
+            header.format
+        )));
+    }
+    if header.version != DUMP_VERSION {
+        return Err(Error::DumpVersion(header.version));
+    }
+    let dump_network =
+        Network::from_str(&header.network).map_err(|e| Error::DumpFormat(format!("{e}")))?;
+    if dump_network != network {
+        return Err(Error::DatabaseNetworkMismatch(dump_network, network));
+    }
+
+    // Column names come from the file, so only known tables and columns are accepted
+    let mut tables = HashMap::new();
+    for table in header.tables.iter() {
+        if !DUMP_TABLES.contains(&table.name.as_str()) {
+            return Err(Error::DumpFormat(format!("unknown table {}", table.name)));
+        }
+        let declared: HashMap<String, String> =
+            table_columns(conn, &table.name)?.into_iter().collect();
+        let types = table
+            .columns
+            .iter()
+            .map(|column| {
+                declared.get(column).cloned().ok_or_else(|| {
+                    Error::DumpFormat(format!("unknown column {}.{column}", table.name))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let placeholders = vec!["?"; types.len()].join(", ");
+        let query = format!(
+            "INSERT INTO {} ({}) VALUES ({placeholders})",
+            table.name,
+            table.columns.join(", ")
+        );
+        tables.insert(table.name.clone(), ImportTable { query, types });
+    }
+
+    let conn_tx = conn.transaction().map_err(Error::StartTransaction)?;
+    for table in DUMP_TABLES.iter().rev() {
+        conn_tx
+            .execute(&format!("DELETE FROM {table}"), [])
+            .map_err(Error::ExecuteQuery)?;
+    }
+
+    let mut rows = 0;
+    loop {
+        let line = next_line()?;
+        if let Ok(end) = serde_json::from_str::<DumpEnd>(&line) {
+            if end.rows != rows {
+                return Err(Error::DumpFormat(format!(
+                    "expected {} rows, got {rows}",
+                    end.rows
+                )));
+            }
+            break;
+        }
+        let row: DumpRow = serde_json::from_str(&line).map_err(Error::DumpJson)?;
+        let table = tables
+            .get(&row.table)
+            .ok_or_else(|| Error::DumpFormat(format!("row of unknown table {}", row.table)))?;
+        if row.values.len() != table.types.len() {
+            return Err(Error::DumpFormat(format!(
+                "row of {} has {} values, expected {}",
+                row.table,
+                row.values.len(),
+                table.types.len()
+            )));
+        }
+        let values = row
+            .values
+            .into_iter()
+            .zip(table.types.iter())
+            .map(|(value, declared)| decode_value(value, declared))
+            .collect::<Result<Vec<_>, _>>()?;
+        conn_tx
+            .prepare_cached(&table.query)
+            .map_err(Error::PrepareQuery)?
+            .execute(params_from_iter(values))
+            .map_err(Error::ExecuteQuery)?;
+        rows += 1;
+    }
+    conn_tx.commit().map_err(Error::CommitTransaction)?;
+    info!("Imported {rows} rows from the dump");
+    Ok(rows)
+}
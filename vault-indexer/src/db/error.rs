@@ -11,6 +11,8 @@ use crate::{
 pub enum Error {
     #[error("Failed to open database: {0}")]
     Open(rusqlite::Error),
+    #[error("Failed to create database directory {0}: {1}")]
+    CreateDir(std::path::PathBuf, std::io::Error),
     #[error("Update SQlite pragma failure: {0}")]
     UpdatePragma(rusqlite::Error),
     #[error("Failed to create tables: {0}")]
@@ -49,6 +51,8 @@ pub enum Error {
     VaultTxNoInputs(Txid),
     #[error("Cannot find vault for given transaction {0}")]
     UnknownVaultTx(Txid),
+    #[error("Transaction {0} spends outputs of several vaults")]
+    AmbiguousVaultParents(Txid),
     #[error("Cannot find vault with given open transcation {0}")]
     UnknownVaultId(Txid),
     #[error("Cannot find UNIT related transction with txid {0}")]
@@ -59,4 +63,12 @@ pub enum Error {
     AssumeUnitTx(#[from] AssumeUnitTxErr),
     #[error("Failed to decode Bitcoin transaction from: {0:x?}, reason: {1}")]
     TransactionDecode(Vec<u8>, bitcoin::consensus::encode::Error),
+    #[error("Failed to read or write the dump: {0}")]
+    DumpIo(std::io::Error),
+    #[error("Cannot encode or decode JSON line of the dump: {0}")]
+    DumpJson(serde_json::Error),
+    #[error("Malformed dump: {0}")]
+    DumpFormat(String),
+    #[error("Unsupported dump version {0}, expected {DUMP_VERSION}", DUMP_VERSION = super::dump::DUMP_VERSION)]
+    DumpVersion(u32),
 }
@@ -1,9 +1,10 @@
 use super::error::Error;
+use super::loaders::{query_count, FieldDecode};
 use bitcoin::{
     block::Header,
-    consensus::{Decodable, Encodable},
+    consensus::{deserialize, serialize, Decodable, Encodable},
     hashes::Hash,
-    BlockHash,
+    Block, BlockHash,
 };
 use core::ops::FnMut;
 use rusqlite::{
@@ -26,9 +27,21 @@ pub trait DatabaseHeaders {
 
     /// Iterate all stored headers and call a closure for them
     fn load_block_headers<F>(&self, body: F) -> Result<(), Error>
+    where
+        F: FnMut(HeaderRecord),
+    {
+        self.load_block_headers_from(0, body)
+    }
+
+    /// Iterate stored headers (including forks) at or above the height and call a closure
+    /// for them
+    fn load_block_headers_from<F>(&self, height: u32, body: F) -> Result<(), Error>
     where
         F: FnMut(HeaderRecord);
 
+    /// Find the hash of the main chain block at the height
+    fn load_main_hash_at(&self, height: u32) -> Result<Option<BlockHash>, Error>;
+
     /// Stores the header in the database, doesn't mark it as longest chain, but checks that we have the parent in place.
     fn store_block_header(&mut self, header: Header) -> Result<(), Error> {
         let parent_header =
@@ -43,6 +56,31 @@ pub trait DatabaseHeaders {
 
     /// Stores the header without checking that we have the parent in the database
     fn store_raw_headers(&mut self, headers: &[(Header, i64, bool)]) -> Result<(), Error>;
+
+    /// Amount of stored headers, including forks
+    fn count_headers(&self) -> Result<u64, Error>;
+
+    /// Amount of stored headers that are not in the main chain
+    fn count_fork_headers(&self) -> Result<u64, Error>;
+
+    /// Iterate the main chain headers ordered by height and call a closure with their
+    /// height, hash and parent hash
+    fn load_main_chain_links<F>(&self, body: F) -> Result<(), Error>
+    where
+        F: FnMut(u32, BlockHash, BlockHash);
+
+    /// Find the height of the first main chain block with timestamp at or after the given one.
+    /// If there is no such block yet, returns the height of the next block after the tip.
+    ///
+    /// Note that block timestamps are not strictly monotonic, so the result is approximate
+    /// within the range of the median time rule.
+    fn height_for_time(&self, timestamp: u32) -> Result<u32, Error>;
+
+    /// Store the full block, replaces already stored one
+    fn store_block(&self, block: &Block) -> Result<(), Error>;
+
+    /// Find the full block stored with [DatabaseHeaders::store_block]
+    fn load_block(&self, block_hash: BlockHash) -> Result<Option<Block>, Error>;
 }
 
 impl DatabaseHeaders for Connection {
@@ -77,14 +115,14 @@ impl DatabaseHeaders for Connection {
         }
     }
 
-    fn load_block_headers<F>(&self, mut body: F) -> Result<(), Error>
+    fn load_block_headers_from<F>(&self, height: u32, mut body: F) -> Result<(), Error>
     where
         F: FnMut(HeaderRecord),
     {
-        let query = "SELECT height, raw, in_longest FROM headers";
+        let query = "SELECT height, raw, in_longest FROM headers WHERE height >= :height";
         let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
         let result = statement
-            .query_map([], |row| {
+            .query_map(named_params! { ":height": height }, |row| {
                 let height = row.get::<_, i64>(0)?;
                 let raw_header = row.get::<_, Vec<u8>>(1)?;
                 let in_longest = row.get::<_, i64>(2)?;
@@ -108,76 +146,192 @@ impl DatabaseHeaders for Connection {
         Ok(())
     }
 
-    fn store_raw_headers(&mut self, headers: &[(Header, i64, bool)]) -> Result<(), Error> {
-        // Size for one batch, tuned manually
-        const BATCH_SIZE: usize = 500;
+    fn load_main_hash_at(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        let query = "SELECT block_hash FROM headers WHERE height = :height AND in_longest = 1";
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let mut result = statement
+            .query_map(named_params! { ":height": height }, |row| {
+                row.field_decode(0)
+            })
+            .map_err(Error::ExecuteQuery)?;
+
+        if let Some(hash) = result.next() {
+            Ok(Some(hash.map_err(Error::FetchRow)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn count_headers(&self) -> Result<u64, Error> {
+        query_count(self, "SELECT count(*) FROM headers")
+    }
+
+    fn count_fork_headers(&self) -> Result<u64, Error> {
+        query_count(self, "SELECT count(*) FROM headers WHERE in_longest = 0")
+    }
+
+    fn load_main_chain_links<F>(&self, mut body: F) -> Result<(), Error>
+    where
+        F: FnMut(u32, BlockHash, BlockHash),
+    {
+        let query = r#"
+            SELECT height, block_hash, prev_block_hash FROM headers
+            WHERE in_longest = 1
+            ORDER BY height
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let result = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)? as u32,
+                    row.field_decode(1)?,
+                    row.field_decode(2)?,
+                ))
+            })
+            .map_err(Error::ExecuteQuery)?;
+
+        for link in result {
+            let (height, hash, prev_hash) = link.map_err(Error::FetchRow)?;
+            body(height, hash, prev_hash)
+        }
+        Ok(())
+    }
+
+    fn store_block(&self, block: &Block) -> Result<(), Error> {
+        let query = "INSERT OR REPLACE INTO blocks (block_hash, raw) VALUES (:block_hash, :raw)";
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        statement
+            .execute(named_params! {
+                ":block_hash": block.block_hash().as_raw_hash().as_byte_array(),
+                ":raw": serialize(block),
+            })
+            .map_err(Error::ExecuteQuery)?;
+        Ok(())
+    }
+
+    fn load_block(&self, block_hash: BlockHash) -> Result<Option<Block>, Error> {
+        let query = "SELECT raw FROM blocks WHERE block_hash = :block_hash LIMIT 1";
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let block_hash_bytes = block_hash.as_raw_hash().as_byte_array();
+        let mut result = statement
+            .query_map(named_params! { ":block_hash": block_hash_bytes }, |row| {
+                let raw_block = row.get::<_, Vec<u8>>(0)?;
+                deserialize(&raw_block).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(0, Type::Blob, Box::new(e))
+                })
+            })
+            .map_err(Error::ExecuteQuery)?;
 
+        if let Some(block) = result.next() {
+            Ok(Some(block.map_err(Error::FetchRow)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn height_for_time(&self, timestamp: u32) -> Result<u32, Error> {
+        let query = r#"
+            SELECT COALESCE(
+                (SELECT MIN(height) FROM headers WHERE in_longest = 1 AND time >= :timestamp),
+                (SELECT MAX(height) + 1 FROM headers WHERE in_longest = 1),
+                0
+            )
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let mut rows = statement
+            .query_map(named_params! { ":timestamp": timestamp }, |row| {
+                row.get::<_, u32>(0)
+            })
+            .map_err(Error::ExecuteQuery)?;
+
+        if let Some(row) = rows.next() {
+            Ok(row.map_err(Error::FetchRow)?)
+        } else {
+            Err(Error::ShouldExecuteOneRow(query.to_owned()))
+        }
+    }
+
+    fn store_raw_headers(&mut self, headers: &[(Header, i64, bool)]) -> Result<(), Error> {
         // The shared transaction for all batches
         let tx = self.transaction().map_err(Error::StartTransaction)?;
+        insert_raw_headers(&tx, headers)?;
 
-        let mut start = 0;
-        while start < headers.len() {
-            let end = (start + BATCH_SIZE).min(headers.len());
-            let batch = &headers[start..end];
+        // Finish this mayhem
+        tx.commit().map_err(Error::CommitTransaction)?;
+        Ok(())
+    }
+}
+
+/// Batched insert of the headers for [DatabaseHeaders::store_raw_headers] that runs inside
+/// the transaction opened by the caller.
+pub(crate) fn insert_raw_headers(
+    tx: &Connection,
+    headers: &[(Header, i64, bool)],
+) -> Result<(), Error> {
+    // Size for one batch, tuned manually
+    const BATCH_SIZE: usize = 500;
 
-            // Start making the batched SQL query
-            let mut sql = String::from(
-                r#"
-                INSERT INTO headers (block_hash, height, prev_block_hash, raw, in_longest)
+    let mut start = 0;
+    while start < headers.len() {
+        let end = (start + BATCH_SIZE).min(headers.len());
+        let batch = &headers[start..end];
+
+        // Start making the batched SQL query
+        let mut sql = String::from(
+            r#"
+                INSERT INTO headers (block_hash, height, prev_block_hash, raw, in_longest, time)
                 VALUES
                 "#,
-            );
-
-            // Collecting N parts "(?, ?, ?, ?, ?)" batch.len() times
-            let mut values_placeholders = Vec::with_capacity(batch.len());
-            for _ in batch {
-                values_placeholders.push("(?, ?, ?, ?, ?)".to_string());
-            }
-            sql.push_str(&values_placeholders.join(", "));
-
-            // Finish query with on conflict part
-            sql.push_str(
-                r#"
+        );
+
+        // Collecting N parts "(?, ?, ?, ?, ?, ?)" batch.len() times
+        let mut values_placeholders = Vec::with_capacity(batch.len());
+        for _ in batch {
+            values_placeholders.push("(?, ?, ?, ?, ?, ?)".to_string());
+        }
+        sql.push_str(&values_placeholders.join(", "));
+
+        // Finish query with on conflict part
+        sql.push_str(
+            r#"
                 ON CONFLICT(block_hash)
                     DO UPDATE SET
-                        in_longest = excluded.in_longest
+                        in_longest = excluded.in_longest,
+                        time = excluded.time
                 "#,
-            );
-
-            let mut stmt = tx.prepare(&sql).map_err(Error::PrepareQuery)?;
-
-            // Collect all parameters
-            let mut params = Vec::with_capacity(batch.len() * 5); // 5 fields per record
-            for (header, height, in_longest) in batch {
-                // Encoding header
-                const HEADER_SIZE: usize = 80;
-                let mut raw = vec![0u8; HEADER_SIZE];
-                header
-                    .consensus_encode(&mut Cursor::new(&mut raw))
-                    .map_err(Error::EncodeHeader)?;
-
-                let prev_hash = header.prev_blockhash;
-
-                // Fill in the same order as (?,?,?,?,?)
-                params.push(Value::Blob(
-                    header.block_hash().as_raw_hash().as_byte_array().to_vec(),
-                ));
-                params.push(Value::Integer(*height));
-                params.push(Value::Blob(
-                    prev_hash.as_raw_hash().as_byte_array().to_vec(),
-                ));
-                params.push(Value::Blob(raw));
-                params.push(Value::Integer(if *in_longest { 1 } else { 0 }));
-            }
-
-            // Bulk insert here
-            stmt.execute(params_from_iter(params))
-                .map_err(Error::ExecuteQuery)?;
-            start = end;
+        );
+
+        let mut stmt = tx.prepare(&sql).map_err(Error::PrepareQuery)?;
+
+        // Collect all parameters
+        let mut params = Vec::with_capacity(batch.len() * 6); // 6 fields per record
+        for (header, height, in_longest) in batch {
+            // Encoding header
+            const HEADER_SIZE: usize = 80;
+            let mut raw = vec![0u8; HEADER_SIZE];
+            header
+                .consensus_encode(&mut Cursor::new(&mut raw))
+                .map_err(Error::EncodeHeader)?;
+
+            let prev_hash = header.prev_blockhash;
+
+            // Fill in the same order as (?,?,?,?,?,?)
+            params.push(Value::Blob(
+                header.block_hash().as_raw_hash().as_byte_array().to_vec(),
+            ));
+            params.push(Value::Integer(*height));
+            params.push(Value::Blob(
+                prev_hash.as_raw_hash().as_byte_array().to_vec(),
+            ));
+            params.push(Value::Blob(raw));
+            params.push(Value::Integer(if *in_longest { 1 } else { 0 }));
+            params.push(Value::Integer(header.time as i64));
         }
 
-        // Finish this mayhem
-        tx.commit().map_err(Error::CommitTransaction)?;
-        Ok(())
+        // Bulk insert here
+        stmt.execute(params_from_iter(params))
+            .map_err(Error::ExecuteQuery)?;
+        start = end;
     }
+    Ok(())
 }
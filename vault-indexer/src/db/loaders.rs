@@ -1,10 +1,13 @@
 use super::Error;
-use crate::vault::{VaultAction, VaultVersion};
+use crate::vault::{LiquidationHash, VaultAction, VaultVersion, LIQUIDATION_HASH_LEN};
 use bitcoin::consensus::Decodable;
 use bitcoin::hashes::Hash;
 use bitcoin::{BlockHash, Transaction, Txid};
 use core::str::FromStr;
-use rusqlite::{types::Type, Row};
+use rusqlite::{
+    types::{Type, ValueRef},
+    Connection, Row,
+};
 use std::io::Cursor;
 
 // Helper that extracts T from field in row. Need separate trait as orphan
@@ -41,6 +44,15 @@ impl FieldDecode<BlockHash> for Row<'_> {
     }
 }
 
+impl FieldDecode<Option<BlockHash>> for Row<'_> {
+    fn field_decode(&self, index: usize) -> Result<Option<BlockHash>, rusqlite::Error> {
+        match self.get_ref(index)? {
+            ValueRef::Null => Ok(None),
+            _ => FieldDecode::<BlockHash>::field_decode(self, index).map(Some),
+        }
+    }
+}
+
 impl FieldDecode<VaultVersion> for Row<'_> {
     fn field_decode(&self, index: usize) -> Result<VaultVersion, rusqlite::Error> {
         let version_str = self.get::<_, String>(index)?;
@@ -61,33 +73,25 @@ impl FieldDecode<VaultAction> for Row<'_> {
     }
 }
 
-impl FieldDecode<[u8; 20]> for Row<'_> {
-    fn field_decode(&self, index: usize) -> Result<[u8; 20], rusqlite::Error> {
+impl FieldDecode<LiquidationHash> for Row<'_> {
+    fn field_decode(&self, index: usize) -> Result<LiquidationHash, rusqlite::Error> {
         let bytes = self.get::<_, Vec<u8>>(index)?;
-        let bytes_sized = bytes.clone().try_into().map_err(|_| {
+        LiquidationHash::from_slice(&bytes).map_err(|_| {
             rusqlite::Error::FromSqlConversionFailure(
                 index,
                 Type::Blob,
-                Box::new(Error::ArrayWrongSize(20, bytes)),
+                Box::new(Error::ArrayWrongSize(LIQUIDATION_HASH_LEN, bytes)),
             )
-        })?;
-        Ok(bytes_sized)
+        })
     }
 }
 
-impl FieldDecode<Option<[u8; 20]>> for Row<'_> {
-    fn field_decode(&self, index: usize) -> Result<Option<[u8; 20]>, rusqlite::Error> {
-        let mbytes = self.get::<_, Option<Vec<u8>>>(index)?;
-        let mbytes_sized = mbytes.map(|bytes| {
-            bytes.clone().try_into().map_err(|_| {
-                rusqlite::Error::FromSqlConversionFailure(
-                    index,
-                    Type::Blob,
-                    Box::new(Error::ArrayWrongSize(20, bytes)),
-                )
-            })
-        });
-        invert(mbytes_sized)
+impl FieldDecode<Option<LiquidationHash>> for Row<'_> {
+    fn field_decode(&self, index: usize) -> Result<Option<LiquidationHash>, rusqlite::Error> {
+        match self.get_ref(index)? {
+            ValueRef::Null => Ok(None),
+            _ => FieldDecode::<LiquidationHash>::field_decode(self, index).map(Some),
+        }
     }
 }
 
@@ -128,6 +132,14 @@ impl<'a> FieldEncode for &'a BlockHash {
     }
 }
 
+impl<'a> FieldEncode for &'a LiquidationHash {
+    type SqlRepr = &'a [u8];
+
+    fn field_encode(&self) -> &'a [u8] {
+        &self.as_bytes()[..]
+    }
+}
+
 impl FieldEncode for VaultAction {
     type SqlRepr = String;
 
@@ -140,3 +152,16 @@ impl FieldEncode for VaultAction {
 pub fn invert<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
     x.map_or(Ok(None), |v| v.map(Some))
 }
+
+/// Execute the query that returns single count (e.g. `SELECT count(*) FROM ...`)
+pub fn query_count(conn: &Connection, query: &str) -> Result<u64, Error> {
+    let mut statement = conn.prepare_cached(query).map_err(Error::PrepareQuery)?;
+    let mut rows = statement
+        .query_map([], |row| row.get::<_, i64>(0))
+        .map_err(Error::ExecuteQuery)?;
+    if let Some(row) = rows.next() {
+        Ok(row.map_err(Error::FetchRow)? as u64)
+    } else {
+        Err(Error::ShouldExecuteOneRow(query.to_owned()))
+    }
+}
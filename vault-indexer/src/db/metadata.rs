@@ -1,6 +1,7 @@
-use crate::Network;
+use crate::{BlockHeight, Network, ScannedHeight};
 
 use super::error::Error;
+use super::loaders::{FieldDecode, FieldEncode};
 use bitcoin::{hashes::Hash, BlockHash};
 use core::convert::TryInto;
 use rusqlite::{named_params, types::Type, Connection};
@@ -10,7 +11,22 @@ use std::str::FromStr;
 pub struct DbMetadata {
     pub network: Network,
     pub tip_block_hash: BlockHash,
-    pub scanned_height: u32,
+    pub scanned_height: ScannedHeight,
+}
+
+/// Persistent record of single handled chain reorganization
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReorgRecord {
+    /// UNIX timestamp when the reorganization was handled
+    pub timestamp: u64,
+    pub old_tip: BlockHash,
+    pub new_tip: BlockHash,
+    /// Height of the last common block of the old and new chains
+    pub fork_height: u32,
+    /// Amount of vault transactions that left the main chain
+    pub vault_txs: u32,
+    /// Amount of UNIT transactions that left the main chain
+    pub unit_txs: u32,
 }
 
 pub trait DatabaseMeta {
@@ -24,10 +40,25 @@ pub trait DatabaseMeta {
     fn set_best_tip(&self, tip: BlockHash) -> Result<(), Error>;
 
     /// Get current scanned height
-    fn get_scanned_height(&self) -> Result<u32, Error>;
+    fn get_scanned_height(&self) -> Result<ScannedHeight, Error>;
 
     /// Update the scanned height (until which block we searched the blockchain)
-    fn set_scanned_height(&self, height: u32) -> Result<(), Error>;
+    fn set_scanned_height(&self, height: ScannedHeight) -> Result<(), Error>;
+
+    /// Remember that the block at the `height` is fully scanned and advance the scanned height
+    /// while the next main chain block is scanned too. Returns the new scanned height.
+    fn mark_block_scanned(
+        &self,
+        height: BlockHeight,
+        block_hash: BlockHash,
+    ) -> Result<ScannedHeight, Error>;
+
+    /// Check if the block was scanned. Blocks at or below the scanned height are not tracked
+    /// individually.
+    fn is_block_scanned(&self, block_hash: BlockHash) -> Result<bool, Error>;
+
+    /// Forget all blocks that are scanned above the scanned height
+    fn drop_scanned_blocks(&self) -> Result<(), Error>;
 
     /// Returns true if we have single row in metadata table
     fn has_metadata(&self) -> Result<bool, Error>;
@@ -37,6 +68,12 @@ pub trait DatabaseMeta {
 
     // Fetch all metadata from table
     fn load_metada(&self) -> Result<DbMetadata, Error>;
+
+    /// Append the reorganization to the log
+    fn record_reorg(&self, record: &ReorgRecord) -> Result<(), Error>;
+
+    /// All logged reorganizations from the oldest to the newest
+    fn load_reorgs(&self) -> Result<Vec<ReorgRecord>, Error>;
 }
 
 impl DatabaseMeta for Connection {
@@ -56,17 +93,71 @@ impl DatabaseMeta for Connection {
         self.store_metadata(&meta)
     }
 
-    fn get_scanned_height(&self) -> Result<u32, Error> {
+    fn get_scanned_height(&self) -> Result<ScannedHeight, Error> {
         let meta = self.load_metada()?;
         Ok(meta.scanned_height)
     }
 
-    fn set_scanned_height(&self, height: u32) -> Result<(), Error> {
+    fn set_scanned_height(&self, height: ScannedHeight) -> Result<(), Error> {
         let mut meta = self.load_metada()?;
         meta.scanned_height = height;
         self.store_metadata(&meta)
     }
 
+    fn mark_block_scanned(
+        &self,
+        height: BlockHeight,
+        block_hash: BlockHash,
+    ) -> Result<ScannedHeight, Error> {
+        let query = "INSERT OR REPLACE INTO scanned_blocks VALUES(:block_hash, :height)";
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        statement
+            .execute(named_params! {
+                ":block_hash": &block_hash.as_raw_hash().as_byte_array()[..],
+                ":height": height.get() as i64,
+            })
+            .map_err(Error::ExecuteQuery)?;
+
+        // Blocks from forks can be left in the table, so only main chain ones count
+        let query = r#"
+            SELECT 1 FROM scanned_blocks s
+            INNER JOIN headers h ON s.block_hash = h.block_hash
+            WHERE s.height = :height AND h.in_longest = 1
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let old_height = self.get_scanned_height()?.get();
+        let mut scanned_height = old_height;
+        while statement
+            .exists(named_params! {":height": scanned_height as i64 + 1})
+            .map_err(Error::ExecuteQuery)?
+        {
+            scanned_height += 1;
+        }
+        if scanned_height != old_height {
+            self.set_scanned_height(ScannedHeight(scanned_height))?;
+            self.execute(
+                "DELETE FROM scanned_blocks WHERE height <= ?1",
+                [scanned_height as i64],
+            )
+            .map_err(Error::ExecuteQuery)?;
+        }
+        Ok(ScannedHeight(scanned_height))
+    }
+
+    fn is_block_scanned(&self, block_hash: BlockHash) -> Result<bool, Error> {
+        let query = "SELECT 1 FROM scanned_blocks WHERE block_hash = ?1";
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        statement
+            .exists([&block_hash.as_raw_hash().as_byte_array()[..]])
+            .map_err(Error::ExecuteQuery)
+    }
+
+    fn drop_scanned_blocks(&self) -> Result<(), Error> {
+        self.execute("DELETE FROM scanned_blocks", [])
+            .map_err(Error::ExecuteQuery)?;
+        Ok(())
+    }
+
     fn has_metadata(&self) -> Result<bool, Error> {
         let query = "SELECT count(id) as count FROM metadata";
         let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
@@ -97,7 +188,7 @@ impl DatabaseMeta for Connection {
             .execute(named_params! {
                 ":network": meta.network.to_str(),
                 ":tip_block_hash": &meta.tip_block_hash.as_raw_hash().as_byte_array()[..],
-                ":scanned_height": meta.scanned_height as i64,
+                ":scanned_height": meta.scanned_height.get() as i64,
             })
             .map_err(Error::ExecuteQuery)?;
         Ok(())
@@ -127,7 +218,7 @@ impl DatabaseMeta for Connection {
                 Ok(DbMetadata {
                     network,
                     tip_block_hash,
-                    scanned_height: scanned_height as u32,
+                    scanned_height: ScannedHeight(scanned_height as u32),
                 })
             })
             .map_err(Error::ExecuteQuery)?;
@@ -138,4 +229,44 @@ impl DatabaseMeta for Connection {
             Err(Error::NoMetadata)
         }
     }
+
+    fn record_reorg(&self, record: &ReorgRecord) -> Result<(), Error> {
+        let query = r#"
+            INSERT INTO reorgs (timestamp, old_tip, new_tip, fork_height, vault_txs, unit_txs)
+            VALUES (:timestamp, :old_tip, :new_tip, :fork_height, :vault_txs, :unit_txs)
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        statement
+            .execute(named_params! {
+                ":timestamp": record.timestamp as i64,
+                ":old_tip": (&record.old_tip).field_encode(),
+                ":new_tip": (&record.new_tip).field_encode(),
+                ":fork_height": record.fork_height,
+                ":vault_txs": record.vault_txs,
+                ":unit_txs": record.unit_txs,
+            })
+            .map_err(Error::ExecuteQuery)?;
+        Ok(())
+    }
+
+    fn load_reorgs(&self) -> Result<Vec<ReorgRecord>, Error> {
+        let query = r#"
+            SELECT timestamp, old_tip, new_tip, fork_height, vault_txs, unit_txs
+            FROM reorgs ORDER BY id ASC
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok(ReorgRecord {
+                    timestamp: row.get::<_, i64>(0)? as u64,
+                    old_tip: row.field_decode(1)?,
+                    new_tip: row.field_decode(2)?,
+                    fork_height: row.get(3)?,
+                    vault_txs: row.get(4)?,
+                    unit_txs: row.get(5)?,
+                })
+            })
+            .map_err(Error::ExecuteQuery)?;
+        rows.collect::<Result<_, _>>().map_err(Error::FetchRow)
+    }
 }
@@ -1,31 +1,80 @@
+pub mod dump;
 pub mod error;
 pub mod header;
 pub mod loaders;
 pub mod metadata;
 pub mod vault;
 
-use crate::db::vault::{DatabaseRune, DatabaseVault};
-use crate::Network;
+use crate::db::vault::{rebuild_volume_buckets, DatabaseRune, DatabaseVault, VOLUME_BUCKET_WIDTH};
+use crate::{Network, ScannedHeight};
 pub use error::Error;
 pub use header::*;
 use log::*;
 pub use metadata::*;
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
 use std::path::Path;
+use std::time::Duration;
+
+/// How long a query waits for the lock held by other connection (e.g. the writer or
+/// a WAL checkpoint) before failing with `SQLITE_BUSY`
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Memory SQLite uses for the database pages. Both limits are per connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Size of the page cache in KiB. The cache is allocated on demand and lives in the
+    /// process heap, so it adds to the resident memory of the indexer.
+    pub cache_size_kib: u64,
+    /// How many bytes of the database file are memory mapped, 0 disables mmap. Mapped pages
+    /// are shared with the OS page cache and can be evicted under memory pressure.
+    pub mmap_size: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            cache_size_kib: 64 * 1024,
+            mmap_size: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Apply the cache limits to the connection
+fn set_cache_pragmas(connection: &Connection, cache: CacheConfig) -> Result<(), Error> {
+    // Negative value is the size in KiB instead of amount of pages
+    connection
+        .pragma_update(None, "cache_size", -(cache.cache_size_kib as i64))
+        .map_err(Error::UpdatePragma)?;
+    connection
+        .pragma_update(None, "mmap_size", cache.mmap_size as i64)
+        .map_err(Error::UpdatePragma)?;
+    Ok(())
+}
 
 pub fn initialize_db<P: AsRef<Path>>(
     filename: P,
     network: Network,
-    start_height: u32,
+    start_height: ScannedHeight,
     rescan: bool,
+    cache: CacheConfig,
 ) -> Result<Connection, Error> {
     trace!("Opening database {:?}", filename.as_ref());
     let mut connection = if filename.as_ref().to_str() == Some(":memory:") {
         Connection::open_in_memory().map_err(Error::Open)?
     } else {
+        // SQLite creates the file, but not the missing directories on its path
+        if let Some(dir) = filename.as_ref().parent() {
+            if !dir.as_os_str().is_empty() && !dir.is_dir() {
+                std::fs::create_dir_all(dir).map_err(|e| Error::CreateDir(dir.to_owned(), e))?;
+            }
+        }
         Connection::open(filename).map_err(Error::Open)?
     };
 
+    connection
+        .busy_timeout(BUSY_TIMEOUT)
+        .map_err(Error::UpdatePragma)?;
+
     trace!("Settings pragmas");
     // Keep temporary tables in memory to speed up copying of big blobs
     connection
@@ -43,6 +92,7 @@ pub fn initialize_db<P: AsRef<Path>>(
     connection
         .pragma_update(None, "journal_size_limit", "6144000")
         .map_err(Error::UpdatePragma)?;
+    set_cache_pragmas(&connection, cache)?;
 
     trace!("Creation of schema");
     let query = r#"
@@ -51,12 +101,19 @@ pub fn initialize_db<P: AsRef<Path>>(
                 height              INTEGER NOT NULL,
                 prev_block_hash     BLOB(32) NOT NULL,
                 raw                 BLOB NOT NULL,
-                in_longest          INTEGER NOT NULL
+                in_longest          INTEGER NOT NULL,
+                time                INTEGER NOT NULL DEFAULT 0 -- Block timestamp duplicated from raw header for queries
             );
 
             CREATE INDEX IF NOT EXISTS idx_headers_prev_hash ON headers(prev_block_hash);
             CREATE INDEX IF NOT EXISTS idx_headers_height ON headers(height);
 
+            -- Full blocks stored only for the configured heights window for debugging
+            CREATE TABLE IF NOT EXISTS blocks(
+                block_hash          BLOB(32) NOT NULL PRIMARY KEY,
+                raw                 BLOB NOT NULL
+            );
+
             CREATE TABLE IF NOT EXISTS metadata(
                 id INTEGER PRIMARY KEY CHECK (id = 0), -- The table has only one row
                 network TEXT NOT NULL,
@@ -64,8 +121,27 @@ pub fn initialize_db<P: AsRef<Path>>(
                 scanned_height INTEGER NOT NULL
             );
 
+            -- Blocks above the scanned height that are already scanned, they arrive out of order
+            CREATE TABLE IF NOT EXISTS scanned_blocks(
+                block_hash          BLOB(32) NOT NULL PRIMARY KEY,
+                height              INTEGER NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_scanned_blocks_height ON scanned_blocks(height);
+
+            -- Append only log of handled chain reorganizations
+            CREATE TABLE IF NOT EXISTS reorgs(
+                id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp           INTEGER NOT NULL, -- UNIX time when the reorganization was handled
+                old_tip             BLOB(32) NOT NULL,
+                new_tip             BLOB(32) NOT NULL,
+                fork_height         INTEGER NOT NULL,
+                vault_txs           INTEGER NOT NULL, -- Inactivated vault transactions
+                unit_txs            INTEGER NOT NULL  -- Inactivated UNIT transactions
+            );
+
             CREATE TABLE IF NOT EXISTS vaults(
-                open_txid           BLOB(32) NOT NULL PRIMARY KEY, -- Assume that we cannot have two vaults operations in single tx
+                open_txid           BLOB(32) NOT NULL PRIMARY KEY, -- Vault is identified by its opening tx, reprocessing of the tx resets the row
                 output              INTEGER NOT NULL,
                 balance             INTEGER NOT NULL,
                 oracle_price        INTEGER NOT NULL,
@@ -73,9 +149,15 @@ pub fn initialize_db<P: AsRef<Path>>(
                 liquidation_price   INTEGER,
                 liquidation_hash    BLOB(32),
                 custody             INTEGER NOT NULL,
-                last_tx             BLOB(32) NOT NULL
+                last_tx             BLOB(32) NOT NULL,
+                closed_at_height    INTEGER, -- Set when the debt is repaid and all BTC withdrawn
+                payload_in_annex    INTEGER NOT NULL DEFAULT 0, -- The opening tx has the payload in the annex, `output` is 0 then
+                in_longest          INTEGER NOT NULL DEFAULT 1 -- The opening tx is in the main chain, the vault is hidden otherwise
             );
 
+            CREATE INDEX IF NOT EXISTS idx_vaults_custody ON vaults(custody);
+            CREATE INDEX IF NOT EXISTS idx_vaults_balance ON vaults(balance);
+
             CREATE TABLE IF NOT EXISTS transactions(
                 txid                BLOB(32) NOT NULL PRIMARY KEY, -- Assume that we cannot have two vaults operations in single tx
                 output              INTEGER NOT NULL,
@@ -98,6 +180,10 @@ pub fn initialize_db<P: AsRef<Path>>(
                 unit_volume         INTEGER NOT NULL, -- Assume that balance delta is units volume
                 btc_volume          INTEGER NOT NULL, -- Assume that BTC volume is sum of other outputs minus change (non tap outputs) and custody counts only for opening transaction
                 prev_tx             BLOB(32),
+                -- Shape of the transaction, NULL for transactions stored by older versions
+                num_inputs          INTEGER,
+                num_outputs         INTEGER,
+                payload_in_annex    INTEGER NOT NULL DEFAULT 0, -- The payload is in the annex of the first input, `output` is 0 then
 
                 FOREIGN KEY (vault_txid) REFERENCES vaults(open_txid),
                 FOREIGN KEY (block_hash) REFERENCES headers(block_hash),
@@ -110,46 +196,342 @@ pub fn initialize_db<P: AsRef<Path>>(
             CREATE INDEX IF NOT EXISTS idx_transactions_height_in_longest ON transactions(height, in_longest);
             CREATE INDEX IF NOT EXISTS idx_transactions_block_hash ON transactions(block_hash);
             CREATE INDEX IF NOT EXISTS idx_transactions_in_longest ON transactions(in_longest);
+            CREATE INDEX IF NOT EXISTS idx_transactions_liquidation_hash ON transactions(liquidation_hash);
 
             -- We will index all transactions which has runestones with UNIT rune
             CREATE TABLE IF NOT EXISTS transactions_runes(
                 txid                BLOB(32) NOT NULL PRIMARY KEY,
                 raw_tx              BLOB NOT NULL,
-                unit_amount         INTEGER NOT NULL
+                unit_amount         INTEGER NOT NULL,
+                -- Block context, NULL for transactions stored by older versions
+                block_hash          BLOB(32),
+                height              INTEGER,
+                in_longest          INTEGER NOT NULL DEFAULT 1
             );
         "#;
     connection
         .execute_batch(query)
         .map_err(Error::CreateSchema)?;
 
+    // Check the network before any writes, the rescan below wipes indexed data
+    if connection.has_metadata()? {
+        let db_network = connection.get_network()?;
+        if network != db_network {
+            return Err(Error::DatabaseNetworkMismatch(db_network, network));
+        }
+    }
+    migrate_headers_time(&mut connection)?;
+    migrate_vaults_closed(&connection)?;
+    migrate_volume_buckets(&connection)?;
+    migrate_payload_in_annex(&connection)?;
+    migrate_vaults_in_longest(&connection)?;
+    add_missing_column(&connection, "transactions", "num_inputs", "INTEGER")?;
+    add_missing_column(&connection, "transactions", "num_outputs", "INTEGER")?;
+    add_missing_column(&connection, "transactions_runes", "block_hash", "BLOB(32)")?;
+    add_missing_column(&connection, "transactions_runes", "height", "INTEGER")?;
+    add_missing_column(
+        &connection,
+        "transactions_runes",
+        "in_longest",
+        "INTEGER NOT NULL DEFAULT 1",
+    )?;
+    connection
+        .execute_batch(
+            r#"
+            CREATE INDEX IF NOT EXISTS idx_headers_time ON headers(time);
+            CREATE INDEX IF NOT EXISTS idx_transactions_runes_block_hash ON transactions_runes(block_hash);
+            "#,
+        )
+        .map_err(Error::CreateSchema)?;
+
+    // Genesis and metadata are stored atomically, otherwise a crash between them leaves
+    // the database without metadata
+    let tx = connection.transaction().map_err(Error::StartTransaction)?;
+
     // Store genesis hash to initiate main chain
     let genesis = network.genesis_header();
-    if connection
-        .load_block_header(genesis.block_hash())?
-        .is_none()
-    {
-        connection.store_raw_headers(&[(genesis, 0i64, true)])?;
+    if tx.load_block_header(genesis.block_hash())?.is_none() {
+        insert_raw_headers(&tx, &[(genesis, 0i64, true)])?;
     }
 
     // Store initial metadata if missing
-    if !connection.has_metadata()? {
-        connection.store_metadata(&DbMetadata {
+    if !tx.has_metadata()? {
+        tx.store_metadata(&DbMetadata {
             network,
             tip_block_hash: genesis.block_hash(),
             scanned_height: start_height,
         })?;
-    } else {
-        let db_network = connection.get_network()?;
-        if network != db_network {
-            return Err(Error::DatabaseNetworkMismatch(db_network, network));
-        }
     }
+    tx.commit().map_err(Error::CommitTransaction)?;
 
     if rescan {
         connection.drop_vaults()?;
         connection.drop_unit_index()?;
+        connection.drop_scanned_blocks()?;
         connection.set_scanned_height(start_height)?;
     }
 
     Ok(connection)
 }
+
+/// Open the database created by [initialize_db] for queries only. The schema isn't created
+/// or migrated, all writes fail.
+pub fn open_db_read_only<P: AsRef<Path>>(
+    filename: P,
+    network: Network,
+    cache: CacheConfig,
+) -> Result<Connection, Error> {
+    trace!("Opening database {:?} in read-only mode", filename.as_ref());
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+        | OpenFlags::SQLITE_OPEN_URI
+        | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    let connection = Connection::open_with_flags(filename, flags).map_err(Error::Open)?;
+    connection
+        .busy_timeout(BUSY_TIMEOUT)
+        .map_err(Error::UpdatePragma)?;
+    set_cache_pragmas(&connection, cache)?;
+    let db_network = connection.get_network()?;
+    if network != db_network {
+        return Err(Error::DatabaseNetworkMismatch(db_network, network));
+    }
+    Ok(connection)
+}
+
+/// Add the column to the table of the database created by older version. Returns `true`
+/// if the column was missing.
+fn add_missing_column(
+    connection: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<bool, Error> {
+    let exists = connection
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")
+        .map_err(Error::PrepareQuery)?
+        .exists([table, column])
+        .map_err(Error::ExecuteQuery)?;
+    if exists {
+        return Ok(false);
+    }
+
+    info!("Migrating {table} table: adding {column} column");
+    connection
+        .execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            [],
+        )
+        .map_err(Error::CreateSchema)?;
+    Ok(true)
+}
+
+/// Databases created before the headers `time` column existed need the column added and
+/// filled from the raw headers.
+fn migrate_headers_time(connection: &mut Connection) -> Result<(), Error> {
+    if !add_missing_column(connection, "headers", "time", "INTEGER NOT NULL DEFAULT 0")? {
+        return Ok(());
+    }
+    let mut headers = vec![];
+    connection.load_block_headers(|record| {
+        headers.push((record.header, record.height as i64, record.in_longest))
+    })?;
+    // Conflicting inserts update the time of existing rows
+    connection.store_raw_headers(&headers)?;
+    Ok(())
+}
+
+/// Mark already closed vaults with the height of their last transaction
+fn migrate_vaults_closed(connection: &Connection) -> Result<(), Error> {
+    if !add_missing_column(connection, "vaults", "closed_at_height", "INTEGER")? {
+        return Ok(());
+    }
+    connection
+        .execute(
+            r#"
+            UPDATE vaults SET closed_at_height = (
+                SELECT height FROM transactions WHERE txid = vaults.last_tx
+            )
+            WHERE balance = 0 AND custody = 0
+            "#,
+            [],
+        )
+        .map_err(Error::ExecuteQuery)?;
+    Ok(())
+}
+
+/// Hide vaults whose opening transaction was disconnected by a reorganization before the
+/// flag existed
+fn migrate_vaults_in_longest(connection: &Connection) -> Result<(), Error> {
+    if !add_missing_column(
+        connection,
+        "vaults",
+        "in_longest",
+        "INTEGER NOT NULL DEFAULT 1",
+    )? {
+        return Ok(());
+    }
+    connection
+        .execute(
+            r#"
+            UPDATE vaults SET in_longest = 0
+            WHERE open_txid IN (SELECT txid FROM transactions WHERE in_longest = 0)
+            "#,
+            [],
+        )
+        .map_err(Error::ExecuteQuery)?;
+    Ok(())
+}
+
+/// Older versions stored payloads in the annex with `u32::MAX` output number, move them to
+/// the flag
+fn migrate_payload_in_annex(connection: &Connection) -> Result<(), Error> {
+    for table in ["transactions", "vaults"] {
+        if !add_missing_column(
+            connection,
+            table,
+            "payload_in_annex",
+            "INTEGER NOT NULL DEFAULT 0",
+        )? {
+            continue;
+        }
+        connection
+            .execute(
+                &format!(
+                    "UPDATE {table} SET output = 0, payload_in_annex = 1 WHERE output = {}",
+                    u32::MAX
+                ),
+                [],
+            )
+            .map_err(Error::ExecuteQuery)?;
+    }
+    Ok(())
+}
+
+/// Create the table of per hour volumes with the triggers that keep it in sync with the
+/// transactions. Databases created before the table existed get it filled from the stored
+/// transactions.
+fn migrate_volume_buckets(connection: &Connection) -> Result<(), Error> {
+    let exists = connection
+        .prepare("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'volume_buckets'")
+        .map_err(Error::PrepareQuery)?
+        .exists([])
+        .map_err(Error::ExecuteQuery)?;
+    // Every write to the transactions (new blocks, moves of reorged transactions, recomputed
+    // volumes, rescans and imports) passes through the triggers, so the buckets never go stale.
+    // Reorgs only flip `in_longest` and the aggregates count transactions of forks too.
+    let query = format!(
+        r#"
+        CREATE TABLE IF NOT EXISTS volume_buckets(
+            action              TEXT NOT NULL,
+            bucket              INTEGER NOT NULL, -- Oracle timestamp rounded down to {width} seconds
+            tx_count            INTEGER NOT NULL,
+            unit_volume         INTEGER NOT NULL, -- Sum of absolute volumes
+            btc_volume          INTEGER NOT NULL, -- Sum of absolute volumes
+            PRIMARY KEY (action, bucket)
+        );
+
+        CREATE TRIGGER IF NOT EXISTS volume_buckets_insert AFTER INSERT ON transactions
+        BEGIN
+            INSERT INTO volume_buckets VALUES(
+                NEW.action, (NEW.oracle_timestamp / {width}) * {width}, 1,
+                abs(NEW.unit_volume), abs(NEW.btc_volume))
+            ON CONFLICT(action, bucket) DO UPDATE SET
+                tx_count = tx_count + 1,
+                unit_volume = unit_volume + excluded.unit_volume,
+                btc_volume = btc_volume + excluded.btc_volume;
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS volume_buckets_delete AFTER DELETE ON transactions
+        BEGIN
+            UPDATE volume_buckets SET
+                tx_count = tx_count - 1,
+                unit_volume = unit_volume - abs(OLD.unit_volume),
+                btc_volume = btc_volume - abs(OLD.btc_volume)
+            WHERE action = OLD.action AND bucket = (OLD.oracle_timestamp / {width}) * {width};
+            DELETE FROM volume_buckets WHERE action = OLD.action
+                AND bucket = (OLD.oracle_timestamp / {width}) * {width} AND tx_count <= 0;
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS volume_buckets_update
+        AFTER UPDATE OF action, oracle_timestamp, unit_volume, btc_volume ON transactions
+        BEGIN
+            UPDATE volume_buckets SET
+                tx_count = tx_count - 1,
+                unit_volume = unit_volume - abs(OLD.unit_volume),
+                btc_volume = btc_volume - abs(OLD.btc_volume)
+            WHERE action = OLD.action AND bucket = (OLD.oracle_timestamp / {width}) * {width};
+            DELETE FROM volume_buckets WHERE action = OLD.action
+                AND bucket = (OLD.oracle_timestamp / {width}) * {width} AND tx_count <= 0;
+            INSERT INTO volume_buckets VALUES(
+                NEW.action, (NEW.oracle_timestamp / {width}) * {width}, 1,
+                abs(NEW.unit_volume), abs(NEW.btc_volume))
+            ON CONFLICT(action, bucket) DO UPDATE SET
+                tx_count = tx_count + 1,
+                unit_volume = unit_volume + excluded.unit_volume,
+                btc_volume = btc_volume + excluded.btc_volume;
+        END;
+        "#,
+        width = VOLUME_BUCKET_WIDTH
+    );
+    connection
+        .execute_batch(&query)
+        .map_err(Error::CreateSchema)?;
+    if !exists {
+        info!("Migrating database: filling volume buckets");
+        rebuild_volume_buckets(connection)?;
+    }
+    Ok(())
+}
+
+/// Result of the WAL checkpoint reported by SQLite
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalCheckpoint {
+    /// The checkpoint was blocked by other connection
+    pub busy: bool,
+    /// Amount of frames in the WAL file
+    pub log_frames: i64,
+    /// Amount of frames moved back to the database
+    pub checkpointed_frames: i64,
+}
+
+/// Move all pages from the WAL file back to the database and truncate the log
+pub fn checkpoint_wal(connection: &Connection) -> Result<WalCheckpoint, Error> {
+    trace!("Checkpointing WAL");
+    let query = "PRAGMA wal_checkpoint(TRUNCATE)";
+    let mut statement = connection
+        .prepare_cached(query)
+        .map_err(Error::PrepareQuery)?;
+    let mut rows = statement
+        .query_map([], |row| {
+            Ok(WalCheckpoint {
+                busy: row.get::<_, i64>(0)? != 0,
+                log_frames: row.get(1)?,
+                checkpointed_frames: row.get(2)?,
+            })
+        })
+        .map_err(Error::ExecuteQuery)?;
+
+    if let Some(row) = rows.next() {
+        Ok(row.map_err(Error::FetchRow)?)
+    } else {
+        Err(Error::ShouldExecuteOneRow(query.to_owned()))
+    }
+}
+
+/// Run `PRAGMA integrity_check` and return found problems, empty if the database is fine
+pub fn integrity_check(connection: &Connection) -> Result<Vec<String>, Error> {
+    trace!("Checking database integrity");
+    let mut statement = connection
+        .prepare("PRAGMA integrity_check")
+        .map_err(Error::PrepareQuery)?;
+    let rows = statement
+        .query_map([], |row| row.get::<_, String>(0))
+        .map_err(Error::ExecuteQuery)?;
+    let mut problems = vec![];
+    for row in rows {
+        let message = row.map_err(Error::FetchRow)?;
+        if message != "ok" {
+            problems.push(message);
+        }
+    }
+    Ok(problems)
+}
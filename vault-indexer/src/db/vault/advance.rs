@@ -1,42 +1,200 @@
-use super::{super::Error, load_vault_meta, VaultTxMeta};
+use super::{super::Error, load_vault_meta, load_vault_state_row, VaultState, VaultTxMeta};
 use crate::{
-    db::loaders::{invert, FieldEncode},
-    vault::{UnitAmount, VaultAction},
+    db::loaders::{invert, FieldDecode, FieldEncode},
+    vault::{OraclePrice, UnitAmount, VaultAction},
 };
 use bitcoin::Txid;
 use rusqlite::{named_params, Connection};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct ActionAggItem {
     pub timestamp_start: u32,
     pub unit_volume: UnitAmount,
     pub btc_volume: u64,
 }
 
+/// Volume of single action in a time bucket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ActionVolume {
+    pub action: VaultAction,
+    pub unit_volume: UnitAmount,
+    pub btc_volume: u64,
+}
+
+/// Volumes of all actions that happened in a time bucket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ActionBreakdownItem {
+    pub timestamp_start: u32,
+    /// Only actions with transactions in the bucket are present
+    pub actions: Vec<ActionVolume>,
+}
+
+/// Totals of UNIT and BTC flows of single vault over its main chain transactions
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct VaultSummary {
+    /// Amount of main chain transactions of the vault
+    pub tx_count: u32,
+    /// UNIT minted by opening and borrowing
+    pub unit_borrowed: u64,
+    /// UNIT burned by repayments
+    pub unit_repaid: u64,
+    /// Outstanding UNIT debt, `unit_borrowed - unit_repaid`
+    pub unit_net: i64,
+    /// BTC (in sats) locked by opening and deposits
+    pub btc_deposited: u64,
+    /// BTC (in sats) released by withdrawals
+    pub btc_withdrawn: u64,
+    /// BTC (in sats) left in the vault, `btc_deposited - btc_withdrawn`
+    pub btc_net: i64,
+}
+
+/// Oracle price recorded by single vault transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct OraclePricePoint {
+    pub oracle_timestamp: u32,
+    pub oracle_price: OraclePrice,
+}
+
+/// Ordering of history queries by height and position in block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    /// Oldest transactions first
+    #[default]
+    Asc,
+    /// Newest transactions first
+    Desc,
+}
+
+impl SortOrder {
+    fn sql(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+/// Position of a transaction in the history, the next page starts right after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryCursor {
+    pub height: u32,
+    pub block_pos: usize,
+    pub txid: Txid,
+}
+
+impl From<&VaultTxMeta> for HistoryCursor {
+    fn from(meta: &VaultTxMeta) -> Self {
+        HistoryCursor {
+            height: meta.height,
+            block_pos: meta.block_pos,
+            txid: meta.vault_tx.txid,
+        }
+    }
+}
+
+/// Current value of the vault used to rank vaults
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum VaultRanking {
+    /// BTC locked in the vault
+    Custody,
+    /// UNIT debt of the vault
+    Balance,
+}
+
+impl VaultRanking {
+    fn sql(&self) -> &'static str {
+        match self {
+            VaultRanking::Custody => "custody",
+            VaultRanking::Balance => "balance",
+        }
+    }
+}
+
 /// Operations with vault in database for some complex queries required for the
 /// websocket service to operate.
 pub trait DatabaseVaultAdvance {
+    /// Get all vault transactions in the oracle timestamp range. Transactions from forks are
+    /// returned only if `include_forks` is set.
     fn range_history_all(
         &self,
         start: Option<u32>,
         end: Option<u32>,
+        sort: SortOrder,
+        include_forks: bool,
     ) -> Result<Vec<VaultTxMeta>, Error>;
 
+    /// The same as [range_history_all](Self::range_history_all), but returns at most `limit`
+    /// transactions after the `cursor`. Ties of height and position (possible with forks) are
+    /// ordered by txid, so consecutive pages neither overlap nor skip transactions.
+    #[allow(clippy::too_many_arguments)]
+    fn range_history_all_page(
+        &self,
+        start: Option<u32>,
+        end: Option<u32>,
+        sort: SortOrder,
+        include_forks: bool,
+        cursor: Option<HistoryCursor>,
+        limit: usize,
+    ) -> Result<Vec<VaultTxMeta>, Error>;
+
+    /// Get transactions of the vault in the oracle timestamp range. Transactions from forks are
+    /// returned only if `include_forks` is set.
     fn range_history_vault(
         &self,
         vault_id: Txid,
         start: Option<u32>,
         end: Option<u32>,
+        sort: SortOrder,
+        include_forks: bool,
     ) -> Result<Vec<VaultTxMeta>, Error>;
 
+    /// Aggregate volumes of the action in time buckets of `timespan` seconds. If the `vault_id`
+    /// is set, only transactions of that vault are counted.
     fn action_aggregated(
         &self,
         action: VaultAction,
         timespan: u32,
+        vault_id: Option<Txid>,
     ) -> Result<Vec<ActionAggItem>, Error>;
 
+    /// Aggregate volumes of all actions in time buckets of `timespan` seconds within the oracle
+    /// timestamp range, grouped per bucket.
+    fn action_breakdown(
+        &self,
+        timespan: u32,
+        start: Option<u32>,
+        end: Option<u32>,
+    ) -> Result<Vec<ActionBreakdownItem>, Error>;
+
+    /// Get at most `limit` main chain vault transactions at or above the given height ordered by
+    /// height and position in block.
+    fn history_from_height(&self, height: u32, limit: usize) -> Result<Vec<VaultTxMeta>, Error>;
+
+    /// Get at most `limit` main chain vault transactions at or above the given height, the
+    /// first ones in the `sort` order are kept
+    fn history_since_height(
+        &self,
+        height: u32,
+        sort: SortOrder,
+        limit: usize,
+    ) -> Result<Vec<VaultTxMeta>, Error>;
+
+    /// Get at most `limit` vaults with the largest current value
+    fn top_vaults(&self, by: VaultRanking, limit: usize) -> Result<Vec<VaultState>, Error>;
+
+    /// Sum up absolute BTC and UNIT volumes of all stored transactions
     fn overall_volume(&self) -> Result<(i64, i64), Error>;
+
+    /// Sum up UNIT and BTC flows of the vault over its main chain transactions
+    fn vault_summary(&self, vault_id: Txid) -> Result<VaultSummary, Error>;
+
+    /// Get oracle prices recorded by main chain transactions of the vault ordered by the
+    /// oracle timestamp
+    fn vault_price_history(&self, vault_id: Txid) -> Result<Vec<OraclePricePoint>, Error>;
 }
 
 impl DatabaseVaultAdvance for Connection {
@@ -44,15 +202,70 @@ impl DatabaseVaultAdvance for Connection {
         &self,
         start: Option<u32>,
         end: Option<u32>,
+        sort: SortOrder,
+        include_forks: bool,
     ) -> Result<Vec<VaultTxMeta>, Error> {
-        let query = r#"
+        let query = format!(
+            r#"
             SELECT * FROM transactions
             WHERE oracle_timestamp >= :start AND oracle_timestamp < :end
-        "#;
-        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+                AND (:include_forks OR in_longest = 1)
+            ORDER BY height {0}, block_pos {0}
+        "#,
+            sort.sql()
+        );
+        let mut statement = self.prepare_cached(&query).map_err(Error::PrepareQuery)?;
         let rows = statement
             .query_map(
-                named_params! {":start": start.unwrap_or(0), ":end": end.unwrap_or(u32::MAX)},
+                named_params! {
+                    ":start": start.unwrap_or(0),
+                    ":end": end.unwrap_or(u32::MAX),
+                    ":include_forks": include_forks,
+                },
+                load_vault_meta,
+            )
+            .map_err(Error::ExecuteQuery)?;
+        rows.map(|row| row.map_err(Error::FetchRow))
+            .collect::<Result<Vec<_>, Error>>()
+    }
+
+    fn range_history_all_page(
+        &self,
+        start: Option<u32>,
+        end: Option<u32>,
+        sort: SortOrder,
+        include_forks: bool,
+        cursor: Option<HistoryCursor>,
+        limit: usize,
+    ) -> Result<Vec<VaultTxMeta>, Error> {
+        let after = match sort {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        };
+        let query = format!(
+            r#"
+            SELECT * FROM transactions
+            WHERE oracle_timestamp >= :start AND oracle_timestamp < :end
+                AND (:include_forks OR in_longest = 1)
+                AND (:no_cursor OR (height, block_pos, txid) {after} (:height, :block_pos, :txid))
+            ORDER BY height {0}, block_pos {0}, txid {0}
+            LIMIT :limit
+        "#,
+            sort.sql()
+        );
+        let mut statement = self.prepare_cached(&query).map_err(Error::PrepareQuery)?;
+        let rows = statement
+            .query_map(
+                named_params! {
+                    ":start": start.unwrap_or(0),
+                    ":end": end.unwrap_or(u32::MAX),
+                    ":include_forks": include_forks,
+                    ":no_cursor": cursor.is_none(),
+                    ":height": cursor.map(|c| c.height),
+                    ":block_pos": cursor.map(|c| c.block_pos as i64),
+                    ":txid": cursor.as_ref().map(|c| (&c.txid).field_encode()),
+                    ":limit": limit as i64,
+                },
                 load_vault_meta,
             )
             .map_err(Error::ExecuteQuery)?;
@@ -65,18 +278,26 @@ impl DatabaseVaultAdvance for Connection {
         vault_id: Txid,
         start: Option<u32>,
         end: Option<u32>,
+        sort: SortOrder,
+        include_forks: bool,
     ) -> Result<Vec<VaultTxMeta>, Error> {
-        let query = r#"
+        let query = format!(
+            r#"
             SELECT * FROM transactions
             WHERE vault_txid = :vault_id AND oracle_timestamp >= :start AND oracle_timestamp < :end
-        "#;
-        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+                AND (:include_forks OR in_longest = 1)
+            ORDER BY height {0}, block_pos {0}
+        "#,
+            sort.sql()
+        );
+        let mut statement = self.prepare_cached(&query).map_err(Error::PrepareQuery)?;
         let rows = statement
             .query_map(
                 named_params! {
                     ":vault_id": (&vault_id).field_encode(),
                     ":start": start.unwrap_or(0),
-                    ":end": end.unwrap_or(u32::MAX)
+                    ":end": end.unwrap_or(u32::MAX),
+                    ":include_forks": include_forks,
                 },
                 load_vault_meta,
             )
@@ -89,13 +310,19 @@ impl DatabaseVaultAdvance for Connection {
         &self,
         action: VaultAction,
         timespan: u32,
+        vault_id: Option<Txid>,
     ) -> Result<Vec<ActionAggItem>, Error> {
+        // Per vault queries use the vault index, spans that don't consist of whole buckets
+        // can't be summed up from them
+        if vault_id.is_some() || timespan == 0 || timespan % VOLUME_BUCKET_WIDTH != 0 {
+            return scan_action_aggregated(self, action, timespan, vault_id);
+        }
         let query = r#"
-            SELECT 
-                (oracle_timestamp / :span) * :span AS time_bucket,
-                SUM(abs(unit_volume)) AS total_unit_volume,
-                SUM(abs(btc_volume))   AS total_btc_volume
-            FROM transactions
+            SELECT
+                (bucket / :span) * :span AS time_bucket,
+                SUM(unit_volume) AS total_unit_volume,
+                SUM(btc_volume)  AS total_btc_volume
+            FROM volume_buckets
             WHERE action = :action
             GROUP BY time_bucket
             ORDER BY time_bucket;
@@ -105,7 +332,7 @@ impl DatabaseVaultAdvance for Connection {
             .query_map(
                 named_params! {
                     ":action": action.field_encode(),
-                    ":span": timespan
+                    ":span": timespan,
                 },
                 |row| {
                     Ok(ActionAggItem {
@@ -120,12 +347,129 @@ impl DatabaseVaultAdvance for Connection {
             .collect::<Result<Vec<_>, Error>>()
     }
 
+    fn action_breakdown(
+        &self,
+        timespan: u32,
+        start: Option<u32>,
+        end: Option<u32>,
+    ) -> Result<Vec<ActionBreakdownItem>, Error> {
+        let query = r#"
+            SELECT
+                (oracle_timestamp / :span) * :span AS time_bucket,
+                action,
+                SUM(abs(unit_volume)) AS total_unit_volume,
+                SUM(abs(btc_volume))   AS total_btc_volume
+            FROM transactions
+            WHERE oracle_timestamp >= :start AND oracle_timestamp < :end
+            GROUP BY time_bucket, action
+            ORDER BY time_bucket, action;
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let rows = statement
+            .query_map(
+                named_params! {
+                    ":span": timespan,
+                    ":start": start.unwrap_or(0),
+                    ":end": end.unwrap_or(u32::MAX),
+                },
+                |row| {
+                    let timestamp_start: u32 = row.get(0)?;
+                    let volume = ActionVolume {
+                        action: row.field_decode(1)?,
+                        unit_volume: row.get::<_, i32>(2)? as u32,
+                        btc_volume: row.get::<_, i64>(3)? as u64,
+                    };
+                    Ok((timestamp_start, volume))
+                },
+            )
+            .map_err(Error::ExecuteQuery)?;
+        let mut items: Vec<ActionBreakdownItem> = vec![];
+        for row in rows {
+            let (timestamp_start, volume) = row.map_err(Error::FetchRow)?;
+            match items.last_mut() {
+                Some(item) if item.timestamp_start == timestamp_start => item.actions.push(volume),
+                _ => items.push(ActionBreakdownItem {
+                    timestamp_start,
+                    actions: vec![volume],
+                }),
+            }
+        }
+        Ok(items)
+    }
+
+    fn history_from_height(&self, height: u32, limit: usize) -> Result<Vec<VaultTxMeta>, Error> {
+        let query = r#"
+            SELECT * FROM transactions
+            WHERE height >= :height AND in_longest = 1
+            ORDER BY height, block_pos
+            LIMIT :limit
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let rows = statement
+            .query_map(
+                named_params! {
+                    ":height": height,
+                    ":limit": limit as i64,
+                },
+                load_vault_meta,
+            )
+            .map_err(Error::ExecuteQuery)?;
+        rows.map(|row| row.map_err(Error::FetchRow))
+            .collect::<Result<Vec<_>, Error>>()
+    }
+
+    fn history_since_height(
+        &self,
+        height: u32,
+        sort: SortOrder,
+        limit: usize,
+    ) -> Result<Vec<VaultTxMeta>, Error> {
+        let query = format!(
+            r#"
+            SELECT * FROM transactions
+            WHERE height >= :height AND in_longest = 1
+            ORDER BY height {0}, block_pos {0}
+            LIMIT :limit
+        "#,
+            sort.sql()
+        );
+        let mut statement = self.prepare_cached(&query).map_err(Error::PrepareQuery)?;
+        let rows = statement
+            .query_map(
+                named_params! {":height": height, ":limit": limit as i64},
+                load_vault_meta,
+            )
+            .map_err(Error::ExecuteQuery)?;
+        rows.map(|row| row.map_err(Error::FetchRow))
+            .collect::<Result<Vec<_>, Error>>()
+    }
+
+    fn top_vaults(&self, by: VaultRanking, limit: usize) -> Result<Vec<VaultState>, Error> {
+        let query = format!(
+            r#"
+            SELECT open_txid, output, balance, oracle_price, oracle_timestamp, liquidation_price,
+                liquidation_hash, custody, last_tx, closed_at_height, payload_in_annex
+            FROM vaults
+            WHERE in_longest = 1
+            ORDER BY {} DESC
+            LIMIT :limit
+        "#,
+            by.sql()
+        );
+        let mut statement = self.prepare_cached(&query).map_err(Error::PrepareQuery)?;
+        let rows = statement
+            .query_map(named_params! {":limit": limit as i64}, load_vault_state_row)
+            .map_err(Error::ExecuteQuery)?;
+        rows.map(|row| row.map_err(Error::FetchRow))
+            .collect::<Result<Vec<_>, Error>>()
+    }
+
     fn overall_volume(&self) -> Result<(i64, i64), Error> {
         let query = r#"
-            SELECT 
-                SUM(abs(btc_volume)) AS total_btc_volume,
-                SUM(abs(unit_volume)) AS total_unit_volume
-            FROM transactions;
+            SELECT
+                COALESCE(SUM(btc_volume), 0) AS total_btc_volume,
+                COALESCE(SUM(unit_volume), 0) AS total_unit_volume
+            FROM volume_buckets;
         "#;
         let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
         let mut rows = statement
@@ -134,4 +478,148 @@ impl DatabaseVaultAdvance for Connection {
         let res = invert(rows.next().map(|row| row.map_err(Error::FetchRow)))?;
         Ok(res.unwrap_or((0, 0)))
     }
+
+    fn vault_summary(&self, vault_id: Txid) -> Result<VaultSummary, Error> {
+        let query = r#"
+            SELECT
+                action,
+                COUNT(*) AS tx_count,
+                SUM(abs(unit_volume)) AS total_unit_volume,
+                SUM(MAX(btc_volume, 0)) AS total_btc_in,
+                SUM(MAX(-btc_volume, 0)) AS total_btc_out
+            FROM transactions
+            WHERE vault_txid = :vault_id AND in_longest = 1
+            GROUP BY action;
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let rows = statement
+            .query_map(
+                named_params! {":vault_id": (&vault_id).field_encode()},
+                |row| {
+                    let action: VaultAction = row.field_decode(0)?;
+                    Ok((
+                        action,
+                        row.get::<_, u32>(1)?,
+                        row.get::<_, i64>(2)? as u64,
+                        row.get::<_, i64>(3)? as u64,
+                        row.get::<_, i64>(4)? as u64,
+                    ))
+                },
+            )
+            .map_err(Error::ExecuteQuery)?;
+        let mut summary = VaultSummary::default();
+        for row in rows {
+            let (action, tx_count, unit_volume, btc_in, btc_out) = row.map_err(Error::FetchRow)?;
+            summary.tx_count += tx_count;
+            if action.unit_volume_sign() > 0 {
+                summary.unit_borrowed += unit_volume;
+            } else {
+                summary.unit_repaid += unit_volume;
+            }
+            summary.btc_deposited += btc_in;
+            summary.btc_withdrawn += btc_out;
+        }
+        summary.unit_net = summary.unit_borrowed as i64 - summary.unit_repaid as i64;
+        summary.btc_net = summary.btc_deposited as i64 - summary.btc_withdrawn as i64;
+        Ok(summary)
+    }
+
+    fn vault_price_history(&self, vault_id: Txid) -> Result<Vec<OraclePricePoint>, Error> {
+        let query = r#"
+            SELECT oracle_timestamp, oracle_price FROM transactions
+            WHERE vault_txid = :vault_id AND in_longest = 1
+            ORDER BY oracle_timestamp, height, block_pos
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let rows = statement
+            .query_map(
+                named_params! {":vault_id": (&vault_id).field_encode()},
+                |row| {
+                    Ok(OraclePricePoint {
+                        oracle_timestamp: row.get(0)?,
+                        oracle_price: row.get(1)?,
+                    })
+                },
+            )
+            .map_err(Error::ExecuteQuery)?;
+        rows.map(|row| row.map_err(Error::FetchRow))
+            .collect::<Result<Vec<_>, Error>>()
+    }
+}
+
+/// Width in seconds of the precomputed volume buckets, all [crate::service::TimeSpan] widths
+/// are multiples of it
+pub const VOLUME_BUCKET_WIDTH: u32 = 3600;
+
+/// Recompute the volume buckets from all stored transactions. The triggers keep the buckets
+/// in sync, so it is needed only when the table is created.
+pub fn rebuild_volume_buckets(conn: &Connection) -> Result<(), Error> {
+    let query = format!(
+        r#"
+        DELETE FROM volume_buckets;
+        INSERT INTO volume_buckets
+            SELECT action, (oracle_timestamp / {width}) * {width} AS bucket, COUNT(*),
+                SUM(abs(unit_volume)), SUM(abs(btc_volume))
+            FROM transactions
+            GROUP BY action, bucket;
+        "#,
+        width = VOLUME_BUCKET_WIDTH
+    );
+    conn.execute_batch(&query).map_err(Error::ExecuteQuery)
+}
+
+/// Same as [DatabaseVaultAdvance::action_aggregated], but scans the transactions instead of
+/// reading the volume buckets
+pub fn scan_action_aggregated(
+    conn: &Connection,
+    action: VaultAction,
+    timespan: u32,
+    vault_id: Option<Txid>,
+) -> Result<Vec<ActionAggItem>, Error> {
+    let query = r#"
+        SELECT 
+            (oracle_timestamp / :span) * :span AS time_bucket,
+            SUM(abs(unit_volume)) AS total_unit_volume,
+            SUM(abs(btc_volume))   AS total_btc_volume
+        FROM transactions
+        WHERE action = :action AND (:vault_id IS NULL OR vault_txid = :vault_id)
+        GROUP BY time_bucket
+        ORDER BY time_bucket;
+    "#;
+    let mut statement = conn.prepare_cached(query).map_err(Error::PrepareQuery)?;
+    let rows = statement
+        .query_map(
+            named_params! {
+                ":action": action.field_encode(),
+                ":span": timespan,
+                ":vault_id": vault_id.as_ref().map(|id| id.field_encode()),
+            },
+            |row| {
+                Ok(ActionAggItem {
+                    timestamp_start: row.get(0)?,
+                    unit_volume: row.get::<_, i32>(1)? as u32,
+                    btc_volume: row.get::<_, i64>(2)? as u64,
+                })
+            },
+        )
+        .map_err(Error::ExecuteQuery)?;
+    rows.map(|row| row.map_err(Error::FetchRow))
+        .collect::<Result<Vec<_>, Error>>()
+}
+
+/// Same as [DatabaseVaultAdvance::overall_volume], but scans the transactions instead of
+/// reading the volume buckets
+pub fn scan_overall_volume(conn: &Connection) -> Result<(i64, i64), Error> {
+    let query = r#"
+        SELECT 
+            COALESCE(SUM(abs(btc_volume)), 0) AS total_btc_volume,
+            COALESCE(SUM(abs(unit_volume)), 0) AS total_unit_volume
+        FROM transactions;
+    "#;
+    let mut statement = conn.prepare_cached(query).map_err(Error::PrepareQuery)?;
+    let mut rows = statement
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)))
+        .map_err(Error::ExecuteQuery)?;
+    let res = invert(rows.next().map(|row| row.map_err(Error::FetchRow)))?;
+    Ok(res.unwrap_or((0, 0)))
 }
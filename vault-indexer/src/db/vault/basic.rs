@@ -3,10 +3,12 @@ use std::io::Cursor;
 use super::super::error::Error;
 use super::super::loaders::*;
 use crate::db::vault::rune::DatabaseRune;
-use crate::vault::{VaultAction, VaultId, VaultTx};
+use crate::vault::{
+    LiquidationHash, OraclePrice, PayloadLocation, UnitAmount, VaultAction, VaultId, VaultTx,
+};
 use bitcoin::consensus::Encodable;
-use bitcoin::{BlockHash, Txid};
-use log::trace;
+use bitcoin::{BlockHash, OutPoint, ScriptBuf, Txid};
+use log::{debug, trace};
 use rusqlite::{named_params, Connection, Row};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -16,10 +18,46 @@ pub struct VaultTxMeta {
     pub block_hash: BlockHash,
     pub block_pos: usize,
     pub height: u32,
+    pub in_longest: bool,
     pub btc_custody: u64,
     pub unit_volume: i32,
     pub btc_volume: i64,
     pub prev_tx: Txid,
+    /// Amount of inputs, missing for transactions stored before it was tracked
+    pub num_inputs: Option<u32>,
+    /// Amount of outputs, missing for transactions stored before it was tracked
+    pub num_outputs: Option<u32>,
+}
+
+/// Current state of the vault after its last transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VaultState {
+    pub vault_id: VaultId,
+    /// Where the opening transaction carries the payload
+    pub payload: PayloadLocation,
+    pub balance: UnitAmount,
+    pub oracle_price: OraclePrice,
+    pub oracle_timestamp: u32,
+    pub liquidation_price: Option<OraclePrice>,
+    pub liquidation_hash: Option<LiquidationHash>,
+    pub custody: u64,
+    pub last_tx: Txid,
+    /// Height of the transaction that repaid all debt and withdrew all BTC
+    pub closed_at_height: Option<u32>,
+}
+
+impl VaultState {
+    pub fn is_closed(&self) -> bool {
+        self.closed_at_height.is_some()
+    }
+}
+
+/// The UTXO that holds BTC of the vault after its last transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VaultCustody {
+    pub outpoint: OutPoint,
+    pub script_pubkey: ScriptBuf,
+    pub value: u64,
 }
 
 /// Operations with vault in database
@@ -37,11 +75,65 @@ pub trait DatabaseVault {
     /// Find vault by transaction that is related to it
     fn find_vault_by_tx(&self, txid: Txid) -> Result<Option<Txid>, Error>;
 
+    /// Find main chain transactions that carry the given liquidation hash in the order of mining
+    fn find_by_liquidation_hash(&self, hash: LiquidationHash) -> Result<Vec<Txid>, Error>;
+
+    /// Load the current state of the vault
+    fn load_vault_state(&self, vault_id: VaultId) -> Result<Option<VaultState>, Error>;
+
+    /// Locate the custody UTXO of the vault by decoding its last stored transaction.
+    /// Returns `None` for unknown and closed vaults.
+    fn load_vault_custody(&self, vault_id: VaultId) -> Result<Option<VaultCustody>, Error>;
+
+    /// Iterate over states of all vaults without loading them at once
+    fn load_vaults<F>(&self, body: F) -> Result<(), Error>
+    where
+        F: FnMut(VaultState);
+
     /// Delete ALL info about vaults and transactions
     fn drop_vaults(&self) -> Result<(), Error>;
+
+    /// Get raw bytes of the stored vault transaction
+    fn get_raw_tx(&self, txid: Txid) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Amount of known vaults
+    fn count_vaults(&self) -> Result<u64, Error>;
+
+    /// Amount of stored vault transactions (including opening ones)
+    fn count_vault_txs(&self) -> Result<u64, Error>;
+
+    /// Mark vault transactions of the blocks as in the main chain or not, called when
+    /// a reorganization (dis)connects the blocks. Returns amount of changed transactions.
+    fn set_vault_txs_in_longest(
+        &self,
+        block_hashes: &[BlockHash],
+        flag: bool,
+    ) -> Result<usize, Error>;
+
+    /// Mark the vault as opened in the main chain or not. Vaults whose opening transaction
+    /// left the main chain are hidden from the queries until it is mined again.
+    fn set_vault_in_longest(&self, vault_id: VaultId, flag: bool) -> Result<(), Error>;
+
+    /// Find transactions that reference a vault missing in the vaults table
+    fn find_dangling_vault_txs(&self) -> Result<Vec<Txid>, Error>;
+
+    /// Vaults that have transactions in any of the blocks
+    fn find_vaults_in_blocks(&self, block_hashes: &[BlockHash]) -> Result<Vec<VaultId>, Error>;
+
+    /// Walk over main chain transactions of the vault in the order of mining and recalculate
+    /// custody, volumes and chaining from the stored raw transactions. Fixes the vault state
+    /// if some of the incremental updates were wrong. Returns amount of processed transactions.
+    fn recompute_vault(&mut self, vault_id: VaultId) -> Result<usize, Error>;
 }
 
 impl DatabaseVault for Connection {
+    fn recompute_vault(&mut self, vault_id: VaultId) -> Result<usize, Error> {
+        let conn_tx = self.transaction().map_err(Error::StartTransaction)?;
+        let count = recompute_vault_in(&conn_tx, vault_id)?;
+        conn_tx.commit().map_err(Error::CommitTransaction)?;
+        Ok(count)
+    }
+
     fn store_vault_tx(
         &mut self,
         tx: &VaultTx,
@@ -64,7 +156,7 @@ impl DatabaseVault for Connection {
         } else {
             trace!("Get vault information");
             let (prev_custody, _, prev_tx) = get_vault_chaining_info(&conn_tx, vault_id)?;
-            let btc_custody = update_vault(&conn_tx, vault_id, tx, raw_tx)?;
+            let btc_custody = update_vault(&conn_tx, vault_id, tx, raw_tx, height)?;
             (btc_custody, prev_custody, prev_tx)
         };
 
@@ -90,10 +182,13 @@ impl DatabaseVault for Connection {
             block_hash,
             block_pos,
             height,
+            in_longest: true,
             btc_custody,
             unit_volume,
             btc_volume,
             prev_tx,
+            num_inputs: Some(raw_tx.input.len() as u32),
+            num_outputs: Some(raw_tx.output.len() as u32),
         })
     }
 
@@ -119,6 +214,94 @@ impl DatabaseVault for Connection {
         }
     }
 
+    fn find_by_liquidation_hash(&self, hash: LiquidationHash) -> Result<Vec<Txid>, Error> {
+        let query = r#"
+            SELECT txid FROM transactions
+            WHERE liquidation_hash = :liquidation_hash AND in_longest = 1
+            ORDER BY height, block_pos
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let rows = statement
+            .query_map(
+                named_params! {":liquidation_hash": (&hash).field_encode()},
+                |row| row.field_decode(0),
+            )
+            .map_err(Error::ExecuteQuery)?;
+        rows.map(|row| row.map_err(Error::FetchRow)).collect()
+    }
+
+    fn load_vault_state(&self, vault_id: VaultId) -> Result<Option<VaultState>, Error> {
+        let query = r#"
+            SELECT open_txid, output, balance, oracle_price, oracle_timestamp, liquidation_price,
+                liquidation_hash, custody, last_tx, closed_at_height, payload_in_annex
+            FROM vaults
+            WHERE open_txid = :vault_id AND in_longest = 1
+            LIMIT 1
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let mut rows = statement
+            .query_map(
+                named_params! {":vault_id": (&vault_id).field_encode()},
+                load_vault_state_row,
+            )
+            .map_err(Error::ExecuteQuery)?;
+
+        if let Some(row) = rows.next() {
+            Ok(Some(row.map_err(Error::FetchRow)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn load_vault_custody(&self, vault_id: VaultId) -> Result<Option<VaultCustody>, Error> {
+        let query = r#"
+            SELECT t.* FROM vaults v
+            JOIN transactions t ON t.txid = v.last_tx
+            WHERE v.open_txid = :vault_id AND v.in_longest = 1 AND v.closed_at_height IS NULL
+            LIMIT 1
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let mut rows = statement
+            .query_map(
+                named_params! {":vault_id": (&vault_id).field_encode()},
+                |row| Ok((load_vault_meta(row)?, load_raw_tx(row)?)),
+            )
+            .map_err(Error::ExecuteQuery)?;
+
+        if let Some(row) = rows.next() {
+            let (meta, raw_tx) = row.map_err(Error::FetchRow)?;
+            let (vout, output) = meta.vault_tx.assume_custody_output(&raw_tx)?;
+            Ok(Some(VaultCustody {
+                outpoint: OutPoint::new(meta.vault_tx.txid, vout),
+                script_pubkey: output.script_pubkey.clone(),
+                value: output.value.to_sat(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn load_vaults<F>(&self, mut body: F) -> Result<(), Error>
+    where
+        F: FnMut(VaultState),
+    {
+        let query = r#"
+            SELECT open_txid, output, balance, oracle_price, oracle_timestamp, liquidation_price,
+                liquidation_hash, custody, last_tx, closed_at_height, payload_in_annex
+            FROM vaults
+            WHERE in_longest = 1
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let result = statement
+            .query_map([], load_vault_state_row)
+            .map_err(Error::ExecuteQuery)?;
+
+        for state in result {
+            body(state.map_err(Error::FetchRow)?)
+        }
+        Ok(())
+    }
+
     fn drop_vaults(&self) -> Result<(), Error> {
         let query = r#"
             DELETE FROM transactions;
@@ -127,6 +310,103 @@ impl DatabaseVault for Connection {
         self.execute_batch(query).map_err(Error::ExecuteQuery)?;
         Ok(())
     }
+
+    fn get_raw_tx(&self, txid: Txid) -> Result<Option<Vec<u8>>, Error> {
+        let query = r#"
+            SELECT raw_tx FROM transactions
+            WHERE txid = :txid
+            LIMIT 1
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let mut rows = statement
+            .query_map(named_params! {":txid": (&txid).field_encode()}, |row| {
+                row.get::<_, Vec<u8>>(0)
+            })
+            .map_err(Error::ExecuteQuery)?;
+
+        if let Some(row) = rows.next() {
+            Ok(Some(row.map_err(Error::FetchRow)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn count_vaults(&self) -> Result<u64, Error> {
+        query_count(self, "SELECT count(*) FROM vaults WHERE in_longest = 1")
+    }
+
+    fn find_dangling_vault_txs(&self) -> Result<Vec<Txid>, Error> {
+        let query = r#"
+            SELECT txid FROM transactions
+            WHERE vault_txid NOT IN (SELECT open_txid FROM vaults)
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let rows = statement
+            .query_map([], |row| row.field_decode(0))
+            .map_err(Error::ExecuteQuery)?;
+        rows.map(|row| row.map_err(Error::FetchRow)).collect()
+    }
+
+    fn find_vaults_in_blocks(&self, block_hashes: &[BlockHash]) -> Result<Vec<VaultId>, Error> {
+        let query = "SELECT DISTINCT vault_txid FROM transactions WHERE block_hash = :block_hash";
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let mut vaults = vec![];
+        for block_hash in block_hashes {
+            let rows = statement
+                .query_map(
+                    named_params! {":block_hash": block_hash.field_encode()},
+                    |row| row.field_decode(0),
+                )
+                .map_err(Error::ExecuteQuery)?;
+            for row in rows {
+                let vault_id = row.map_err(Error::FetchRow)?;
+                if !vaults.contains(&vault_id) {
+                    vaults.push(vault_id);
+                }
+            }
+        }
+        Ok(vaults)
+    }
+
+    fn count_vault_txs(&self) -> Result<u64, Error> {
+        query_count(self, "SELECT count(*) FROM transactions")
+    }
+
+    fn set_vault_txs_in_longest(
+        &self,
+        block_hashes: &[BlockHash],
+        flag: bool,
+    ) -> Result<usize, Error> {
+        let query = r#"
+            UPDATE transactions SET in_longest = :in_longest
+            WHERE block_hash = :block_hash AND in_longest != :in_longest
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let mut changed = 0;
+        for block_hash in block_hashes {
+            changed += statement
+                .execute(named_params! {
+                    ":in_longest": flag,
+                    ":block_hash": block_hash.field_encode(),
+                })
+                .map_err(Error::ExecuteQuery)?;
+        }
+        Ok(changed)
+    }
+
+    fn set_vault_in_longest(&self, vault_id: VaultId, flag: bool) -> Result<(), Error> {
+        let query = r#"
+            UPDATE vaults SET in_longest = :in_longest WHERE open_txid = :vault_id
+        "#;
+        self.prepare_cached(query)
+            .map_err(Error::PrepareQuery)?
+            .execute(named_params! {
+                ":in_longest": flag,
+                ":vault_id": (&vault_id).field_encode(),
+            })
+            .map_err(Error::ExecuteQuery)?;
+        Ok(())
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -163,7 +443,33 @@ fn insert_vault_tx_raw(
             :btc_custody,
             :unit_volume,
             :btc_volume,
-            :prev_tx)
+            :prev_tx,
+            :num_inputs,
+            :num_outputs,
+            :payload_in_annex)
+        -- The same transaction is processed again after reorg, move it to the new block
+        ON CONFLICT(txid) DO UPDATE SET
+            output = excluded.output,
+            block_pos = excluded.block_pos,
+            vault_txid = excluded.vault_txid,
+            version = excluded.version,
+            action = excluded.action,
+            balance = excluded.balance,
+            oracle_price = excluded.oracle_price,
+            oracle_timestamp = excluded.oracle_timestamp,
+            liquidation_price = excluded.liquidation_price,
+            liquidation_hash = excluded.liquidation_hash,
+            block_hash = excluded.block_hash,
+            height = excluded.height,
+            in_longest = excluded.in_longest,
+            raw_tx = excluded.raw_tx,
+            btc_custody = excluded.btc_custody,
+            unit_volume = excluded.unit_volume,
+            btc_volume = excluded.btc_volume,
+            prev_tx = excluded.prev_tx,
+            num_inputs = excluded.num_inputs,
+            num_outputs = excluded.num_outputs,
+            payload_in_annex = excluded.payload_in_annex
     "#;
 
     let mut tx_bytes = vec![];
@@ -181,7 +487,7 @@ fn insert_vault_tx_raw(
     statement
         .execute(named_params! {
             ":txid": (&tx.txid).field_encode(),
-            ":output": tx.output as i64,
+            ":output": tx.payload.output().unwrap_or(0) as i64,
             ":block_pos": block_pos as i64,
             ":vault_txid": (&vault_id).field_encode(),
             ":version": tx.version.to_string(),
@@ -190,7 +496,7 @@ fn insert_vault_tx_raw(
             ":oracle_price": tx.oracle_price as i64,
             ":oracle_timestamp": tx.oracle_timestamp as i64,
             ":liquidation_price": tx.liquidation_price,
-            ":liquidation_hash": tx.liquidation_hash,
+            ":liquidation_hash": tx.liquidation_hash.as_ref().map(|h| h.field_encode()),
             ":block_hash": (&block_hash).field_encode(),
             ":height": height as i64,
             ":in_longest": 1, // assume that we don't scan forks
@@ -199,17 +505,130 @@ fn insert_vault_tx_raw(
             ":unit_volume": unit_volume,
             ":btc_volume": btc_volume,
             ":prev_tx": (&prev_tx).field_encode(),
+            ":num_inputs": raw_tx.input.len() as u32,
+            ":num_outputs": raw_tx.output.len() as u32,
+            ":payload_in_annex": tx.payload == PayloadLocation::Annex,
         })
         .map_err(Error::ExecuteQuery)?;
     Ok(btc_volume)
 }
 
+/// The same as [DatabaseVault::recompute_vault], but runs inside the transaction of the caller,
+/// so the recalculation can be committed together with other changes (e.g. a reorganization)
+pub fn recompute_vault_in(conn: &Connection, vault_id: VaultId) -> Result<usize, Error> {
+    let history = load_vault_raw_history(conn, vault_id)?;
+    let last = if let Some((last, _)) = history.last() {
+        last.clone()
+    } else {
+        return Err(Error::UnknownVaultId(vault_id));
+    };
+
+    let update_query = r#"
+        UPDATE transactions SET
+            btc_custody = :btc_custody,
+            unit_volume = :unit_volume,
+            btc_volume = :btc_volume,
+            prev_tx = :prev_tx
+        WHERE txid = :txid
+    "#;
+    let mut prev_custody = 0;
+    // Opening transaction is chained to itself
+    let mut prev_tx = vault_id;
+    for (meta, raw_tx) in history.iter() {
+        let tx = &meta.vault_tx;
+        let btc_custody = tx.assume_custody_value(raw_tx)?;
+        let btc_volume = if tx.action == VaultAction::Open {
+            btc_custody as i64
+        } else {
+            btc_custody as i64 - prev_custody as i64
+        };
+        let unit_volume = get_unit_volume(conn, tx, raw_tx)?;
+        if btc_custody != meta.btc_custody
+            || btc_volume != meta.btc_volume
+            || unit_volume != meta.unit_volume
+            || prev_tx != meta.prev_tx
+        {
+            debug!(
+                "Fixing stored state of vault {vault_id} transaction {}",
+                tx.txid
+            );
+        }
+
+        let mut statement = conn
+            .prepare_cached(update_query)
+            .map_err(Error::PrepareQuery)?;
+        statement
+            .execute(named_params! {
+                ":txid": (&tx.txid).field_encode(),
+                ":btc_custody": btc_custody,
+                ":unit_volume": unit_volume,
+                ":btc_volume": btc_volume,
+                ":prev_tx": (&prev_tx).field_encode(),
+            })
+            .map_err(Error::ExecuteQuery)?;
+        prev_custody = btc_custody;
+        prev_tx = tx.txid;
+    }
+
+    // The vault state is the state after the last transaction
+    let query = r#"
+        UPDATE vaults SET
+            balance = :balance,
+            oracle_price = :oracle_price,
+            oracle_timestamp = :oracle_timestamp,
+            liquidation_price = :liquidation_price,
+            liquidation_hash = :liquidation_hash,
+            custody = :custody,
+            last_tx = :last_tx,
+            closed_at_height = :closed_at_height,
+            in_longest = 1
+        WHERE open_txid = :vault_id
+    "#;
+    let last_tx = &last.vault_tx;
+    conn.prepare_cached(query)
+        .map_err(Error::PrepareQuery)?
+        .execute(named_params! {
+            ":vault_id": (&vault_id).field_encode(),
+            ":balance": last_tx.balance as i64,
+            ":oracle_price": last_tx.oracle_price as i64,
+            ":oracle_timestamp": last_tx.oracle_timestamp as i64,
+            ":liquidation_price": last_tx.liquidation_price,
+            ":liquidation_hash": last_tx.liquidation_hash.as_ref().map(|h| h.field_encode()),
+            ":custody": prev_custody,
+            ":last_tx": (&last_tx.txid).field_encode(),
+            ":closed_at_height": closed_at_height(last_tx, prev_custody, last.height),
+        })
+        .map_err(Error::ExecuteQuery)?;
+    Ok(history.len())
+}
+
+/// Load main chain transactions of the vault with decoded raw transactions in the order of mining
+fn load_vault_raw_history(
+    conn: &Connection,
+    vault_id: VaultId,
+) -> Result<Vec<(VaultTxMeta, bitcoin::Transaction)>, Error> {
+    let query = r#"
+        SELECT * FROM transactions
+        WHERE vault_txid = :vault_id AND in_longest = 1
+        ORDER BY height, block_pos
+    "#;
+    let mut statement = conn.prepare_cached(query).map_err(Error::PrepareQuery)?;
+    let rows = statement
+        .query_map(
+            named_params! {":vault_id": (&vault_id).field_encode()},
+            |row| Ok((load_vault_meta(row)?, load_raw_tx(row)?)),
+        )
+        .map_err(Error::ExecuteQuery)?;
+    rows.map(|row| row.map_err(Error::FetchRow))
+        .collect::<Result<Vec<_>, Error>>()
+}
+
 fn get_unit_volume(
     conn: &Connection,
     tx: &VaultTx,
     raw_tx: &bitcoin::Transaction,
 ) -> Result<i32, Error> {
-    if let Some(unit_txid) = tx.assume_parent_unit_tx(&raw_tx)? {
+    if let Some(unit_txid) = tx.assume_parent_unit_tx(raw_tx)? {
         let unit_tx = conn.load_unit_tx(unit_txid)?;
         Ok(tx.action.unit_volume_sign() * (unit_tx.unit_amount as i32))
     } else {
@@ -230,7 +649,10 @@ fn create_vault(
     );
     let custody = tx.assume_custody_value(raw_tx)?;
     let query = r#"
-            INSERT INTO vaults VALUES(
+            INSERT INTO vaults (open_txid, output, balance, oracle_price, oracle_timestamp,
+                liquidation_price, liquidation_hash, custody, last_tx, closed_at_height,
+                payload_in_annex)
+            VALUES(
                 :open_txid,
                 :output,
                 :balance,
@@ -239,21 +661,37 @@ fn create_vault(
                 :liquidation_price,
                 :liquidation_hash,
                 :custody,
-                :last_tx
+                :last_tx,
+                NULL,
+                :payload_in_annex
             )
+            -- Reopening of the same vault (after reorg) resets its running state to the open values
+            ON CONFLICT(open_txid) DO UPDATE SET
+                output = excluded.output,
+                balance = excluded.balance,
+                oracle_price = excluded.oracle_price,
+                oracle_timestamp = excluded.oracle_timestamp,
+                liquidation_price = excluded.liquidation_price,
+                liquidation_hash = excluded.liquidation_hash,
+                custody = excluded.custody,
+                last_tx = excluded.last_tx,
+                closed_at_height = NULL,
+                payload_in_annex = excluded.payload_in_annex,
+                in_longest = 1
         "#;
     let mut statement = conn.prepare_cached(query).map_err(Error::PrepareQuery)?;
     statement
         .execute(named_params! {
             ":open_txid": (&tx.txid).field_encode(),
-            ":output": tx.output as i64,
+            ":output": tx.payload.output().unwrap_or(0) as i64,
             ":balance": tx.balance as i64,
             ":oracle_price": tx.oracle_price as i64,
             ":oracle_timestamp": tx.oracle_timestamp as i64,
             ":liquidation_price": tx.liquidation_price,
-            ":liquidation_hash": tx.liquidation_hash,
+            ":liquidation_hash": tx.liquidation_hash.as_ref().map(|h| h.field_encode()),
             ":custody": custody,
             ":last_tx": (&tx.txid).field_encode(),
+            ":payload_in_annex": tx.payload == PayloadLocation::Annex,
         })
         .map_err(Error::ExecuteQuery)?;
     Ok(custody)
@@ -264,6 +702,7 @@ fn update_vault(
     vault_id: Txid,
     tx: &VaultTx,
     raw_tx: &bitcoin::Transaction,
+    height: u32,
 ) -> Result<u64, Error> {
     trace!("Updating vault in db");
     assert!(
@@ -280,7 +719,8 @@ fn update_vault(
                 liquidation_price = :liquidation_price,
                 liquidation_hash = :liquidation_hash,
                 custody = :custody,
-                last_tx = :last_tx
+                last_tx = :last_tx,
+                closed_at_height = :closed_at_height
             WHERE open_txid = :vault_id
         "#;
     let mut statement = conn.prepare_cached(query).map_err(Error::PrepareQuery)?;
@@ -291,14 +731,25 @@ fn update_vault(
             ":oracle_price": tx.oracle_price as i64,
             ":oracle_timestamp": tx.oracle_timestamp as i64,
             ":liquidation_price": tx.liquidation_price,
-            ":liquidation_hash": tx.liquidation_hash,
+            ":liquidation_hash": tx.liquidation_hash.as_ref().map(|h| h.field_encode()),
             ":custody": next_custody,
             ":last_tx": (&tx.txid).field_encode(),
+            ":closed_at_height": closed_at_height(tx, next_custody, height),
         })
         .map_err(Error::ExecuteQuery)?;
     Ok(next_custody)
 }
 
+/// The vault is closed when all debt is repaid and all BTC is withdrawn. Any other transaction
+/// reactivates the vault.
+fn closed_at_height(tx: &VaultTx, custody: u64, height: u32) -> Option<u32> {
+    if tx.action != VaultAction::Open && tx.balance == 0 && custody == 0 {
+        Some(height)
+    } else {
+        None
+    }
+}
+
 // Helper that inspects bitcoin transaction and tries to identify vault by inputs
 fn find_parent_vault(
     conn: &Connection,
@@ -317,6 +768,15 @@ fn find_parent_vault(
         let vault_id = conn
             .find_vault_by_tx(parent_txid)?
             .ok_or(Error::UnknownVaultTx(vtx.txid))?;
+
+        // Spending outputs of other vaults (merging positions) makes the chaining ambiguous
+        for input in raw_tx.input.iter().skip(1) {
+            if let Some(other_id) = conn.find_vault_by_tx(input.previous_output.txid)? {
+                if other_id != vault_id {
+                    return Err(Error::AmbiguousVaultParents(vtx.txid));
+                }
+            }
+        }
         Ok(vault_id)
     }
 }
@@ -347,12 +807,46 @@ fn get_vault_chaining_info(conn: &Connection, vault_id: Txid) -> Result<(u64, u3
     }
 }
 
+pub fn load_vault_state_row(row: &Row<'_>) -> Result<VaultState, rusqlite::Error> {
+    Ok(VaultState {
+        vault_id: row.field_decode(0)?,
+        payload: load_payload(row, 1, 10)?,
+        balance: row.get(2)?,
+        oracle_price: row.get(3)?,
+        oracle_timestamp: row.get(4)?,
+        liquidation_price: row.get(5)?,
+        liquidation_hash: row.field_decode(6)?,
+        custody: row.get(7)?,
+        last_tx: row.field_decode(8)?,
+        closed_at_height: row.get(9)?,
+    })
+}
+
+/// The payload location is stored as the output number and the annex flag
+fn load_payload(
+    row: &Row<'_>,
+    output: usize,
+    in_annex: usize,
+) -> Result<PayloadLocation, rusqlite::Error> {
+    if row.get::<_, i64>(in_annex)? != 0 {
+        Ok(PayloadLocation::Annex)
+    } else {
+        Ok(PayloadLocation::Output(row.get(output)?))
+    }
+}
+
+/// Decode the raw transaction of the `transactions` row, the column is found by its name
+fn load_raw_tx(row: &Row<'_>) -> Result<bitcoin::Transaction, rusqlite::Error> {
+    let index = row.as_ref().column_index("raw_tx")?;
+    row.field_decode(index)
+}
+
 pub fn load_vault_meta(row: &Row<'_>) -> Result<VaultTxMeta, rusqlite::Error> {
     Ok(VaultTxMeta {
         vault_id: row.field_decode(3)?,
         vault_tx: VaultTx {
             txid: row.field_decode(0)?,
-            output: row.get(1)?,
+            payload: load_payload(row, 1, 21)?,
             version: row.field_decode(4)?,
             action: row.field_decode(5)?,
             balance: row.get(6)?,
@@ -364,9 +858,12 @@ pub fn load_vault_meta(row: &Row<'_>) -> Result<VaultTxMeta, rusqlite::Error> {
         block_hash: row.field_decode(11)?,
         block_pos: row.get(2)?,
         height: row.get(12)?,
+        in_longest: row.get::<_, i64>(13)? != 0,
         btc_custody: row.get(15)?,
         unit_volume: row.get(16)?,
         btc_volume: row.get(17)?,
         prev_tx: row.field_decode(18)?,
+        num_inputs: row.get(19)?,
+        num_outputs: row.get(20)?,
     })
 }
@@ -1,9 +1,10 @@
 use super::super::Error;
+use crate::db::loaders::query_count;
 use crate::db::loaders::FieldDecode;
 use crate::db::loaders::FieldEncode;
 use crate::vault::UnitAmount;
 use bitcoin::consensus::Encodable;
-use bitcoin::{Transaction, Txid};
+use bitcoin::{BlockHash, Transaction, Txid};
 use rusqlite::{named_params, Connection};
 use std::io::Cursor;
 
@@ -11,24 +12,59 @@ use std::io::Cursor;
 pub struct UnitTxMeta {
     pub transaction: Transaction,
     pub unit_amount: UnitAmount,
+    /// Block of the transaction, missing for transactions stored before it was tracked
+    pub block_hash: Option<BlockHash>,
+    pub height: Option<u32>,
+    /// The block is in the main chain
+    pub in_longest: bool,
 }
 
 /// Operations with UNIT rune token in database
 pub trait DatabaseRune {
-    /// Store UNIT related transaction to the DB
-    fn store_unit_tx(&mut self, tx: &Transaction, unit_amount: UnitAmount) -> Result<(), Error>;
+    /// Store UNIT related transaction mined in the main chain block to the DB. The transaction
+    /// that is already stored (e.g. from a reorganized block) is moved to the new block.
+    fn store_unit_tx(
+        &mut self,
+        tx: &Transaction,
+        unit_amount: UnitAmount,
+        block_hash: BlockHash,
+        height: u32,
+    ) -> Result<(), Error>;
 
     /// Find the UNIT transaction by its txid
     fn load_unit_tx(&self, txid: Txid) -> Result<UnitTxMeta, Error>;
 
     /// Delete ALL info about UNIT transactions
     fn drop_unit_index(&self) -> Result<(), Error>;
+
+    /// Amount of stored UNIT transactions in the main chain
+    fn count_unit_txs(&self) -> Result<u64, Error>;
+
+    /// Mark UNIT transactions of the blocks as in the main chain or not, called when
+    /// a reorganization (dis)connects the blocks. Returns amount of changed transactions.
+    fn set_unit_txs_in_longest(
+        &self,
+        block_hashes: &[BlockHash],
+        flag: bool,
+    ) -> Result<usize, Error>;
 }
 
 impl DatabaseRune for Connection {
-    fn store_unit_tx(&mut self, tx: &Transaction, unit_amount: UnitAmount) -> Result<(), Error> {
+    fn store_unit_tx(
+        &mut self,
+        tx: &Transaction,
+        unit_amount: UnitAmount,
+        block_hash: BlockHash,
+        height: u32,
+    ) -> Result<(), Error> {
         let query = r#"
-            INSERT INTO transactions_runes VALUES(:txid, :raw_tx, :unit_amount)
+            INSERT INTO transactions_runes (txid, raw_tx, unit_amount, block_hash, height, in_longest)
+            VALUES(:txid, :raw_tx, :unit_amount, :block_hash, :height, 1)
+            -- The same transaction is processed again after reorg, move it to the new block
+            ON CONFLICT(txid) DO UPDATE SET
+                block_hash = excluded.block_hash,
+                height = excluded.height,
+                in_longest = excluded.in_longest
         "#;
         let mut tx_bytes = vec![];
         tx.consensus_encode(&mut Cursor::new(&mut tx_bytes))
@@ -40,6 +76,8 @@ impl DatabaseRune for Connection {
                 ":txid": (&tx.compute_txid()).field_encode(),
                 ":raw_tx": tx_bytes,
                 ":unit_amount": unit_amount,
+                ":block_hash": (&block_hash).field_encode(),
+                ":height": height,
             })
             .map_err(Error::ExecuteQuery)?;
         Ok(())
@@ -61,6 +99,9 @@ impl DatabaseRune for Connection {
                     Ok(UnitTxMeta {
                         transaction,
                         unit_amount,
+                        block_hash: row.field_decode(3)?,
+                        height: row.get(4)?,
+                        in_longest: row.get::<_, i64>(5)? != 0,
                     })
                 },
             )
@@ -80,4 +121,33 @@ impl DatabaseRune for Connection {
         self.execute_batch(query).map_err(Error::ExecuteQuery)?;
         Ok(())
     }
+
+    fn count_unit_txs(&self) -> Result<u64, Error> {
+        query_count(
+            self,
+            "SELECT count(*) FROM transactions_runes WHERE in_longest = 1",
+        )
+    }
+
+    fn set_unit_txs_in_longest(
+        &self,
+        block_hashes: &[BlockHash],
+        flag: bool,
+    ) -> Result<usize, Error> {
+        let query = r#"
+            UPDATE transactions_runes SET in_longest = :in_longest
+            WHERE block_hash = :block_hash AND in_longest != :in_longest
+        "#;
+        let mut statement = self.prepare_cached(query).map_err(Error::PrepareQuery)?;
+        let mut changed = 0;
+        for block_hash in block_hashes {
+            changed += statement
+                .execute(named_params! {
+                    ":in_longest": flag,
+                    ":block_hash": block_hash.field_encode(),
+                })
+                .map_err(Error::ExecuteQuery)?;
+        }
+        Ok(changed)
+    }
 }
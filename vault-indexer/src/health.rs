@@ -0,0 +1,111 @@
+//! Plain HTTP health endpoint for load balancers and orchestrators that can't speak websocket.
+//! `GET /health` answers `200 OK` when the indexer follows the node and `503 Service Unavailable`
+//! otherwise, the body is [HealthStatus] as JSON.
+use crate::{Indexer, NodeStatus, SyncPhase};
+use core::time::Duration;
+use log::*;
+use serde::Serialize;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+
+/// How long the health server waits for the request of a client
+pub const HEALTH_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Path of the health endpoint
+pub const HEALTH_PATH: &str = "/health";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthStatus {
+    /// The indexer follows the node: it is connected, the headers caught up and the tip isn't
+    /// stale. The read-only indexer is healthy while it can serve queries.
+    pub healthy: bool,
+    /// Whether the indexer is connected to the node, always `false` for the read-only one
+    pub node_connected: bool,
+    /// The initial headers sync caught up with the node, see [Indexer::is_ready]
+    pub ready: bool,
+    /// The node is silent about new blocks for too long, see [Indexer::is_tip_stale]
+    pub tip_stale: bool,
+    /// Seconds since the node sent the last inventory, headers or block
+    pub seconds_since_last_block: Option<u64>,
+    /// Current sync phase, missing if the heights can't be read
+    pub phase: Option<SyncPhase>,
+}
+
+impl HealthStatus {
+    pub fn collect(indexer: &Indexer) -> Self {
+        let node_connected = indexer.node_status() == NodeStatus::Connected;
+        let ready = indexer.is_ready();
+        let tip_stale = indexer.is_tip_stale();
+        let phase = match indexer.phase() {
+            Err(e) => {
+                error!("Failed to get sync phase for health check: {e}");
+                None
+            }
+            Ok(phase) => Some(phase),
+        };
+        let healthy = if indexer.is_read_only() {
+            phase.is_some()
+        } else {
+            node_connected && ready && !tip_stale && phase.is_some()
+        };
+        HealthStatus {
+            healthy,
+            node_connected,
+            ready,
+            tip_stale,
+            seconds_since_last_block: indexer.seconds_since_last_block(),
+            phase,
+        }
+    }
+}
+
+/// Bind the health endpoint and serve it from a background thread. Returns the bound address.
+pub fn start_health_server(indexer: Arc<Indexer>, bind_addr: &str) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind(bind_addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        trace!("Spawn health server thread");
+        for stream in listener.incoming().filter_map(Result::ok) {
+            // Requests are tiny and answered at once, no need for a thread per client
+            if let Err(e) = serve_health(&indexer, stream) {
+                debug!("Failed to answer health request: {e}");
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+fn serve_health(indexer: &Indexer, mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(HEALTH_READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers, the endpoint has no parameters
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(HEALTH_PATH)) => {
+            let health = HealthStatus::collect(indexer);
+            let status = if health.healthy {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let body = serde_json::to_string(&health).map_err(std::io::Error::other)?;
+            (status, body)
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
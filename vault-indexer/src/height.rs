@@ -0,0 +1,114 @@
+//! Distinct types for heights that mean different things, so the compiler catches mixups
+//! like comparing the scanned height with the height of a single block.
+use core::fmt::{self, Display};
+
+macro_rules! height_newtype {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(pub u32);
+
+        impl $name {
+            /// Raw height number
+            pub const fn get(self) -> u32 {
+                self.0
+            }
+        }
+
+        impl From<u32> for $name {
+            fn from(height: u32) -> Self {
+                $name(height)
+            }
+        }
+
+        impl From<$name> for u32 {
+            fn from(height: $name) -> Self {
+                height.0
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+height_newtype!(
+    /// Height of the main chain tip, either ours from the headers sync or the one reported
+    /// by the node
+    ChainHeight
+);
+
+height_newtype!(
+    /// Height of the main chain tip reported by the node on the handshake. It is raised when
+    /// our headers get past it, zero until the first handshake.
+    RemoteHeight
+);
+
+height_newtype!(
+    /// Height up to which all main chain blocks are scanned for vault and UNIT transactions
+    ScannedHeight
+);
+
+height_newtype!(
+    /// Height of a single block
+    BlockHeight
+);
+
+impl ChainHeight {
+    /// Amount of blocks the node has above our headers
+    pub fn blocks_behind(self, remote: RemoteHeight) -> u32 {
+        remote.0.saturating_sub(self.0)
+    }
+
+    /// Whether our headers got to the given scanned height (e.g. the stop height)
+    pub fn reached(self, height: ScannedHeight) -> bool {
+        self.0 >= height.0
+    }
+}
+
+impl RemoteHeight {
+    /// Whether the node told us its height, that happens on the handshake
+    pub fn is_known(self) -> bool {
+        self.0 > 0
+    }
+
+    /// The remote height that isn't below our headers
+    pub fn raise_to(self, chain: ChainHeight) -> Self {
+        RemoteHeight(self.0.max(chain.0))
+    }
+}
+
+impl ScannedHeight {
+    /// Whether there are main chain blocks above the scanned height
+    pub fn is_behind(self, chain: ChainHeight) -> bool {
+        self.0 < chain.0
+    }
+
+    /// Amount of main chain blocks that are not scanned yet
+    pub fn blocks_left(self, chain: ChainHeight) -> u32 {
+        chain.0.saturating_sub(self.0)
+    }
+
+    /// Amount of blocks to scan until the given height (e.g. the stop height)
+    pub fn blocks_until(self, height: ScannedHeight) -> u32 {
+        height.0.saturating_sub(self.0)
+    }
+
+    /// Height of the first block that isn't scanned yet
+    pub fn next_block(self) -> BlockHeight {
+        BlockHeight(self.0 + 1)
+    }
+
+    /// Whether the block is at or below the scanned height
+    pub fn covers(self, block: BlockHeight) -> bool {
+        block.0 <= self.0
+    }
+
+    /// The height that covers the block too
+    pub fn cover(self, block: BlockHeight) -> Self {
+        ScannedHeight(self.0.max(block.0))
+    }
+}
@@ -0,0 +1,62 @@
+//! Wrappers to embed the indexer into tokio services. The indexer itself stays thread-based,
+//! the blocking parts run on the tokio blocking pool.
+use super::event::{Event, EVENTS_CAPACITY};
+use super::{Error, ErrorKind, Indexer};
+use core::future::Future;
+use core::time::Duration;
+use log::*;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use tokio::sync::mpsc;
+
+/// How often the idle forwarding thread checks that the async reader is still alive
+pub const ASYNC_READER_POLL: Duration = Duration::from_millis(500);
+
+/// Forward the events to a new async channel from a separate thread. The thread ends when
+/// the events are disconnected or, within [ASYNC_READER_POLL], after the async receiver is
+/// dropped, even if no events come.
+pub(crate) fn forward_events(events: Receiver<Event>) -> mpsc::Receiver<Event> {
+    let (sender, receiver) = mpsc::channel(EVENTS_CAPACITY);
+    thread::spawn(move || loop {
+        match events.recv_timeout(ASYNC_READER_POLL) {
+            Ok(event) => {
+                if sender.blocking_send(event).is_err() {
+                    trace!("Async events reader is dropped");
+                    return;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if sender.is_closed() => {
+                trace!("Async events reader is dropped");
+                return;
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    });
+    receiver
+}
+
+impl Indexer {
+    /// The same as [Indexer::run], but awaitable from async code. The indexer runs on the
+    /// blocking pool of the current tokio runtime, so the call must happen inside one.
+    ///
+    /// Dropping the future doesn't stop the indexer, use [Indexer::shutdown_handle] for that.
+    pub fn run_async(self: Arc<Self>) -> impl Future<Output = Result<(), Error>> {
+        let handle = tokio::task::spawn_blocking(move || self.run());
+        async move {
+            match handle.await {
+                Ok(res) => res,
+                Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                Err(e) => Err(ErrorKind::BlockingTask(e).into()),
+            }
+        }
+    }
+
+    /// The same as [Indexer::add_event_reader], but the events are received with `.await`.
+    /// Events are forwarded by a separate thread that ends when the receiver is dropped or
+    /// the indexer disconnects the reader.
+    pub fn add_async_event_reader(&self) -> Result<mpsc::Receiver<Event>, Error> {
+        Ok(forward_events(self.add_event_reader()?))
+    }
+}
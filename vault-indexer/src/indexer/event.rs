@@ -1,5 +1,12 @@
 use crate::{db::vault::VaultTxMeta, vault::UnitTransaction};
-use bitcoin::{p2p::message::NetworkMessage, BlockHash};
+use bitcoin::{
+    p2p::{message::NetworkMessage, ServiceFlags},
+    BlockHash, Txid,
+};
+use log::*;
+use schemars::JsonSchema;
+use serde::{Serialize, Serializer};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
 
 /// Amount of events in the internal bus allowed unprocessed
 pub const EVENTS_CAPACITY: usize = 32000;
@@ -13,13 +20,34 @@ pub struct NewUnitTx {
     pub height: u32,
 }
 
+/// What the node told about itself in the version message of the handshake
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct PeerInfo {
+    /// P2P protocol version
+    pub version: u32,
+    pub user_agent: String,
+    /// Advertised services, e.g. whether the node serves witness data or compact filters
+    #[serde(serialize_with = "serialize_services")]
+    #[schemars(with = "String")]
+    pub services: ServiceFlags,
+    /// Height of the node chain at the connection time
+    pub start_height: u32,
+}
+
+/// Services are encoded as the list of known flags, e.g. `ServiceFlags(NETWORK|WITNESS)`
+fn serialize_services<S: Serializer>(services: &ServiceFlags, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_str(services)
+}
+
 /// Events that are passed between parts of the system
 #[derive(Debug, Clone)]
 pub enum Event {
-    /// Node passes handshake process, returns height of remote node
-    Handshaked(u32),
+    /// Node passes handshake process, carries what the node advertised
+    Handshaked(PeerInfo),
     /// We lost connection to the node
     Disconnected,
+    /// We try to connect to the node again, carries amount of attempts since the last handshake
+    NodeReconnected { attempt: u32 },
     /// Node sent a new message to us
     IncomingMessage(NetworkMessage),
     /// We want to send a message to node
@@ -30,4 +58,77 @@ pub enum Event {
     NewTransaction(VaultTxMeta),
     /// Event fired when we encounter new UNIT transaction
     NewUnitTransaction(NewUnitTx),
+    /// Block scanning reached the configured stop height (payload), no more blocks are requested
+    Synced(u32),
+    /// Progress of headers sync that precedes the blocks scanning. Emitted at most once per
+    /// [HEADER_PROGRESS_INTERVAL](crate::indexer::HEADER_PROGRESS_INTERVAL) and when
+    /// the headers are synced.
+    HeaderSyncProgress { current: u32, remote: u32 },
+    /// Detected transaction failed to be stored, the indexer skips it and continues
+    ProcessingError {
+        txid: Txid,
+        height: u32,
+        reason: String,
+    },
+    /// Headers of a fork with more work were ignored as the reorganization would disconnect
+    /// more main chain blocks (`depth`) than allowed
+    ReorgTooDeep { depth: u32 },
+    /// The node hasn't sent inventory, headers or blocks for longer than the configured
+    /// threshold, it might be stuck. Published once until new blocks activity.
+    TipStale { seconds: u64 },
+    /// Transactions of the block don't match the merkle root (or witness commitment) of the
+    /// header or spend outputs of later transactions, the block isn't indexed and is requested again
+    BlockRejected { hash: BlockHash, reason: String },
+    /// Ask the node worker to disconnect from the misbehaving peer and connect to the next one
+    DropPeer { reason: String },
+}
+
+impl Event {
+    /// Whether the event is meant for the readers outside of the indexer. Raw node messages and
+    /// commands to the node worker are internal, [Event::Termination] is kept for readers to
+    /// know that the indexer stops.
+    pub fn is_public(&self) -> bool {
+        !matches!(
+            self,
+            Event::IncomingMessage(_) | Event::OutcomingMessage(_) | Event::DropPeer { .. }
+        )
+    }
+}
+
+/// Fan-out of events to the readers outside of the indexer (e.g. websocket clients). Unlike
+/// the internal bus, sending never blocks: a reader that doesn't keep up is disconnected, it
+/// gets the queued events and then the channel closes.
+#[derive(Debug, Default)]
+pub struct EventReaders {
+    senders: Vec<SyncSender<Event>>,
+}
+
+impl EventReaders {
+    /// Make a new reader that can hold at most `capacity` unread events
+    pub fn add_reader(&mut self, capacity: usize) -> Receiver<Event> {
+        let (sender, receiver) = sync_channel(capacity);
+        self.senders.push(sender);
+        receiver
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    /// Send the public event to all readers, drops readers that are full or gone. Internal
+    /// events (see [Event::is_public]) are skipped.
+    pub fn publish(&mut self, event: Event) {
+        if !event.is_public() {
+            return;
+        }
+        self.senders
+            .retain(|sender| match sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("Disconnecting events reader that doesn't keep up");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
 }
@@ -1,41 +1,65 @@
 use crate::db::vault::rune::DatabaseRune;
 use bitcoin::{
     block::Header,
-    p2p::{message::NetworkMessage, message_blockdata::Inventory},
-    Block, BlockHash, Transaction,
+    p2p::{
+        message::NetworkMessage,
+        message_blockdata::{GetHeadersMessage, Inventory},
+    },
+    Block, BlockHash, Transaction, Txid,
 };
-use bus::{Bus, BusReader};
+use bus::Bus;
 use core::{
     result::Result,
     sync::atomic::{self, AtomicBool, AtomicU32},
     time::Duration,
 };
-use event::{Event, NewUnitTx, EVENTS_CAPACITY};
+use event::{Event, EventReaders, NewUnitTx, EVENTS_CAPACITY};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use log::*;
 pub use network::Network;
 use rusqlite::Connection;
+pub use stats::{IndexerStats, StatsCache, SyncPhase, STATS_TTL};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
 use std::{
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
     sync::{
         mpmc::{self, Sender},
-        mpsc::SendError,
+        mpsc::{Receiver, SendError},
         Arc, Mutex,
     },
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use std::{sync::mpmc::sync_channel, thread};
 use thiserror::Error;
+pub use verify::VerifyReport;
 
-use node::{node_worker, MAX_HEADERS_PER_MSG};
+use node::{node_worker, DEFAULT_MAX_DECODE_FAILURES, MAX_HEADERS_PER_MSG};
+use seed::{parse_seed, PeerSource, SystemResolver};
 
 use crate::{
-    cache::headers::HeadersCache,
-    db::{self, initialize_db, metadata::DatabaseMeta, vault::DatabaseVault},
-    vault::{UnitTransaction, VaultTx},
+    cache::headers::{make_get_data, ChainUpdate, HeadersCache, DEFAULT_MAX_REORG_DEPTH},
+    db::{
+        self, checkpoint_wal, initialize_db,
+        metadata::{DatabaseMeta, ReorgRecord},
+        open_db_read_only,
+        vault::{recompute_vault_in, DatabaseVault, VaultState},
+        CacheConfig, DatabaseHeaders, HeaderRecord, WalCheckpoint,
+    },
+    vault::{UnitTransaction, VaultTx, DEFAULT_PROTOCOL_PREFIXES},
+    webhook::{self, Webhook},
+    BlockHeight, ChainHeight, RemoteHeight, ScannedHeight,
 };
 
+#[cfg(feature = "tokio")]
+pub(crate) mod asynchronous;
 pub mod event;
 pub mod network;
-mod node;
+pub(crate) mod node;
+pub(crate) mod seed;
+pub mod stats;
+pub mod verify;
 
 #[derive(Error, Debug)]
 #[error(transparent)]
@@ -70,6 +94,44 @@ pub enum ErrorKind {
     DatabaseLock,
     #[error("Failed to lock on events bus, poisoned")]
     EventsBusLock,
+    #[error("Failed to lock on event readers, poisoned")]
+    EventReadersLock,
+    #[error("Failed to lock on headers progress timer, poisoned")]
+    HeaderProgressLock,
+    #[error("Failed to lock on rejected blocks counter, poisoned")]
+    BlockRejectionsLock,
+    #[error("Webhook failure: {0}")]
+    Webhook(#[from] webhook::Error),
+    #[error("Transaction {txid} in block {block} spends outputs of later transaction {parent}")]
+    BlockTxOrder {
+        block: BlockHash,
+        txid: Txid,
+        parent: Txid,
+    },
+    #[error("Cannot rescan blocks with read-only database")]
+    ReadOnlyRescan,
+    #[error("Cannot skip blocks with read-only database")]
+    ReadOnlySkip,
+    #[error("Cannot import dump into read-only database")]
+    ReadOnlyImport,
+    #[error("Failed to access dump file {0}: {1}")]
+    DumpFile(PathBuf, std::io::Error),
+    #[error(
+        "Skipping blocks isn't confirmed, transactions in the skipped blocks are never indexed"
+    )]
+    SkipNotConfirmed,
+    #[error("Cannot skip to height {height} above the chain tip {tip}")]
+    SkipAboveTip {
+        height: ScannedHeight,
+        tip: ChainHeight,
+    },
+    #[error("Headers are still syncing, the chain height isn't known yet")]
+    NotReady,
+    #[error("Headers window needs a database file, in-memory database keeps all headers anyway")]
+    HeadersWindowInMemory,
+    #[cfg(feature = "tokio")]
+    #[error("Blocking task of the indexer failed: {0}")]
+    BlockingTask(tokio::task::JoinError),
 }
 
 /// The possible state of connection to bitcoin node we have.
@@ -82,19 +144,76 @@ pub enum NodeStatus {
     Connected,
 }
 
+/// Handle that allows to stop the indexer from other thread (e.g. signal handler).
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    stop_flag: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Ask the indexer to finish current work and return from [Indexer::run]
+    pub fn stop(&self) {
+        self.stop_flag.store(true, atomic::Ordering::Relaxed);
+    }
+
+    /// Returns true if the stop was requested
+    pub fn is_stopped(&self) -> bool {
+        self.stop_flag.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// Custom logic that is called for each scanned block with its height
+pub type BlockHook = Box<dyn Fn(&Block, u32) + Send + Sync>;
+
 /// The core object that holds all resources of the indexer server. The main object
 /// the user of the code should interact with.
 pub struct Indexer {
     network: Network,
     node_address: String,
-    start_height: u32,
+    // Host of DNS seed in `host[:port]` format to discover nodes
+    dns_seed: Option<String>,
+    start_height: ScannedHeight,
+    stop_height: Option<ScannedHeight>,
+    // Set when scanning reached the stop height to notify only once
+    stop_reached: AtomicBool,
     node_connected: Arc<AtomicBool>,
     database: Arc<Mutex<Connection>>,
     headers_cache: Arc<Mutex<HeadersCache>>,
-    batch_size: u32,
+    batch_size: AtomicU32,
+    adaptive_batch: bool,
     remote_height: Arc<AtomicU32>,
     rescan: bool,
+    read_only: bool,
+    // Internal events used to communicate between indexer threads
     events_bus: Arc<Mutex<Bus<Event>>>,
+    // Readers of events outside the indexer that must not block it
+    event_readers: Arc<Mutex<EventReaders>>,
+    stop_flag: Arc<AtomicBool>,
+    checkpoint_interval: Duration,
+    stats_cache: StatsCache,
+    block_hook: Option<BlockHook>,
+    webhook: Option<Webhook>,
+    // Heights window (inclusive) of blocks which are stored in full
+    store_blocks_in: Option<(u32, u32)>,
+    // Messages in a row that fail to decode before reconnecting to the node
+    max_decode_failures: u32,
+    // Failed connections to the node in a row before the indexer fails, None retries forever
+    max_reconnect_attempts: Option<u32>,
+    // When the last headers sync progress event was sent
+    header_progress_at: Mutex<Option<Instant>>,
+    // Set when the initial headers sync caught up with the node
+    ready: AtomicBool,
+    // Silence of the node about new blocks after which the tip is considered stale
+    tip_stale_after: Duration,
+    // Set when the stale tip is reported to notify only once
+    tip_stale_reported: AtomicBool,
+    // Check that block transactions match the header before indexing them, tests with
+    // synthetic blocks turn it off
+    verify_blocks: bool,
+    // How many times in a row the current peer sent each block that didn't match its header
+    block_rejections: Mutex<HashMap<BlockHash, u32>>,
+    // Opcodes after OP_RETURN that mark vault outputs
+    protocol_prefixes: Vec<u8>,
 }
 
 impl Indexer {
@@ -107,6 +226,11 @@ impl Indexer {
         self.network
     }
 
+    /// Whether the indexer only serves queries from the database without connecting to the node
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
     /// Get current state of connection the node
     pub fn node_status(&self) -> NodeStatus {
         let connected = self.node_connected.load(atomic::Ordering::Relaxed);
@@ -117,8 +241,58 @@ impl Indexer {
         }
     }
 
+    /// Seconds since the node sent the last inventory, headers or block. Missing until the
+    /// indexer is run.
+    pub fn seconds_since_last_block(&self) -> Option<u64> {
+        self.stats_cache
+            .since_block_activity()
+            .map(|elapsed| elapsed.as_secs())
+    }
+
+    /// Whether the node is silent about new blocks for longer than the configured threshold,
+    /// e.g. the node itself is stuck. Health checks should treat the indexer as unhealthy then.
+    pub fn is_tip_stale(&self) -> bool {
+        self.stats_cache
+            .since_block_activity()
+            .is_some_and(|elapsed| elapsed > self.tip_stale_after)
+    }
+
+    /// Publish [Event::TipStale] once the tip becomes stale
+    pub(crate) fn check_tip_age(&self, events_sender: &Sender<Event>) -> Result<(), Error> {
+        if !self.is_tip_stale() {
+            self.tip_stale_reported
+                .store(false, atomic::Ordering::Relaxed);
+            return Ok(());
+        }
+        if !self
+            .tip_stale_reported
+            .swap(true, atomic::Ordering::Relaxed)
+        {
+            let seconds = self.seconds_since_last_block().unwrap_or_default();
+            warn!("The node hasn't sent new blocks for {seconds} seconds, the tip might be stale");
+            events_sender.send(Event::TipStale { seconds })?;
+        }
+        Ok(())
+    }
+
+    /// Whether the initial headers sync caught up with the node (within [READY_HEIGHT_LAG]
+    /// blocks), so the chain height reflects the network. Read-only indexer is always ready.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(atomic::Ordering::Relaxed)
+    }
+
+    /// The same as [Indexer::chain_height], but fails with [ErrorKind::NotReady] until the
+    /// initial headers sync caught up with the node. Use it to tell a chain that is genuinely
+    /// at the genesis from headers that aren't loaded yet.
+    pub fn chain_height_checked(&self) -> Result<ChainHeight, Error> {
+        if !self.is_ready() {
+            return Err(ErrorKind::NotReady.into());
+        }
+        self.chain_height()
+    }
+
     /// Get the height of known main chain of blocks we have sequence of headers for
-    pub fn chain_height(&self) -> Result<u32, Error> {
+    pub fn chain_height(&self) -> Result<ChainHeight, Error> {
         Ok(self
             .headers_cache
             .lock()
@@ -126,9 +300,174 @@ impl Indexer {
             .get_current_height())
     }
 
+    /// Tips of the known chains that aren't the main one with their heights, from the highest.
+    /// Helps to diagnose reorganizations without digging through the logs.
+    pub fn forks(&self) -> Result<Vec<(BlockHash, u32)>, Error> {
+        Ok(self
+            .headers_cache
+            .lock()
+            .map_err(|_| ErrorKind::HeadersCacheLock)?
+            .fork_tips())
+    }
+
+    /// Hashes of received headers that don't connect to any known block yet
+    pub fn orphans(&self) -> Result<Vec<BlockHash>, Error> {
+        Ok(self
+            .headers_cache
+            .lock()
+            .map_err(|_| ErrorKind::HeadersCacheLock)?
+            .orphan_hashes())
+    }
+
+    /// Whether the indexer connects to the node, downloads headers, scans blocks or follows
+    /// the tip. Blocks above the stop height don't count once it is reached. The read-only
+    /// indexer never connects, its headers are taken as the whole chain.
+    pub fn phase(&self) -> Result<SyncPhase, Error> {
+        if self.stop_reached.load(atomic::Ordering::Relaxed) {
+            return Ok(SyncPhase::Synced);
+        }
+        let chain_height = self.chain_height()?;
+        let scanned_height = self.scanned_height()?;
+        // Headers past the stop height are never requested, so the chain stays below the node
+        if self.read_only || self.headers_reached_stop(chain_height) {
+            Ok(SyncPhase::from_local_heights(chain_height, scanned_height))
+        } else {
+            Ok(SyncPhase::from_heights(
+                self.remote_height(),
+                chain_height,
+                scanned_height,
+            ))
+        }
+    }
+
+    /// Height of the node tip, zero before the first handshake
+    fn remote_height(&self) -> RemoteHeight {
+        RemoteHeight(self.remote_height.load(atomic::Ordering::Relaxed))
+    }
+
+    fn set_remote_height(&self, height: RemoteHeight) {
+        self.remote_height
+            .store(height.get(), atomic::Ordering::Relaxed);
+    }
+
+    /// Get the hash of the main chain block at the given height. Returns `None` for heights
+    /// above the current tip.
+    pub fn block_hash_at(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        let cache = self
+            .headers_cache
+            .lock()
+            .map_err(|_| ErrorKind::HeadersCacheLock)?;
+        if height > cache.get_current_height().get() {
+            return Ok(None);
+        }
+        Ok(cache.get_blockhash_at(height))
+    }
+
+    /// Get the main chain header at the given height. Returns `None` for heights above the
+    /// current tip.
+    pub fn header_at_height(&self, height: u32) -> Result<Option<Header>, Error> {
+        let cache = self
+            .headers_cache
+            .lock()
+            .map_err(|_| ErrorKind::HeadersCacheLock)?;
+        Ok(cache.find_header_at(height)?.map(|record| record.header))
+    }
+
+    /// Get the known header with its height, including headers of forks
+    pub fn header(&self, hash: BlockHash) -> Result<Option<HeaderRecord>, Error> {
+        let cache = self
+            .headers_cache
+            .lock()
+            .map_err(|_| ErrorKind::HeadersCacheLock)?;
+        Ok(cache.find_header(hash)?)
+    }
+
+    /// Change how many blocks are requested per batch. Takes effect for the next requested batch.
+    /// Zero is raised to one block, empty batches would stall the scanning.
+    pub fn set_batch_size(&self, size: u32) {
+        self.batch_size
+            .store(size.max(1), atomic::Ordering::Relaxed);
+    }
+
     /// Get the height we already have scanned
-    pub fn scanned_height(&self) -> Result<u32, Error> {
-        Ok(self.start_height)
+    pub fn scanned_height(&self) -> Result<ScannedHeight, Error> {
+        let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+        Ok(conn.get_scanned_height()?)
+    }
+
+    /// Advance the scanned height to `height` without downloading the blocks in between, for
+    /// ranges that are known (e.g. verified externally) to have no vault and UNIT transactions.
+    /// If the assumption is wrong, transactions of the skipped blocks are never indexed and
+    /// states of their vaults are wrong until the rescan, so `confirm` must be set to proceed.
+    ///
+    /// Unlike the start height, it works for the running indexer: blocks that are already
+    /// requested are skipped on arrival, the next batch starts after the new height. Heights
+    /// at or below the scanned one are left as is. Returns the new scanned height.
+    pub fn skip_to(&self, height: ScannedHeight, confirm: bool) -> Result<ScannedHeight, Error> {
+        if !confirm {
+            return Err(ErrorKind::SkipNotConfirmed.into());
+        }
+        if self.read_only {
+            return Err(ErrorKind::ReadOnlySkip.into());
+        }
+        let cache = self
+            .headers_cache
+            .lock()
+            .map_err(|_| ErrorKind::HeadersCacheLock)?;
+        let tip = cache.get_current_height();
+        if !tip.reached(height) {
+            return Err(ErrorKind::SkipAboveTip { height, tip }.into());
+        }
+        let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+        let scanned_height = conn.get_scanned_height()?;
+        if height <= scanned_height {
+            return Ok(scanned_height);
+        }
+        warn!(
+            "Skipping blocks {}..={height} without scanning",
+            scanned_height.next_block()
+        );
+        conn.set_scanned_height(height)?;
+        Ok(height)
+    }
+
+    /// Write all indexed data to the gzipped newline delimited JSON file, see [db::dump] for
+    /// the format. Unlike a copy of the SQLite file, the dump can be imported by other versions
+    /// of the indexer. Returns amount of written rows.
+    pub fn export_dump<P: AsRef<Path>>(&self, path: P) -> Result<u64, Error> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|e| ErrorKind::DumpFile(path.to_owned(), e))?;
+        let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+        let rows = {
+            let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+            db::dump::export_dump(&conn, &mut encoder)?
+        };
+        encoder
+            .finish()
+            .and_then(|mut writer| writer.flush())
+            .map_err(|e| ErrorKind::DumpFile(path.to_owned(), e))?;
+        info!("Exported {rows} rows to {path:?}");
+        Ok(rows)
+    }
+
+    /// Replace all indexed data with the dump written by [Indexer::export_dump] and reload
+    /// the headers. Intended for the indexer that isn't running yet. Returns amount of
+    /// imported rows.
+    pub fn import_dump<P: AsRef<Path>>(&self, path: P) -> Result<u64, Error> {
+        if self.read_only {
+            return Err(ErrorKind::ReadOnlyImport.into());
+        }
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| ErrorKind::DumpFile(path.to_owned(), e))?;
+        let reader = BufReader::new(GzDecoder::new(file));
+        let mut cache = self
+            .headers_cache
+            .lock()
+            .map_err(|_| ErrorKind::HeadersCacheLock)?;
+        let mut conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+        let rows = db::dump::import_dump(&mut conn, reader)?;
+        cache.reload(&conn)?;
+        Ok(rows)
     }
 
     /// Get access to internal database (for making queries)
@@ -136,20 +475,97 @@ impl Indexer {
         self.database.clone()
     }
 
-    /// Make a events receiver to listen events about the indexing
-    pub fn add_event_reader(&self) -> Result<BusReader<Event>, Error> {
-        let mut events_bus = self
-            .events_bus
+    /// Get access to the headers cache (for resolving main chain blocks)
+    pub(crate) fn get_headers_cache(&self) -> Arc<Mutex<HeadersCache>> {
+        self.headers_cache.clone()
+    }
+
+    /// Get amounts of indexed vaults, transactions and headers. The result is cached
+    /// for [STATS_TTL] to keep repeated calls cheap.
+    pub fn stats(&self) -> Result<IndexerStats, Error> {
+        let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+        Ok(self.stats_cache.get(&conn)?)
+    }
+
+    /// Get the shared stats cache (e.g. to serve stats from the websocket service)
+    pub fn stats_cache(&self) -> StatsCache {
+        self.stats_cache.clone()
+    }
+
+    /// JSON schema of the websocket API requests and responses, see [crate::service::api_schema]
+    pub fn api_schema(&self) -> serde_json::Value {
+        crate::service::api_schema()
+    }
+
+    /// Check the database consistency (e.g. after a crash) and report found problems
+    pub fn verify(&self) -> Result<VerifyReport, Error> {
+        let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+        Ok(VerifyReport::collect(&conn)?)
+    }
+
+    /// Call the closure for the current state of each vault. The vaults are streamed from the
+    /// database that is locked until the iteration ends, so the closure must not call the
+    /// indexer methods that query the database.
+    pub fn for_each_vault<F: FnMut(VaultState)>(&self, f: F) -> Result<(), Error> {
+        let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+        conn.load_vaults(f)?;
+        Ok(())
+    }
+
+    /// Recalculate the stored custody, volumes and chaining of the vault transactions from
+    /// the raw transactions. Returns amount of processed transactions.
+    pub fn recompute_vault(&self, vault_id: Txid) -> Result<usize, Error> {
+        let mut conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+        Ok(conn.recompute_vault(vault_id)?)
+    }
+
+    /// Flush the SQLite WAL file into the database and truncate it.
+    pub fn checkpoint(&self) -> Result<WalCheckpoint, Error> {
+        let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+        let result = checkpoint_wal(&conn)?;
+        debug!(
+            "WAL checkpoint: {} frames in log, {} checkpointed, busy: {}",
+            result.log_frames, result.checkpointed_frames, result.busy
+        );
+        Ok(result)
+    }
+
+    /// Make a handle that can stop the running indexer from other thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            stop_flag: self.stop_flag.clone(),
+        }
+    }
+
+    /// Make a events receiver to listen events about the indexing. The reader gets only public
+    /// events ([Event::is_public]), raw node messages stay internal. The reader that has
+    /// [EVENTS_CAPACITY] unread events is disconnected to not block the indexing.
+    pub fn add_event_reader(&self) -> Result<Receiver<Event>, Error> {
+        let mut readers = self
+            .event_readers
             .lock()
-            .map_err(|_| ErrorKind::EventsBusLock)?;
-        Ok(events_bus.add_rx())
+            .map_err(|_| ErrorKind::EventReadersLock)?;
+        Ok(readers.add_reader(EVENTS_CAPACITY))
+    }
+
+    /// Send the event to the external readers
+    fn publish(&self, event: Event) -> Result<(), Error> {
+        let mut readers = self
+            .event_readers
+            .lock()
+            .map_err(|_| ErrorKind::EventReadersLock)?;
+        readers.publish(event);
+        Ok(())
     }
 
     /// Executes the internal threads (connection to the node, indexing worker) and awaits
     /// of their termination. Intended to be run in separate thread.
     pub fn run(&self) -> Result<(), Error> {
+        if self.read_only {
+            return self.run_read_only();
+        }
         // Make events fan-in
-        let (events_sender, events_receiver) = sync_channel(EVENTS_CAPACITY);
+        let (events_sender, events_receiver) = sync_channel::<Event>(EVENTS_CAPACITY);
         // Make events fan-out
         let mut events_bus = self
             .events_bus
@@ -160,21 +576,50 @@ impl Indexer {
         let mut main_receiver = events_bus.add_rx();
         // Don't hold lock
         drop(events_bus);
-        // Make a flag to terminate threads after the main runner exits
-        let stop_flag = Arc::new(AtomicBool::new(false));
+        // Flag to terminate threads after the main runner exits
+        let stop_flag = self.stop_flag.clone();
 
         // User requested rescan of blocks
         if self.rescan {
             let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+            conn.drop_scanned_blocks()?;
             conn.set_scanned_height(self.start_height)?;
         }
 
+        if let Some(webhook) = &self.webhook {
+            let headers_cache = self.get_headers_cache();
+            let event_readers = self.event_readers.clone();
+            webhook.clone().spawn(
+                self.network,
+                move || {
+                    event_readers
+                        .lock()
+                        .ok()
+                        .map(|mut readers| readers.add_reader(EVENTS_CAPACITY))
+                },
+                move || {
+                    headers_cache
+                        .lock()
+                        .map_or(0, |cache| cache.get_current_height().get())
+                },
+            );
+        }
+
         // Connect fain-in and fan-out through dispatcher thread
         thread::spawn({
             let events_bus = self.events_bus.clone();
+            let event_readers = self.event_readers.clone();
             move || -> Result<(), Error> {
                 // Will end as soon as events receiver is dropped
                 for event in events_receiver.iter() {
+                    {
+                        let mut readers = event_readers
+                            .lock()
+                            .map_err(|_| ErrorKind::EventReadersLock)?;
+                        if !readers.is_empty() {
+                            readers.publish(event.clone());
+                        }
+                    }
                     let mut events_bus = events_bus.lock().map_err(|_| ErrorKind::EventsBusLock)?;
                     events_bus.broadcast(event);
                 }
@@ -183,15 +628,25 @@ impl Indexer {
         });
 
         let node_handle = {
-            let address = self.node_address.clone();
             let network = self.network;
-            let start_height = self.start_height;
+            let peers = PeerSource::new(
+                self.node_address.clone(),
+                self.dns_seed
+                    .as_deref()
+                    .map(|seed| parse_seed(seed, network.default_port())),
+                Box::new(SystemResolver),
+            );
+            let start_height = self.start_height.get();
+            let max_decode_failures = self.max_decode_failures;
+            let max_reconnect_attempts = self.max_reconnect_attempts;
             let events_sender = events_sender.clone();
             thread::spawn(move || -> Result<(), Error> {
                 node_worker(
-                    &address,
+                    peers,
                     network,
                     start_height,
+                    max_decode_failures,
+                    max_reconnect_attempts,
                     events_sender,
                     node_receiver,
                 )?;
@@ -201,8 +656,29 @@ impl Indexer {
 
         // Here we track how many blocks we
         let mut batch_left = 0;
-        let mut max_scanned_height = 0;
+        // Blocks we requested and still wait for, others are ignored
+        let mut expected_blocks = HashSet::new();
+        let mut max_scanned_height = ScannedHeight::default();
+        let mut last_checkpoint = Instant::now();
+        // Silence of the node is counted from the start
+        self.stats_cache.record_block_activity();
         loop {
+            // User requested graceful shutdown
+            if stop_flag.load(atomic::Ordering::Relaxed) {
+                info!("Shutting down the indexer");
+                events_sender.send(Event::Termination)?;
+                break;
+            }
+
+            // Keep the WAL file bounded during long syncs
+            if last_checkpoint.elapsed() >= self.checkpoint_interval {
+                self.checkpoint()?;
+                last_checkpoint = Instant::now();
+            }
+
+            self.check_tip_age(&events_sender)?;
+            self.stats_cache.record_phase(self.phase()?);
+
             // Terminate if node worker ends with unrecoverable error
             if node_handle.is_finished() {
                 stop_flag.store(true, atomic::Ordering::Relaxed);
@@ -215,46 +691,142 @@ impl Indexer {
                 }
             }
 
-            match main_receiver.recv_timeout(Duration::from_millis(100)) {
-                Err(mpmc::RecvTimeoutError::Timeout) => (), // take a chance to check termination
+            let mut next = match main_receiver.recv_timeout(Duration::from_millis(100)) {
+                Err(mpmc::RecvTimeoutError::Timeout) => None, // take a chance to check termination
                 Err(mpmc::RecvTimeoutError::Disconnected) => {
                     stop_flag.store(true, atomic::Ordering::Relaxed);
                     events_sender.send(Event::Termination)?;
                     return Err(ErrorKind::EventBusRecv.into());
                 }
-                Ok(Event::Handshaked(remote_height)) => {
-                    self.on_handshake(remote_height, &events_sender)?
+                Ok(event) => Some(event),
+            };
+            // Drain the backlog before the housekeeping above runs again, otherwise the node
+            // reader blocks on the full channel during the initial sync. The amount is bounded
+            // to check the termination and checkpoints in time.
+            let mut drained = 0;
+            while let Some(event) = next {
+                self.on_event(
+                    event,
+                    &events_sender,
+                    &mut batch_left,
+                    &mut expected_blocks,
+                    &mut max_scanned_height,
+                )?;
+                drained += 1;
+                next = if drained < MAX_EVENTS_PER_ITERATION {
+                    main_receiver.try_recv().ok()
+                } else {
+                    None
+                };
+            }
+        }
+
+        self.flush()
+    }
+
+    /// Reaction of the main loop to the single event from the node worker
+    fn on_event(
+        &self,
+        event: Event,
+        events_sender: &Sender<Event>,
+        batch_left: &mut i64,
+        expected_blocks: &mut HashSet<BlockHash>,
+        max_scanned_height: &mut ScannedHeight,
+    ) -> Result<(), Error> {
+        match event {
+            Event::Handshaked(peer) => {
+                info!(
+                    "Connected to node {} (protocol {}), services {}, height {}",
+                    peer.user_agent, peer.version, peer.services, peer.start_height
+                );
+                let remote_height = RemoteHeight(peer.start_height);
+                self.stats_cache.record_peer(peer);
+                self.on_handshake(remote_height, events_sender)?
+            }
+            Event::NodeReconnected { attempt } => {
+                debug!("Reconnecting to the node, attempt {attempt}");
+                self.stats_cache.record_reconnect();
+            }
+            Event::Disconnected => {
+                self.node_connected.store(false, atomic::Ordering::Relaxed);
+                // Requested blocks are lost with the connection, request them again after reconnect
+                *batch_left = 0;
+                expected_blocks.clear();
+            }
+            Event::IncomingMessage(msg) => match msg {
+                NetworkMessage::Ping(nonce) => {
+                    events_sender.send(Event::OutcomingMessage(NetworkMessage::Pong(nonce)))?
                 }
-                Ok(Event::Disconnected) => {
-                    self.node_connected.store(false, atomic::Ordering::Relaxed);
+                NetworkMessage::Headers(headers) => {
+                    self.stats_cache.record_block_activity();
+                    self.on_new_headers(headers, events_sender, batch_left, expected_blocks)?
                 }
-                Ok(Event::IncomingMessage(msg)) => match msg {
-                    NetworkMessage::Ping(nonce) => {
-                        events_sender.send(Event::OutcomingMessage(NetworkMessage::Pong(nonce)))?
-                    }
-                    NetworkMessage::Headers(headers) => {
-                        self.on_new_headers(headers, &events_sender, &mut batch_left)?
-                    }
-                    NetworkMessage::Block(block) => self.on_new_block(
+                NetworkMessage::Block(block) => {
+                    self.stats_cache.record_block_activity();
+                    self.on_new_block(
                         block,
-                        &events_sender,
-                        &mut batch_left,
-                        &mut max_scanned_height,
-                    )?,
-                    NetworkMessage::Inv(invs) => self.on_new_invs(invs, &events_sender)?,
-                    _ => (),
-                },
+                        events_sender,
+                        batch_left,
+                        expected_blocks,
+                        max_scanned_height,
+                    )?
+                }
+                NetworkMessage::Inv(invs) => {
+                    self.stats_cache.record_block_activity();
+                    self.on_new_invs(invs, events_sender)?
+                }
                 _ => (),
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Wait for the shutdown without connecting to the node. Headers are reloaded from the
+    /// database each [REPLICA_REFRESH_INTERVAL] to follow the chain written by other process.
+    fn run_read_only(&self) -> Result<(), Error> {
+        info!("Running in read-only mode, the node isn't connected");
+        let mut last_refresh = Instant::now();
+        while !self.stop_flag.load(atomic::Ordering::Relaxed) {
+            if last_refresh.elapsed() >= REPLICA_REFRESH_INTERVAL {
+                let mut cache = self
+                    .headers_cache
+                    .lock()
+                    .map_err(|_| ErrorKind::HeadersCacheLock)?;
+                let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+                cache.reload(&conn)?;
+                last_refresh = Instant::now();
             }
+            self.stats_cache.record_phase(self.phase()?);
+            thread::sleep(Duration::from_millis(100));
         }
+        info!("Shutting down the indexer");
+        Ok(())
+    }
 
+    /// Dump all pending state to the database and checkpoint the WAL file. Called when
+    /// the indexer stops or is dropped, so headers received before the exit are not
+    /// downloaded again after restart.
+    pub fn flush(&self) -> Result<(), Error> {
+        {
+            let mut cache = self
+                .headers_cache
+                .lock()
+                .map_err(|_| ErrorKind::HeadersCacheLock)?;
+            let mut conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+            cache.store(&mut conn)?;
+        }
+        self.checkpoint()?;
         Ok(())
     }
 
-    fn on_handshake(&self, remote_height: u32, events_sender: &Sender<Event>) -> Result<(), Error> {
+    fn on_handshake(
+        &self,
+        remote_height: RemoteHeight,
+        events_sender: &Sender<Event>,
+    ) -> Result<(), Error> {
         self.node_connected.store(true, atomic::Ordering::Relaxed);
-        self.remote_height
-            .store(remote_height, atomic::Ordering::Relaxed);
+        self.set_remote_height(remote_height);
 
         // start requesting headers
         trace!("Requesting first headers");
@@ -262,53 +834,141 @@ impl Indexer {
             .headers_cache
             .lock()
             .map_err(|_| ErrorKind::HeadersCacheLock)?;
-        let headers_msg = cache.make_get_headers()?;
+        let headers_msg = self.make_get_headers(&cache)?;
         events_sender.send(Event::OutcomingMessage(NetworkMessage::GetHeaders(
             headers_msg,
         )))?;
         Ok(())
     }
 
+    /// Headers request that doesn't go past the stop height when we know its block. The
+    /// block is known only after the headers reached the stop height, then the request
+    /// on handshake gets the stop header alone, and no other requests are sent.
+    fn make_get_headers(&self, cache: &HeadersCache) -> Result<GetHeadersMessage, Error> {
+        let stop_hash = self
+            .stop_height
+            .and_then(|height| cache.get_blockhash_at(height.get()));
+        Ok(cache.make_get_headers(stop_hash)?)
+    }
+
+    /// Whether the main chain reached the stop height, so no more headers are requested
+    fn headers_reached_stop(&self, chain_height: ChainHeight) -> bool {
+        self.stop_height
+            .is_some_and(|stop_height| chain_height.reached(stop_height))
+    }
+
     /// Reaction to the new headers from remote peer. Also requests a batch of blocks if
-    /// we synced all headers. Updates the local batch counter for the [on_new_block]
-    fn on_new_headers(
+    /// we synced all headers. Updates the local batch counter and set of expected blocks
+    /// for the [on_new_block]
+    pub(crate) fn on_new_headers(
         &self,
         headers: Vec<Header>,
         events_sender: &Sender<Event>,
         batch_left: &mut i64,
+        expected_blocks: &mut HashSet<BlockHash>,
     ) -> Result<(), Error> {
         debug!("Got {} headers from remote node", headers.len());
-        {
+        let synced = {
             // Very important to lock first on the cache and next to the connection everywhere or we can deadlock
             let mut cache = self
                 .headers_cache
                 .lock()
                 .map_err(|_| ErrorKind::HeadersCacheLock)?;
-            cache.update_longest_chain(&headers)?;
+            let update = match cache.update_longest_chain(&headers) {
+                Err(crate::cache::Error::ReorgTooDeep { depth }) => {
+                    error!("Ignoring headers of a fork that reorganizes {depth} blocks, check the node");
+                    // Don't request more headers, the node would send the same fork again
+                    self.publish(Event::ReorgTooDeep { depth })?;
+                    return Ok(());
+                }
+                res => res?,
+            };
+            // Blocks that left and joined the main chain
+            let mut reorged = None;
+            match update {
+                ChainUpdate::NoOp => trace!("Headers didn't change known chains"),
+                ChainUpdate::Extended { from, to } => {
+                    debug!("Main chain extended from {from} to {to}")
+                }
+                ChainUpdate::ForkStored => debug!("Stored headers of a fork"),
+                ChainUpdate::Reorged {
+                    old_tip,
+                    new_tip,
+                    fork_height,
+                } => {
+                    warn!(
+                        "Chain reorganization at height {fork_height}, old tip {old_tip}, new tip {new_tip}"
+                    );
+                    let disconnected = cache.get_fork_branch(old_tip)?;
+                    let connected = cache.get_blocks_range(
+                        fork_height + 1,
+                        cache.get_current_height().get() - fork_height,
+                    )?;
+                    reorged = Some((disconnected, connected, old_tip, new_tip, fork_height));
+                }
+            }
             let mut conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
             cache.store(&mut conn)?;
+            if let Some((disconnected, connected, old_tip, new_tip, fork_height)) = reorged {
+                // Flags, vault states and the log must not disagree after a crash in between
+                let conn_tx = conn.transaction().map_err(db::Error::StartTransaction)?;
+                let vault_txs = conn_tx.set_vault_txs_in_longest(&disconnected, false)?;
+                let unit_txs = conn_tx.set_unit_txs_in_longest(&disconnected, false)?;
+                conn_tx.set_vault_txs_in_longest(&connected, true)?;
+                conn_tx.set_unit_txs_in_longest(&connected, true)?;
+                let affected = [disconnected, connected].concat();
+                for vault_id in conn_tx.find_vaults_in_blocks(&affected)? {
+                    match recompute_vault_in(&conn_tx, vault_id) {
+                        // The opening transaction left the main chain
+                        Err(db::Error::UnknownVaultId(_)) => {
+                            warn!("Vault {vault_id} has no transactions in the main chain after reorg, hiding it");
+                            conn_tx.set_vault_in_longest(vault_id, false)?;
+                        }
+                        res => {
+                            res?;
+                        }
+                    }
+                }
+                conn_tx.record_reorg(&ReorgRecord {
+                    timestamp: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs()),
+                    old_tip,
+                    new_tip,
+                    fork_height,
+                    vault_txs: vault_txs as u32,
+                    unit_txs: unit_txs as u32,
+                })?;
+                conn_tx.commit().map_err(db::Error::CommitTransaction)?;
+            }
             let current_height = cache.get_current_height();
-            let mut remote_height = self.remote_height.load(atomic::Ordering::Relaxed);
             // Avoid messages that we synced over 100% (remote height is set on the handshake time)
-            if current_height > remote_height {
-                self.remote_height
-                    .store(current_height, atomic::Ordering::Relaxed);
-                remote_height = current_height;
-            }
-            let progress = 100.0 * current_height as f64 / remote_height as f64;
+            let remote_height = self.remote_height().raise_to(current_height);
+            self.set_remote_height(remote_height);
+            let progress = progress_percent(current_height.get(), remote_height.get());
             info!(
                 "New headers height {}, progress: {:.03}%",
                 current_height, progress
             );
-        }
+            // Headers past the stop height aren't needed, the node can have much more of them
+            let synced =
+                headers.len() < MAX_HEADERS_PER_MSG || self.headers_reached_stop(current_height);
+            if (synced || current_height.blocks_behind(remote_height) <= READY_HEIGHT_LAG)
+                && !self.ready.swap(true, atomic::Ordering::Relaxed)
+            {
+                info!("Headers caught up with the node at height {current_height}");
+            }
+            self.notify_header_progress(current_height, remote_height, synced)?;
+            synced
+        };
 
-        if headers.len() == MAX_HEADERS_PER_MSG {
+        if !synced {
             let headers_msg = {
                 let cache = self
                     .headers_cache
                     .lock()
                     .map_err(|_| ErrorKind::HeadersCacheLock)?;
-                cache.make_get_headers()?
+                self.make_get_headers(&cache)?
             };
             debug!("Requesting next headers batch");
             events_sender.send(Event::OutcomingMessage(NetworkMessage::GetHeaders(
@@ -320,47 +980,146 @@ impl Indexer {
                 .headers_cache
                 .lock()
                 .map_err(|_| ErrorKind::HeadersCacheLock)?;
-            let height = cache.get_current_height();
             let scanned_height = {
                 let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
                 conn.get_scanned_height()?
             };
-
-            let msg: NetworkMessage = cache.make_get_blocks(scanned_height + 1, self.batch_size)?;
-            events_sender.send(Event::OutcomingMessage(msg))?;
             // Remember how much blocks we expect
-            let actual_batch = self.batch_size.min(height - scanned_height);
-            debug!("Request {} blocks", actual_batch);
-            *batch_left += actual_batch as i64;
+            *batch_left +=
+                self.request_blocks(&cache, scanned_height, events_sender, expected_blocks)? as i64;
         }
         Ok(())
     }
 
+    /// Broadcast headers sync progress if the last one is older than [HEADER_PROGRESS_INTERVAL]
+    /// or the headers are `synced`
+    pub(crate) fn notify_header_progress(
+        &self,
+        current: ChainHeight,
+        remote: RemoteHeight,
+        synced: bool,
+    ) -> Result<(), Error> {
+        let mut last_sent = self
+            .header_progress_at
+            .lock()
+            .map_err(|_| ErrorKind::HeaderProgressLock)?;
+        let now = Instant::now();
+        let elapsed = last_sent.is_none_or(|at| now.duration_since(at) >= HEADER_PROGRESS_INTERVAL);
+        if synced || elapsed {
+            *last_sent = Some(now);
+            self.publish(Event::HeaderSyncProgress {
+                current: current.get(),
+                remote: remote.get(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Request the next batch of blocks after the `scanned_height` and remember them as expected.
+    /// Returns amount of requested blocks, that is zero when the main chain has nothing above
+    /// the scanned height (e.g. fresh chain with only genesis block).
+    fn request_blocks(
+        &self,
+        cache: &HeadersCache,
+        scanned_height: ScannedHeight,
+        events_sender: &Sender<Event>,
+        expected_blocks: &mut HashSet<BlockHash>,
+    ) -> Result<u32, Error> {
+        let current_height = cache.get_current_height();
+        if let Some(stop_height) = self.stop_height {
+            if scanned_height >= stop_height {
+                if !self.stop_reached.swap(true, atomic::Ordering::Relaxed) {
+                    info!("Reached stop height {stop_height}, block scanning is finished");
+                    events_sender.send(Event::Synced(stop_height.get()))?;
+                }
+                return Ok(0);
+            }
+        }
+        if !scanned_height.is_behind(current_height) {
+            trace!("No blocks to request, scanned {scanned_height} of {current_height}");
+            return Ok(0);
+        }
+        let mut amount = self.batch_size.load(atomic::Ordering::Relaxed);
+        if self.adaptive_batch {
+            amount = adaptive_batch_size(amount, scanned_height.blocks_left(current_height));
+        }
+        if let Some(stop_height) = self.stop_height {
+            amount = amount.min(scanned_height.blocks_until(stop_height));
+        }
+        let hashes = cache.get_blocks_range(scanned_height.next_block().get(), amount)?;
+        events_sender.send(Event::OutcomingMessage(make_get_data(&hashes)))?;
+        let actual_batch = hashes.len() as u32;
+        expected_blocks.extend(hashes);
+        debug!("Request {} blocks", actual_batch);
+        Ok(actual_batch)
+    }
+
     /// React on new arrived block. Also updates the local information how many blocks left in batches and
-    /// cached maximum height of that batch.
-    fn on_new_block(
+    /// cached maximum height of that batch. Blocks that we didn't request (or already got) are ignored.
+    pub(crate) fn on_new_block(
         &self,
         block: Block,
         events_sender: &Sender<Event>,
         batch_left: &mut i64,
-        max_scanned_height: &mut u32,
+        expected_blocks: &mut HashSet<BlockHash>,
+        max_scanned_height: &mut ScannedHeight,
     ) -> Result<(), Error> {
         trace!("Current batch size: {}", *batch_left);
         let hash = block.block_hash();
+        if !expected_blocks.remove(&hash) {
+            debug!("Ignoring block {hash} that wasn't requested");
+            return Ok(());
+        }
+        // The hash covers only the header, the node could send it with other transactions
+        if let Some(reason) = self.block_mismatch(&block) {
+            return self.reject_block(hash, reason, events_sender, expected_blocks);
+        }
         let height = {
             let cache = self
                 .headers_cache
                 .lock()
                 .map_err(|_| ErrorKind::HeadersCacheLock)?;
-            cache.get_header(hash)?.height
+            BlockHeight(cache.get_header(hash)?.height)
         };
 
         debug!("Got block: {}", hash);
-        self.process_block(block, height)?;
+        let already_scanned = {
+            let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+            conn.get_scanned_height()?.covers(height) || conn.is_block_scanned(hash)?
+        };
+        // The block is already in the database, parsing it again only produces failing inserts
+        if already_scanned && !self.rescan {
+            debug!("Skipping already scanned block {hash} at height {height}");
+        } else {
+            match self.process_block(block, height.get()) {
+                // The peer can craft such block as the header PoW is trivial on signet
+                Err(err) if matches!(*err.0, ErrorKind::BlockTxOrder { .. }) => {
+                    return self.reject_block(
+                        hash,
+                        err.to_string(),
+                        events_sender,
+                        expected_blocks,
+                    );
+                }
+                res => res?,
+            }
+        }
+        self.block_rejections
+            .lock()
+            .map_err(|_| ErrorKind::BlockRejectionsLock)?
+            .remove(&hash);
+        let stored_height = {
+            // Blocks can arrive out of order, so the stored scanned height covers only
+            // the blocks without gaps below. Gaps are requested again after restart.
+            let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+            let stored_height = conn.mark_block_scanned(height, hash)?;
+            trace!("Stored scanned height: {stored_height}");
+            stored_height
+        };
         *batch_left -= 1;
 
-        // Remember max height we scanned
-        let scanned_height = (*max_scanned_height).max(height);
+        // Remember max height we scanned, the stored one is ahead after [Indexer::skip_to]
+        let scanned_height = max_scanned_height.cover(height).max(stored_height);
         *max_scanned_height = scanned_height;
         // Scanned all blocks from batch, request next one
         trace!("Batch left: {}", batch_left);
@@ -370,29 +1129,72 @@ impl Indexer {
                 .headers_cache
                 .lock()
                 .map_err(|_| ErrorKind::HeadersCacheLock)?;
+            // Reorganization could shorten the main chain below the blocks we scanned
             let current_height = cache.get_current_height();
-            let scanned_part = 100.0 * scanned_height as f64 / current_height as f64;
+            let scanned_part = progress_percent(scanned_height.get(), current_height.get());
             info!(
                 "Scanned {}/{} {:.03}%",
-                scanned_height, current_height, scanned_part
+                scanned_height.get().min(current_height.get()),
+                current_height,
+                scanned_part
             );
 
-            // Store how much we scanned
-            let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
-            conn.set_scanned_height(scanned_height)?;
-
-            if scanned_height < current_height {
-                let msg: NetworkMessage =
-                    cache.make_get_blocks(scanned_height + 1, self.batch_size)?;
-                events_sender.send(Event::OutcomingMessage(msg))?;
-                let actual_batch = self.batch_size.min(current_height - scanned_height);
-                debug!("Request {} blocks", actual_batch);
-                *batch_left += actual_batch as i64;
-            }
+            // Blocks up to the max received height are requested already, even if the stored
+            // scanned height lags behind because of gaps, so the next batch continues after them
+            *batch_left +=
+                self.request_blocks(&cache, scanned_height, events_sender, expected_blocks)? as i64;
         }
         Ok(())
     }
 
+    /// Skip the invalid block and request it again, the peer is dropped after it sends the block
+    /// [MAX_BLOCK_REJECTIONS] times
+    fn reject_block(
+        &self,
+        hash: BlockHash,
+        reason: String,
+        events_sender: &Sender<Event>,
+        expected_blocks: &mut HashSet<BlockHash>,
+    ) -> Result<(), Error> {
+        self.publish(Event::BlockRejected {
+            hash,
+            reason: reason.clone(),
+        })?;
+        let mut rejections = self
+            .block_rejections
+            .lock()
+            .map_err(|_| ErrorKind::BlockRejectionsLock)?;
+        let count = rejections.entry(hash).or_default();
+        *count += 1;
+        if *count >= MAX_BLOCK_REJECTIONS {
+            // The peer keeps sending the broken block, the blocks are requested again
+            // from the next one after reconnect
+            warn!("Rejecting block {hash}: {reason}, dropping the peer that sent it {count} times");
+            rejections.clear();
+            events_sender.send(Event::DropPeer {
+                reason: format!("sent invalid block {hash} {MAX_BLOCK_REJECTIONS} times"),
+            })?;
+        } else {
+            warn!("Rejecting block {hash}: {reason}, requesting it again");
+            events_sender.send(Event::OutcomingMessage(make_get_data(&[hash])))?;
+            expected_blocks.insert(hash);
+        }
+        Ok(())
+    }
+
+    /// Reason why transactions of the block don't belong to its header, if they don't
+    fn block_mismatch(&self, block: &Block) -> Option<String> {
+        if !self.verify_blocks {
+            None
+        } else if !block.check_merkle_root() {
+            Some("transactions don't match the merkle root".to_owned())
+        } else if !block.check_witness_commitment() {
+            Some("witnesses don't match the witness commitment".to_owned())
+        } else {
+            None
+        }
+    }
+
     /// Remote node will send inventory messages if there are new blocks mined.
     /// Here we request header of that block to trigger sync logic above in [on_new_headers]
     /// and [on_new_block]
@@ -408,9 +1210,11 @@ impl Indexer {
                     .lock()
                     .map_err(|_| ErrorKind::HeadersCacheLock)?;
 
-                // Check if we know the header
-                if cache.get_header(hash).is_err() {
-                    let headers_msg = cache.make_get_headers()?;
+                // Check if we know the header, new blocks past the stop height aren't needed
+                if cache.get_header(hash).is_err()
+                    && !self.headers_reached_stop(cache.get_current_height())
+                {
+                    let headers_msg = self.make_get_headers(&cache)?;
                     events_sender.send(Event::OutcomingMessage(NetworkMessage::GetHeaders(
                         headers_msg,
                     )))?;
@@ -422,15 +1226,35 @@ impl Indexer {
 
     /// Iterate over transactions in the block and parse them. Stores the found vault
     /// transactions in database.
-    fn process_block(&self, block: Block, height: u32) -> Result<(), Error> {
+    ///
+    /// The vault chaining reads the state left by the previous transactions, so parents must
+    /// be stored before children. Transactions are processed in the block order that is
+    /// topological by consensus, the block that violates it is rejected before any writes.
+    pub(crate) fn process_block(&self, block: Block, height: u32) -> Result<(), Error> {
         let block_hash = block.block_hash();
-        for (i, tx) in block.txdata.into_iter().enumerate() {
+        check_block_order(&block)?;
+        if self
+            .store_blocks_in
+            .is_some_and(|(from, to)| (from..=to).contains(&height))
+        {
+            debug!("Storing full block {block_hash} at height {height}");
+            let conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
+            conn.store_block(&block)?;
+        }
+        for (i, tx) in block.txdata.iter().enumerate() {
+            // Coinbase has no real inputs to chain it to a vault, even if its OP_RETURN looks like a vault payload
+            if tx.is_coinbase() {
+                continue;
+            }
             // Detect vault transactions
-            if self.detect_vault_tx(block_hash, height, i, &tx)? {
+            if self.detect_vault_tx(block_hash, height, i, tx)? {
                 continue;
             }
             // Detect UNIT token transactions
-            self.detect_unit_tx(block_hash, height, i, &tx)?;
+            self.detect_unit_tx(block_hash, height, i, tx)?;
+        }
+        if let Some(hook) = &self.block_hook {
+            hook(&block, height);
         }
         Ok(())
     }
@@ -443,10 +1267,11 @@ impl Indexer {
         i: usize,
         tx: &Transaction,
     ) -> Result<bool, Error> {
-        match VaultTx::from_tx(&tx) {
+        match VaultTx::from_tx_with_prefixes(tx, &self.protocol_prefixes) {
             Err(err) => {
                 if !err.is_definetely_not_vault() {
                     error!("Got transaction {}, that possible vault related, but we failed to parse with: {err}", tx.compute_wtxid());
+                    self.stats_cache.record_unparsed_vault_tx();
                     //panic!("Stop here for debug");
                 }
                 Ok(false)
@@ -456,19 +1281,18 @@ impl Indexer {
                 debug!("Found a vault transaction: {:#?}", vtx);
 
                 let mut conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
-                match conn.store_vault_tx(&vtx, block_hash, i, height, &tx) {
+                let event = match conn.store_vault_tx(&vtx, block_hash, i, height, tx) {
                     Err(e) => {
                         error!("Failed to store vault tx {} from block {block_hash} at height {height}, reason: {e}", vtx.txid);
-                        //panic!("Stop here for debug");
-                    }
-                    Ok(meta) => {
-                        let mut events_bus = self
-                            .events_bus
-                            .lock()
-                            .map_err(|_| ErrorKind::EventsBusLock)?;
-                        events_bus.broadcast(Event::NewTransaction(meta));
+                        Event::ProcessingError {
+                            txid: vtx.txid,
+                            height,
+                            reason: e.to_string(),
+                        }
                     }
-                }
+                    Ok(meta) => Event::NewTransaction(meta),
+                };
+                self.publish(event)?;
                 Ok(true)
             }
         }
@@ -482,10 +1306,11 @@ impl Indexer {
         i: usize,
         tx: &Transaction,
     ) -> Result<bool, Error> {
-        match UnitTransaction::from_tx(&tx) {
+        match UnitTransaction::from_tx(tx) {
             Err(err) => {
-                if !err.is_definetely_not_unit() {
+                if err.touches_unit() {
                     trace!("Got transaction {}, that possible UNIT related, but we failed to parse with error: {err}", tx.compute_txid());
+                    self.stats_cache.record_unparsed_unit_tx();
                     //panic!("Stop here for debug");
                 }
                 Ok(false)
@@ -495,24 +1320,23 @@ impl Indexer {
                 debug!("Found a vault transaction: {:#?}", utx);
 
                 let mut conn = self.database.lock().map_err(|_| ErrorKind::DatabaseLock)?;
-                match conn.store_unit_tx(&tx, utx.unit_amount) {
+                let event = match conn.store_unit_tx(tx, utx.unit_amount, block_hash, height) {
                     Err(e) => {
-                        error!("Failed to store vault tx {} from block {block_hash} at height {height}, reason: {e}", tx.compute_txid());
-                        //panic!("Stop here for debug");
-                    }
-                    Ok(_) => {
-                        let mut events_bus = self
-                            .events_bus
-                            .lock()
-                            .map_err(|_| ErrorKind::EventsBusLock)?;
-                        events_bus.broadcast(Event::NewUnitTransaction(NewUnitTx {
-                            utx,
-                            block_hash,
-                            block_pos: i,
+                        error!("Failed to store UNIT tx {} from block {block_hash} at height {height}, reason: {e}", utx.txid);
+                        Event::ProcessingError {
+                            txid: utx.txid,
                             height,
-                        }));
+                            reason: e.to_string(),
+                        }
                     }
-                }
+                    Ok(_) => Event::NewUnitTransaction(NewUnitTx {
+                        utx,
+                        block_hash,
+                        block_pos: i,
+                        height,
+                    }),
+                };
+                self.publish(event)?;
                 Ok(true)
             }
         }
@@ -524,15 +1348,107 @@ impl Indexer {
 // change the result.
 type LazyBuilder<T> = Box<dyn FnOnce() -> T>;
 
+/// Check that transactions of the block spend only outputs of the earlier ones
+fn check_block_order(block: &Block) -> Result<(), Error> {
+    let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+    let positions: HashMap<Txid, usize> = txids
+        .iter()
+        .enumerate()
+        .map(|(i, txid)| (*txid, i))
+        .collect();
+    for (i, tx) in block.txdata.iter().enumerate() {
+        for input in tx.input.iter() {
+            let parent = input.previous_output.txid;
+            if positions.get(&parent).is_some_and(|pos| *pos >= i) {
+                return Err(ErrorKind::BlockTxOrder {
+                    block: block.block_hash(),
+                    txid: txids[i],
+                    parent,
+                }
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Drop for Indexer {
+    fn drop(&mut self) {
+        // Read-only replica doesn't own the headers, they are written by other process
+        if self.read_only {
+            return;
+        }
+        if let Err(e) = self.flush() {
+            error!("Failed to flush the indexer state on drop: {e}");
+        }
+    }
+}
+
+/// The main loop handles at most that many queued events before checking the termination,
+/// checkpoints and the tip age again
+const MAX_EVENTS_PER_ITERATION: usize = EVENTS_CAPACITY;
+
+/// How many times the peer can send a block that doesn't match its header before the indexer
+/// drops the peer and connects to the next one
+pub const MAX_BLOCK_REJECTIONS: u32 = 3;
+
+/// Minimal interval between headers sync progress events
+pub const HEADER_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The indexer is ready when the headers sync is that many blocks behind the node or closer
+pub const READY_HEIGHT_LAG: u32 = 6;
+
+/// Tip is stale after 3 expected block intervals without new blocks from the node
+pub const DEFAULT_TIP_STALE_AFTER: Duration = Duration::from_secs(3 * 10 * 60);
+
+/// How often the read-only indexer reloads headers written by other process
+pub const REPLICA_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Adaptive batch is a fraction of the blocks left to scan
+const ADAPTIVE_BATCH_DIVISOR: u32 = 4;
+/// Adaptive batch is never smaller than this (unless less blocks are left)
+const MIN_ADAPTIVE_BATCH: u32 = 10;
+
+/// Size of the next batch when `blocks_left` blocks are left to scan up to the tip
+pub(crate) fn adaptive_batch_size(batch_size: u32, blocks_left: u32) -> u32 {
+    (blocks_left / ADAPTIVE_BATCH_DIVISOR)
+        .max(MIN_ADAPTIVE_BATCH)
+        .min(batch_size)
+        .max(1)
+}
+
+/// Percent of `done` out of `total` heights. The tip can move below the scanned height on
+/// reorganizations, such progress is reported as 100%.
+pub(crate) fn progress_percent(done: u32, total: u32) -> f64 {
+    // Fresh chain can have only genesis block at both sides
+    (100.0 * done as f64 / total.max(1) as f64).clamp(0.0, 100.0)
+}
+
 /// Builder of indexer allows to specify parameters to the system before actually making a new instance
 /// of the service.
 pub struct IndexerBuilder {
     network_builder: LazyBuilder<Network>,
     node_builder: LazyBuilder<String>,
-    start_height_builder: LazyBuilder<u32>,
+    dns_seed_builder: LazyBuilder<Option<String>>,
+    start_height_builder: LazyBuilder<Option<ScannedHeight>>,
+    stop_height_builder: LazyBuilder<Option<ScannedHeight>>,
     db_path_builder: LazyBuilder<PathBuf>,
     batch_size_builder: LazyBuilder<u32>,
+    adaptive_batch_builder: LazyBuilder<bool>,
     rescan_builder: LazyBuilder<bool>,
+    checkpoint_interval_builder: LazyBuilder<Duration>,
+    block_hook_builder: LazyBuilder<Option<BlockHook>>,
+    webhook_builder: LazyBuilder<Option<(String, Option<String>)>>,
+    read_only_builder: LazyBuilder<bool>,
+    max_reorg_depth_builder: LazyBuilder<u32>,
+    store_blocks_in_builder: LazyBuilder<Option<(u32, u32)>>,
+    max_decode_failures_builder: LazyBuilder<u32>,
+    max_reconnect_attempts_builder: LazyBuilder<Option<u32>>,
+    tip_stale_after_builder: LazyBuilder<Duration>,
+    db_cache_builder: LazyBuilder<CacheConfig>,
+    protocol_prefixes_builder: LazyBuilder<Vec<u8>>,
+    headers_window_builder: LazyBuilder<Option<u32>>,
+    verify_blocks_builder: LazyBuilder<bool>,
 }
 
 impl IndexerBuilder {
@@ -540,10 +1456,26 @@ impl IndexerBuilder {
         IndexerBuilder {
             network_builder: Box::new(|| Network::Bitcoin),
             node_builder: Box::new(|| "45.79.52.207:38333".to_owned()),
-            start_height_builder: Box::new(|| 0),
+            dns_seed_builder: Box::new(|| None),
+            start_height_builder: Box::new(|| None),
+            stop_height_builder: Box::new(|| None),
             db_path_builder: Box::new(|| ":memory:".into()),
             batch_size_builder: Box::new(|| 500),
+            adaptive_batch_builder: Box::new(|| false),
             rescan_builder: Box::new(|| false),
+            checkpoint_interval_builder: Box::new(|| Duration::from_secs(300)),
+            block_hook_builder: Box::new(|| None),
+            webhook_builder: Box::new(|| None),
+            read_only_builder: Box::new(|| false),
+            max_reorg_depth_builder: Box::new(|| DEFAULT_MAX_REORG_DEPTH),
+            store_blocks_in_builder: Box::new(|| None),
+            max_decode_failures_builder: Box::new(|| DEFAULT_MAX_DECODE_FAILURES),
+            max_reconnect_attempts_builder: Box::new(|| None),
+            tip_stale_after_builder: Box::new(|| DEFAULT_TIP_STALE_AFTER),
+            db_cache_builder: Box::new(CacheConfig::default),
+            protocol_prefixes_builder: Box::new(|| DEFAULT_PROTOCOL_PREFIXES.to_vec()),
+            headers_window_builder: Box::new(|| None),
+            verify_blocks_builder: Box::new(|| true),
         }
     }
 
@@ -558,6 +1490,16 @@ impl IndexerBuilder {
         self
     }
 
+    /// Discover nodes with the DNS seed in `host` or `host:port` format, the port is the
+    /// default one of the network if omitted. Resolved nodes are tried in turns when the
+    /// connection fails, the [node](IndexerBuilder::node) address is the fallback if the seed
+    /// returns nothing. By default the seed isn't used.
+    pub fn dns_seed<S: Into<String>>(mut self, seed: S) -> Self {
+        let seed = seed.into();
+        self.dns_seed_builder = Box::new(move || Some(seed));
+        self
+    }
+
     /// Setup SQlite state path. By default is ":memory:"
     pub fn db<P: AsRef<Path>>(mut self, path: P) -> Self {
         let path_buf = path.as_ref().into();
@@ -565,15 +1507,56 @@ impl IndexerBuilder {
         self
     }
 
-    /// Setup how many blocks request per one request
+    /// Size of the SQLite page cache in KiB. The cache lives in the process memory, larger
+    /// cache speeds up queries over big databases. By default is 64 MiB.
+    pub fn db_cache_size(mut self, size_kib: u64) -> Self {
+        let prev = self.db_cache_builder;
+        self.db_cache_builder = Box::new(move || CacheConfig {
+            cache_size_kib: size_kib,
+            ..prev()
+        });
+        self
+    }
+
+    /// How many bytes of the SQLite database file are memory mapped, 0 disables mmap. Mapped
+    /// pages are shared with the OS page cache and don't count as the indexer own memory,
+    /// but reserve the address space. By default is 256 MiB.
+    pub fn db_mmap_size(mut self, size: u64) -> Self {
+        let prev = self.db_cache_builder;
+        self.db_cache_builder = Box::new(move || CacheConfig {
+            mmap_size: size,
+            ..prev()
+        });
+        self
+    }
+
+    /// Setup how many blocks request per one request, zero is raised to one block
     pub fn batch_size(mut self, size: u32) -> Self {
-        self.batch_size_builder = Box::new(move || size);
+        self.batch_size_builder = Box::new(move || size.max(1));
+        self
+    }
+
+    /// Shrink batches when the scanning approaches the tip. Large batches are efficient for
+    /// the initial sync, but a new block found while a large batch is in flight waits for
+    /// the whole batch. Smaller batches near the tip lower the latency of new transactions
+    /// for the price of more round trips. By default is disabled.
+    pub fn adaptive_batch(mut self, flag: bool) -> Self {
+        self.adaptive_batch_builder = Box::new(move || flag);
         self
     }
 
-    /// From which block to start scanning the blockchain
-    pub fn start_height(mut self, height: u32) -> Self {
-        self.start_height_builder = Box::new(move || height);
+    /// From which block to start scanning the blockchain. By default is
+    /// [Network::default_start_height] of the configured network.
+    pub fn start_height(mut self, height: ScannedHeight) -> Self {
+        self.start_height_builder = Box::new(move || Some(height));
+        self
+    }
+
+    /// At which block (inclusive) to stop scanning the blockchain. Headers are not requested
+    /// once the chain reaches the height, the last batch of headers can go past it. By default
+    /// scans up to the tip.
+    pub fn stop_height(mut self, height: Option<ScannedHeight>) -> Self {
+        self.stop_height_builder = Box::new(move || height);
         self
     }
 
@@ -584,24 +1567,164 @@ impl IndexerBuilder {
         self
     }
 
+    /// How often to checkpoint the SQLite WAL file while running. By default is 5 minutes.
+    pub fn checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval_builder = Box::new(move || interval);
+        self
+    }
+
+    /// Custom logic that is called for each scanned block after the built-in vault and UNIT
+    /// detection. The hook runs on the indexing thread and blocks the scanning, so heavy
+    /// work should be offloaded to other thread.
+    pub fn on_block(mut self, hook: BlockHook) -> Self {
+        self.block_hook_builder = Box::new(move || Some(hook));
+        self
+    }
+
+    /// POST each new vault transaction as JSON to the URL. If the `secret` is set, the body
+    /// is signed with HMAC-SHA256 in the [SIGNATURE_HEADER](crate::webhook::SIGNATURE_HEADER)
+    /// header. Failed deliveries are retried with exponential backoff.
+    pub fn webhook<U: Into<String>>(mut self, url: U, secret: Option<String>) -> Self {
+        let url = url.into();
+        self.webhook_builder = Box::new(move || Some((url, secret)));
+        self
+    }
+
+    /// Open the existing database read-only and don't connect to the node, the indexer only
+    /// serves queries (e.g. from a copy of the database of other indexer). By default is disabled.
+    pub fn read_only(mut self, flag: bool) -> Self {
+        self.read_only_builder = Box::new(move || flag);
+        self
+    }
+
+    /// How many blocks of the main chain a reorganization can disconnect. Headers of deeper
+    /// forks are ignored and [Event::ReorgTooDeep] is published. By default is
+    /// [DEFAULT_MAX_REORG_DEPTH].
+    pub fn max_reorg_depth(mut self, depth: u32) -> Self {
+        self.max_reorg_depth_builder = Box::new(move || depth);
+        self
+    }
+
+    /// Store full scanned blocks with heights in the window (inclusive) to load them later
+    /// for debugging. Keep the window small, blocks take much more space than the indexed
+    /// transactions. By default no blocks are stored.
+    pub fn store_blocks_in(mut self, window: Option<(u32, u32)>) -> Self {
+        self.store_blocks_in_builder = Box::new(move || window);
+        self
+    }
+
+    /// How many messages from the node in a row can fail to decode before the connection
+    /// is considered corrupted and reestablished. By default is 10.
+    pub fn max_decode_failures(mut self, amount: u32) -> Self {
+        self.max_decode_failures_builder = Box::new(move || amount.max(1));
+        self
+    }
+
+    /// How many connections to the node in a row can fail before [Indexer::run] returns
+    /// the error instead of reconnecting, so a supervisor can alert or restart the process.
+    /// The counter is reset by each successful handshake. By default the indexer reconnects
+    /// forever.
+    pub fn max_reconnect_attempts(mut self, amount: Option<u32>) -> Self {
+        self.max_reconnect_attempts_builder = Box::new(move || amount.map(|n| n.max(1)));
+        self
+    }
+
+    /// How long the node can be silent about new blocks before the tip is considered stale
+    /// and [Event::TipStale] is published. By default is [DEFAULT_TIP_STALE_AFTER].
+    pub fn tip_stale_after(mut self, threshold: Duration) -> Self {
+        self.tip_stale_after_builder = Box::new(move || threshold);
+        self
+    }
+
+    /// Whether transactions of each block are checked against the merkle root and the witness
+    /// commitment of its header before indexing. A block that doesn't match is requested
+    /// again, after [MAX_BLOCK_REJECTIONS] the peer is dropped. Turning it off only makes
+    /// sense for synthetic blocks in tests. By default is `true`.
+    pub fn verify_blocks(mut self, verify: bool) -> Self {
+        self.verify_blocks_builder = Box::new(move || verify);
+        self
+    }
+
+    /// Opcodes that are recognized after OP_RETURN as the vault protocol marker. Allows to
+    /// index a deployment with other marker. By default is [DEFAULT_PROTOCOL_PREFIXES].
+    pub fn protocol_prefixes(mut self, prefixes: Vec<u8>) -> Self {
+        self.protocol_prefixes_builder = Box::new(move || prefixes);
+        self
+    }
+
+    /// Keep in memory only headers of the last `size` blocks (at least the max reorg depth)
+    /// and read older ones from the database on demand. Saves RAM for the long chains at the
+    /// cost of occasional database reads. Requires a database file. By default all headers
+    /// are kept in memory.
+    pub fn headers_window(mut self, size: u32) -> Self {
+        self.headers_window_builder = Box::new(move || Some(size));
+        self
+    }
+
     pub fn build(self) -> Result<Indexer, Error> {
-        let start_height = (self.start_height_builder)();
         let db_path = (self.db_path_builder)();
         let network = (self.network_builder)();
+        let start_height =
+            (self.start_height_builder)().unwrap_or_else(|| network.default_start_height());
         let rescan = (self.rescan_builder)();
-        let database = initialize_db(&db_path, network, start_height, rescan)?;
-        let headers_cache = HeadersCache::load(&database)?;
+        let read_only = (self.read_only_builder)();
+        let db_cache = (self.db_cache_builder)();
+        let database = if read_only {
+            if rescan {
+                return Err(ErrorKind::ReadOnlyRescan.into());
+            }
+            open_db_read_only(&db_path, network, db_cache)?
+        } else {
+            initialize_db(&db_path, network, start_height, rescan, db_cache)?
+        };
+        let mut headers_cache = match (self.headers_window_builder)() {
+            Some(size) => {
+                if db_path == Path::new(":memory:") {
+                    return Err(ErrorKind::HeadersWindowInMemory.into());
+                }
+                let backing = open_db_read_only(&db_path, network, db_cache)?;
+                HeadersCache::load_window(&database, size, backing)?
+            }
+            None => HeadersCache::load(&database)?,
+        };
+        headers_cache.set_max_reorg_depth((self.max_reorg_depth_builder)());
+        let webhook = match (self.webhook_builder)() {
+            Some((url, secret)) => Some(Webhook::new(url, secret).map_err(ErrorKind::from)?),
+            None => None,
+        };
         Ok(Indexer {
             network,
             node_address: (self.node_builder)(),
+            dns_seed: (self.dns_seed_builder)(),
             start_height,
+            stop_height: (self.stop_height_builder)(),
+            stop_reached: AtomicBool::new(false),
             node_connected: Arc::new(AtomicBool::new(false)),
             database: Arc::new(Mutex::new(database)),
             headers_cache: Arc::new(Mutex::new(headers_cache)),
-            batch_size: (self.batch_size_builder)(),
+            batch_size: AtomicU32::new((self.batch_size_builder)()),
+            adaptive_batch: (self.adaptive_batch_builder)(),
             remote_height: Arc::new(AtomicU32::new(0)),
             rescan,
+            read_only,
             events_bus: Arc::new(Mutex::new(Bus::new(EVENTS_CAPACITY))),
+            event_readers: Arc::new(Mutex::new(EventReaders::default())),
+            stop_flag: Arc::new(AtomicBool::new(false)),
+            checkpoint_interval: (self.checkpoint_interval_builder)(),
+            stats_cache: StatsCache::new(STATS_TTL),
+            block_hook: (self.block_hook_builder)(),
+            webhook,
+            store_blocks_in: (self.store_blocks_in_builder)(),
+            max_decode_failures: (self.max_decode_failures_builder)(),
+            max_reconnect_attempts: (self.max_reconnect_attempts_builder)(),
+            header_progress_at: Mutex::new(None),
+            tip_stale_after: (self.tip_stale_after_builder)(),
+            tip_stale_reported: AtomicBool::new(false),
+            protocol_prefixes: (self.protocol_prefixes_builder)(),
+            verify_blocks: (self.verify_blocks_builder)(),
+            block_rejections: Mutex::new(HashMap::new()),
+            // Read-only indexer serves the headers synced by other process
+            ready: AtomicBool::new(read_only),
         })
     }
 }
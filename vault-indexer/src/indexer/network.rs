@@ -1,5 +1,7 @@
+use crate::ScannedHeight;
 use clap::ValueEnum;
 use core::{fmt::Display, str::FromStr};
+use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 use thiserror::Error;
 
@@ -14,8 +16,14 @@ const MUTINY_SIGNET_GENESIS_HEADER: [u8; 80] = [
     0x4b, 0x1e, 0x5e, 0x4a, 0x00, 0x8f, 0x4d, 0x5f, 0xae, 0x77, 0x03, 0x1e, 0x8a, 0xd2, 0x22, 0x03,
 ];
 
+/// Height of the Mutiny signet block before the first vault transactions
+pub const MUTINYNET_START_HEIGHT: u32 = 1527651;
+
 /// Extended network enum that includes also the Mutiny signet
-#[derive(Copy, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Debug, ValueEnum)]
+#[derive(
+    Copy, PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Debug, ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
 pub enum Network {
     /// Mainnet Bitcoin.
     Bitcoin,
@@ -125,4 +133,28 @@ impl Network {
             Network::Mutinynet => "https://mutinynet.com/tx",
         }
     }
+
+    /// Height to start scanning blocks from when it isn't set explicitly. Vaults are deployed
+    /// only on the Mutiny signet, other networks are scanned from the genesis.
+    pub fn default_start_height(self) -> ScannedHeight {
+        match self {
+            Network::Mutinynet => ScannedHeight(MUTINYNET_START_HEIGHT),
+            Network::Bitcoin
+            | Network::Testnet
+            | Network::Testnet4
+            | Network::Signet
+            | Network::Regtest => ScannedHeight(0),
+        }
+    }
+
+    /// P2P port of the nodes of the network, Mutiny signet uses the signet port
+    pub fn default_port(self) -> u16 {
+        match self {
+            Network::Bitcoin => 8333,
+            Network::Testnet => 18333,
+            Network::Testnet4 => 48333,
+            Network::Signet | Network::Mutinynet => 38333,
+            Network::Regtest => 18444,
+        }
+    }
 }
@@ -20,7 +20,8 @@ use thiserror::Error;
 
 use crate::Network;
 
-use super::event::Event;
+use super::event::{Event, PeerInfo};
+use super::seed::PeerSource;
 
 /// How we introduce ourselves to other nodes
 /// TODO: make configurable
@@ -29,6 +30,10 @@ const DEFAULT_USER_AGENT: &str = "Vault indexer 0.1.0";
 /// The maximum amount of headers node will return for getheaders message
 pub const MAX_HEADERS_PER_MSG: usize = 2000;
 
+/// How many messages in a row can fail to decode before the stream is considered
+/// corrupted and the connection is reestablished
+pub const DEFAULT_MAX_DECODE_FAILURES: u32 = 10;
+
 #[derive(Error, Debug)]
 #[error(transparent)]
 pub struct Error(Box<ErrorKind>);
@@ -75,67 +80,121 @@ pub enum ErrorKind {
     NoVerackMessage,
     #[error("Connected to self, identical nonce in version messages")]
     SelfConnection,
+    #[error("Stream looks corrupted, {0} messages in a row failed to decode")]
+    CorruptStream(u32),
+    #[error("Giving up after {0} failed connection attempts in a row")]
+    ReconnectLimit(u32),
+    #[error("Dropping the peer: {0}")]
+    PeerDropped(String),
 }
 
 /// Reconnection delay in seconds
 const RECONNECTION_TIMEOUT: u64 = 10;
 
-// The endless blocking worker for the node connection, will process events and recoverable errors inside
+// The blocking worker for the node connection, will process events and recoverable errors inside.
+// Reconnects endlessly unless `max_reconnect_attempts` is set.
+#[allow(clippy::too_many_arguments)]
 pub fn node_worker(
-    address: &str,
+    mut peers: PeerSource,
     network: Network,
     start_height: u32,
+    max_decode_failures: u32,
+    max_reconnect_attempts: Option<u32>,
     events_sender: Sender<Event>,
-    events_receiver: BusReader<Event>,
+    mut events_receiver: BusReader<Event>,
 ) -> Result<(), Error> {
-    let (res, next_receiver) = node_process(
-        address,
-        network,
-        start_height,
-        events_sender.clone(),
-        events_receiver,
-    );
-    match res.map_err(|e| *e.0) {
-        Err(
-            e
-            @ (ErrorKind::EventBusSend(_) | ErrorKind::EventBusRecv | ErrorKind::WrongMagic(_, _)),
-        ) => {
-            // We consider that reconnection doesn't have sense in these cases
-            error!("{e}");
-            Err(e.into())
-        }
-        Err(e) => {
-            error!("{e}");
-            events_sender.send(Event::Disconnected)?;
-            warn!("Reconnecting to the node in {RECONNECTION_TIMEOUT} seconds...");
-            sleep(Duration::from_secs(RECONNECTION_TIMEOUT));
-            node_worker(address, network, start_height, events_sender, next_receiver)
-        }
-        Ok(_) => {
-            // Termination procedure
-            Ok(())
+    // Amount of reconnections since the last successful handshake
+    let mut attempt = 0;
+    loop {
+        let address = peers.next_address();
+        let (res, next_receiver) = match node_connect(&address) {
+            Err(e) => (Err(e), events_receiver),
+            Ok((stream, node_addr)) => node_process(
+                stream,
+                &node_addr,
+                network,
+                start_height,
+                max_decode_failures,
+                events_sender.clone(),
+                events_receiver,
+                &mut attempt,
+            ),
+        };
+        events_receiver = next_receiver;
+        match res.map_err(|e| *e.0) {
+            Err(
+                e @ (ErrorKind::EventBusSend(_)
+                | ErrorKind::EventBusRecv
+                | ErrorKind::WrongMagic(_, _)),
+            ) => {
+                // We consider that reconnection doesn't have sense in these cases
+                error!("{e}");
+                return Err(e.into());
+            }
+            Err(e) => {
+                error!("{e}");
+                events_sender.send(Event::Disconnected)?;
+                attempt += 1;
+                if max_reconnect_attempts.is_some_and(|max| attempt >= max) {
+                    let e = ErrorKind::ReconnectLimit(attempt);
+                    error!("{e}");
+                    return Err(e.into());
+                }
+                warn!("Reconnecting to the node in {RECONNECTION_TIMEOUT} seconds...");
+                sleep(Duration::from_secs(RECONNECTION_TIMEOUT));
+                events_sender.send(Event::NodeReconnected { attempt })?;
+            }
+            Ok(_) => {
+                // Termination procedure
+                return Ok(());
+            }
         }
     }
 }
 
-// Body of worker that connects to the node and processes all messages incoming and outcoming
+/// Duplex connection to the peer that can be shared between reading and writing threads.
+/// Production code uses [TcpStream], tests can script the peer with in-memory streams.
+pub(crate) trait PeerStream: Read + Write + Send + Sized + 'static {
+    /// Make another handle to the same connection
+    fn try_clone(&self) -> std::io::Result<Self>;
+
+    /// Close both directions of the connection to unblock pending operations
+    fn shutdown(&self) -> std::io::Result<()>;
+}
+
+impl PeerStream for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+// Body of worker that handshakes with the connected node and processes all messages incoming and outcoming
 //
 // Note that we MUST rescure the events receiver bus. It is not cloneable and we want to be able to
 // restart all connection if something went wrong.
-fn node_process(
-    address: &str,
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn node_process<S: PeerStream>(
+    mut stream: S,
+    node_addr: &SocketAddr,
     network: Network,
     start_height: u32,
+    max_decode_failures: u32,
     events_sender: Sender<Event>,
     mut events_receiver: BusReader<Event>,
+    attempt: &mut u32,
 ) -> (Result<(), Error>, BusReader<Event>) {
     // Perform handshake sequence
-    let (mut stream, remote_height) = match node_handshake(address, network, start_height) {
+    let peer = match node_handshake(&mut stream, node_addr, network, start_height) {
         Err(e) => return (Err(e), events_receiver),
-        Ok(stream) => stream,
+        Ok(peer) => peer,
     };
+    *attempt = 0;
     // Notify top level logic that we are connected
-    if let Err(e) = events_sender.send(Event::Handshaked(remote_height)) {
+    if let Err(e) = events_sender.send(Event::Handshaked(peer)) {
         return (Err(ErrorKind::EventBusSend(e).into()), events_receiver);
     }
     debug!("Handshake event sent");
@@ -150,6 +209,8 @@ fn node_process(
         let stop_flag = stop_flag.clone();
         let events_sender = events_sender.clone();
         thread::spawn(move || -> Result<(), Error> {
+            // Single bad message is skipped, but a run of them means that we lost the framing
+            let mut decode_failures = 0;
             loop {
                 if stop_flag.load(atomic::Ordering::Relaxed) {
                     break Ok(());
@@ -157,6 +218,7 @@ fn node_process(
 
                 match receive_message(&mut receiver_stream, network).map_err(|e| *e.0) {
                     Ok(msg) => {
+                        decode_failures = 0;
                         events_sender
                             .send(Event::IncomingMessage(msg))
                             .map_err(ErrorKind::EventBusSend)?;
@@ -164,6 +226,10 @@ fn node_process(
                     Err(e @ ErrorKind::DecodingMessage(_, _)) => {
                         // We consider that recoverable
                         error!("{e}");
+                        decode_failures += 1;
+                        if decode_failures >= max_decode_failures {
+                            return Err(ErrorKind::CorruptStream(decode_failures).into());
+                        }
                     }
                     Err(e) => return Err(e.into()), // Should reconnect
                 }
@@ -189,10 +255,7 @@ fn node_process(
                 // Notify other threads that we are done
                 stop_flag.store(true, atomic::Ordering::Relaxed);
                 // Shutdown socket to force unblocking operations on it, ignore error here if occurs
-                if let Err(e) = stream
-                    .shutdown(Shutdown::Both)
-                    .map_err(ErrorKind::SocketShutdownFail)
-                {
+                if let Err(e) = stream.shutdown().map_err(ErrorKind::SocketShutdownFail) {
                     error!("At shutdown procedure we got {e}");
                 }
 
@@ -208,26 +271,27 @@ fn node_process(
                 // Notify other threads that we are done
                 stop_flag.store(true, atomic::Ordering::Relaxed);
                 // Shutdown socket to force unblocking operations on it, ignore error here if occurs
-                if let Err(e) = stream
-                    .shutdown(Shutdown::Both)
-                    .map_err(ErrorKind::SocketShutdownFail)
-                {
+                if let Err(e) = stream.shutdown().map_err(ErrorKind::SocketShutdownFail) {
                     error!("At shutdown procedure we got {e}");
                 }
 
                 return (Ok(()), events_receiver);
             }
+            Ok(Event::DropPeer { reason }) => {
+                stop_flag.store(true, atomic::Ordering::Relaxed);
+                if let Err(e) = stream.shutdown().map_err(ErrorKind::SocketShutdownFail) {
+                    error!("At disconnection from the peer we got {e}");
+                }
+                // Reconnects to the next address like after any connection failure
+                return (Err(ErrorKind::PeerDropped(reason).into()), events_receiver);
+            }
             _ => (),
         }
     }
 }
 
-// Connect to node and do all handshake protocol (version exchange and verack messages)
-fn node_handshake(
-    address: &str,
-    network: Network,
-    start_height: u32,
-) -> Result<(TcpStream, u32), Error> {
+// Resolve address of the node and open TCP connection to it
+fn node_connect(address: &str) -> Result<(TcpStream, SocketAddr), Error> {
     debug!("Resolving address to node {address}...");
     let mut sock_addrs = address
         .to_socket_addrs()
@@ -240,45 +304,59 @@ fn node_handshake(
 
     // TODO: use connect_timeout and list of nodes
     debug!("Connecting to the {address} node...");
-    let mut stream =
+    let stream =
         TcpStream::connect(address).map_err(|e| ErrorKind::Connection(address.to_owned(), e))?;
     info!("Connected to the {address} node");
+    Ok((stream, node_addr))
+}
 
+// Do all handshake protocol (version exchange and verack messages), returns height of the node
+fn node_handshake<S: PeerStream>(
+    stream: &mut S,
+    node_addr: &SocketAddr,
+    network: Network,
+    start_height: u32,
+) -> Result<PeerInfo, Error> {
     trace!("Handshaking");
-    let ver_msg = build_version_message(&node_addr, DEFAULT_USER_AGENT, start_height);
+    let ver_msg = build_version_message(node_addr, DEFAULT_USER_AGENT, start_height);
     let self_nonce = ver_msg.nonce;
-    send_message(&mut stream, network, NetworkMessage::Version(ver_msg))?;
+    send_message(stream, network, NetworkMessage::Version(ver_msg))?;
     trace!("Sent version message, awaiting version msg from peer...");
 
-    let first_msg = receive_message(&mut stream, network)?;
-    let remote_height = if let NetworkMessage::Version(ver) = first_msg {
-        // really don't care the correctness of the message
+    let first_msg = receive_message(stream, network)?;
+    let peer = if let NetworkMessage::Version(ver) = first_msg {
+        // really don't care the correctness of the message, keep only diagnostic info
         debug!("Got version message from peer");
         if ver.nonce == self_nonce {
             return Err(ErrorKind::SelfConnection.into());
         }
-        ver.start_height
+        PeerInfo {
+            version: ver.version,
+            user_agent: ver.user_agent,
+            services: ver.services,
+            start_height: ver.start_height as u32,
+        }
     } else {
         return Err(ErrorKind::NoVersionMessage.into());
     };
 
     // Send verack message that we accept their version
-    send_message(&mut stream, network, NetworkMessage::Verack)?;
+    send_message(stream, network, NetworkMessage::Verack)?;
     debug!("Sent verack message");
 
     trace!("Awaiting verack from their side");
-    let second_msg = receive_message(&mut stream, network)?;
+    let second_msg = receive_message(stream, network)?;
     if let NetworkMessage::Verack = second_msg {
         debug!("Got verack message from peer");
     } else {
         return Err(ErrorKind::NoVerackMessage.into());
     }
     debug!("Handshake finish");
-    Ok((stream, remote_height as u32))
+    Ok(peer)
 }
 
-fn send_message(
-    stream: &mut TcpStream,
+pub(crate) fn send_message<S: Write>(
+    stream: &mut S,
     network: Network,
     msg: NetworkMessage,
 ) -> Result<(), Error> {
@@ -292,7 +370,10 @@ fn send_message(
     Ok(())
 }
 
-fn receive_message(stream: &mut TcpStream, network: Network) -> Result<NetworkMessage, Error> {
+pub(crate) fn receive_message<S: Read>(
+    stream: &mut S,
+    network: Network,
+) -> Result<NetworkMessage, Error> {
     // Header size is 24 bytes
     const HEADER_SIZE: usize = 24;
     let mut header_buf = [0u8; HEADER_SIZE];
@@ -327,7 +408,7 @@ fn receive_message(stream: &mut TcpStream, network: Network) -> Result<NetworkMe
 }
 
 // https://en.bitcoin.it/wiki/Protocol_documentation#version
-fn build_version_message(
+pub(crate) fn build_version_message(
     address: &SocketAddr,
     user_agent: &str,
     start_height: u32,
@@ -0,0 +1,86 @@
+use core::net::{IpAddr, SocketAddr};
+use log::*;
+use std::net::ToSocketAddrs;
+
+/// Resolves the host name of DNS seed to addresses of the nodes
+pub trait SeedResolver: Send {
+    fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>>;
+}
+
+/// Resolves A/AAAA records with the system resolver
+pub struct SystemResolver;
+
+impl SeedResolver for SystemResolver {
+    fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        Ok((host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+    }
+}
+
+/// Split the seed in `host` or `host:port` format, nodes listen on the `default_port`
+/// if the port is omitted.
+pub fn parse_seed(seed: &str, default_port: u16) -> (String, u16) {
+    match seed.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => (host.to_owned(), port),
+            Err(_) => (seed.to_owned(), default_port),
+        },
+        None => (seed.to_owned(), default_port),
+    }
+}
+
+/// Addresses of nodes the indexer connects to. Nodes from the DNS seed are tried in turns
+/// on each connection attempt and the seed is resolved again when all of them were tried.
+/// The fixed node address is used when there is no seed or it returns no nodes.
+pub struct PeerSource {
+    node: String,
+    seed: Option<(String, u16)>,
+    resolver: Box<dyn SeedResolver>,
+    // Nodes left to try in reversed order
+    peers: Vec<SocketAddr>,
+}
+
+impl PeerSource {
+    pub fn new(node: String, seed: Option<(String, u16)>, resolver: Box<dyn SeedResolver>) -> Self {
+        PeerSource {
+            node,
+            seed,
+            resolver,
+            peers: vec![],
+        }
+    }
+
+    /// Address of the node for the next connection attempt
+    pub fn next_address(&mut self) -> String {
+        if self.peers.is_empty() {
+            self.refill();
+        }
+        match self.peers.pop() {
+            Some(addr) => addr.to_string(),
+            None => self.node.clone(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let Some((host, port)) = &self.seed else {
+            return;
+        };
+        debug!("Resolving nodes of DNS seed {host}...");
+        match self.resolver.resolve(host) {
+            Ok(ips) if ips.is_empty() => {
+                warn!("DNS seed {host} returned no nodes, using {}", self.node)
+            }
+            Ok(ips) => {
+                info!("DNS seed {host} returned {} nodes", ips.len());
+                self.peers = ips
+                    .into_iter()
+                    .rev()
+                    .map(|ip| SocketAddr::new(ip, *port))
+                    .collect();
+            }
+            Err(e) => warn!(
+                "Failed to resolve DNS seed {host}: {e}, using {}",
+                self.node
+            ),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use super::event::PeerInfo;
+use crate::db::{
+    self,
+    vault::{DatabaseRune, DatabaseVault},
+    DatabaseHeaders,
+};
+use crate::{ChainHeight, RemoteHeight, ScannedHeight};
+use core::time::Duration;
+use rusqlite::Connection;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+/// How long the collected stats are reused before querying the database again
+pub const STATS_TTL: Duration = Duration::from_secs(5);
+
+/// What the indexer is busy with, derived from the remote, chain and scanned heights
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPhase {
+    /// The node hasn't handshaked yet, so its height is unknown
+    Connecting,
+    /// Headers are still downloaded, the chain height is below the node height
+    HeaderSync,
+    /// All headers are known, but not all blocks of the chain are scanned
+    BlockScan,
+    /// Blocks are scanned up to the tip (or the stop height)
+    Synced,
+}
+
+impl SyncPhase {
+    /// Derive the phase from the heights. The remote height is zero until the node handshake,
+    /// the local heights alone can't tell whether the indexer is synced then.
+    pub fn from_heights(
+        remote_height: RemoteHeight,
+        chain_height: ChainHeight,
+        scanned_height: ScannedHeight,
+    ) -> Self {
+        if !remote_height.is_known() {
+            SyncPhase::Connecting
+        } else if chain_height.blocks_behind(remote_height) > 0 {
+            SyncPhase::HeaderSync
+        } else {
+            Self::from_local_heights(chain_height, scanned_height)
+        }
+    }
+
+    /// Derive the phase when the headers are taken as the whole chain, e.g. for the read-only
+    /// indexer or after the headers reached the stop height
+    pub fn from_local_heights(chain_height: ChainHeight, scanned_height: ScannedHeight) -> Self {
+        if scanned_height.is_behind(chain_height) {
+            SyncPhase::BlockScan
+        } else {
+            SyncPhase::Synced
+        }
+    }
+}
+
+/// Snapshot of amounts of indexed entities, intended for dashboards and monitoring
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct IndexerStats {
+    /// Amount of known vaults
+    pub vaults: u64,
+    /// Amount of vault transactions (including opening ones)
+    pub vault_transactions: u64,
+    /// Amount of UNIT rune transactions
+    pub unit_transactions: u64,
+    /// Amount of stored headers including forks
+    pub headers: u64,
+    /// Amount of stored headers that are not in the main chain
+    pub fork_headers: u64,
+    /// Amount of reconnections to the node since the indexer start
+    pub reconnects: u64,
+    /// Amount of transactions since the indexer start that look like vault ones, but the
+    /// parser failed on them. Growth means a new payload format that isn't supported yet.
+    pub unparsed_vault_txs: u64,
+    /// Amount of runestones since the indexer start that might carry UNIT, but failed to
+    /// parse (cenotaphs or runestones without UNIT edicts)
+    pub unparsed_unit_txs: u64,
+    /// What the node advertised on the last handshake, missing until the first one
+    pub peer: Option<PeerInfo>,
+    /// Seconds since the node sent the last inventory, headers or block
+    pub seconds_since_last_block: Option<u64>,
+    /// Current sync phase, missing until the indexer starts
+    pub phase: Option<SyncPhase>,
+}
+
+impl IndexerStats {
+    /// Query all counters from the database. Runtime counters are left zero.
+    pub fn collect(conn: &Connection) -> Result<Self, db::Error> {
+        Ok(IndexerStats {
+            vaults: conn.count_vaults()?,
+            vault_transactions: conn.count_vault_txs()?,
+            unit_transactions: conn.count_unit_txs()?,
+            headers: conn.count_headers()?,
+            fork_headers: conn.count_fork_headers()?,
+            reconnects: 0,
+            unparsed_vault_txs: 0,
+            unparsed_unit_txs: 0,
+            peer: None,
+            seconds_since_last_block: None,
+            phase: None,
+        })
+    }
+}
+
+/// Shared cache of the last collected [IndexerStats] so frequent requests don't hit the
+/// database each time.
+#[derive(Debug, Clone)]
+pub struct StatsCache {
+    last: Arc<Mutex<Option<(Instant, IndexerStats)>>>,
+    ttl: Duration,
+    reconnects: Arc<AtomicU64>,
+    unparsed_vault_txs: Arc<AtomicU64>,
+    unparsed_unit_txs: Arc<AtomicU64>,
+    peer: Arc<Mutex<Option<PeerInfo>>>,
+    last_block_activity: Arc<Mutex<Option<Instant>>>,
+    phase: Arc<Mutex<Option<SyncPhase>>>,
+}
+
+impl StatsCache {
+    pub fn new(ttl: Duration) -> Self {
+        StatsCache {
+            last: Arc::new(Mutex::new(None)),
+            ttl,
+            reconnects: Arc::new(AtomicU64::new(0)),
+            unparsed_vault_txs: Arc::new(AtomicU64::new(0)),
+            unparsed_unit_txs: Arc::new(AtomicU64::new(0)),
+            peer: Arc::new(Mutex::new(None)),
+            last_block_activity: Arc::new(Mutex::new(None)),
+            phase: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Count one more reconnection to the node
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Remember what the node advertised on the handshake
+    pub fn record_peer(&self, peer: PeerInfo) {
+        *self.peer.lock().unwrap_or_else(|e| e.into_inner()) = Some(peer);
+    }
+
+    /// Remember that the node sent something about new blocks (inventory, headers or block)
+    pub fn record_block_activity(&self) {
+        *self
+            .last_block_activity
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+    }
+
+    /// Time since the node sent anything about new blocks, missing until the indexer runs
+    pub fn since_block_activity(&self) -> Option<Duration> {
+        self.last_block_activity
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .map(|at| at.elapsed())
+    }
+
+    /// Remember the current sync phase
+    pub fn record_phase(&self, phase: SyncPhase) {
+        *self.phase.lock().unwrap_or_else(|e| e.into_inner()) = Some(phase);
+    }
+
+    /// Count one more possible vault transaction that failed to parse
+    pub fn record_unparsed_vault_tx(&self) {
+        self.unparsed_vault_txs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count one more possible UNIT transaction that failed to parse
+    pub fn record_unparsed_unit_tx(&self) {
+        self.unparsed_unit_txs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get cached stats or collect them again if they are older than TTL. Runtime counters
+    /// are always fresh.
+    pub fn get(&self, conn: &Connection) -> Result<IndexerStats, db::Error> {
+        let mut stats = self.get_db_stats(conn)?;
+        stats.reconnects = self.reconnects.load(Ordering::Relaxed);
+        stats.unparsed_vault_txs = self.unparsed_vault_txs.load(Ordering::Relaxed);
+        stats.unparsed_unit_txs = self.unparsed_unit_txs.load(Ordering::Relaxed);
+        stats.peer = self.peer.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        stats.seconds_since_last_block = self.since_block_activity().map(|d| d.as_secs());
+        stats.phase = *self.phase.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(stats)
+    }
+
+    fn get_db_stats(&self, conn: &Connection) -> Result<IndexerStats, db::Error> {
+        // Poisoned lock means only that other thread failed while collecting, the value is still valid
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((collected, stats)) = &*last {
+            if collected.elapsed() < self.ttl {
+                return Ok(stats.clone());
+            }
+        }
+        let stats = IndexerStats::collect(conn)?;
+        *last = Some((Instant::now(), stats.clone()));
+        Ok(stats)
+    }
+}
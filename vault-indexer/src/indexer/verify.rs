@@ -0,0 +1,110 @@
+use crate::db::{
+    self, integrity_check, open_db_read_only, vault::DatabaseVault, CacheConfig, DatabaseHeaders,
+    DatabaseMeta,
+};
+use crate::{ChainHeight, Network, ScannedHeight};
+use bitcoin::{BlockHash, Txid};
+use log::*;
+use rusqlite::Connection;
+use std::path::Path;
+
+/// Inconsistencies found by the database self-check, see [VerifyReport::collect]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Problems reported by SQLite `PRAGMA integrity_check`
+    pub integrity_errors: Vec<String>,
+    /// Heights of main chain headers that don't continue the previous one: the height is
+    /// skipped or repeated, or the parent hash doesn't match
+    pub chain_breaks: Vec<u32>,
+    /// The tip in metadata isn't the last header of the main chain
+    pub tip_mismatch: bool,
+    /// Vault transactions that reference a missing vault
+    pub dangling_transactions: Vec<Txid>,
+    /// Height of the last main chain header
+    pub chain_height: ChainHeight,
+    /// Height the blocks are scanned up to
+    pub scanned_height: ScannedHeight,
+}
+
+impl VerifyReport {
+    /// Run all checks over the database. Checks walk whole tables, so the call is slow
+    /// for the synced database.
+    pub fn collect(conn: &Connection) -> Result<Self, db::Error> {
+        let integrity_errors = integrity_check(conn)?;
+
+        let mut chain_breaks = vec![];
+        let mut last: Option<(u32, BlockHash)> = None;
+        conn.load_main_chain_links(|height, hash, prev_hash| {
+            let linked = match last {
+                None => height == 0,
+                Some((last_height, last_hash)) => {
+                    height == last_height + 1 && prev_hash == last_hash
+                }
+            };
+            if !linked {
+                chain_breaks.push(height);
+            }
+            last = Some((height, hash));
+        })?;
+        let (chain_height, last_hash) = last.unzip();
+        let tip_mismatch = last_hash != Some(conn.get_main_tip()?);
+
+        Ok(VerifyReport {
+            integrity_errors,
+            chain_breaks,
+            tip_mismatch,
+            dangling_transactions: conn.find_dangling_vault_txs()?,
+            chain_height: ChainHeight(chain_height.unwrap_or_default()),
+            scanned_height: conn.get_scanned_height()?,
+        })
+    }
+
+    /// Check the database file without building the indexer. The indexer migrates the database
+    /// on start and fails to load a broken chain of headers, so the file is opened read-only
+    /// and checked as is.
+    pub fn collect_file<P: AsRef<Path>>(
+        path: P,
+        network: Network,
+        cache: CacheConfig,
+    ) -> Result<Self, db::Error> {
+        let conn = open_db_read_only(path, network, cache)?;
+        VerifyReport::collect(&conn)
+    }
+
+    /// Log each found problem as an error
+    pub fn log_problems(&self) {
+        for problem in &self.integrity_errors {
+            error!("Integrity check: {problem}");
+        }
+        for height in &self.chain_breaks {
+            error!("Main chain is broken at height {height}");
+        }
+        if self.tip_mismatch {
+            error!("Stored tip isn't the last header of the main chain");
+        }
+        for txid in &self.dangling_transactions {
+            error!("Transaction {txid} references missing vault");
+        }
+        if self.scanned_above_tip() {
+            error!(
+                "Scanned height {} is above the chain height {}",
+                self.scanned_height, self.chain_height
+            );
+        }
+    }
+
+    /// Scanned height is above the chain tip. Note that it is also the case while headers
+    /// are synced up to the start height for the first time.
+    pub fn scanned_above_tip(&self) -> bool {
+        self.scanned_height.get() > self.chain_height.get()
+    }
+
+    /// No inconsistencies found
+    pub fn is_ok(&self) -> bool {
+        self.integrity_errors.is_empty()
+            && self.chain_breaks.is_empty()
+            && !self.tip_mismatch
+            && self.dangling_transactions.is_empty()
+            && !self.scanned_above_tip()
+    }
+}
@@ -2,10 +2,14 @@
 
 mod cache;
 pub mod db;
+pub mod health;
+pub mod height;
 pub mod indexer;
 pub mod service;
 mod vault;
+pub mod webhook;
 
+pub use height::{BlockHeight, ChainHeight, RemoteHeight, ScannedHeight};
 pub use indexer::*;
 
 #[cfg(test)]
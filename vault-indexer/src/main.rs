@@ -1,8 +1,17 @@
-use clap::Parser;
+use bitcoin::Txid;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use core::result::Result;
+use env_logger::Env;
 use log::*;
-use std::path::PathBuf;
+use serde::Deserialize;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use thiserror::Error;
 use vault_indexer::*;
 
@@ -12,6 +21,20 @@ enum Error {
     Indexer(#[from] indexer::Error),
     #[error("Service failure: {0}")]
     Service(#[from] service::Error),
+    #[error("Failed to install signal handlers: {0}")]
+    Signals(std::io::Error),
+    #[error("Failed to parse CLI arguments: {0}")]
+    Args(#[from] clap::Error),
+    #[error("Failed to read config file {0:?}: {1}")]
+    ConfigRead(PathBuf, std::io::Error),
+    #[error("Failed to parse config file {0:?}: {1}")]
+    ConfigParse(PathBuf, toml::de::Error),
+    #[error("Database failure: {0}")]
+    Database(#[from] db::Error),
+    #[error("Database verification found inconsistencies")]
+    VerifyFailed,
+    #[error("Failed to start health endpoint: {0}")]
+    Health(std::io::Error),
 }
 
 #[derive(Parser, Debug)]
@@ -25,46 +48,277 @@ struct Args {
     #[arg(short, long, default_value = "45.79.52.207:38333")]
     address: String,
 
-    /// Path to database of the indexer
+    /// DNS seed (host or host:port) to discover nodes, the --address node is used
+    /// when the seed returns nothing
+    #[arg(long)]
+    dns_seed: Option<String>,
+
+    /// Path to database of the indexer, missing parent directories are created
     #[arg(short, long, default_value = "indexer.sqlite")]
     database: PathBuf,
 
+    /// Size of the SQLite page cache in KiB, it is allocated in the indexer memory
+    #[arg(long, default_value_t = 65536)]
+    db_cache_size: u64,
+
+    /// How many bytes of the database file SQLite maps into memory, 0 disables mmap
+    #[arg(long, default_value_t = 268435456)]
+    db_mmap_size: u64,
+
+    /// Keep in memory only headers of the last N blocks and read older ones from
+    /// the database on demand. By default all headers are kept in memory.
+    #[arg(long)]
+    headers_window: Option<u32>,
+
     /// Amount of blocks to query per batch
     #[arg(short, long, default_value_t = 500)]
     batch: u32,
 
+    /// Shrink block batches when scanning approaches the tip to lower latency of new blocks
+    #[arg(long)]
+    adaptive_batch: bool,
+
     /// The height of blockhcain we start scanning from. Note that we still need download all
-    /// headers from the genesis.
-    #[arg(short, long, default_value_t = 1527651)]
-    start_height: u32,
+    /// headers from the genesis. Default is 1527651 for Mutiny signet and 0 for other networks.
+    #[arg(short, long)]
+    start_height: Option<u32>,
+
+    /// The height of blockchain (inclusive) we stop scanning at. Headers are not
+    /// requested past it.
+    #[arg(long)]
+    stop_height: Option<u32>,
 
     /// Websocket service bind address
     #[arg(short, long, default_value = "127.0.0.1:39987")]
     websocket_address: String,
 
+    /// Bind address of the HTTP health endpoint (GET /health), disabled by default
+    #[arg(long)]
+    health_address: Option<String>,
+
     /// Start scanning blocks from begining (--start-height), doesn't
     /// redownload headers.
     #[arg(long)]
     rescan: bool,
+
+    /// URL to POST new vault transactions to
+    #[arg(long)]
+    webhook_url: Option<String>,
+
+    /// Secret to sign webhook requests with HMAC-SHA256
+    #[arg(long)]
+    webhook_secret: Option<String>,
+
+    /// Open the existing database read-only and serve queries without connecting
+    /// to the node
+    #[arg(long)]
+    read_only: bool,
+
+    /// How many blocks of the main chain a reorganization can disconnect, headers
+    /// of deeper forks are ignored
+    #[arg(long, default_value_t = 100)]
+    max_reorg_depth: u32,
+
+    /// Opcode after OP_RETURN that marks vault outputs, can be repeated to index
+    /// several deployments. Default is OP_PUSHNUM_8 (88).
+    #[arg(long = "protocol-prefix", default_values_t = [88u8])]
+    protocol_prefixes: Vec<u8>,
+
+    /// How often (in seconds) to flush the SQLite WAL file into the database
+    #[arg(long, default_value_t = 300)]
+    checkpoint_interval: u64,
+
+    /// Exit with error after N failed connections to the node in a row instead of
+    /// reconnecting forever
+    #[arg(long)]
+    max_reconnect_attempts: Option<u32>,
+
+    /// Recalculate stored custody and volumes of the vault with the given opening txid
+    /// and exit without indexing
+    #[arg(long)]
+    recompute_vault: Option<Txid>,
+
+    /// Check the database consistency, print found problems and exit without indexing
+    #[arg(long)]
+    verify: bool,
+
+    /// Path to TOML file with the same options as the flags. Flags passed
+    /// explicitly override values from the file.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
+
+/// Options that can be set in the TOML configuration file. Keys are named
+/// as the long CLI flags with underscores.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    network: Option<Network>,
+    address: Option<String>,
+    dns_seed: Option<String>,
+    database: Option<PathBuf>,
+    db_cache_size: Option<u64>,
+    db_mmap_size: Option<u64>,
+    headers_window: Option<u32>,
+    batch: Option<u32>,
+    adaptive_batch: Option<bool>,
+    start_height: Option<u32>,
+    stop_height: Option<u32>,
+    websocket_address: Option<String>,
+    health_address: Option<String>,
+    rescan: Option<bool>,
+    read_only: Option<bool>,
+    webhook_url: Option<String>,
+    webhook_secret: Option<String>,
+    max_reorg_depth: Option<u32>,
+    protocol_prefixes: Option<Vec<u8>>,
+    checkpoint_interval: Option<u64>,
+    max_reconnect_attempts: Option<u32>,
+}
+
+impl Config {
+    fn load(path: &Path) -> Result<Self, Error> {
+        let content =
+            fs::read_to_string(path).map_err(|e| Error::ConfigRead(path.to_owned(), e))?;
+        toml::from_str(&content).map_err(|e| Error::ConfigParse(path.to_owned(), e))
+    }
+}
+
+impl Args {
+    /// Parse CLI arguments and merge them with the config file if it is provided.
+    ///
+    /// Precedence: explicit CLI flag, then config file value, then flag default.
+    #[allow(clippy::result_large_err)]
+    fn load() -> Result<Self, Error> {
+        let matches = Args::command().get_matches();
+        let mut args = Args::from_arg_matches(&matches)?;
+        if let Some(path) = &args.config {
+            let config = Config::load(path)?;
+            args.merge_config(&matches, config);
+        }
+        Ok(args)
+    }
+
+    fn merge_config(&mut self, matches: &ArgMatches, config: Config) {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        macro_rules! merge {
+            ($field:ident) => {
+                if let Some(value) = config.$field {
+                    if !from_cli(stringify!($field)) {
+                        self.$field = value;
+                    }
+                }
+            };
+        }
+        merge!(network);
+        merge!(address);
+        if config.dns_seed.is_some() && !from_cli("dns_seed") {
+            self.dns_seed = config.dns_seed;
+        }
+        merge!(database);
+        merge!(db_cache_size);
+        merge!(db_mmap_size);
+        if config.headers_window.is_some() && !from_cli("headers_window") {
+            self.headers_window = config.headers_window;
+        }
+        merge!(batch);
+        merge!(adaptive_batch);
+        if config.start_height.is_some() && !from_cli("start_height") {
+            self.start_height = config.start_height;
+        }
+        if config.stop_height.is_some() && !from_cli("stop_height") {
+            self.stop_height = config.stop_height;
+        }
+        merge!(websocket_address);
+        if config.health_address.is_some() && !from_cli("health_address") {
+            self.health_address = config.health_address;
+        }
+        if config.webhook_url.is_some() && !from_cli("webhook_url") {
+            self.webhook_url = config.webhook_url;
+        }
+        if config.webhook_secret.is_some() && !from_cli("webhook_secret") {
+            self.webhook_secret = config.webhook_secret;
+        }
+        merge!(rescan);
+        merge!(read_only);
+        merge!(max_reorg_depth);
+        merge!(protocol_prefixes);
+        merge!(checkpoint_interval);
+        if config.max_reconnect_attempts.is_some() && !from_cli("max_reconnect_attempts") {
+            self.max_reconnect_attempts = config.max_reconnect_attempts;
+        }
+    }
 }
 
+/// Logging when `RUST_LOG` isn't set: indexer progress is visible, but the per message
+/// tracing of the P2P connection is muted. Set `RUST_LOG=vault_indexer=trace` to see everything.
+const DEFAULT_LOG_FILTER: &str = "vault_indexer=info,vault_indexer::indexer::node=warn";
+
 #[allow(clippy::result_large_err)]
 fn main() -> Result<(), Error> {
-    if env::var("RUST_LOG").is_err() {
-        let _ = env::set_var("RUST_LOG", "debug");
+    env_logger::Builder::from_env(Env::default().default_filter_or(DEFAULT_LOG_FILTER)).init();
+    let args = match Args::load() {
+        Err(e) => {
+            error!("{e}");
+            return Err(e);
+        }
+        Ok(args) => args,
+    };
+
+    // Building the indexer writes to the database and fails on the broken headers chain,
+    // so the check runs on the file as is
+    if args.verify {
+        info!("Verifying database {:?}", args.database);
+        let cache = db::CacheConfig {
+            cache_size_kib: args.db_cache_size,
+            mmap_size: args.db_mmap_size,
+        };
+        let report = match VerifyReport::collect_file(&args.database, args.network, cache) {
+            Err(e) => {
+                error!("Failed to verify the database: {e}");
+                return Err(e.into());
+            }
+            Ok(report) => report,
+        };
+        report.log_problems();
+        return if report.is_ok() {
+            info!("Database is consistent");
+            Ok(())
+        } else {
+            Err(Error::VerifyFailed)
+        };
     }
-    env_logger::init();
-    let args = Args::parse();
 
     debug!("Configuring indexer");
-    let m_indexer = Indexer::builder()
+    let mut builder = Indexer::builder()
         .network(args.network)
         .node(&args.address)
         .db(&args.database)
+        .db_cache_size(args.db_cache_size)
+        .db_mmap_size(args.db_mmap_size)
         .batch_size(args.batch)
-        .start_height(args.start_height)
+        .adaptive_batch(args.adaptive_batch)
+        .stop_height(args.stop_height.map(ScannedHeight))
         .rescan(args.rescan)
-        .build();
+        .read_only(args.read_only)
+        .max_reorg_depth(args.max_reorg_depth)
+        .protocol_prefixes(args.protocol_prefixes.clone())
+        .max_reconnect_attempts(args.max_reconnect_attempts)
+        .checkpoint_interval(Duration::from_secs(args.checkpoint_interval));
+    // Without the height the builder starts from the default of the network
+    if let Some(height) = args.start_height {
+        builder = builder.start_height(ScannedHeight(height));
+    }
+    if let Some(size) = args.headers_window {
+        builder = builder.headers_window(size);
+    }
+    if let Some(seed) = &args.dns_seed {
+        builder = builder.dns_seed(seed);
+    }
+    if let Some(url) = &args.webhook_url {
+        builder = builder.webhook(url, args.webhook_secret.clone());
+    }
+    let m_indexer = builder.build();
 
     let indexer = match m_indexer {
         Err(e) => {
@@ -74,10 +328,54 @@ fn main() -> Result<(), Error> {
         Ok(indexer) => Arc::new(indexer),
     };
 
+    if let Some(vault_id) = args.recompute_vault {
+        info!("Recomputing vault {vault_id}");
+        return match indexer.recompute_vault(vault_id) {
+            Err(e) => {
+                error!("Failed to recompute vault {vault_id}: {e}");
+                Err(e.into())
+            }
+            Ok(count) => {
+                info!("Recomputed {count} transactions of vault {vault_id}");
+                Ok(())
+            }
+        };
+    }
+
+    debug!("Install signal handlers");
+    let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+        Err(e) => {
+            error!("Failed to install signal handlers: {e}");
+            return Err(Error::Signals(e));
+        }
+        Ok(signals) => signals,
+    };
+    thread::spawn({
+        let shutdown = indexer.shutdown_handle();
+        move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("Received signal {signal}, stopping the indexer");
+                shutdown.stop();
+            }
+        }
+    });
+
     debug!("Spawn weboscket service");
-    if let Err(e) = service::start_websocket_server(indexer.clone(), &args.websocket_address) {
-        error!("Failed to start websocket service: {e}");
-        return Err(e.into());
+    match service::start_websocket_server(indexer.clone(), &args.websocket_address) {
+        Err(e) => {
+            error!("Failed to start websocket service: {e}");
+            return Err(e.into());
+        }
+        Ok(addr) => info!("Websocket service is listening on ws://{addr}"),
+    }
+    if let Some(address) = &args.health_address {
+        match health::start_health_server(indexer.clone(), address) {
+            Err(e) => {
+                error!("Failed to start health endpoint: {e}");
+                return Err(Error::Health(e));
+            }
+            Ok(addr) => info!("Health endpoint is listening on http://{addr}/health"),
+        }
     }
 
     debug!("Start indexer");
@@ -85,5 +383,6 @@ fn main() -> Result<(), Error> {
         error!("Indexing fatal error: {e}");
         return Err(e.into());
     }
+    info!("Indexer stopped");
     Ok(())
 }
@@ -1,16 +1,32 @@
+use crate::cache::headers::HeadersCache;
 use crate::db::vault::advance::DatabaseVaultAdvance;
-use crate::db::vault::{ActionAggItem, VaultTxMeta};
-use crate::vault::{OraclePrice, UnitAmount, VaultAction, VaultId, VaultTx};
+use crate::db::vault::DatabaseVault;
+use crate::db::vault::{
+    ActionAggItem, ActionBreakdownItem, HistoryCursor, OraclePricePoint, SortOrder, VaultRanking,
+    VaultState, VaultSummary, VaultTxMeta,
+};
+use crate::db::{DatabaseHeaders, DatabaseMeta, HeaderRecord};
+use crate::indexer::stats::{IndexerStats, StatsCache};
+use crate::vault::{
+    oracle_price_decimal, unit_amount_decimal, unit_volume_decimal, LiquidationHash, OraclePrice,
+    UnitAmount, UnitTransaction, VaultAction, VaultId, VaultTx, LIQUIDATION_HASH_LEN,
+};
 use crate::Network;
 use crate::{indexer::event::Event, Indexer};
+use bitcoin::consensus::{deserialize, serialize};
 use bitcoin::hex::HexToArrayError;
-use bitcoin::{BlockHash, Txid};
-use bus::BusReader;
+use bitcoin::{BlockHash, Transaction, Txid};
 use core::str::FromStr;
-use log::{error, trace};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, error, trace};
 use rusqlite::Connection;
+use schemars::{schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
-use std::sync::{mpsc, Arc, Mutex};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use thiserror::Error;
 use websocket::stream::sync::TcpStream;
@@ -36,24 +52,92 @@ pub enum Error {
     SendingBus,
     #[error("Failed to get lock on database")]
     DbLock,
+    #[error("Failed to get lock on headers cache")]
+    HeadersCacheLock,
+    #[error("Failed to get lock on event filter")]
+    FilterLock,
+    #[error("Transaction {0} is not indexed")]
+    UnknownTx(Txid),
+    #[error("Vault {0} is not indexed")]
+    UnknownVault(Txid),
+    #[error("Cannot decode hex of raw transaction: {0}")]
+    RawTxHex(#[from] hex::FromHexError),
+    #[error("Cannot decode raw transaction: {0}")]
+    RawTxDecode(#[from] bitcoin::consensus::encode::Error),
+    #[error("Liquidation hash must be {LIQUIDATION_HASH_LEN} bytes encoded in hex, got {0}")]
+    ValidateLiquidationHash(String),
+    #[error("Cannot parse block hash {0}, reason: {1}")]
+    ValidateBlockHash(String, HexToArrayError),
+    #[error("Block {0} is not stored")]
+    UnknownBlock(BlockHash),
+    #[error("Header {0} is not known")]
+    UnknownHeader(String),
+    #[error("Headers cache error: {0}")]
+    HeadersCache(#[from] crate::cache::Error),
+    #[error("Cannot compress response: {0}")]
+    Compress(std::io::Error),
+    #[error("Range start {start} is after its end {end}")]
+    InvalidRange { start: u32, end: u32 },
+    #[error("Indexer error: {0}")]
+    Indexer(#[from] crate::indexer::Error),
 }
 
-/// Starts a background thread that implements websocket service for indexer
-pub fn start_websocket_server(indexer: Arc<Indexer>, bind_addr: &str) -> Result<(), Error> {
+/// Error code of the request for the vault that is not indexed
+pub const VAULT_NOT_FOUND: &str = "VAULT_NOT_FOUND";
+
+/// Error code of the request with malformed liquidation hash
+pub const INVALID_LIQUIDATION_HASH: &str = "INVALID_LIQUIDATION_HASH";
+
+/// Error code of the request with the range start after its end
+pub const INVALID_RANGE: &str = "INVALID_RANGE";
+
+/// Error code of the request for the transaction that is not indexed
+pub const TX_NOT_FOUND: &str = "TX_NOT_FOUND";
+
+/// Error code of the request for the block that is not stored
+pub const BLOCK_NOT_FOUND: &str = "BLOCK_NOT_FOUND";
+
+/// Error code of the request for the header that is not known
+pub const HEADER_NOT_FOUND: &str = "HEADER_NOT_FOUND";
+
+/// Error code of the request with malformed transaction hash
+pub const INVALID_TXID: &str = "INVALID_TXID";
+
+impl Error {
+    /// Machine readable code of the error that clients could match on
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            Error::UnknownVault(_) => Some(VAULT_NOT_FOUND),
+            Error::ValidateLiquidationHash(_) => Some(INVALID_LIQUIDATION_HASH),
+            Error::InvalidRange { .. } => Some(INVALID_RANGE),
+            Error::UnknownTx(_) => Some(TX_NOT_FOUND),
+            Error::UnknownBlock(_) => Some(BLOCK_NOT_FOUND),
+            Error::UnknownHeader(_) => Some(HEADER_NOT_FOUND),
+            Error::ValidateTxid(..) => Some(INVALID_TXID),
+            _ => None,
+        }
+    }
+}
+
+/// Starts a background thread that implements websocket service for indexer. Returns
+/// the address the server is bound to, that is useful when the port is 0 (chosen by OS).
+pub fn start_websocket_server(indexer: Arc<Indexer>, bind_addr: &str) -> Result<SocketAddr, Error> {
     let server = Server::bind(bind_addr)?;
+    let local_addr = server.local_addr()?;
     let network = indexer.network();
     // Listen new connections in new thread
     thread::spawn(move || {
         trace!("Spawn websocket server thread");
         for connection in server.filter_map(Result::ok) {
-            let events_bus = match indexer.add_event_reader() {
+            let events = match indexer.add_event_reader() {
                 Err(e) => {
-                    error!("Failed to get events bus for new connection: {e}");
+                    error!("Failed to get events reader for new connection: {e}");
                     continue;
                 }
                 Ok(v) => v,
             };
-            let database = indexer.get_database().clone();
+            let compress = wants_compression(&connection.uri());
+            let indexer = indexer.clone();
 
             // Spawn a new thread for each connection.
             trace!("New websocket connection");
@@ -72,7 +156,7 @@ pub fn start_websocket_server(indexer: Arc<Indexer>, bind_addr: &str) -> Result<
                     .peer_addr()
                     .map_or("".to_owned(), |addr| addr.to_string());
                 trace!("Handshaked with {addr}");
-                match client_handler(network, client, &addr, events_bus, database) {
+                match client_handler(network, client, &addr, compress, events, indexer) {
                     Err(e) => {
                         error!("Connection with {addr} closed with error: {e}");
                     }
@@ -83,10 +167,12 @@ pub fn start_websocket_server(indexer: Arc<Indexer>, bind_addr: &str) -> Result<
             });
         }
     });
-    Ok(())
+    Ok(local_addr)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize, JsonSchema,
+)]
 pub enum TimeSpan {
     Hour,
     Day,
@@ -106,69 +192,486 @@ impl TimeSpan {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(tag = "method")]
 pub enum Request {
     #[serde(rename = "range_history_all")]
     AllHistory {
         timestamp_start: Option<u32>,
         timestamp_end: Option<u32>,
+        sort: Option<SortOrder>,
+        include_forks: Option<bool>,
+    },
+    /// The same as `range_history_all`, but each transaction is sent as its own message
+    /// followed by [StreamEnd], so neither side buffers the whole history
+    #[serde(rename = "stream_history_all")]
+    StreamHistory {
+        timestamp_start: Option<u32>,
+        timestamp_end: Option<u32>,
+        sort: Option<SortOrder>,
+        include_forks: Option<bool>,
     },
     #[serde(rename = "vault_history_tx")]
     VaultHistory {
         vault_open_txid: String,
         timestamp_start: Option<u32>,
         timestamp_end: Option<u32>,
+        sort: Option<SortOrder>,
+        include_forks: Option<bool>,
     },
     #[serde(rename = "action_history")]
     ActionHistory {
         action: VaultAction,
         timespan: Option<TimeSpan>,
+        vault_open_txid: Option<String>,
+    },
+    #[serde(rename = "action_breakdown")]
+    ActionBreakdown {
+        timespan: Option<TimeSpan>,
+        timestamp_start: Option<u32>,
+        timestamp_end: Option<u32>,
     },
     #[serde(rename = "overall_volume")]
     OverallVolume {},
+    #[serde(rename = "stats")]
+    Stats {},
+    #[serde(rename = "raw_tx")]
+    RawTx { txid: String },
+    #[serde(rename = "replay_from")]
+    ReplayFrom { height: u32 },
+    #[serde(rename = "block_hash_at")]
+    BlockHashAt { height: u32 },
+    #[serde(rename = "parse_tx")]
+    ParseTx { raw_tx_hex: String },
+    #[serde(rename = "vault_state")]
+    VaultState { vault_open_txid: String },
+    #[serde(rename = "vault_summary")]
+    VaultSummary { vault_open_txid: String },
+    #[serde(rename = "vault_price_history")]
+    VaultPriceHistory { vault_open_txid: String },
+    #[serde(rename = "recent_history")]
+    RecentHistory {
+        blocks: u32,
+        sort: Option<SortOrder>,
+    },
+    #[serde(rename = "top_vaults")]
+    TopVaults { by: VaultRanking, limit: usize },
+    #[serde(rename = "by_liquidation_hash")]
+    ByLiquidationHash { hash_hex: String },
+    #[serde(rename = "block")]
+    Block { hash: String },
+    #[serde(rename = "header")]
+    Header { height_or_hash: HeightOrHash },
+    #[serde(rename = "schema")]
+    Schema {},
+    #[serde(rename = "subscribe")]
+    Subscribe {
+        min_balance: Option<UnitAmount>,
+        min_btc_volume: Option<u64>,
+    },
+    #[serde(rename = "reorg_history")]
+    ReorgHistory {},
+    #[serde(rename = "forks")]
+    Forks {},
 }
 
-#[derive(Debug, Serialize)]
+impl Request {
+    /// Check the arguments that don't depend on the indexed data, e.g. an inverted range
+    /// would silently return nothing otherwise
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            Request::AllHistory {
+                timestamp_start,
+                timestamp_end,
+                ..
+            }
+            | Request::StreamHistory {
+                timestamp_start,
+                timestamp_end,
+                ..
+            }
+            | Request::VaultHistory {
+                timestamp_start,
+                timestamp_end,
+                ..
+            }
+            | Request::ActionBreakdown {
+                timestamp_start,
+                timestamp_end,
+                ..
+            } => check_range(*timestamp_start, *timestamp_end),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reject the range (of timestamps or heights) that starts after its end. Missing bounds
+/// are open, so they are never inverted.
+pub fn check_range(start: Option<u32>, end: Option<u32>) -> Result<(), Error> {
+    match (start, end) {
+        (Some(start), Some(end)) if start > end => Err(Error::InvalidRange { start, end }),
+        _ => Ok(()),
+    }
+}
+
+/// Main chain height or hash of any known block
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum HeightOrHash {
+    Height(u32),
+    Hash(String),
+}
+
+/// Thresholds of `NewTranscation` notifications for single connection, smaller transactions
+/// are not sent to the client
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct EventFilter {
+    /// Minimal UNIT balance of the vault after the transaction
+    pub min_balance: Option<UnitAmount>,
+    /// Minimal amount of BTC (in sats) locked or unlocked by the transaction. Applies only to
+    /// open, deposit and withdraw, borrow and repay don't move BTC and pass it.
+    pub min_btc_volume: Option<u64>,
+}
+
+impl EventFilter {
+    pub fn passes(&self, info: &VaultTxInfo) -> bool {
+        let moves_btc = VaultAction::from_str(&info.action).is_ok_and(VaultAction::moves_btc);
+        self.min_balance.is_none_or(|min| info.balance >= min)
+            && (!moves_btc
+                || self
+                    .min_btc_volume
+                    .is_none_or(|min| info.btc_volume.unsigned_abs() >= min))
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
 pub struct OverallVolume {
     btc_volume: i64,
     unit_volume: i64,
 }
 
+/// Vault transaction that carries the requested liquidation hash
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct LiquidationHashInfo {
+    txid: String,
+    vault_id: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct RawTxInfo {
+    txid: String,
+    raw_tx_hex: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct BlockInfo {
+    block_hash: String,
+    raw_block_hex: String,
+}
+
+/// Oracle price recorded by a vault transaction
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PricePointInfo {
+    pub oracle_timestamp: u32,
+    pub oracle_price: OraclePrice,
+    /// USD price of 1 BTC with applied decimals
+    pub oracle_price_decimal: f64,
+}
+
+impl From<OraclePricePoint> for PricePointInfo {
+    fn from(point: OraclePricePoint) -> Self {
+        PricePointInfo {
+            oracle_timestamp: point.oracle_timestamp,
+            oracle_price: point.oracle_price,
+            oracle_price_decimal: oracle_price_decimal(point.oracle_price),
+        }
+    }
+}
+
+/// Block header fields that allow clients to validate the chain independently
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct HeaderInfo {
+    pub block_hash: String,
+    pub height: u32,
+    pub in_longest: bool,
+    pub version: i32,
+    pub prev_blockhash: String,
+    pub merkle_root: String,
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+    /// Consensus encoded header (80 bytes) in hex
+    pub raw_header_hex: String,
+}
+
+impl From<&HeaderRecord> for HeaderInfo {
+    fn from(record: &HeaderRecord) -> Self {
+        let header = &record.header;
+        HeaderInfo {
+            block_hash: header.block_hash().to_string(),
+            height: record.height,
+            in_longest: record.in_longest,
+            version: header.version.to_consensus(),
+            prev_blockhash: header.prev_blockhash.to_string(),
+            merkle_root: header.merkle_root.to_string(),
+            time: header.time,
+            bits: header.bits.to_consensus(),
+            nonce: header.nonce,
+            raw_header_hex: hex::encode(serialize(header)),
+        }
+    }
+}
+
+/// Chain reorganization handled by the indexer
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ReorgInfo {
+    /// UNIX timestamp when the reorganization was handled
+    pub timestamp: u64,
+    pub old_tip: String,
+    pub new_tip: String,
+    /// Height of the last common block of the old and new chains
+    pub fork_height: u32,
+    /// Amount of vault transactions that left the main chain
+    pub vault_txs: u32,
+    /// Amount of UNIT transactions that left the main chain
+    pub unit_txs: u32,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct BlockHashInfo {
+    height: u32,
+    /// Missing if the height is above the current tip
+    block_hash: Option<String>,
+}
+
+/// Tip of a chain that isn't the main one
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ForkTipInfo {
+    pub block_hash: String,
+    pub height: u32,
+}
+
+/// Headers the cache keeps aside of the main chain
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ForksInfo {
+    /// Inactive chain tips from the highest
+    pub tips: Vec<ForkTipInfo>,
+    /// Headers waiting for their unknown parent
+    pub orphans: Vec<String>,
+}
+
+/// Current state of the vault
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct VaultStateInfo {
+    pub vault_id: String,
+    /// OP_RETURN output of the opening transaction, `null` for payloads in the annex
+    pub output: Option<u32>,
+    pub balance: UnitAmount,
+    pub oracle_price: OraclePrice,
+    pub oracle_timestamp: u32,
+    pub liquidation_price: Option<OraclePrice>,
+    pub liquidation_hash: Option<String>,
+    pub custody: u64,
+    pub last_tx: String,
+    /// Height where the vault was closed, missing for active vaults
+    pub closed_at_height: Option<u32>,
+    /// Outpoint `txid:vout` of the UTXO holding the custody, only reported by `vault_state`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custody_outpoint: Option<String>,
+    /// Hex encoded scriptPubKey of the custody UTXO, only reported by `vault_state`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custody_script: Option<String>,
+}
+
+impl From<&VaultState> for VaultStateInfo {
+    fn from(state: &VaultState) -> Self {
+        VaultStateInfo {
+            vault_id: state.vault_id.to_string(),
+            output: state.payload.output(),
+            balance: state.balance,
+            oracle_price: state.oracle_price,
+            oracle_timestamp: state.oracle_timestamp,
+            liquidation_price: state.liquidation_price,
+            liquidation_hash: state.liquidation_hash.map(|hash| hash.to_string()),
+            custody: state.custody,
+            last_tx: state.last_tx.to_string(),
+            closed_at_height: state.closed_at_height,
+            custody_outpoint: None,
+            custody_script: None,
+        }
+    }
+}
+
+/// Fields of vault payload parsed without any chain state
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ParsedVaultInfo {
+    /// `null` for payloads in the annex
+    pub op_return_output: Option<u32>,
+    pub version: String,
+    pub action: String,
+    pub balance: UnitAmount,
+    pub oracle_price: OraclePrice,
+    pub oracle_timestamp: u32,
+    pub liquidation_price: Option<OraclePrice>,
+    pub liquidation_hash: Option<String>,
+}
+
+impl From<&VaultTx> for ParsedVaultInfo {
+    fn from(vault_tx: &VaultTx) -> Self {
+        ParsedVaultInfo {
+            op_return_output: vault_tx.payload.output(),
+            version: vault_tx.version.to_string(),
+            action: vault_tx.action.to_string(),
+            balance: vault_tx.balance,
+            oracle_price: vault_tx.oracle_price,
+            oracle_timestamp: vault_tx.oracle_timestamp,
+            liquidation_price: vault_tx.liquidation_price,
+            liquidation_hash: vault_tx.liquidation_hash.map(|hash| hash.to_string()),
+        }
+    }
+}
+
+/// Result of running the parsers over the raw transaction. For each parser either
+/// the result or the parse error is filled.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ParsedTxInfo {
+    pub txid: String,
+    pub vault: Option<ParsedVaultInfo>,
+    pub vault_error: Option<String>,
+    pub unit_amount: Option<UnitAmount>,
+    pub unit_error: Option<String>,
+}
+
+impl ParsedTxInfo {
+    /// Parse the hex encoded transaction as vault and UNIT transaction
+    pub fn parse(raw_tx_hex: &str) -> Result<Self, Error> {
+        let raw_tx: Transaction = deserialize(&hex::decode(raw_tx_hex)?)?;
+        let (vault, vault_error) = match VaultTx::from_tx(&raw_tx) {
+            Ok(vault_tx) => (Some(ParsedVaultInfo::from(&vault_tx)), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        let (unit_amount, unit_error) = match UnitTransaction::from_tx(&raw_tx) {
+            Ok(utx) => (Some(utx.unit_amount), None),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        Ok(ParsedTxInfo {
+            txid: raw_tx.compute_txid().to_string(),
+            vault,
+            vault_error,
+            unit_amount,
+            unit_error,
+        })
+    }
+}
+
 #[allow(clippy::large_enum_variant)]
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub enum Response {
     NewTranscation(VaultTxInfo),
     AllHistory(Vec<VaultTxInfo>),
     VaultHistory(Vec<VaultTxInfo>),
     ActionHistory(Vec<ActionAggItem>),
+    ActionBreakdown(Vec<ActionBreakdownItem>),
     OverallVolume(OverallVolume),
+    Stats(IndexerStats),
+    RawTx(RawTxInfo),
+    ReplayFinished(ReplayInfo),
+    BlockHashAt(BlockHashInfo),
+    ParsedTx(ParsedTxInfo),
+    VaultState(VaultStateInfo),
+    VaultSummary(VaultSummary),
+    VaultPriceHistory(Vec<PricePointInfo>),
+    ProcessingError(ProcessingErrorInfo),
+    TopVaults(Vec<VaultStateInfo>),
+    RecentHistory(RecentHistoryInfo),
+    ByLiquidationHash(Vec<LiquidationHashInfo>),
+    Block(BlockInfo),
+    Header(HeaderInfo),
+    /// JSON schema of the API, see [api_schema]
+    Schema(serde_json::Value),
+    /// Filter of new transactions that is active for the connection
+    Subscribed(EventFilter),
+    /// Logged chain reorganizations from the oldest to the newest
+    ReorgHistory(Vec<ReorgInfo>),
+    /// Inactive chain tips and orphan headers in the headers cache
+    Forks(ForksInfo),
+}
+
+/// Transaction that the indexer detected, but failed to store
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ProcessingErrorInfo {
+    pub txid: String,
+    pub height: u32,
+    pub reason: String,
+}
+
+/// Vault transactions of the last blocks, see [Request::RecentHistory]
+#[derive(Serialize, JsonSchema)]
+pub struct RecentHistoryInfo {
+    pub transactions: Vec<VaultTxInfo>,
+    /// The history hit [MAX_RECENT_HISTORY_TXS] limit, only the first transactions in the
+    /// requested order are returned
+    pub truncated: bool,
+}
+
+/// Marks the end of replayed transactions, next ones are live
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ReplayInfo {
+    /// Amount of replayed transactions
+    pub count: usize,
+    /// The replay hit [MAX_REPLAY_TXS] limit, the client should query remaining history
+    pub truncated: bool,
 }
 
-#[derive(Serialize)]
+/// Last message of the streamed history, see [Request::StreamHistory]
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct StreamEnd {
+    /// Always set, distinguishes the message from the streamed transactions
+    pub done: bool,
+    /// Amount of streamed transactions
+    pub count: usize,
+}
+
+#[derive(Serialize, JsonSchema)]
 pub struct ClientError {
     pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<&'static str>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub struct VaultTxInfo {
     pub vault_id: String,
     pub txid: String,
-    pub op_return_output: u32,
+    /// `null` for payloads in the annex
+    pub op_return_output: Option<u32>,
     pub version: String,
     pub action: String,
     pub balance: UnitAmount,
+    /// UNIT balance with applied decimals
+    pub balance_decimal: f64,
     pub oracle_price: OraclePrice,
+    /// USD price of 1 BTC with applied decimals
+    pub oracle_price_decimal: f64,
     pub oracle_timestamp: u32,
     pub liquidation_price: Option<OraclePrice>,
+    pub liquidation_price_decimal: Option<f64>,
     pub liquidation_hash: Option<String>,
     pub block_hash: String,
     pub height: u32,
+    pub in_longest: bool,
+    /// Amount of main chain blocks from the transaction block to the current tip (inclusive),
+    /// zero for transactions that are not in the main chain
+    pub confirmations: u32,
     pub tx_url: String,
     pub btc_custody: u64,
     pub unit_volume: i32,
+    pub unit_volume_decimal: f64,
     pub btc_volume: i64,
     pub prev_tx: String,
+    /// Missing for transactions indexed before the counts were stored
+    pub num_inputs: Option<u32>,
+    pub num_outputs: Option<u32>,
 }
 
 impl VaultTxInfo {
@@ -179,6 +682,7 @@ impl VaultTxInfo {
         vault_tx: &VaultTx,
         block_hash: BlockHash,
         height: u32,
+        in_longest: bool,
         btc_custody: u64,
         unit_volume: i32,
         btc_volume: i64,
@@ -187,81 +691,167 @@ impl VaultTxInfo {
         VaultTxInfo {
             vault_id: vault_id.to_string(),
             txid: vault_tx.txid.to_string(),
-            op_return_output: vault_tx.output,
+            op_return_output: vault_tx.payload.output(),
             version: vault_tx.version.to_string(),
             action: vault_tx.action.to_string(),
             balance: vault_tx.balance,
+            balance_decimal: unit_amount_decimal(vault_tx.balance),
             oracle_price: vault_tx.oracle_price,
+            oracle_price_decimal: oracle_price_decimal(vault_tx.oracle_price),
             oracle_timestamp: vault_tx.oracle_timestamp,
             liquidation_price: vault_tx.liquidation_price,
-            liquidation_hash: vault_tx.liquidation_hash.map(hex::encode),
+            liquidation_price_decimal: vault_tx.liquidation_price.map(oracle_price_decimal),
+            liquidation_hash: vault_tx.liquidation_hash.map(|hash| hash.to_string()),
             block_hash: block_hash.to_string(),
             height,
+            in_longest,
+            confirmations: 0,
             tx_url: network.explorer_url(vault_tx.txid),
             btc_custody,
             unit_volume,
+            unit_volume_decimal: unit_volume_decimal(unit_volume),
             btc_volume,
             prev_tx: network.explorer_url(prev_tx),
+            num_inputs: None,
+            num_outputs: None,
         }
     }
 
     pub fn from_db_metainfo(network: Network, meta: &VaultTxMeta) -> Self {
-        VaultTxInfo::new(
-            network,
-            meta.vault_id,
-            &meta.vault_tx,
-            meta.block_hash,
-            meta.height,
-            meta.btc_custody,
-            meta.unit_volume,
-            meta.btc_volume,
-            meta.prev_tx,
-        )
+        VaultTxInfo {
+            num_inputs: meta.num_inputs,
+            num_outputs: meta.num_outputs,
+            ..VaultTxInfo::new(
+                network,
+                meta.vault_id,
+                &meta.vault_tx,
+                meta.block_hash,
+                meta.height,
+                meta.in_longest,
+                meta.btc_custody,
+                meta.unit_volume,
+                meta.btc_volume,
+                meta.prev_tx,
+            )
+        }
+    }
+
+    /// Fill the confirmations for the given height of the main chain tip
+    pub fn with_confirmations(mut self, chain_height: u32) -> Self {
+        self.confirmations = match chain_height.checked_sub(self.height) {
+            Some(depth) if self.in_longest => depth + 1,
+            _ => 0,
+        };
+        self
     }
 }
 
 /// Max amount of queued messages in websocket
 const MAX_WEBSOCKET_MESSAGES: usize = 10000;
 
+/// Responses longer than the threshold are compressed for clients that opted in
+pub const COMPRESS_THRESHOLD: usize = 16 * 1024;
+
+/// Clients opt in compression of responses with `compress=gzip` query param of the handshake URL
+pub fn wants_compression(uri: &str) -> bool {
+    uri.split_once('?')
+        .is_some_and(|(_, query)| query.split('&').any(|param| param == "compress=gzip"))
+}
+
+/// Encode the JSON response as websocket message. When `compress` is set and the response is
+/// longer than [COMPRESS_THRESHOLD], it is gzipped and sent as binary frame.
+pub fn encode_response(json: String, compress: bool) -> Result<Message<'static>, Error> {
+    if !compress || json.len() <= COMPRESS_THRESHOLD {
+        return Ok(Message::text(json));
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(json.as_bytes())
+        .map_err(Error::Compress)?;
+    let compressed = encoder.finish().map_err(Error::Compress)?;
+    Ok(Message::binary(compressed))
+}
+
+/// Max amount of transactions sent for single replay request
+pub const MAX_REPLAY_TXS: usize = 5000;
+
+/// Max amount of transactions in single recent history response
+pub const MAX_RECENT_HISTORY_TXS: usize = 5000;
+
+/// Amount of transactions loaded from the database at once while streaming history. The
+/// database lock is released between pages, so a slow client doesn't stall the indexer.
+pub const STREAM_PAGE_SIZE: usize = 500;
+
+/// Max amount of vaults in single top vaults response
+pub const MAX_TOP_VAULTS: usize = 1000;
+
+#[allow(clippy::too_many_arguments)]
 fn client_handler(
     network: Network,
     client: Client<TcpStream>,
     addr: &str,
-    events_bus: BusReader<Event>,
-    database: Arc<Mutex<Connection>>,
+    compress: bool,
+    events: Receiver<Event>,
+    indexer: Arc<Indexer>,
 ) -> Result<(), Error> {
     let (mut client_receiver, mut client_sender) = client.split().unwrap();
     let (bus_sender, bus_receiver) = mpsc::sync_channel(MAX_WEBSOCKET_MESSAGES);
+    let filter = Arc::new(Mutex::new(EventFilter::default()));
 
     // Spawn listener of indexer events
     thread::spawn({
         let sender = bus_sender.clone();
         let addr = addr.to_owned();
+        let filter = filter.clone();
+        let headers_cache = indexer.get_headers_cache();
         move || -> Result<(), Error> {
-            for event in events_bus {
-                if let Event::NewTransaction(new_tx) = event {
-                    trace!(
-                        "Got message about new tx {} for vault {}",
-                        new_tx.vault_tx.txid,
-                        new_tx.vault_id
-                    );
-                    let info = VaultTxInfo::from_db_metainfo(network, &new_tx);
-                    let encoded_info = match serde_json::to_string(&Response::NewTranscation(info))
-                    {
-                        Err(e) => {
-                            error!(
-                                "Failed to encode tx {} for vault {} for client {addr}, reason: {}",
-                                new_tx.vault_tx.txid, new_tx.vault_id, e
-                            );
+            for event in events {
+                let (txid, response) = match event {
+                    Event::NewTransaction(new_tx) => {
+                        trace!(
+                            "Got message about new tx {} for vault {}",
+                            new_tx.vault_tx.txid,
+                            new_tx.vault_id
+                        );
+                        let info = VaultTxInfo::from_db_metainfo(network, &new_tx)
+                            .with_confirmations(chain_height(&headers_cache)?);
+                        if !filter.lock().map_err(|_| Error::FilterLock)?.passes(&info) {
+                            trace!("Tx {} is filtered for client {addr}", new_tx.vault_tx.txid);
                             continue;
                         }
-                        Ok(str) => str,
-                    };
-                    sender
-                        .send(Message::text(encoded_info))
-                        .map_err(|_| Error::SendingBus)?;
-                }
+                        (new_tx.vault_tx.txid, Response::NewTranscation(info))
+                    }
+                    Event::ProcessingError {
+                        txid,
+                        height,
+                        reason,
+                    } => {
+                        trace!("Got message about failed processing of tx {txid}");
+                        let info = ProcessingErrorInfo {
+                            txid: txid.to_string(),
+                            height,
+                            reason,
+                        };
+                        (txid, Response::ProcessingError(info))
+                    }
+                    _ => continue,
+                };
+                let encoded_info = match serde_json::to_string(&response) {
+                    Err(e) => {
+                        error!("Failed to encode tx {txid} event for client {addr}, reason: {e}");
+                        continue;
+                    }
+                    Ok(str) => str,
+                };
+                sender
+                    .send(Message::text(encoded_info))
+                    .map_err(|_| Error::SendingBus)?;
             }
+            // The indexer stopped or disconnected the reader that didn't keep up with the events
+            debug!("Events stream for client {addr} is closed, closing the connection");
+            sender
+                .send(Message::close_because(1008, "Events stream is closed"))
+                .map_err(|_| Error::SendingBus)?;
             Ok(())
         }
     });
@@ -284,6 +874,7 @@ fn client_handler(
                         error!("Failed to decode client {addr} request: {e}");
                         let err_msg = serde_json::to_string(&ClientError {
                             error: e.to_string(),
+                            code: None,
                         })?;
                         sender
                             .send(Message::text(err_msg))
@@ -293,23 +884,27 @@ fn client_handler(
                     Ok(request) => request,
                 };
                 trace!("Client {addr} request: {request:?}");
-                let response = match process_request(network, request, database.clone()) {
+                let responses = match process_request(network, request, &indexer, &filter, &sender)
+                {
                     Err(e) => {
                         error!("Failed to process client {addr} request: {e}");
                         let err_msg = serde_json::to_string(&ClientError {
                             error: e.to_string(),
+                            code: e.code(),
                         })?;
                         sender
                             .send(Message::text(err_msg))
                             .map_err(|_| Error::SendingBus)?;
                         continue;
                     }
-                    Ok(response) => response,
+                    Ok(responses) => responses,
                 };
-                let encoded_response = serde_json::to_string(&response)?;
-                sender
-                    .send(Message::text(encoded_response))
-                    .map_err(|_| Error::SendingBus)?;
+                for response in responses {
+                    let encoded_response = serde_json::to_string(&response)?;
+                    sender
+                        .send(encode_response(encoded_response, compress)?)
+                        .map_err(|_| Error::SendingBus)?;
+                }
             }
             OwnedMessage::Binary(_) => {
                 sender
@@ -335,56 +930,265 @@ fn client_handler(
 fn process_request(
     network: Network,
     request: Request,
-    database: Arc<Mutex<Connection>>,
-) -> Result<Response, Error> {
-    match request {
+    indexer: &Indexer,
+    filter: &Mutex<EventFilter>,
+    sender: &SyncSender<Message<'static>>,
+) -> Result<Vec<Response>, Error> {
+    request.validate()?;
+    let database = indexer.get_database();
+    let stats_cache = &indexer.stats_cache();
+    let headers_cache = &*indexer.get_headers_cache();
+    let response = match request {
         Request::AllHistory {
             timestamp_start,
             timestamp_end,
-        } => handler_all_history(network, database, timestamp_start, timestamp_end),
+            sort,
+            include_forks,
+        } => handler_all_history(
+            network,
+            database,
+            chain_height(headers_cache)?,
+            timestamp_start,
+            timestamp_end,
+            sort.unwrap_or_default(),
+            include_forks.unwrap_or(false),
+        ),
+        Request::StreamHistory {
+            timestamp_start,
+            timestamp_end,
+            sort,
+            include_forks,
+        } => {
+            handler_stream_history(
+                network,
+                database,
+                headers_cache,
+                sender,
+                timestamp_start,
+                timestamp_end,
+                sort.unwrap_or_default(),
+                include_forks.unwrap_or(false),
+            )?;
+            return Ok(vec![]);
+        }
         Request::VaultHistory {
             vault_open_txid,
             timestamp_start,
             timestamp_end,
+            sort,
+            include_forks,
         } => {
             let txid = Txid::from_str(&vault_open_txid)
                 .map_err(|e| Error::ValidateTxid(vault_open_txid, e))?;
-            handler_vault_history(network, database, txid, timestamp_start, timestamp_end)
+            handler_vault_history(
+                network,
+                database,
+                chain_height(headers_cache)?,
+                txid,
+                timestamp_start,
+                timestamp_end,
+                sort.unwrap_or_default(),
+                include_forks.unwrap_or(false),
+            )
         }
-        Request::ActionHistory { action, timespan } => {
-            handler_action_history(database, action, timespan)
+        Request::ActionHistory {
+            action,
+            timespan,
+            vault_open_txid,
+        } => {
+            let vault_id = vault_open_txid
+                .map(|txid| Txid::from_str(&txid).map_err(|e| Error::ValidateTxid(txid, e)))
+                .transpose()?;
+            handler_action_history(database, action, timespan, vault_id)
         }
+        Request::ActionBreakdown {
+            timespan,
+            timestamp_start,
+            timestamp_end,
+        } => handler_action_breakdown(database, timespan, timestamp_start, timestamp_end),
         Request::OverallVolume {} => handler_overall_volume(database),
-    }
+        Request::Stats {} => handler_stats(database, stats_cache),
+        Request::RawTx { txid } => {
+            let txid = Txid::from_str(&txid).map_err(|e| Error::ValidateTxid(txid, e))?;
+            handler_raw_tx(database, txid)
+        }
+        Request::ReplayFrom { height } => {
+            let chain_height = chain_height(headers_cache)?;
+            return handler_replay(network, database, chain_height, height);
+        }
+        Request::BlockHashAt { height } => handler_block_hash_at(indexer, height),
+        Request::Header { height_or_hash } => handler_header(headers_cache, height_or_hash),
+        Request::ParseTx { raw_tx_hex } => {
+            Ok(Response::ParsedTx(ParsedTxInfo::parse(&raw_tx_hex)?))
+        }
+        Request::VaultState { vault_open_txid } => {
+            let txid = Txid::from_str(&vault_open_txid)
+                .map_err(|e| Error::ValidateTxid(vault_open_txid, e))?;
+            handler_vault_state(database, txid)
+        }
+        Request::VaultSummary { vault_open_txid } => {
+            let txid = Txid::from_str(&vault_open_txid)
+                .map_err(|e| Error::ValidateTxid(vault_open_txid, e))?;
+            handler_vault_summary(database, txid)
+        }
+        Request::VaultPriceHistory { vault_open_txid } => {
+            let txid = Txid::from_str(&vault_open_txid)
+                .map_err(|e| Error::ValidateTxid(vault_open_txid, e))?;
+            handler_vault_price_history(database, txid)
+        }
+        Request::TopVaults { by, limit } => handler_top_vaults(database, by, limit),
+        Request::RecentHistory { blocks, sort } => handler_recent_history(
+            network,
+            database,
+            headers_cache,
+            blocks,
+            sort.unwrap_or_default(),
+        ),
+        Request::ByLiquidationHash { hash_hex } => {
+            handler_by_liquidation_hash(database, parse_liquidation_hash(&hash_hex)?)
+        }
+        Request::Schema {} => Ok(Response::Schema(api_schema())),
+        Request::Subscribe {
+            min_balance,
+            min_btc_volume,
+        } => {
+            let new_filter = EventFilter {
+                min_balance,
+                min_btc_volume,
+            };
+            *filter.lock().map_err(|_| Error::FilterLock)? = new_filter;
+            Ok(Response::Subscribed(new_filter))
+        }
+        Request::Block { hash } => {
+            let block_hash =
+                BlockHash::from_str(&hash).map_err(|e| Error::ValidateBlockHash(hash, e))?;
+            handler_block(database, block_hash)
+        }
+        Request::ReorgHistory {} => handler_reorg_history(database),
+        Request::Forks {} => handler_forks(headers_cache),
+    }?;
+    Ok(vec![response])
+}
+
+/// JSON schemas of the requests, responses (including notifications) and errors the service
+/// sends. The schemas are generated from the types, so they always match the actual API.
+pub fn api_schema() -> serde_json::Value {
+    serde_json::json!({
+        "request": schema_for!(Request),
+        "response": schema_for!(Response),
+        "error": schema_for!(ClientError),
+        "stream_end": schema_for!(StreamEnd),
+    })
+}
+
+/// Decode the liquidation hash from hex, it must be exactly [LIQUIDATION_HASH_LEN] bytes
+pub fn parse_liquidation_hash(hash_hex: &str) -> Result<LiquidationHash, Error> {
+    LiquidationHash::from_str(hash_hex)
+        .map_err(|_| Error::ValidateLiquidationHash(hash_hex.to_owned()))
+}
+
+/// Height of the main chain tip that confirmations are counted from
+fn chain_height(headers_cache: &Mutex<HeadersCache>) -> Result<u32, Error> {
+    let cache = headers_cache.lock().map_err(|_| Error::HeadersCacheLock)?;
+    Ok(cache.get_current_height().get())
 }
 
 fn handler_all_history(
     network: Network,
     database: Arc<Mutex<Connection>>,
+    chain_height: u32,
     timestamp_start: Option<u32>,
     timestamp_end: Option<u32>,
+    sort: SortOrder,
+    include_forks: bool,
 ) -> Result<Response, Error> {
     let conn = database.lock().map_err(|_| Error::DbLock)?;
-    let metas = conn.range_history_all(timestamp_start, timestamp_end)?;
+    let metas = conn.range_history_all(timestamp_start, timestamp_end, sort, include_forks)?;
     let infos = metas
         .into_iter()
-        .map(|meta| VaultTxInfo::from_db_metainfo(network, &meta))
+        .map(|meta| VaultTxInfo::from_db_metainfo(network, &meta).with_confirmations(chain_height))
         .collect();
     Ok(Response::AllHistory(infos))
 }
 
+/// Send the history page by page directly to the client queue, each transaction as separate
+/// message, and finish with [StreamEnd]. The queue is bounded, so the sending blocks while
+/// the client falls behind, but the database lock is held only while a page is loaded.
+#[allow(clippy::too_many_arguments)]
+fn handler_stream_history(
+    network: Network,
+    database: Arc<Mutex<Connection>>,
+    headers_cache: &Mutex<HeadersCache>,
+    sender: &SyncSender<Message<'static>>,
+    timestamp_start: Option<u32>,
+    timestamp_end: Option<u32>,
+    sort: SortOrder,
+    include_forks: bool,
+) -> Result<(), Error> {
+    let mut cursor = None;
+    let mut count = 0;
+    loop {
+        let metas = {
+            let conn = database.lock().map_err(|_| Error::DbLock)?;
+            conn.range_history_all_page(
+                timestamp_start,
+                timestamp_end,
+                sort,
+                include_forks,
+                cursor,
+                STREAM_PAGE_SIZE,
+            )?
+        };
+        let Some(last) = metas.last() else {
+            break;
+        };
+        cursor = Some(HistoryCursor::from(last));
+        let chain_height = chain_height(headers_cache)?;
+        for meta in &metas {
+            let info =
+                VaultTxInfo::from_db_metainfo(network, meta).with_confirmations(chain_height);
+            sender
+                .send(Message::text(serde_json::to_string(&info)?))
+                .map_err(|_| Error::SendingBus)?;
+        }
+        count += metas.len();
+        if metas.len() < STREAM_PAGE_SIZE {
+            break;
+        }
+    }
+    let end = StreamEnd { done: true, count };
+    sender
+        .send(Message::text(serde_json::to_string(&end)?))
+        .map_err(|_| Error::SendingBus)?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 fn handler_vault_history(
     network: Network,
     database: Arc<Mutex<Connection>>,
+    chain_height: u32,
     vault_open_txid: Txid,
     timestamp_start: Option<u32>,
     timestamp_end: Option<u32>,
+    sort: SortOrder,
+    include_forks: bool,
 ) -> Result<Response, Error> {
     let conn = database.lock().map_err(|_| Error::DbLock)?;
-    let metas = conn.range_history_vault(vault_open_txid, timestamp_start, timestamp_end)?;
+    // Empty history is valid for known vaults, but unknown ones are reported to catch typos
+    if conn.load_vault_state(vault_open_txid)?.is_none() {
+        return Err(Error::UnknownVault(vault_open_txid));
+    }
+    let metas = conn.range_history_vault(
+        vault_open_txid,
+        timestamp_start,
+        timestamp_end,
+        sort,
+        include_forks,
+    )?;
     let infos = metas
         .into_iter()
-        .map(|meta| VaultTxInfo::from_db_metainfo(network, &meta))
+        .map(|meta| VaultTxInfo::from_db_metainfo(network, &meta).with_confirmations(chain_height))
         .collect();
     Ok(Response::VaultHistory(infos))
 }
@@ -393,15 +1197,32 @@ fn handler_action_history(
     database: Arc<Mutex<Connection>>,
     action: VaultAction,
     timespan: Option<TimeSpan>,
+    vault_id: Option<Txid>,
 ) -> Result<Response, Error> {
     let conn = database.lock().map_err(|_| Error::DbLock)?;
     let aggs = conn.action_aggregated(
         action,
         timespan.map_or(TimeSpan::Day.time_width(), |t| t.time_width()),
+        vault_id,
     )?;
     Ok(Response::ActionHistory(aggs))
 }
 
+fn handler_action_breakdown(
+    database: Arc<Mutex<Connection>>,
+    timespan: Option<TimeSpan>,
+    timestamp_start: Option<u32>,
+    timestamp_end: Option<u32>,
+) -> Result<Response, Error> {
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    let items = conn.action_breakdown(
+        timespan.map_or(TimeSpan::Day.time_width(), |t| t.time_width()),
+        timestamp_start,
+        timestamp_end,
+    )?;
+    Ok(Response::ActionBreakdown(items))
+}
+
 fn handler_overall_volume(database: Arc<Mutex<Connection>>) -> Result<Response, Error> {
     let conn = database.lock().map_err(|_| Error::DbLock)?;
     let (btc_volume, unit_volume) = conn.overall_volume()?;
@@ -410,3 +1231,228 @@ fn handler_overall_volume(database: Arc<Mutex<Connection>>) -> Result<Response,
         unit_volume,
     }))
 }
+
+fn handler_stats(
+    database: Arc<Mutex<Connection>>,
+    stats_cache: &StatsCache,
+) -> Result<Response, Error> {
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    let stats = stats_cache.get(&conn)?;
+    Ok(Response::Stats(stats))
+}
+
+fn handler_raw_tx(database: Arc<Mutex<Connection>>, txid: Txid) -> Result<Response, Error> {
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    let raw_tx = conn.get_raw_tx(txid)?.ok_or(Error::UnknownTx(txid))?;
+    Ok(Response::RawTx(RawTxInfo {
+        txid: txid.to_string(),
+        raw_tx_hex: hex::encode(raw_tx),
+    }))
+}
+
+fn handler_vault_state(
+    database: Arc<Mutex<Connection>>,
+    vault_id: Txid,
+) -> Result<Response, Error> {
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    let state = conn
+        .load_vault_state(vault_id)?
+        .ok_or(Error::UnknownVault(vault_id))?;
+    let custody = conn.load_vault_custody(vault_id)?;
+    Ok(Response::VaultState(VaultStateInfo {
+        custody_outpoint: custody.as_ref().map(|c| c.outpoint.to_string()),
+        custody_script: custody.map(|c| hex::encode(c.script_pubkey.as_bytes())),
+        ..VaultStateInfo::from(&state)
+    }))
+}
+
+fn handler_vault_summary(
+    database: Arc<Mutex<Connection>>,
+    vault_id: Txid,
+) -> Result<Response, Error> {
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    if conn.load_vault_state(vault_id)?.is_none() {
+        return Err(Error::UnknownVault(vault_id));
+    }
+    Ok(Response::VaultSummary(conn.vault_summary(vault_id)?))
+}
+
+fn handler_vault_price_history(
+    database: Arc<Mutex<Connection>>,
+    vault_id: Txid,
+) -> Result<Response, Error> {
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    if conn.load_vault_state(vault_id)?.is_none() {
+        return Err(Error::UnknownVault(vault_id));
+    }
+    let points = conn
+        .vault_price_history(vault_id)?
+        .into_iter()
+        .map(PricePointInfo::from)
+        .collect();
+    Ok(Response::VaultPriceHistory(points))
+}
+
+fn handler_top_vaults(
+    database: Arc<Mutex<Connection>>,
+    by: VaultRanking,
+    limit: usize,
+) -> Result<Response, Error> {
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    let states = conn.top_vaults(by, limit.min(MAX_TOP_VAULTS))?;
+    Ok(Response::TopVaults(
+        states.iter().map(VaultStateInfo::from).collect(),
+    ))
+}
+
+fn handler_block(
+    database: Arc<Mutex<Connection>>,
+    block_hash: BlockHash,
+) -> Result<Response, Error> {
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    let block = conn
+        .load_block(block_hash)?
+        .ok_or(Error::UnknownBlock(block_hash))?;
+    Ok(Response::Block(BlockInfo {
+        block_hash: block_hash.to_string(),
+        raw_block_hex: hex::encode(serialize(&block)),
+    }))
+}
+
+fn handler_reorg_history(database: Arc<Mutex<Connection>>) -> Result<Response, Error> {
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    let reorgs = conn
+        .load_reorgs()?
+        .into_iter()
+        .map(|record| ReorgInfo {
+            timestamp: record.timestamp,
+            old_tip: record.old_tip.to_string(),
+            new_tip: record.new_tip.to_string(),
+            fork_height: record.fork_height,
+            vault_txs: record.vault_txs,
+            unit_txs: record.unit_txs,
+        })
+        .collect();
+    Ok(Response::ReorgHistory(reorgs))
+}
+
+fn handler_block_hash_at(indexer: &Indexer, height: u32) -> Result<Response, Error> {
+    let block_hash = indexer.block_hash_at(height)?;
+    Ok(Response::BlockHashAt(BlockHashInfo {
+        height,
+        block_hash: block_hash.map(|hash| hash.to_string()),
+    }))
+}
+
+fn handler_forks(headers_cache: &Mutex<HeadersCache>) -> Result<Response, Error> {
+    let cache = headers_cache.lock().map_err(|_| Error::HeadersCacheLock)?;
+    let tips = cache
+        .fork_tips()
+        .into_iter()
+        .map(|(hash, height)| ForkTipInfo {
+            block_hash: hash.to_string(),
+            height,
+        })
+        .collect();
+    let orphans = cache
+        .orphan_hashes()
+        .iter()
+        .map(|hash| hash.to_string())
+        .collect();
+    Ok(Response::Forks(ForksInfo { tips, orphans }))
+}
+
+fn handler_header(
+    headers_cache: &Mutex<HeadersCache>,
+    height_or_hash: HeightOrHash,
+) -> Result<Response, Error> {
+    let cache = headers_cache.lock().map_err(|_| Error::HeadersCacheLock)?;
+    let record = match height_or_hash {
+        HeightOrHash::Height(height) => cache
+            .find_header_at(height)?
+            .ok_or_else(|| Error::UnknownHeader(height.to_string()))?,
+        HeightOrHash::Hash(hash) => {
+            let block_hash =
+                BlockHash::from_str(&hash).map_err(|e| Error::ValidateBlockHash(hash, e))?;
+            cache
+                .find_header(block_hash)?
+                .ok_or_else(|| Error::UnknownHeader(block_hash.to_string()))?
+        }
+    };
+    Ok(Response::Header(HeaderInfo::from(&record)))
+}
+
+/// First height of the last `blocks` blocks of the chain with the given tip height. Zero blocks
+/// start above the tip.
+pub fn recent_blocks_start(chain_height: u32, blocks: u32) -> u32 {
+    chain_height.saturating_add(1).saturating_sub(blocks)
+}
+
+fn handler_recent_history(
+    network: Network,
+    database: Arc<Mutex<Connection>>,
+    headers_cache: &Mutex<HeadersCache>,
+    blocks: u32,
+    sort: SortOrder,
+) -> Result<Response, Error> {
+    let chain_height = chain_height(headers_cache)?;
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    let mut metas = conn.history_since_height(
+        recent_blocks_start(chain_height, blocks),
+        sort,
+        MAX_RECENT_HISTORY_TXS + 1,
+    )?;
+    let truncated = metas.len() > MAX_RECENT_HISTORY_TXS;
+    metas.truncate(MAX_RECENT_HISTORY_TXS);
+    let transactions = metas
+        .into_iter()
+        .map(|meta| VaultTxInfo::from_db_metainfo(network, &meta).with_confirmations(chain_height))
+        .collect();
+    Ok(Response::RecentHistory(RecentHistoryInfo {
+        transactions,
+        truncated,
+    }))
+}
+
+fn handler_by_liquidation_hash(
+    database: Arc<Mutex<Connection>>,
+    hash: LiquidationHash,
+) -> Result<Response, Error> {
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    let mut infos = vec![];
+    for txid in conn.find_by_liquidation_hash(hash)? {
+        let vault_id = conn.find_vault_by_tx(txid)?.ok_or(Error::UnknownTx(txid))?;
+        infos.push(LiquidationHashInfo {
+            txid: txid.to_string(),
+            vault_id: vault_id.to_string(),
+        });
+    }
+    Ok(Response::ByLiquidationHash(infos))
+}
+
+/// Backfill of past vault transactions as if the client was listening since the given height
+/// Replay only covers vault transactions, the same as the live stream of the websocket. UNIT
+/// transactions are indexed with their heights, but never sent to clients as events.
+fn handler_replay(
+    network: Network,
+    database: Arc<Mutex<Connection>>,
+    chain_height: u32,
+    height: u32,
+) -> Result<Vec<Response>, Error> {
+    // Indexer stores and broadcasts new transactions under the same lock, so the replay
+    // cannot miss a transaction between history and live events.
+    let conn = database.lock().map_err(|_| Error::DbLock)?;
+    let mut metas = conn.history_from_height(height, MAX_REPLAY_TXS + 1)?;
+    let truncated = metas.len() > MAX_REPLAY_TXS;
+    metas.truncate(MAX_REPLAY_TXS);
+    let count = metas.len();
+    let mut responses: Vec<Response> = metas
+        .into_iter()
+        .map(|meta| {
+            let info = VaultTxInfo::from_db_metainfo(network, &meta);
+            Response::NewTranscation(info.with_confirmations(chain_height))
+        })
+        .collect();
+    responses.push(Response::ReplayFinished(ReplayInfo { count, truncated }));
+    Ok(responses)
+}
@@ -1,17 +1,12 @@
-use std::io::Cursor;
-
-use crate::cache::headers::HeadersCache;
+use crate::cache::headers::{ChainUpdate, HeadersCache};
 use crate::db::*;
 use crate::tests::framework::*;
-use crate::Network;
-use bitcoin::block::Header;
-use bitcoin::consensus::Decodable;
+use crate::tests::transaction::OPEN_VAULT_TX;
+use crate::{ChainHeight, Network, ScannedHeight};
+use bitcoin::hashes::Hash;
+use bitcoin::BlockHash;
 use serial_test::serial;
 
-const HEADER_HEIGHT_1: &'static str = "00000020f61eee3b63a380a477a063af32b2bbc97c9ff9f01f2c4225e973988108000000011ba17baed1cacfb3793ba391383c305e401b3c54b3ce611c05d8b29927ad9e023d2f64ae77031ec0db7a01";
-const HEADER_HEIGHT_2: &'static str = "00000020f95429cd19fc22dac910fce4fe26a3580577fc5efcaf4eb2a9a0935885020000899658c98e65e369651736e8a5c206ab318260ddaaa5ca337644b074e6209a71363d2f64ae77031ee1b25700";
-const HEADER_HEIGHT_3: &'static str = "0000002096e0e15c52707f525d4b40bac68dd2712e9f032d374157e786bac0314d01000093f673cea9778c92f3a6fc64306144f055852542e2ebd72edbef3d3000134b4b5a3d2f64ae77031ea1542500";
-
 #[test]
 #[serial]
 fn db_genesis() {
@@ -119,7 +114,7 @@ fn db_fork_active() {
 
     let tip_hash = db.get_main_tip().unwrap();
     assert_eq!(fork_header2.block_hash(), tip_hash);
-    assert_eq!(cache.get_current_height(), 2);
+    assert_eq!(cache.get_current_height(), ChainHeight(2));
 }
 
 #[test]
@@ -147,26 +142,662 @@ fn db_fork_active_longer() {
 
     let tip_hash = db.get_main_tip().unwrap();
     assert_eq!(fork_header3.block_hash(), tip_hash);
-    assert_eq!(cache.get_current_height(), 3);
-}
-
-fn fake_fork_mine(mut header: Header) -> Header {
-    let start_work = header.work();
-    loop {
-        header.nonce += 1;
-        if header.work() >= start_work {
-            println!(
-                "Header {}, mined fake nonce : {}",
-                header.block_hash(),
-                header.nonce
-            );
-            break;
+    assert_eq!(cache.get_current_height(), ChainHeight(3));
+}
+
+#[test]
+#[serial]
+fn db_chain_update_outcome() {
+    let db = init_db();
+    let mut cache = HeadersCache::load(&db).unwrap();
+    let genesis_hash = Network::Mutinynet.genesis_header().block_hash();
+
+    let test_header1 = mk_header(HEADER_HEIGHT_1);
+    let test_header2 = mk_header(HEADER_HEIGHT_2);
+    let fork_header2 = fake_fork_mine(test_header2);
+    let mut fork_header3 = mk_header(HEADER_HEIGHT_3);
+    fork_header3.prev_blockhash = fork_header2.block_hash();
+    let fork_header3 = fake_fork_mine(fork_header3);
+
+    assert_eq!(cache.update_longest_chain(&[]).unwrap(), ChainUpdate::NoOp);
+    assert_eq!(
+        cache
+            .update_longest_chain(&[test_header1, test_header2])
+            .unwrap(),
+        ChainUpdate::Extended {
+            from: genesis_hash,
+            to: test_header2.block_hash()
+        }
+    );
+    // Orphan header doesn't change anything
+    assert_eq!(
+        cache.update_longest_chain(&[fork_header3]).unwrap(),
+        ChainUpdate::NoOp
+    );
+    // Parent of the orphan arrives and the fork becomes longer
+    assert_eq!(
+        cache.update_longest_chain(&[fork_header2]).unwrap(),
+        ChainUpdate::Reorged {
+            old_tip: test_header2.block_hash(),
+            new_tip: fork_header3.block_hash(),
+            fork_height: 1,
+        }
+    );
+    // Short fork from genesis doesn't affect the main chain
+    let fork_header1 = fake_fork_mine(test_header1);
+    assert_eq!(
+        cache.update_longest_chain(&[fork_header1]).unwrap(),
+        ChainUpdate::ForkStored
+    );
+}
+
+#[test]
+#[serial]
+fn db_reorg_inactivates_first_disconnected() {
+    let mut db = init_db();
+    let mut cache = HeadersCache::load(&db).unwrap();
+    let genesis_hash = Network::Mutinynet.genesis_header().block_hash();
+
+    let test_header1 = mk_header(HEADER_HEIGHT_1);
+    let test_header2 = mk_header(HEADER_HEIGHT_2);
+    let fork_header1 = fake_fork_mine(test_header1);
+    let mut fork_header2 = test_header2;
+    fork_header2.prev_blockhash = fork_header1.block_hash();
+    let fork_header2 = fake_fork_mine(fork_header2);
+    let mut fork_header3 = mk_header(HEADER_HEIGHT_3);
+    fork_header3.prev_blockhash = fork_header2.block_hash();
+    let fork_header3 = fake_fork_mine(fork_header3);
+
+    cache
+        .update_longest_chain(&[test_header1, test_header2])
+        .unwrap();
+    // The fork starts right after genesis, so every block of the old chain is disconnected
+    assert_eq!(
+        cache
+            .update_longest_chain(&[fork_header1, fork_header2, fork_header3])
+            .unwrap(),
+        ChainUpdate::Reorged {
+            old_tip: test_header2.block_hash(),
+            new_tip: fork_header3.block_hash(),
+            fork_height: 0,
         }
+    );
+    cache.store(&mut db).unwrap();
+
+    let cache = HeadersCache::load(&db).unwrap();
+    for header in [test_header1, test_header2] {
+        assert!(!cache.get_header(header.block_hash()).unwrap().in_longest);
+    }
+    for header in [fork_header1, fork_header2, fork_header3] {
+        assert!(cache.get_header(header.block_hash()).unwrap().in_longest);
+    }
+    assert!(cache.get_header(genesis_hash).unwrap().in_longest);
+    assert_eq!(cache.get_blockhash_at(1), Some(fork_header1.block_hash()));
+    assert_eq!(cache.get_current_height(), ChainHeight(3));
+}
+
+#[test]
+#[serial]
+fn db_wal_checkpoint() {
+    let db = init_db();
+    let result = checkpoint_wal(&db).unwrap();
+    assert!(!result.busy);
+}
+
+#[test]
+#[serial]
+fn db_locator_short_chain() {
+    let db = init_db();
+    let mut cache = HeadersCache::load(&db).unwrap();
+    let genesis_hash = Network::Mutinynet.genesis_header().block_hash();
+
+    // Only genesis is known
+    assert_eq!(cache.get_locator_main_chain().unwrap(), vec![genesis_hash]);
+    let test_header1 = mk_header(HEADER_HEIGHT_1);
+    cache.update_longest_chain(&[test_header1]).unwrap();
+    assert_eq!(
+        cache.get_locator_main_chain().unwrap(),
+        vec![test_header1.block_hash(), genesis_hash]
+    );
+}
+
+#[test]
+#[serial]
+fn db_height_for_time() {
+    let mut db = init_db();
+    let mut cache = HeadersCache::load(&db).unwrap();
+    let genesis = Network::Mutinynet.genesis_header();
+    let test_header1 = mk_header(HEADER_HEIGHT_1);
+    let test_header2 = mk_header(HEADER_HEIGHT_2);
+    cache
+        .update_longest_chain(&[test_header1, test_header2])
+        .unwrap();
+    cache.store(&mut db).unwrap();
+
+    assert_eq!(db.height_for_time(0).unwrap(), 0);
+    assert_eq!(db.height_for_time(genesis.time).unwrap(), 0);
+    assert_eq!(db.height_for_time(genesis.time + 1).unwrap(), 1);
+    assert_eq!(db.height_for_time(test_header2.time).unwrap(), 2);
+    // No blocks yet, the next block height
+    assert_eq!(db.height_for_time(test_header2.time + 1).unwrap(), 3);
+}
+
+#[test]
+#[serial]
+fn db_migrate_headers_time() {
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_migrate_headers_time.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let genesis = Network::Mutinynet.genesis_header();
+    {
+        // Schema without the time column
+        let old = rusqlite::Connection::open(&path).unwrap();
+        old.execute_batch(
+            r#"
+            CREATE TABLE headers(
+                block_hash          BLOB(32) NOT NULL PRIMARY KEY,
+                height              INTEGER NOT NULL,
+                prev_block_hash     BLOB(32) NOT NULL,
+                raw                 BLOB NOT NULL,
+                in_longest          INTEGER NOT NULL
+            );
+            "#,
+        )
+        .unwrap();
+        let mut raw = vec![];
+        bitcoin::consensus::Encodable::consensus_encode(&genesis, &mut raw).unwrap();
+        old.execute(
+            "INSERT INTO headers VALUES (?1, 0, ?2, ?3, 1)",
+            rusqlite::params![
+                genesis.block_hash().to_byte_array().to_vec(),
+                genesis.prev_blockhash.to_byte_array().to_vec(),
+                raw
+            ],
+        )
+        .unwrap();
+    }
+
+    let db = initialize_db(
+        &path,
+        Network::Mutinynet,
+        ScannedHeight(0),
+        false,
+        CacheConfig::default(),
+    )
+    .unwrap();
+    let time: u32 = db
+        .query_row("SELECT time FROM headers WHERE height = 0", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(time, genesis.time);
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+fn db_migrate_payload_in_annex() {
+    use crate::db::vault::{DatabaseVault, DatabaseVaultAdvance};
+    use crate::vault::PayloadLocation;
+
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_migrate_payload_in_annex.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let meta = {
+        let mut db = initialize_db(
+            &path,
+            Network::Mutinynet,
+            ScannedHeight(0),
+            false,
+            CacheConfig::default(),
+        )
+        .unwrap();
+        let meta = store_vault_fixture(&mut db, OPEN_VAULT_TX, 2, 1);
+        // Older versions had no flag and marked annex payloads with the maximum output number
+        db.execute_batch(
+            r#"
+            ALTER TABLE transactions DROP COLUMN payload_in_annex;
+            ALTER TABLE vaults DROP COLUMN payload_in_annex;
+            UPDATE transactions SET output = 4294967295;
+            UPDATE vaults SET output = 4294967295;
+            "#,
+        )
+        .unwrap();
+        meta
+    };
+
+    let db = initialize_db(
+        &path,
+        Network::Mutinynet,
+        ScannedHeight(0),
+        false,
+        CacheConfig::default(),
+    )
+    .unwrap();
+    let history = db.history_from_height(0, 10).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].vault_tx.payload, PayloadLocation::Annex);
+    let state = db.load_vault_state(meta.vault_id).unwrap().unwrap();
+    assert_eq!(state.payload, PayloadLocation::Annex);
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+fn db_get_headers_until() {
+    let db = init_db();
+    let mut cache = HeadersCache::load(&db).unwrap();
+    let genesis = Network::Mutinynet.genesis_header().block_hash();
+    let headers = [
+        mk_header(HEADER_HEIGHT_1),
+        mk_header(HEADER_HEIGHT_2),
+        mk_header(HEADER_HEIGHT_3),
+    ];
+
+    // Unknown stop block, ask from the tip
+    let stop = headers[1].block_hash();
+    let msg = cache.make_get_headers(Some(stop)).unwrap();
+    assert_eq!(msg.stop_hash, stop);
+    assert_eq!(msg.locator_hashes, vec![genesis]);
+    // No stop block is encoded as all-zero hash on the wire
+    let msg = cache.make_get_headers(None).unwrap();
+    assert_eq!(msg.stop_hash, BlockHash::all_zeros());
+    assert_eq!(msg.locator_hashes, vec![genesis]);
+
+    // Known stop block, ask for the stop header only
+    cache.update_longest_chain(&headers).unwrap();
+    let msg = cache.make_get_headers(Some(stop)).unwrap();
+    assert_eq!(msg.stop_hash, stop);
+    assert_eq!(msg.locator_hashes[0], headers[0].block_hash());
+}
+
+#[test]
+#[serial]
+fn db_network_mismatch_rescan() {
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_network_mismatch.sqlite");
+    let _ = std::fs::remove_file(&path);
+    {
+        let mut db = initialize_db(
+            &path,
+            Network::Mutinynet,
+            ScannedHeight(0),
+            false,
+            CacheConfig::default(),
+        )
+        .unwrap();
+        store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 0);
+        db.set_scanned_height(ScannedHeight(1)).unwrap();
     }
-    header
+
+    let res = initialize_db(
+        &path,
+        Network::Bitcoin,
+        ScannedHeight(0),
+        true,
+        CacheConfig::default(),
+    );
+    assert!(matches!(
+        res,
+        Err(Error::DatabaseNetworkMismatch(
+            Network::Mutinynet,
+            Network::Bitcoin
+        ))
+    ));
+
+    let db = initialize_db(
+        &path,
+        Network::Mutinynet,
+        ScannedHeight(0),
+        false,
+        CacheConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(db.get_scanned_height().unwrap(), ScannedHeight(1));
+    let vaults: u32 = db
+        .query_row("SELECT COUNT(*) FROM vaults", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(vaults, 1);
+    let bitcoin_genesis = Network::Bitcoin.genesis_header().block_hash();
+    assert!(db.load_block_header(bitcoin_genesis).unwrap().is_none());
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+fn db_load_chain_gap() {
+    let mut db = init_db();
+    let headers = [
+        mk_header(HEADER_HEIGHT_1),
+        mk_header(HEADER_HEIGHT_2),
+        mk_header(HEADER_HEIGHT_3),
+    ];
+    db.store_raw_headers(&[
+        (headers[0], 1, true),
+        (headers[1], 2, true),
+        (headers[2], 3, true),
+    ])
+    .unwrap();
+    db.set_best_tip(headers[2].block_hash()).unwrap();
+    assert_eq!(
+        HeadersCache::load(&db).unwrap().get_current_height(),
+        ChainHeight(3)
+    );
+
+    // Missing intermediate header
+    db.execute("DELETE FROM headers WHERE height = 2", [])
+        .unwrap();
+    assert!(matches!(
+        HeadersCache::load(&db),
+        Err(crate::cache::Error::MissingHeader(hash)) if hash == headers[1].block_hash()
+    ));
+
+    // Intermediate header with inconsistent height
+    db.store_raw_headers(&[(headers[1], 5, true)]).unwrap();
+    assert!(matches!(
+        HeadersCache::load(&db),
+        Err(crate::cache::Error::ChainGap {
+            expected: 2,
+            found: 5
+        })
+    ));
+}
+
+#[test]
+#[serial]
+fn db_partial_initialization() {
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_partial_init.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let genesis = Network::Mutinynet.genesis_header().block_hash();
+    {
+        // Genesis header is stored, but the metadata isn't
+        let db = initialize_db(
+            &path,
+            Network::Mutinynet,
+            ScannedHeight(0),
+            false,
+            CacheConfig::default(),
+        )
+        .unwrap();
+        db.execute("DELETE FROM metadata", []).unwrap();
+        assert!(matches!(db.load_metada(), Err(Error::NoMetadata)));
+        assert!(db.load_block_header(genesis).unwrap().is_some());
+    }
+
+    let db = initialize_db(
+        &path,
+        Network::Mutinynet,
+        ScannedHeight(42),
+        false,
+        CacheConfig::default(),
+    )
+    .unwrap();
+    let meta = db.load_metada().unwrap();
+    assert_eq!(meta.network, Network::Mutinynet);
+    assert_eq!(meta.tip_block_hash, genesis);
+    assert_eq!(meta.scanned_height, ScannedHeight(42));
+    drop(db);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+fn db_busy_waits_for_lock() {
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_busy.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let db = initialize_db(
+        &path,
+        Network::Mutinynet,
+        ScannedHeight(0),
+        false,
+        CacheConfig::default(),
+    )
+    .unwrap();
+
+    // Other writer holds the lock for a while
+    let other = rusqlite::Connection::open(&path).unwrap();
+    other.execute_batch("BEGIN IMMEDIATE").unwrap();
+    let writer = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        other.execute_batch("COMMIT").unwrap();
+    });
+    let reader = std::thread::spawn({
+        let path = path.clone();
+        move || {
+            let db = open_db_read_only(&path, Network::Mutinynet, CacheConfig::default()).unwrap();
+            for _ in 0..100 {
+                db.get_scanned_height().unwrap();
+            }
+        }
+    });
+
+    db.set_scanned_height(ScannedHeight(5)).unwrap();
+    checkpoint_wal(&db).unwrap();
+    writer.join().unwrap();
+    reader.join().unwrap();
+    assert_eq!(db.get_scanned_height().unwrap(), ScannedHeight(5));
+    drop(db);
+    let _ = std::fs::remove_file(&path);
 }
 
-fn mk_header(hex: &str) -> Header {
-    let header_bytes = hex::decode(hex).expect("correct hex encoded header");
-    Header::consensus_decode(&mut Cursor::new(&header_bytes)).expect("decoded header from bytes")
+#[test]
+#[serial]
+fn db_cache_pragmas() {
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_cache_pragmas.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let pragma = |db: &rusqlite::Connection, name: &str| -> i64 {
+        db.pragma_query_value(None, name, |row| row.get(0)).unwrap()
+    };
+
+    let db = initialize_db(
+        &path,
+        Network::Mutinynet,
+        ScannedHeight(0),
+        false,
+        CacheConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(pragma(&db, "cache_size"), -64 * 1024);
+    assert_eq!(pragma(&db, "mmap_size"), 256 * 1024 * 1024);
+    drop(db);
+
+    let cache = CacheConfig {
+        cache_size_kib: 1024,
+        mmap_size: 0,
+    };
+    let db = open_db_read_only(&path, Network::Mutinynet, cache).unwrap();
+    assert_eq!(pragma(&db, "cache_size"), -1024);
+    assert_eq!(pragma(&db, "mmap_size"), 0);
+    drop(db);
+
+    // Builder options override the defaults one by one
+    let indexer = crate::Indexer::builder()
+        .network(Network::Mutinynet)
+        .db(&path)
+        .db_cache_size(2048)
+        .db_mmap_size(1024 * 1024)
+        .build()
+        .unwrap();
+    let db = indexer.get_database();
+    let db = db.lock().unwrap();
+    assert_eq!(pragma(&db, "cache_size"), -2048);
+    assert_eq!(pragma(&db, "mmap_size"), 1024 * 1024);
+    drop(db);
+    drop(indexer);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+fn db_headers_window() {
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_headers_window.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let mut db = initialize_db(
+        &path,
+        Network::Mutinynet,
+        ScannedHeight(0),
+        false,
+        CacheConfig::default(),
+    )
+    .unwrap();
+    let backing = open_db_read_only(&path, Network::Mutinynet, CacheConfig::default()).unwrap();
+    let mut cache = HeadersCache::load_window(&db, 1, backing).unwrap();
+    cache.set_max_reorg_depth(1);
+
+    let genesis_hash = Network::Mutinynet.genesis_header().block_hash();
+    let header1 = mk_header(HEADER_HEIGHT_1);
+    let header2 = mk_header(HEADER_HEIGHT_2);
+    let header3 = mk_header(HEADER_HEIGHT_3);
+    cache
+        .update_longest_chain(&[header1, header2, header3])
+        .unwrap();
+    cache.store(&mut db).unwrap();
+
+    // Only the last block and the window below it stay in memory
+    assert_eq!(cache.headers_in_memory(), 2);
+    assert_eq!(cache.get_blockhash_at(0), Some(genesis_hash));
+    assert_eq!(cache.get_blockhash_at(1), Some(header1.block_hash()));
+    assert_eq!(cache.get_blockhash_at(4), None);
+    let record = cache.get_header(header1.block_hash()).unwrap();
+    assert_eq!(record.height, 1);
+    assert!(record.in_longest);
+    assert_eq!(
+        cache.get_blocks_range(0, 10).unwrap(),
+        vec![
+            genesis_hash,
+            header1.block_hash(),
+            header2.block_hash(),
+            header3.block_hash()
+        ]
+    );
+
+    // Fork from an evicted block has less work
+    let mut fork_header2 = header2;
+    fork_header2.nonce += 1;
+    let fork_header2 = fake_fork_mine(fork_header2);
+    assert_eq!(
+        cache.update_longest_chain(&[fork_header2]).unwrap(),
+        ChainUpdate::ForkStored
+    );
+
+    // Reorganization inside the window
+    let mut fork_header3 = header3;
+    fork_header3.nonce += 1;
+    let fork_header3 = fake_fork_mine(fork_header3);
+    let mut fork_header4 = header3;
+    fork_header4.prev_blockhash = fork_header3.block_hash();
+    let fork_header4 = fake_fork_mine(fork_header4);
+    assert_eq!(
+        cache
+            .update_longest_chain(&[fork_header3, fork_header4])
+            .unwrap(),
+        ChainUpdate::Reorged {
+            old_tip: header3.block_hash(),
+            new_tip: fork_header4.block_hash(),
+            fork_height: 2,
+        }
+    );
+    cache.store(&mut db).unwrap();
+    assert_eq!(cache.get_current_height(), ChainHeight(4));
+    assert_eq!(cache.get_blockhash_at(3), Some(fork_header3.block_hash()));
+    assert_eq!(cache.get_blockhash_at(2), Some(header2.block_hash()));
+    assert!(!cache.get_header(header3.block_hash()).unwrap().in_longest);
+
+    // Reloaded cache sees the same chain
+    cache.reload(&db).unwrap();
+    assert_eq!(cache.get_current_height(), ChainHeight(4));
+    assert_eq!(cache.get_blockhash_at(1), Some(header1.block_hash()));
+    assert_eq!(cache.get_blockhash_at(4), Some(fork_header4.block_hash()));
+    drop(cache);
+    drop(db);
+
+    // In-memory database can't be read by the other connection
+    let err = crate::Indexer::builder()
+        .network(Network::Mutinynet)
+        .headers_window(1000)
+        .build()
+        .err()
+        .expect("window needs a file");
+    assert!(err.to_string().contains("database file"));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+fn db_create_parent_dirs() {
+    init_parser();
+    let root = std::env::temp_dir().join("vault_indexer_nested_dirs");
+    let _ = std::fs::remove_dir_all(&root);
+    let path = root.join("a").join("b").join("indexer.sqlite");
+    let db = initialize_db(
+        &path,
+        Network::Mutinynet,
+        ScannedHeight(0),
+        false,
+        CacheConfig::default(),
+    )
+    .unwrap();
+    assert_eq!(db.get_scanned_height().unwrap(), ScannedHeight(0));
+    drop(db);
+    assert!(path.exists());
+
+    // The directory can't be created over a regular file
+    let blocked = root.join("file").join("indexer.sqlite");
+    std::fs::write(root.join("file"), b"").unwrap();
+    let res = initialize_db(
+        &blocked,
+        Network::Mutinynet,
+        ScannedHeight(0),
+        false,
+        CacheConfig::default(),
+    );
+    assert!(matches!(res, Err(Error::CreateDir(dir, _)) if dir == root.join("file")));
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+#[serial]
+fn db_fork_set() {
+    let db = init_db();
+    let mut cache = HeadersCache::load(&db).unwrap();
+
+    let test_header1 = mk_header(HEADER_HEIGHT_1);
+    let test_header2 = mk_header(HEADER_HEIGHT_2);
+    let fork_header2 = fake_fork_mine(test_header2);
+    let mut fork_header3 = mk_header(HEADER_HEIGHT_3);
+    fork_header3.prev_blockhash = fork_header2.block_hash();
+    let fork_header3 = fake_fork_mine(fork_header3);
+
+    cache
+        .update_longest_chain(&[test_header1, test_header2])
+        .unwrap();
+    assert!(cache.fork_tips().is_empty());
+    assert!(cache.orphan_hashes().is_empty());
+
+    cache.update_longest_chain(&[fork_header3]).unwrap();
+    assert!(cache.fork_tips().is_empty());
+    assert_eq!(cache.orphan_hashes(), vec![fork_header3.block_hash()]);
+
+    // Short fork from the genesis is listed with its height
+    let fork_header1 = fake_fork_mine(test_header1);
+    cache.update_longest_chain(&[fork_header1]).unwrap();
+    assert_eq!(cache.fork_tips(), vec![(fork_header1.block_hash(), 1)]);
+
+    // The orphan connects and wins, the old main chain becomes a fork
+    cache.update_longest_chain(&[fork_header2]).unwrap();
+    assert!(cache.orphan_hashes().is_empty());
+    assert_eq!(
+        cache.fork_tips(),
+        vec![
+            (test_header2.block_hash(), 2),
+            (fork_header1.block_hash(), 1)
+        ]
+    );
 }
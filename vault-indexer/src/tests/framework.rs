@@ -1,16 +1,31 @@
 use core::time::Duration;
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::sync::mpmc::{sync_channel, Receiver, Sender};
 use std::sync::Arc;
 use std::sync::Once;
 use std::thread;
 
+use bitcoin::block::Header;
+use bitcoin::consensus::{deserialize, serialize, Decodable};
+use bitcoin::{Block, BlockHash, Transaction};
 use log::LevelFilter;
-use rusqlite::Connection;
+use rusqlite::{params, Connection};
 
-use crate::db::initialize_db;
-use crate::{Indexer, Network};
+use crate::db::loaders::FieldEncode;
+use crate::db::vault::{DatabaseVault, VaultTxMeta};
+use crate::db::{initialize_db, CacheConfig};
+use crate::indexer::event::{Event, EVENTS_CAPACITY};
+use crate::indexer::Error;
+use crate::vault::VaultTx;
+use crate::{Indexer, Network, ScannedHeight};
 
 /// Mutiny signet local node (run with `start-regtest`)
-const NODE_ADDRESS: &'static str = "127.0.0.1:18444";
+const NODE_ADDRESS: &str = "127.0.0.1:18444";
+
+pub const HEADER_HEIGHT_1: &str = "00000020f61eee3b63a380a477a063af32b2bbc97c9ff9f01f2c4225e973988108000000011ba17baed1cacfb3793ba391383c305e401b3c54b3ce611c05d8b29927ad9e023d2f64ae77031ec0db7a01";
+pub const HEADER_HEIGHT_2: &str = "00000020f95429cd19fc22dac910fce4fe26a3580577fc5efcaf4eb2a9a0935885020000899658c98e65e369651736e8a5c206ab318260ddaaa5ca337644b074e6209a71363d2f64ae77031ee1b25700";
+pub const HEADER_HEIGHT_3: &str = "0000002096e0e15c52707f525d4b40bac68dd2712e9f032d374157e786bac0314d01000093f673cea9778c92f3a6fc64306144f055852542e2ebd72edbef3d3000134b4b5a3d2f64ae77031ea1542500";
 
 static INIT: Once = Once::new();
 
@@ -30,6 +45,7 @@ pub fn init_indexer() -> Arc<Indexer> {
     let indexer = Arc::new(
         Indexer::builder()
             .network(Network::Mutinynet)
+            .start_height(ScannedHeight(0))
             .node(NODE_ADDRESS)
             .build()
             .expect("Indexer configured"),
@@ -45,10 +61,30 @@ pub fn init_indexer() -> Arc<Indexer> {
     indexer
 }
 
+/// Indexer that is not connected to any node. Tests feed the node messages directly.
+pub fn init_offline_indexer() -> Indexer {
+    init_parser();
+
+    Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        // Tests attach fixture transactions to real headers
+        .verify_blocks(false)
+        .build()
+        .expect("Indexer configured")
+}
+
 pub fn init_db() -> Connection {
     init_parser();
 
-    initialize_db(":memory:", Network::Mutinynet, 0, false).expect("Database created")
+    initialize_db(
+        ":memory:",
+        Network::Mutinynet,
+        ScannedHeight(0),
+        false,
+        CacheConfig::default(),
+    )
+    .expect("Database created")
 }
 
 /// Helper that polls the function for `count` times and waits for `delay` between calls.
@@ -60,9 +96,167 @@ where
     for _ in 0..count {
         let res = body();
         if res {
-            return ();
+            return;
         }
         thread::sleep(delay);
     }
     panic!("Failed to finish action in wait_until in time");
 }
+
+/// Change nonce of the header until it has the same work, gives a fork header
+pub fn fake_fork_mine(mut header: Header) -> Header {
+    let start_work = header.work();
+    loop {
+        header.nonce += 1;
+        if header.work() >= start_work {
+            println!(
+                "Header {}, mined fake nonce : {}",
+                header.block_hash(),
+                header.nonce
+            );
+            break;
+        }
+    }
+    header
+}
+
+pub fn mk_header(hex: &str) -> Header {
+    let header_bytes = hex::decode(hex).expect("correct hex encoded header");
+    Header::consensus_decode(&mut Cursor::new(&header_bytes)).expect("decoded header from bytes")
+}
+
+/// State of the indexer main loop that tests drive by hand instead of a node connection
+pub struct SyncState {
+    /// Messages to the node and other events the indexer sends
+    pub sender: Sender<Event>,
+    pub receiver: Receiver<Event>,
+    pub batch_left: i64,
+    pub expected_blocks: HashSet<BlockHash>,
+    pub max_scanned_height: ScannedHeight,
+}
+
+impl Default for SyncState {
+    fn default() -> Self {
+        let (sender, receiver) = sync_channel(EVENTS_CAPACITY);
+        SyncState {
+            sender,
+            receiver,
+            batch_left: 0,
+            expected_blocks: HashSet::new(),
+            max_scanned_height: ScannedHeight::default(),
+        }
+    }
+}
+
+impl SyncState {
+    /// Pass the headers to the indexer as if the node sent them
+    pub fn headers(&mut self, indexer: &Indexer, headers: &[Header]) -> Result<(), Error> {
+        indexer.on_new_headers(
+            headers.to_vec(),
+            &self.sender,
+            &mut self.batch_left,
+            &mut self.expected_blocks,
+        )
+    }
+
+    /// Pass the block to the indexer as if the node sent it
+    pub fn block(&mut self, indexer: &Indexer, block: Block) -> Result<(), Error> {
+        indexer.on_new_block(
+            block,
+            &self.sender,
+            &mut self.batch_left,
+            &mut self.expected_blocks,
+            &mut self.max_scanned_height,
+        )
+    }
+
+    /// Pass the block of the header with the given transactions
+    pub fn deliver(&mut self, indexer: &Indexer, header: Header, txdata: Vec<Transaction>) {
+        self.block(indexer, Block { header, txdata })
+            .expect("block processed");
+    }
+}
+
+/// Start the hand driven sync with the headers from the node
+pub fn feed_headers(indexer: &Indexer, headers: &[Header]) -> SyncState {
+    let mut sync = SyncState::default();
+    sync.headers(indexer, headers).expect("headers processed");
+    sync
+}
+
+/// Amount of UNIT tokens in the fake parent transactions made by [store_vault_fixture]
+pub const FIXTURE_UNIT_AMOUNT: u32 = 1000;
+
+/// Parse the vault transaction from hex and store it in the database. The database doesn't
+/// have history of the vault, so the parent UNIT transaction is faked.
+pub fn store_vault_fixture(
+    db: &mut Connection,
+    tx_hex: &str,
+    height: u32,
+    block_pos: usize,
+) -> VaultTxMeta {
+    let raw_tx: Transaction =
+        deserialize(&hex::decode(tx_hex).expect("valid hex")).expect("valid transaction");
+    store_raw_vault_fixture(db, &raw_tx, height, block_pos)
+}
+
+/// The same as [store_vault_fixture], but takes the transaction that the test could modify
+pub fn store_raw_vault_fixture(
+    db: &mut Connection,
+    raw_tx: &Transaction,
+    height: u32,
+    block_pos: usize,
+) -> VaultTxMeta {
+    let vault_tx = VaultTx::from_tx(raw_tx).expect("valid vault tx");
+    if let Some(unit_txid) = vault_tx.assume_parent_unit_tx(raw_tx).unwrap() {
+        db.execute(
+            "INSERT OR IGNORE INTO transactions_runes (txid, raw_tx, unit_amount) VALUES(?1, ?2, ?3)",
+            params![
+                (&unit_txid).field_encode(),
+                serialize(raw_tx),
+                FIXTURE_UNIT_AMOUNT
+            ],
+        )
+        .expect("fake UNIT tx stored");
+    }
+    let block_hash = Network::Mutinynet.genesis_header().block_hash();
+    db.store_vault_tx(&vault_tx, block_hash, block_pos, height, raw_tx)
+        .expect("vault tx stored")
+}
+
+/// Store the history of one vault: the first transaction opens it and each next one spends the
+/// previous. Takes heights with transaction hex, all transactions are at position 1 in the block.
+pub fn store_vault_chain(db: &mut Connection, txs: &[(u32, &str)]) -> Vec<VaultTxMeta> {
+    let mut metas: Vec<VaultTxMeta> = vec![];
+    for (height, tx_hex) in txs {
+        let mut raw_tx: Transaction =
+            deserialize(&hex::decode(tx_hex).expect("valid hex")).expect("valid transaction");
+        if let Some(parent) = metas.last() {
+            raw_tx.input[0].previous_output.txid = parent.vault_tx.txid;
+        }
+        metas.push(store_raw_vault_fixture(db, &raw_tx, *height, 1));
+    }
+    metas
+}
+
+/// Move the OP_RETURN payload of the vault transaction into the Taproot annex of the first input
+pub fn move_payload_to_annex(tx: &Transaction) -> Transaction {
+    let mut annexed = tx.clone();
+    let pos = annexed
+        .output
+        .iter()
+        .position(|out| out.script_pubkey.is_op_return())
+        .expect("vault tx with OP_RETURN");
+    let op_return = annexed.output.remove(pos);
+    let mut annex = vec![0x50];
+    annex.extend_from_slice(&op_return.script_pubkey.as_bytes()[1..]);
+    let witness = &mut annexed.input[0].witness;
+    let mut elements: Vec<Vec<u8>> = witness.iter().map(|el| el.to_vec()).collect();
+    // The annex is recognized only after other witness elements
+    if elements.is_empty() {
+        elements.push(vec![0; 64]);
+    }
+    elements.push(annex);
+    *witness = bitcoin::Witness::from_slice(&elements);
+    annexed
+}
@@ -0,0 +1,64 @@
+use crate::db::{initialize_db, CacheConfig};
+use crate::health::start_health_server;
+use crate::{Indexer, Network, ScannedHeight};
+use serial_test::serial;
+use std::sync::Arc;
+
+use super::framework::{init_offline_indexer, init_parser};
+
+/// Status code and body of the health endpoint response
+fn get_health(url: &str) -> (u16, serde_json::Value) {
+    let response = match ureq::get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => panic!("health request failed: {e}"),
+    };
+    let status = response.status();
+    let body = response.into_string().unwrap();
+    let json = if body.is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::from_str(&body).unwrap()
+    };
+    (status, json)
+}
+
+#[test]
+#[serial]
+fn health_endpoint() {
+    // Not connected to the node, so not healthy
+    let indexer = Arc::new(init_offline_indexer());
+    let addr = start_health_server(indexer, "127.0.0.1:0").unwrap();
+    let (status, body) = get_health(&format!("http://{addr}/health"));
+    assert_eq!(status, 503);
+    assert_eq!(body["healthy"], false);
+    assert_eq!(body["node_connected"], false);
+    assert!(body["phase"].is_string());
+    let (status, _) = get_health(&format!("http://{addr}/other"));
+    assert_eq!(status, 404);
+
+    // Read-only indexer serves the database without the node
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_health.sqlite");
+    let _ = std::fs::remove_file(&path);
+    initialize_db(
+        &path,
+        Network::Mutinynet,
+        ScannedHeight(0),
+        false,
+        CacheConfig::default(),
+    )
+    .unwrap();
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        .db(&path)
+        .read_only(true)
+        .build()
+        .unwrap();
+    let addr = start_health_server(Arc::new(indexer), "127.0.0.1:0").unwrap();
+    let (status, body) = get_health(&format!("http://{addr}/health"));
+    assert_eq!(status, 200);
+    assert_eq!(body["healthy"], true);
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,1797 @@
+use crate::db::vault::advance::{DatabaseVaultAdvance, VaultRanking};
+use crate::db::vault::{DatabaseRune, DatabaseVault, SortOrder, VaultState};
+use crate::db::{initialize_db, CacheConfig, DatabaseHeaders, DatabaseMeta};
+use crate::indexer::event::{Event, PeerInfo, EVENTS_CAPACITY};
+use crate::indexer::node::MAX_HEADERS_PER_MSG;
+use crate::indexer::{adaptive_batch_size, progress_percent, MAX_BLOCK_REJECTIONS};
+use crate::tests::framework::*;
+use crate::tests::runes::{BORROW_TX_PHASE1, OPEN_VAULT_TX_PHASE1};
+use crate::tests::transaction::{DEPOSIT_TX, OPEN_VAULT_TX, UNKNOWN_VAULT_TX};
+use crate::vault::{PayloadLocation, VaultAction, VaultTx, UNIT_RUNE_ID};
+use crate::{
+    BlockHeight, ChainHeight, Indexer, IndexerStats, Network, NodeStatus, RemoteHeight,
+    ScannedHeight, SyncPhase, VerifyReport,
+};
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::p2p::{message::NetworkMessage, message_blockdata::Inventory, ServiceFlags};
+use bitcoin::{
+    absolute::LockTime, block::Header, pow::CompactTarget, transaction, Amount, Block, BlockHash,
+    OutPoint, ScriptBuf, Transaction, TxIn, TxOut, Witness,
+};
+use core::time::Duration;
+use ordinals::{Edict, RuneId, Runestone};
+use serial_test::serial;
+use std::sync::mpmc::{sync_channel, Receiver};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Take the next message to the node and expect it to be a blocks request
+fn expect_get_data(receiver: &Receiver<Event>) -> Vec<BlockHash> {
+    match receiver.try_recv() {
+        Ok(Event::OutcomingMessage(NetworkMessage::GetData(invs))) => invs
+            .into_iter()
+            .map(|inv| match inv {
+                Inventory::Block(hash) => hash,
+                other => panic!("Expected block inventory, got {other:?}"),
+            })
+            .collect(),
+        other => panic!("Expected GetData message, got {other:?}"),
+    }
+}
+
+/// Take the next event from the reader skipping headers sync progress
+fn next_event(events: &mpsc::Receiver<Event>) -> Result<Event, TryRecvError> {
+    loop {
+        match events.try_recv() {
+            Ok(Event::HeaderSyncProgress { .. }) => continue,
+            other => return other,
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn indexer_sync_from_genesis() {
+    let indexer = init_offline_indexer();
+
+    // Remote node has only genesis block too, nothing to request
+    let mut sync = feed_headers(&indexer, &[]);
+    assert_eq!(sync.batch_left, 0);
+    assert!(sync.receiver.try_recv().is_err());
+
+    // First blocks are mined, we should start scanning from height 1
+    let headers = vec![
+        mk_header(HEADER_HEIGHT_1),
+        mk_header(HEADER_HEIGHT_2),
+        mk_header(HEADER_HEIGHT_3),
+    ];
+    sync.headers(&indexer, &headers).unwrap();
+    assert_eq!(indexer.chain_height().unwrap(), ChainHeight(3));
+    assert_eq!(sync.batch_left, 3);
+    let requested = expect_get_data(&sync.receiver);
+    let expected: Vec<BlockHash> = headers.iter().map(|h| h.block_hash()).collect();
+    assert_eq!(requested, expected);
+}
+
+#[test]
+#[serial]
+fn indexer_ignores_unrequested_block() {
+    let indexer = init_offline_indexer();
+    let headers = vec![mk_header(HEADER_HEIGHT_1), mk_header(HEADER_HEIGHT_2)];
+    let mut sync = feed_headers(&indexer, &headers);
+    assert_eq!(expect_get_data(&sync.receiver).len(), 2);
+
+    // Genesis block was never requested, the same for the second copy of the first block
+    sync.deliver(&indexer, Network::Mutinynet.genesis_header(), vec![]);
+    sync.deliver(&indexer, headers[0], vec![]);
+    sync.deliver(&indexer, headers[0], vec![]);
+    sync.deliver(&indexer, headers[1], vec![]);
+
+    assert_eq!(sync.batch_left, 0);
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(2));
+    // Batch is finished at the tip, no duplicate requests
+    assert!(sync.receiver.try_recv().is_err());
+}
+
+#[test]
+#[serial]
+fn indexer_skips_scanned_block() {
+    let indexer = init_offline_indexer();
+    let events = indexer.add_event_reader().unwrap();
+    let headers = vec![
+        mk_header(HEADER_HEIGHT_1),
+        mk_header(HEADER_HEIGHT_2),
+        mk_header(HEADER_HEIGHT_3),
+    ];
+    let mut sync = feed_headers(&indexer, &headers);
+    // Pretend that we already scanned first two blocks in previous run
+    indexer
+        .get_database()
+        .lock()
+        .unwrap()
+        .set_scanned_height(ScannedHeight(2))
+        .unwrap();
+
+    let unit_tx: Transaction =
+        deserialize(&hex::decode(BORROW_TX_PHASE1).unwrap()).expect("valid transaction");
+    sync.deliver(&indexer, headers[0], vec![unit_tx.clone()]);
+    assert!(next_event(&events).is_err(), "Old block must be skipped");
+    sync.deliver(&indexer, headers[2], vec![unit_tx]);
+    match next_event(&events) {
+        Ok(Event::NewUnitTransaction(new_tx)) => assert_eq!(new_tx.height, 3),
+        other => panic!("Expected new UNIT transaction, got {other:?}"),
+    }
+}
+
+#[test]
+#[serial]
+fn indexer_stats() {
+    let indexer = init_offline_indexer();
+    let stats = indexer.stats().unwrap();
+    assert_eq!(
+        stats,
+        IndexerStats {
+            vaults: 0,
+            vault_transactions: 0,
+            unit_transactions: 0,
+            headers: 1,
+            fork_headers: 0,
+            reconnects: 0,
+            unparsed_vault_txs: 0,
+            unparsed_unit_txs: 0,
+            peer: None,
+            seconds_since_last_block: None,
+            phase: None,
+        }
+    );
+
+    // Stats are cached, new headers are not visible until TTL passes
+    feed_headers(
+        &indexer,
+        &[mk_header(HEADER_HEIGHT_1), mk_header(HEADER_HEIGHT_2)],
+    );
+    assert_eq!(indexer.stats().unwrap().headers, 1);
+    let fresh = IndexerStats::collect(&indexer.get_database().lock().unwrap()).unwrap();
+    assert_eq!(fresh.headers, 3);
+
+    // Runtime counters are not cached
+    indexer.stats_cache().record_reconnect();
+    assert_eq!(indexer.stats().unwrap().reconnects, 1);
+    let peer = PeerInfo {
+        version: 70016,
+        user_agent: "/Satoshi:28.0.0/".to_owned(),
+        services: ServiceFlags::NETWORK | ServiceFlags::WITNESS,
+        start_height: 2,
+    };
+    indexer.stats_cache().record_peer(peer.clone());
+    assert_eq!(indexer.stats().unwrap().peer, Some(peer));
+}
+
+#[test]
+#[serial]
+fn indexer_block_hash_at() {
+    let indexer = init_offline_indexer();
+    let genesis = Network::Mutinynet.genesis_header().block_hash();
+    assert_eq!(indexer.block_hash_at(0).unwrap(), Some(genesis));
+    assert_eq!(indexer.block_hash_at(1).unwrap(), None);
+
+    let headers = vec![mk_header(HEADER_HEIGHT_1), mk_header(HEADER_HEIGHT_2)];
+    feed_headers(&indexer, &headers);
+    assert_eq!(
+        indexer.block_hash_at(2).unwrap(),
+        Some(headers[1].block_hash())
+    );
+    assert_eq!(indexer.block_hash_at(3).unwrap(), None);
+    assert_eq!(indexer.block_hash_at(u32::MAX).unwrap(), None);
+}
+
+#[test]
+#[serial]
+fn indexer_stop_height_headers() {
+    init_parser();
+    // Full batch of headers, the node would have more after them
+    let mut prev = Network::Mutinynet.genesis_header();
+    let headers: Vec<Header> = (0..MAX_HEADERS_PER_MSG)
+        .map(|_| {
+            prev = Header {
+                prev_blockhash: prev.block_hash(),
+                time: prev.time + 600,
+                ..prev
+            };
+            prev
+        })
+        .collect();
+    let sync_fresh = |stop_height| {
+        let indexer = Indexer::builder()
+            .network(Network::Mutinynet)
+            .start_height(ScannedHeight(0))
+            .stop_height(stop_height)
+            .verify_blocks(false)
+            .build()
+            .unwrap();
+        let sync = feed_headers(&indexer, &headers);
+        let requests_headers = sync.receiver.try_iter().any(|event| {
+            matches!(
+                event,
+                Event::OutcomingMessage(NetworkMessage::GetHeaders(_))
+            )
+        });
+        (indexer, requests_headers, sync.batch_left)
+    };
+
+    // Below the stop height (or without it) the next headers are requested
+    assert!(sync_fresh(None).1);
+    assert!(sync_fresh(Some(ScannedHeight(MAX_HEADERS_PER_MSG as u32 + 1))).1);
+    // The stop height is reached, blocks are requested instead of the headers after it
+    let (indexer, requests_headers, batch_left) = sync_fresh(Some(ScannedHeight(10)));
+    assert!(!requests_headers);
+    assert_eq!(batch_left, 10);
+    assert!(indexer.is_ready());
+    assert_eq!(indexer.phase().unwrap(), SyncPhase::BlockScan);
+}
+
+#[test]
+#[serial]
+fn indexer_stop_height() {
+    init_parser();
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        .stop_height(Some(ScannedHeight(2)))
+        .verify_blocks(false)
+        .build()
+        .unwrap();
+    let headers = vec![
+        mk_header(HEADER_HEIGHT_1),
+        mk_header(HEADER_HEIGHT_2),
+        mk_header(HEADER_HEIGHT_3),
+    ];
+    let mut sync = feed_headers(&indexer, &headers);
+    // Headers are synced to the tip, but blocks only up to the stop height
+    assert_eq!(indexer.chain_height().unwrap(), ChainHeight(3));
+    assert_eq!(
+        expect_get_data(&sync.receiver),
+        vec![headers[0].block_hash(), headers[1].block_hash()]
+    );
+
+    for header in &headers[0..2] {
+        sync.deliver(&indexer, *header, vec![]);
+    }
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(2));
+    match sync.receiver.try_recv() {
+        Ok(Event::Synced(height)) => assert_eq!(height, 2),
+        other => panic!("Expected synced event, got {other:?}"),
+    }
+
+    // New headers don't trigger more block requests or repeated notifications
+    sync.headers(&indexer, &[]).unwrap();
+    assert!(sync.receiver.try_recv().is_err());
+}
+
+#[test]
+#[serial]
+fn indexer_change_batch_size() {
+    let indexer = init_offline_indexer();
+    indexer.set_batch_size(1);
+    let headers = vec![
+        mk_header(HEADER_HEIGHT_1),
+        mk_header(HEADER_HEIGHT_2),
+        mk_header(HEADER_HEIGHT_3),
+    ];
+    let mut sync = feed_headers(&indexer, &headers);
+    assert_eq!(
+        expect_get_data(&sync.receiver),
+        vec![headers[0].block_hash()]
+    );
+
+    // Next batch takes the new size
+    indexer.set_batch_size(2);
+    sync.deliver(&indexer, headers[0], vec![]);
+    assert_eq!(
+        expect_get_data(&sync.receiver),
+        vec![headers[1].block_hash(), headers[2].block_hash()]
+    );
+    assert_eq!(sync.batch_left, 2);
+}
+
+#[test]
+#[serial]
+fn indexer_zero_batch_size() {
+    let indexer = init_offline_indexer();
+    // Zero would request empty batches forever, it is raised to one block
+    indexer.set_batch_size(0);
+    let headers = vec![mk_header(HEADER_HEIGHT_1), mk_header(HEADER_HEIGHT_2)];
+    let sync = feed_headers(&indexer, &headers);
+    assert_eq!(
+        expect_get_data(&sync.receiver),
+        vec![headers[0].block_hash()]
+    );
+    assert_eq!(sync.batch_left, 1);
+}
+
+#[test]
+#[serial]
+fn indexer_adaptive_batch_size() {
+    assert_eq!(adaptive_batch_size(500, 10000), 500);
+    assert_eq!(adaptive_batch_size(500, 400), 100);
+    assert_eq!(adaptive_batch_size(500, 3), 10);
+    assert_eq!(adaptive_batch_size(1, 10000), 1);
+    assert_eq!(adaptive_batch_size(1, 0), 1);
+    assert_eq!(adaptive_batch_size(0, 10000), 1);
+    assert_eq!(adaptive_batch_size(0, 0), 1);
+}
+
+#[test]
+#[serial]
+fn indexer_processing_error_event() {
+    let indexer = init_offline_indexer();
+    let events = indexer.add_event_reader().unwrap();
+    let headers = vec![mk_header(HEADER_HEIGHT_1)];
+    let mut sync = feed_headers(&indexer, &headers);
+
+    // Deposit to the vault that the index doesn't know
+    let deposit_tx: Transaction =
+        deserialize(&hex::decode(DEPOSIT_TX).unwrap()).expect("valid transaction");
+    sync.deliver(&indexer, headers[0], vec![deposit_tx.clone()]);
+    match next_event(&events) {
+        Ok(Event::ProcessingError {
+            txid,
+            height,
+            reason,
+        }) => {
+            assert_eq!(txid, deposit_tx.compute_txid());
+            assert_eq!(height, 1);
+            assert!(reason.contains("Cannot find vault"), "{reason}");
+        }
+        other => panic!("Expected processing error, got {other:?}"),
+    }
+    // The indexer keeps going
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(1));
+}
+
+#[test]
+#[serial]
+fn indexer_out_of_order_blocks_restart() {
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_out_of_order_blocks.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let open_indexer = || {
+        Indexer::builder()
+            .network(Network::Mutinynet)
+            .start_height(ScannedHeight(0))
+            .db(&path)
+            .verify_blocks(false)
+            .build()
+            .unwrap()
+    };
+    let unit_tx: Transaction =
+        deserialize(&hex::decode(BORROW_TX_PHASE1).unwrap()).expect("valid transaction");
+    let headers = vec![
+        mk_header(HEADER_HEIGHT_1),
+        mk_header(HEADER_HEIGHT_2),
+        mk_header(HEADER_HEIGHT_3),
+    ];
+    let deliver = |sync: &mut SyncState, indexer: &Indexer, header: Header| {
+        // Unique UNIT transaction in each block
+        let mut tx = unit_tx.clone();
+        tx.lock_time = LockTime::from_consensus(header.time);
+        sync.deliver(indexer, header, vec![tx]);
+    };
+
+    {
+        let indexer = open_indexer();
+        let mut sync = feed_headers(&indexer, &headers);
+        assert_eq!(expect_get_data(&sync.receiver).len(), 3);
+
+        // The last block arrives first, the scanned height waits for the gap
+        deliver(&mut sync, &indexer, headers[2]);
+        assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(0));
+        deliver(&mut sync, &indexer, headers[0]);
+        assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(1));
+        // Process dies before the second block arrives
+    }
+
+    let indexer = open_indexer();
+    let events = indexer.add_event_reader().unwrap();
+    let mut sync = feed_headers(&indexer, &[]);
+    assert_eq!(
+        expect_get_data(&sync.receiver),
+        vec![headers[1].block_hash(), headers[2].block_hash()]
+    );
+
+    deliver(&mut sync, &indexer, headers[2]);
+    assert!(
+        next_event(&events).is_err(),
+        "Scanned block must be skipped"
+    );
+    deliver(&mut sync, &indexer, headers[1]);
+    match next_event(&events) {
+        Ok(Event::NewUnitTransaction(new_tx)) => assert_eq!(new_tx.height, 2),
+        other => panic!("Expected new UNIT transaction, got {other:?}"),
+    }
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(3));
+}
+
+#[test]
+#[serial]
+fn indexer_block_hook() {
+    init_parser();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        .on_block(Box::new({
+            let seen = seen.clone();
+            move |block, height| {
+                seen.lock()
+                    .unwrap()
+                    .push((block.block_hash(), block.txdata.len(), height))
+            }
+        }))
+        .verify_blocks(false)
+        .build()
+        .unwrap();
+    let headers = vec![mk_header(HEADER_HEIGHT_1), mk_header(HEADER_HEIGHT_2)];
+    let mut sync = feed_headers(&indexer, &headers);
+    let unit_tx: Transaction =
+        deserialize(&hex::decode(BORROW_TX_PHASE1).unwrap()).expect("valid transaction");
+    for header in &headers {
+        sync.deliver(&indexer, *header, vec![unit_tx.clone()]);
+    }
+    // The built-in detection doesn't consume the block
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            (headers[0].block_hash(), 1, 1),
+            (headers[1].block_hash(), 1, 2)
+        ]
+    );
+}
+
+#[test]
+#[serial]
+fn indexer_skips_coinbase() {
+    let indexer = init_offline_indexer();
+    let events = indexer.add_event_reader().unwrap();
+    let headers = vec![mk_header(HEADER_HEIGHT_1)];
+    let mut sync = feed_headers(&indexer, &headers);
+
+    // Coinbase with the OP_RETURN of a deposit
+    let mut coinbase: Transaction =
+        deserialize(&hex::decode(DEPOSIT_TX).unwrap()).expect("valid transaction");
+    coinbase.input = vec![TxIn {
+        previous_output: OutPoint::null(),
+        ..Default::default()
+    }];
+    assert!(coinbase.is_coinbase());
+    assert!(VaultTx::from_tx(&coinbase).is_ok());
+
+    sync.deliver(&indexer, headers[0], vec![coinbase]);
+    assert!(next_event(&events).is_err(), "Coinbase must be skipped");
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(1));
+}
+
+#[test]
+#[serial]
+fn indexer_header_sync_progress() {
+    let indexer = init_offline_indexer();
+    let events = indexer.add_event_reader().unwrap();
+    feed_headers(
+        &indexer,
+        &[mk_header(HEADER_HEIGHT_1), mk_header(HEADER_HEIGHT_2)],
+    );
+    match events.try_recv() {
+        Ok(Event::HeaderSyncProgress { current, remote }) => {
+            assert_eq!(current, 2);
+            assert_eq!(remote, 2);
+        }
+        other => panic!("Expected headers progress, got {other:?}"),
+    }
+
+    // Intermediate progress is rate limited, but the end of sync is always reported
+    indexer
+        .notify_header_progress(ChainHeight(3), RemoteHeight(10), false)
+        .unwrap();
+    assert!(events.try_recv().is_err());
+    indexer
+        .notify_header_progress(ChainHeight(10), RemoteHeight(10), true)
+        .unwrap();
+    assert!(matches!(
+        events.try_recv(),
+        Ok(Event::HeaderSyncProgress {
+            current: 10,
+            remote: 10
+        })
+    ));
+}
+
+#[test]
+#[serial]
+fn indexer_slow_reader_disconnected() {
+    let indexer = init_offline_indexer();
+    // The reader is never drained until the end of the test
+    let slow_events = indexer.add_event_reader().unwrap();
+    let fast_events = indexer.add_event_reader().unwrap();
+    for i in 0..=EVENTS_CAPACITY as u32 {
+        indexer
+            .notify_header_progress(ChainHeight(i), RemoteHeight(i), true)
+            .unwrap();
+        assert!(fast_events.try_recv().is_ok());
+    }
+
+    // Indexing continues after the slow reader overflows
+    let headers = vec![mk_header(HEADER_HEIGHT_1)];
+    let mut sync = feed_headers(&indexer, &headers);
+    sync.deliver(&indexer, headers[0], vec![]);
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(1));
+    assert!(fast_events.try_recv().is_ok());
+
+    // The slow reader gets the buffered events and then is disconnected
+    for _ in 0..EVENTS_CAPACITY {
+        assert!(slow_events.try_recv().is_ok());
+    }
+    assert!(matches!(
+        slow_events.try_recv(),
+        Err(TryRecvError::Disconnected)
+    ));
+}
+
+#[test]
+#[serial]
+fn indexer_process_block_fixture() {
+    let indexer = init_offline_indexer();
+    let events = indexer.add_event_reader().unwrap();
+
+    // The opening tx spends the UNIT connector created by the first phase
+    let unit_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX_PHASE1).unwrap()).expect("valid transaction");
+    let mut open_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    open_tx.input[1].previous_output = OutPoint::new(unit_tx.compute_txid(), 0);
+    let vault_id = open_tx.compute_txid();
+
+    let header = mk_header(HEADER_HEIGHT_1);
+    let block = Block {
+        header,
+        txdata: vec![unit_tx.clone(), open_tx.clone()],
+    };
+    indexer.process_block(block, 1).unwrap();
+
+    let meta = match next_event(&events) {
+        Ok(Event::NewUnitTransaction(new_utx)) => {
+            assert_eq!(new_utx.utx.txid, unit_tx.compute_txid());
+            assert_eq!(new_utx.block_pos, 0);
+            match next_event(&events) {
+                Ok(Event::NewTransaction(meta)) => meta,
+                other => panic!("Expected new vault tx, got {other:?}"),
+            }
+        }
+        other => panic!("Expected new UNIT tx, got {other:?}"),
+    };
+    assert_eq!(meta.vault_id, vault_id);
+    assert_eq!(meta.vault_tx.txid, vault_id);
+    assert_eq!(meta.vault_tx.action, VaultAction::Open);
+    assert_eq!(meta.block_hash, header.block_hash());
+    assert_eq!(meta.block_pos, 1);
+    assert_eq!(meta.height, 1);
+    assert!(meta.in_longest);
+    assert_eq!(meta.prev_tx, vault_id);
+    assert_eq!(meta.vault_tx.balance, 1392952);
+    assert_eq!(meta.unit_volume, 10528);
+    assert_eq!(meta.btc_custody, 49980000);
+    assert_eq!(meta.btc_volume, 49980000);
+
+    let db = indexer.get_database();
+    let conn = db.lock().unwrap();
+    let unit = conn.load_unit_tx(unit_tx.compute_txid()).unwrap();
+    assert_eq!(unit.unit_amount, 10528);
+    let history = conn
+        .range_history_vault(vault_id, None, None, SortOrder::Asc, false)
+        .unwrap();
+    assert_eq!(history, vec![meta.clone()]);
+    let state = conn
+        .load_vault_state(vault_id)
+        .unwrap()
+        .expect("vault stored");
+    assert_eq!(
+        state,
+        VaultState {
+            vault_id,
+            payload: PayloadLocation::Output(4),
+            balance: 1392952,
+            oracle_price: 99094,
+            oracle_timestamp: 1738004441,
+            liquidation_price: None,
+            liquidation_hash: None,
+            custody: 49980000,
+            last_tx: vault_id,
+            closed_at_height: None,
+        }
+    );
+}
+
+#[test]
+#[serial]
+fn indexer_read_only() {
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_read_only.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let vault_id = {
+        let mut db = initialize_db(
+            &path,
+            Network::Mutinynet,
+            ScannedHeight(0),
+            false,
+            CacheConfig::default(),
+        )
+        .unwrap();
+        store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 0).vault_id
+    };
+    let open_indexer = |rescan| {
+        Indexer::builder()
+            .network(Network::Mutinynet)
+            .start_height(ScannedHeight(0))
+            .db(&path)
+            .rescan(rescan)
+            .read_only(true)
+            // Nothing listens there, the read-only indexer must not connect
+            .node("127.0.0.1:1")
+            .build()
+    };
+    assert!(open_indexer(true).is_err());
+
+    let indexer = Arc::new(open_indexer(false).unwrap());
+    assert!(indexer.is_read_only());
+    {
+        let db = indexer.get_database();
+        let conn = db.lock().unwrap();
+        assert!(conn.load_vault_state(vault_id).unwrap().is_some());
+        assert!(conn.set_scanned_height(ScannedHeight(10)).is_err());
+    }
+
+    let handle = thread::spawn({
+        let indexer = indexer.clone();
+        move || indexer.run()
+    });
+    indexer.shutdown_handle().stop();
+    handle.join().unwrap().unwrap();
+    assert_eq!(indexer.node_status(), NodeStatus::Disconnected);
+    drop(indexer);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+fn indexer_block_tx_order() {
+    let indexer = init_offline_indexer();
+    let unit_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX_PHASE1).unwrap()).expect("valid transaction");
+    let mut open_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    open_tx.input[1].previous_output = OutPoint::new(unit_tx.compute_txid(), 0);
+
+    // The child is placed before its parent
+    let block = Block {
+        header: mk_header(HEADER_HEIGHT_1),
+        txdata: vec![open_tx.clone(), unit_tx.clone()],
+    };
+    let err = indexer.process_block(block, 1).unwrap_err();
+    assert!(
+        err.to_string().contains(&format!(
+            "{} in block {} spends outputs of later transaction {}",
+            open_tx.compute_txid(),
+            mk_header(HEADER_HEIGHT_1).block_hash(),
+            unit_tx.compute_txid()
+        )),
+        "{err}"
+    );
+
+    // Nothing is stored from the rejected block
+    let db = indexer.get_database();
+    let conn = db.lock().unwrap();
+    assert_eq!(conn.count_unit_txs().unwrap(), 0);
+    assert_eq!(conn.count_vault_txs().unwrap(), 0);
+}
+
+#[test]
+#[serial]
+fn indexer_default_start_height() {
+    assert_eq!(
+        Network::Mutinynet.default_start_height(),
+        ScannedHeight(1527651)
+    );
+    for network in [
+        Network::Bitcoin,
+        Network::Testnet,
+        Network::Testnet4,
+        Network::Signet,
+        Network::Regtest,
+    ] {
+        assert_eq!(
+            network.default_start_height(),
+            ScannedHeight(0),
+            "{network}"
+        );
+    }
+
+    init_parser();
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .build()
+        .unwrap();
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(1527651));
+    let indexer = Indexer::builder()
+        .network(Network::Regtest)
+        .build()
+        .unwrap();
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(0));
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(10))
+        .build()
+        .unwrap();
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(10));
+}
+
+#[test]
+#[serial]
+fn indexer_for_each_vault() {
+    let indexer = init_offline_indexer();
+    let (first, second) = {
+        let db = indexer.get_database();
+        let mut conn = db.lock().unwrap();
+        (
+            store_vault_fixture(&mut conn, OPEN_VAULT_TX, 1, 0),
+            store_vault_fixture(&mut conn, UNKNOWN_VAULT_TX, 2, 0),
+        )
+    };
+
+    let mut vaults = vec![];
+    indexer
+        .for_each_vault(|state| vaults.push((state.vault_id, state.balance)))
+        .unwrap();
+    vaults.sort();
+    let mut expected = vec![
+        (first.vault_id, first.vault_tx.balance),
+        (second.vault_id, second.vault_tx.balance),
+    ];
+    expected.sort();
+    assert_eq!(vaults, expected);
+}
+
+#[test]
+#[serial]
+fn indexer_reorg_too_deep() {
+    init_parser();
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        .max_reorg_depth(1)
+        .build()
+        .unwrap();
+    let events = indexer.add_event_reader().unwrap();
+    // A batch is in flight, the headers don't trigger block requests
+    let mut sync = SyncState {
+        batch_left: 1,
+        ..Default::default()
+    };
+
+    let header1 = mk_header(HEADER_HEIGHT_1);
+    let header2 = mk_header(HEADER_HEIGHT_2);
+    sync.headers(&indexer, &[header1, header2]).unwrap();
+
+    // Fork from genesis has more work, but disconnects 2 blocks
+    let fork_header1 = fake_fork_mine(header1);
+    let mut fork_header2 = header2;
+    fork_header2.prev_blockhash = fork_header1.block_hash();
+    let fork_header2 = fake_fork_mine(fork_header2);
+    let mut fork_header3 = mk_header(HEADER_HEIGHT_3);
+    fork_header3.prev_blockhash = fork_header2.block_hash();
+    let fork_header3 = fake_fork_mine(fork_header3);
+    sync.headers(&indexer, &[fork_header1, fork_header2, fork_header3])
+        .unwrap();
+
+    assert!(matches!(
+        next_event(&events),
+        Ok(Event::ReorgTooDeep { depth: 2 })
+    ));
+    assert_eq!(indexer.chain_height().unwrap(), ChainHeight(2));
+    assert_eq!(
+        indexer.block_hash_at(2).unwrap(),
+        Some(header2.block_hash())
+    );
+    assert!(sync.receiver.try_recv().is_err());
+}
+
+#[test]
+#[serial]
+fn indexer_unparsed_stats() {
+    let indexer = init_offline_indexer();
+
+    // Vault payload with unknown action
+    let mut vault_tx: Transaction =
+        deserialize(&hex::decode(DEPOSIT_TX).unwrap()).expect("valid transaction");
+    let op_return = vault_tx
+        .output
+        .iter_mut()
+        .find(|out| out.script_pubkey.is_op_return())
+        .unwrap();
+    let mut script = op_return.script_pubkey.to_bytes();
+    script[4] = 0xff;
+    op_return.script_pubkey = script.into();
+    assert!(VaultTx::from_tx(&vault_tx).is_err());
+
+    let rune_tx = |runestone: Runestone, vout| Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(vault_tx.compute_txid(), vout),
+            ..Default::default()
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: runestone.encipher(),
+        }],
+    };
+    let edict = |id, amount, output| Edict { id, amount, output };
+    // Runestone that moves other rune isn't about UNIT at all
+    let other_rune = rune_tx(
+        Runestone {
+            edicts: vec![edict(RuneId { block: 1, tx: 1 }, 1, 0)],
+            ..Default::default()
+        },
+        0,
+    );
+    // UNIT amount doesn't fit
+    let overflow = rune_tx(
+        Runestone {
+            edicts: vec![
+                edict(UNIT_RUNE_ID, u32::MAX as u128, 0),
+                edict(UNIT_RUNE_ID, 1, 0),
+            ],
+            ..Default::default()
+        },
+        1,
+    );
+    // Edict to missing output makes the cenotaph that keeps only the minted rune
+    let cenotaph = rune_tx(
+        Runestone {
+            edicts: vec![edict(UNIT_RUNE_ID, 1, 10)],
+            mint: Some(UNIT_RUNE_ID),
+            ..Default::default()
+        },
+        2,
+    );
+
+    let block = Block {
+        header: mk_header(HEADER_HEIGHT_1),
+        txdata: vec![vault_tx.clone(), other_rune, overflow, cenotaph],
+    };
+    indexer.process_block(block, 1).unwrap();
+
+    let stats = indexer.stats().unwrap();
+    assert_eq!(stats.unparsed_vault_txs, 1);
+    assert_eq!(stats.unparsed_unit_txs, 2);
+    assert_eq!(stats.unit_transactions, 0);
+    assert_eq!(stats.vault_transactions, 0);
+}
+
+#[test]
+#[serial]
+fn indexer_store_blocks_in() {
+    init_parser();
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        .store_blocks_in(Some((2, 3)))
+        .build()
+        .unwrap();
+
+    let deposit: Transaction =
+        deserialize(&hex::decode(DEPOSIT_TX).unwrap()).expect("valid transaction");
+    let outside = Block {
+        header: mk_header(HEADER_HEIGHT_1),
+        txdata: vec![deposit.clone()],
+    };
+    let inside = Block {
+        header: mk_header(HEADER_HEIGHT_2),
+        txdata: vec![deposit],
+    };
+    indexer.process_block(outside.clone(), 1).unwrap();
+    indexer.process_block(inside.clone(), 2).unwrap();
+
+    let db = indexer.get_database();
+    let conn = db.lock().unwrap();
+    assert_eq!(conn.load_block(inside.block_hash()).unwrap(), Some(inside));
+    assert_eq!(conn.load_block(outside.block_hash()).unwrap(), None);
+}
+
+#[test]
+#[serial]
+fn indexer_verify() {
+    let indexer = init_offline_indexer();
+    feed_headers(
+        &indexer,
+        &[mk_header(HEADER_HEIGHT_1), mk_header(HEADER_HEIGHT_2)],
+    );
+    {
+        let db = indexer.get_database();
+        let mut conn = db.lock().unwrap();
+        store_vault_fixture(&mut conn, OPEN_VAULT_TX, 1, 0);
+    }
+    let report = indexer.verify().unwrap();
+    assert!(report.is_ok(), "{report:?}");
+    assert_eq!(report.chain_height, ChainHeight(2));
+
+    // Break the main chain and drop the vault of the stored transaction
+    {
+        let db = indexer.get_database();
+        let conn = db.lock().unwrap();
+        conn.execute("DELETE FROM headers WHERE height = 1", [])
+            .unwrap();
+        conn.execute("DELETE FROM vaults", []).unwrap();
+        conn.set_scanned_height(ScannedHeight(5)).unwrap();
+    }
+    let report = indexer.verify().unwrap();
+    assert!(!report.is_ok());
+    assert!(report.integrity_errors.is_empty());
+    assert_eq!(report.chain_breaks, vec![2]);
+    assert!(!report.tip_mismatch);
+    assert_eq!(report.dangling_transactions.len(), 1);
+    assert!(report.scanned_above_tip());
+}
+
+#[test]
+#[serial]
+fn indexer_verify_file_gap() {
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_verify_gap.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let open_indexer = || {
+        Indexer::builder()
+            .network(Network::Mutinynet)
+            .start_height(ScannedHeight(0))
+            .db(&path)
+            .build()
+    };
+    {
+        let indexer = open_indexer().unwrap();
+        feed_headers(
+            &indexer,
+            &[
+                mk_header(HEADER_HEIGHT_1),
+                mk_header(HEADER_HEIGHT_2),
+                mk_header(HEADER_HEIGHT_3),
+            ],
+        );
+        let db = indexer.get_database();
+        let conn = db.lock().unwrap();
+        conn.set_scanned_height(ScannedHeight(1)).unwrap();
+    }
+    {
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute("DELETE FROM headers WHERE height = 2", [])
+            .unwrap();
+    }
+    let header_rows = || {
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.query_row("SELECT COUNT(*) FROM headers", [], |row| {
+            row.get::<_, u32>(0)
+        })
+        .unwrap()
+    };
+    let rows_before = header_rows();
+
+    // The check of `--verify` works on the database the indexer refuses to load
+    assert!(open_indexer().is_err());
+    let report =
+        VerifyReport::collect_file(&path, Network::Mutinynet, CacheConfig::default()).unwrap();
+    report.log_problems();
+    assert!(!report.is_ok());
+    assert_eq!(report.chain_breaks, vec![3]);
+    assert_eq!(report.scanned_height, ScannedHeight(1));
+    assert_eq!(header_rows(), rows_before);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+fn indexer_tip_stale() {
+    init_parser();
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        .tip_stale_after(Duration::from_millis(50))
+        .build()
+        .unwrap();
+    let (sender, receiver) = sync_channel(EVENTS_CAPACITY);
+
+    // Not running indexer is never stale
+    assert_eq!(indexer.seconds_since_last_block(), None);
+    indexer.check_tip_age(&sender).unwrap();
+    assert!(receiver.try_recv().is_err());
+
+    indexer.stats_cache().record_block_activity();
+    assert_eq!(indexer.seconds_since_last_block(), Some(0));
+    assert!(!indexer.is_tip_stale());
+    indexer.check_tip_age(&sender).unwrap();
+    assert!(receiver.try_recv().is_err());
+
+    // Reported once while the node is silent
+    thread::sleep(Duration::from_millis(100));
+    assert!(indexer.is_tip_stale());
+    indexer.check_tip_age(&sender).unwrap();
+    assert!(matches!(receiver.try_recv(), Ok(Event::TipStale { .. })));
+    indexer.check_tip_age(&sender).unwrap();
+    assert!(receiver.try_recv().is_err());
+    assert!(indexer.stats().unwrap().seconds_since_last_block.is_some());
+
+    // New block activity resets the report
+    indexer.stats_cache().record_block_activity();
+    indexer.check_tip_age(&sender).unwrap();
+    assert!(!indexer.is_tip_stale());
+    thread::sleep(Duration::from_millis(100));
+    indexer.check_tip_age(&sender).unwrap();
+    assert!(matches!(receiver.try_recv(), Ok(Event::TipStale { .. })));
+}
+
+#[test]
+#[serial]
+fn indexer_reorg_unit_tx() {
+    let indexer = init_offline_indexer();
+    let header1 = mk_header(HEADER_HEIGHT_1);
+    let header2 = mk_header(HEADER_HEIGHT_2);
+    let mut sync = feed_headers(&indexer, &[header1, header2]);
+    let unit_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX_PHASE1).unwrap()).expect("valid transaction");
+    let unit_txid = unit_tx.compute_txid();
+    indexer
+        .process_block(
+            Block {
+                header: header2,
+                txdata: vec![unit_tx.clone()],
+            },
+            2,
+        )
+        .unwrap();
+    let load = || {
+        let db = indexer.get_database();
+        let conn = db.lock().unwrap();
+        (
+            conn.load_unit_tx(unit_txid).unwrap(),
+            conn.count_unit_txs().unwrap(),
+        )
+    };
+    let (stored, count) = load();
+    assert_eq!(stored.block_hash, Some(header2.block_hash()));
+    assert_eq!(stored.height, Some(2));
+    assert!(stored.in_longest);
+    assert_eq!(count, 1);
+
+    // Fork from the first block with more work disconnects the second one
+    let mut fork_header2 = header2;
+    fork_header2.nonce += 1;
+    let fork_header2 = fake_fork_mine(fork_header2);
+    let mut fork_header3 = mk_header(HEADER_HEIGHT_3);
+    fork_header3.prev_blockhash = fork_header2.block_hash();
+    let fork_header3 = fake_fork_mine(fork_header3);
+    sync.headers(&indexer, &[fork_header2, fork_header3])
+        .unwrap();
+    assert_eq!(
+        indexer.block_hash_at(2).unwrap(),
+        Some(fork_header2.block_hash())
+    );
+    let (stale, count) = load();
+    assert_eq!(stale.block_hash, Some(header2.block_hash()));
+    assert!(!stale.in_longest);
+    assert_eq!(count, 0);
+
+    // The transaction is mined again in the new chain
+    indexer
+        .process_block(
+            Block {
+                header: fork_header3,
+                txdata: vec![unit_tx],
+            },
+            3,
+        )
+        .unwrap();
+    let (moved, count) = load();
+    assert_eq!(moved.block_hash, Some(fork_header3.block_hash()));
+    assert_eq!(moved.height, Some(3));
+    assert!(moved.in_longest);
+    assert_eq!(count, 1);
+}
+
+#[test]
+#[serial]
+fn indexer_chain_height_checked() {
+    let indexer = init_offline_indexer();
+
+    // Headers aren't synced yet, the genesis height is not the real one
+    assert!(!indexer.is_ready());
+    assert_eq!(indexer.chain_height().unwrap(), ChainHeight(0));
+    let err = indexer.chain_height_checked().unwrap_err();
+    assert!(err.to_string().contains("still syncing"), "{err}");
+
+    // The node has no headers after the genesis, the chain is genuinely empty
+    let mut sync = feed_headers(&indexer, &[]);
+    assert!(indexer.is_ready());
+    assert_eq!(indexer.chain_height_checked().unwrap(), ChainHeight(0));
+
+    sync.headers(
+        &indexer,
+        &[mk_header(HEADER_HEIGHT_1), mk_header(HEADER_HEIGHT_2)],
+    )
+    .unwrap();
+    assert_eq!(indexer.chain_height_checked().unwrap(), ChainHeight(2));
+}
+
+#[test]
+#[serial]
+fn indexer_protocol_prefixes() {
+    init_parser();
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        .protocol_prefixes(vec![0x59])
+        .build()
+        .expect("Indexer configured");
+    let events = indexer.add_event_reader().unwrap();
+
+    let unit_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX_PHASE1).unwrap()).expect("valid transaction");
+    let mut open_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    open_tx.input[1].previous_output = OutPoint::new(unit_tx.compute_txid(), 0);
+    // Deployment that marks vault outputs with OP_PUSHNUM_9
+    let mut script = open_tx.output[4].script_pubkey.to_bytes();
+    script[1] = 0x59;
+    open_tx.output[4].script_pubkey = ScriptBuf::from_bytes(script);
+
+    let block = Block {
+        header: mk_header(HEADER_HEIGHT_1),
+        txdata: vec![unit_tx, open_tx.clone()],
+    };
+    indexer.process_block(block, 1).unwrap();
+
+    assert!(matches!(
+        next_event(&events),
+        Ok(Event::NewUnitTransaction(_))
+    ));
+    match next_event(&events) {
+        Ok(Event::NewTransaction(meta)) => {
+            assert_eq!(meta.vault_id, open_tx.compute_txid());
+            assert_eq!(meta.vault_tx.action, VaultAction::Open);
+        }
+        other => panic!("Expected new vault tx, got {other:?}"),
+    }
+}
+
+#[test]
+#[serial]
+fn indexer_reorg_vault_state() {
+    let indexer = init_offline_indexer();
+    let header1 = mk_header(HEADER_HEIGHT_1);
+    let header2 = mk_header(HEADER_HEIGHT_2);
+    let mut sync = feed_headers(&indexer, &[header1, header2]);
+    // The vault is opened in the genesis block and its last transaction is in the second one
+    let (open, deposit) = {
+        let db = indexer.get_database();
+        let mut conn = db.lock().unwrap();
+        let open = store_vault_fixture(&mut conn, OPEN_VAULT_TX, 0, 1);
+        let mut raw_tx: Transaction = deserialize(&hex::decode(DEPOSIT_TX).unwrap()).unwrap();
+        raw_tx.input[0].previous_output.txid = open.vault_tx.txid;
+        let vault_tx = VaultTx::from_tx(&raw_tx).unwrap();
+        let deposit = conn
+            .store_vault_tx(&vault_tx, header2.block_hash(), 1, 2, &raw_tx)
+            .unwrap();
+        (open, deposit)
+    };
+    let vault_state = || {
+        indexer
+            .get_database()
+            .lock()
+            .unwrap()
+            .load_vault_state(open.vault_id)
+            .unwrap()
+            .unwrap()
+    };
+    assert_eq!(vault_state().last_tx, deposit.vault_tx.txid);
+
+    // Fork from the first block with more work disconnects the deposit
+    let mut fork_header2 = header2;
+    fork_header2.nonce += 1;
+    let fork_header2 = fake_fork_mine(fork_header2);
+    let mut fork_header3 = mk_header(HEADER_HEIGHT_3);
+    fork_header3.prev_blockhash = fork_header2.block_hash();
+    let fork_header3 = fake_fork_mine(fork_header3);
+    sync.headers(&indexer, &[fork_header2, fork_header3])
+        .unwrap();
+
+    let state = vault_state();
+    assert_eq!(state.last_tx, open.vault_tx.txid);
+    assert_eq!(state.balance, open.vault_tx.balance);
+    assert_eq!(state.custody, open.btc_custody);
+}
+
+#[test]
+#[serial]
+fn indexer_reorg_hides_orphaned_vault() {
+    let indexer = init_offline_indexer();
+    let header1 = mk_header(HEADER_HEIGHT_1);
+    let header2 = mk_header(HEADER_HEIGHT_2);
+    let mut sync = feed_headers(&indexer, &[header1, header2]);
+    let unit_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX_PHASE1).unwrap()).expect("valid transaction");
+    let mut open_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    open_tx.input[1].previous_output = OutPoint::new(unit_tx.compute_txid(), 0);
+    let vault_id = open_tx.compute_txid();
+    let txdata = vec![unit_tx, open_tx];
+    indexer
+        .process_block(
+            Block {
+                header: header2,
+                txdata: txdata.clone(),
+            },
+            2,
+        )
+        .unwrap();
+    let visible = || {
+        let db = indexer.get_database();
+        let conn = db.lock().unwrap();
+        let state = conn.load_vault_state(vault_id).unwrap();
+        let top = conn.top_vaults(VaultRanking::Custody, 10).unwrap();
+        assert_eq!(top.len() as u64, conn.count_vaults().unwrap());
+        assert_eq!(state.is_some(), !top.is_empty());
+        state.is_some()
+    };
+    assert!(visible());
+
+    // Fork from the first block with more work disconnects the opening transaction
+    let mut fork_header2 = header2;
+    fork_header2.nonce += 1;
+    let fork_header2 = fake_fork_mine(fork_header2);
+    let mut fork_header3 = mk_header(HEADER_HEIGHT_3);
+    fork_header3.prev_blockhash = fork_header2.block_hash();
+    let fork_header3 = fake_fork_mine(fork_header3);
+    sync.headers(&indexer, &[fork_header2, fork_header3])
+        .unwrap();
+    assert!(!visible());
+
+    // The opening transaction is mined again in the new chain
+    indexer
+        .process_block(
+            Block {
+                header: fork_header3,
+                txdata,
+            },
+            3,
+        )
+        .unwrap();
+    assert!(visible());
+}
+
+#[test]
+#[serial]
+fn indexer_reorg_log() {
+    let indexer = init_offline_indexer();
+    let header1 = mk_header(HEADER_HEIGHT_1);
+    let header2 = mk_header(HEADER_HEIGHT_2);
+    let mut sync = feed_headers(&indexer, &[header1, header2]);
+    let unit_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX_PHASE1).unwrap()).expect("valid transaction");
+    let mut open_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    open_tx.input[1].previous_output = OutPoint::new(unit_tx.compute_txid(), 0);
+    indexer
+        .process_block(
+            Block {
+                header: header2,
+                txdata: vec![unit_tx, open_tx],
+            },
+            2,
+        )
+        .unwrap();
+    assert!(indexer
+        .get_database()
+        .lock()
+        .unwrap()
+        .load_reorgs()
+        .unwrap()
+        .is_empty());
+
+    // Fork from the first block with more work disconnects the second one
+    let mut fork_header2 = header2;
+    fork_header2.nonce += 1;
+    let fork_header2 = fake_fork_mine(fork_header2);
+    let mut fork_header3 = mk_header(HEADER_HEIGHT_3);
+    fork_header3.prev_blockhash = fork_header2.block_hash();
+    let fork_header3 = fake_fork_mine(fork_header3);
+    sync.headers(&indexer, &[fork_header2, fork_header3])
+        .unwrap();
+
+    let db = indexer.get_database();
+    let conn = db.lock().unwrap();
+    let reorgs = conn.load_reorgs().unwrap();
+    assert_eq!(reorgs.len(), 1);
+    let reorg = &reorgs[0];
+    assert_eq!(reorg.old_tip, header2.block_hash());
+    assert_eq!(reorg.new_tip, fork_header3.block_hash());
+    assert_eq!(reorg.fork_height, 1);
+    assert_eq!(reorg.vault_txs, 1);
+    assert_eq!(reorg.unit_txs, 1);
+    assert!(reorg.timestamp > 0);
+    assert_eq!(
+        conn.range_history_all(None, None, SortOrder::Asc, false)
+            .unwrap()
+            .len(),
+        0
+    );
+}
+
+#[test]
+#[serial]
+fn indexer_sync_phase() {
+    let phase = |remote, chain, scanned| {
+        SyncPhase::from_heights(
+            RemoteHeight(remote),
+            ChainHeight(chain),
+            ScannedHeight(scanned),
+        )
+    };
+    // Node is ahead of the known headers
+    assert_eq!(phase(100, 50, 0), SyncPhase::HeaderSync);
+    assert_eq!(phase(100, 99, 99), SyncPhase::HeaderSync);
+    // Headers caught up, blocks are behind
+    assert_eq!(phase(100, 100, 50), SyncPhase::BlockScan);
+    // Local chain can be ahead of the height announced on the handshake
+    assert_eq!(phase(100, 101, 100), SyncPhase::BlockScan);
+    assert_eq!(phase(100, 100, 100), SyncPhase::Synced);
+    // Before the handshake the remote height is unknown
+    assert_eq!(phase(0, 10, 10), SyncPhase::Connecting);
+    assert_eq!(phase(0, 0, 0), SyncPhase::Connecting);
+    // Scanned height above the tip (first sync up to the start height) has no blocks left
+    assert!(ScannedHeight(5).is_behind(ChainHeight(7)));
+    assert_eq!(ScannedHeight(5).blocks_left(ChainHeight(7)), 2);
+    assert!(!ScannedHeight(10).is_behind(ChainHeight(7)));
+    assert_eq!(ScannedHeight(10).blocks_left(ChainHeight(7)), 0);
+    // Headers taken as the whole chain
+    assert_eq!(
+        SyncPhase::from_local_heights(ChainHeight(7), ScannedHeight(5)),
+        SyncPhase::BlockScan
+    );
+    assert_eq!(
+        SyncPhase::from_local_heights(ChainHeight(7), ScannedHeight(7)),
+        SyncPhase::Synced
+    );
+    assert_eq!(ChainHeight(7).blocks_behind(RemoteHeight(10)), 3);
+    assert_eq!(RemoteHeight(5).raise_to(ChainHeight(7)), RemoteHeight(7));
+    assert!(ChainHeight(7).reached(ScannedHeight(7)));
+    assert!(!ChainHeight(6).reached(ScannedHeight(7)));
+    assert_eq!(ScannedHeight(7).next_block(), BlockHeight(8));
+    assert!(ScannedHeight(7).covers(BlockHeight(7)));
+    assert!(!ScannedHeight(7).covers(BlockHeight(8)));
+    assert_eq!(ScannedHeight(7).cover(BlockHeight(9)), ScannedHeight(9));
+    assert_eq!(ScannedHeight(7).blocks_until(ScannedHeight(10)), 3);
+
+    let indexer = init_offline_indexer();
+    assert_eq!(indexer.phase().unwrap(), SyncPhase::Connecting);
+    feed_headers(
+        &indexer,
+        &[mk_header(HEADER_HEIGHT_1), mk_header(HEADER_HEIGHT_2)],
+    );
+    assert_eq!(indexer.phase().unwrap(), SyncPhase::BlockScan);
+}
+
+#[test]
+#[serial]
+fn indexer_progress_after_shortening_reorg() {
+    let indexer = init_offline_indexer();
+    let header1 = mk_header(HEADER_HEIGHT_1);
+    let header2 = mk_header(HEADER_HEIGHT_2);
+    let header3 = mk_header(HEADER_HEIGHT_3);
+    let mut sync = feed_headers(&indexer, &[header1, header2, header3]);
+    let scanned_height = 3;
+    let current_height = indexer
+        .get_headers_cache()
+        .lock()
+        .unwrap()
+        .get_current_height()
+        .get();
+    assert_eq!(current_height, 3);
+    assert_eq!(progress_percent(scanned_height, current_height), 100.0);
+
+    // Single block with 256 times smaller target outweighs two blocks of the main chain
+    let mut fork_header2 = header2;
+    fork_header2.bits = CompactTarget::from_consensus(header2.bits.to_consensus() - 0x0100_0000);
+    assert!(fork_header2.work() > header2.work() + header3.work());
+    sync.headers(&indexer, &[fork_header2]).unwrap();
+    let current_height = indexer
+        .get_headers_cache()
+        .lock()
+        .unwrap()
+        .get_current_height()
+        .get();
+    assert_eq!(current_height, 2);
+    assert_eq!(progress_percent(scanned_height, current_height), 100.0);
+    assert_eq!(progress_percent(1, current_height), 50.0);
+    assert_eq!(progress_percent(0, 0), 0.0);
+}
+
+#[test]
+#[serial]
+fn indexer_header_lookup() {
+    let indexer = init_offline_indexer();
+    let header1 = mk_header(HEADER_HEIGHT_1);
+    let header2 = mk_header(HEADER_HEIGHT_2);
+    feed_headers(&indexer, &[header1, header2]);
+
+    let genesis = Network::Mutinynet.genesis_header();
+    assert_eq!(indexer.header_at_height(0).unwrap(), Some(genesis));
+    assert_eq!(indexer.header_at_height(2).unwrap(), Some(header2));
+    assert_eq!(indexer.header_at_height(3).unwrap(), None);
+
+    let record = indexer.header(header1.block_hash()).unwrap().unwrap();
+    assert_eq!(record.header, header1);
+    assert_eq!(record.height, 1);
+    assert!(record.in_longest);
+    let unknown = mk_header(HEADER_HEIGHT_3).block_hash();
+    assert!(indexer.header(unknown).unwrap().is_none());
+}
+
+#[test]
+#[serial]
+fn indexer_flush_on_drop() {
+    init_parser();
+    let path = std::env::temp_dir().join("vault_indexer_flush_on_drop.sqlite");
+    let _ = std::fs::remove_file(&path);
+    let build = || {
+        Indexer::builder()
+            .network(Network::Mutinynet)
+            .db(&path)
+            .start_height(ScannedHeight(0))
+            .build()
+            .expect("Indexer configured")
+    };
+
+    let header1 = mk_header(HEADER_HEIGHT_1);
+    let header2 = mk_header(HEADER_HEIGHT_2);
+    let indexer = build();
+    // Headers are only in memory until the cache is stored
+    indexer
+        .get_headers_cache()
+        .lock()
+        .unwrap()
+        .update_longest_chain(&[header1, header2])
+        .unwrap();
+    drop(indexer);
+
+    let indexer = build();
+    assert_eq!(indexer.chain_height().unwrap(), ChainHeight(2));
+    assert_eq!(
+        indexer.block_hash_at(2).unwrap(),
+        Some(header2.block_hash())
+    );
+    drop(indexer);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+#[serial]
+fn indexer_reconnect_limit() {
+    init_parser();
+    // Nothing listens on the port, so every connection is refused
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        .node("127.0.0.1:1")
+        .max_reconnect_attempts(Some(1))
+        .build()
+        .unwrap();
+    let err = indexer.run().unwrap_err();
+    assert!(
+        err.to_string().contains("1 failed connection attempts"),
+        "{err}"
+    );
+}
+
+#[test]
+#[serial]
+fn indexer_rejects_tampered_block() {
+    init_parser();
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        .build()
+        .unwrap();
+    let events = indexer.add_event_reader().unwrap();
+
+    // Coinbase commits to the witness of the vault transaction
+    let vault_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    let reserved = [0u8; 32];
+    let coinbase = Transaction {
+        version: transaction::Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            witness: Witness::from_slice(&[reserved]),
+            ..Default::default()
+        }],
+        output: vec![],
+    };
+    let vault_txid = vault_tx.compute_txid();
+    let mut block = Block {
+        header: mk_header(HEADER_HEIGHT_1),
+        txdata: vec![coinbase, vault_tx],
+    };
+    let commitment = Block::compute_witness_commitment(&block.witness_root().unwrap(), &reserved);
+    let mut commitment_script = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+    commitment_script.extend(commitment.as_byte_array());
+    block.txdata[0].output.push(TxOut {
+        value: Amount::ZERO,
+        script_pubkey: ScriptBuf::from_bytes(commitment_script),
+    });
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    assert!(block.check_merkle_root() && block.check_witness_commitment());
+    let hash = block.block_hash();
+
+    let mut sync = feed_headers(&indexer, &[block.header]);
+    assert_eq!(expect_get_data(&sync.receiver), vec![hash]);
+
+    let mut altered_tx = block.clone();
+    altered_tx.txdata[1].output[0].value += Amount::from_sat(1);
+    let mut altered_witness = block.clone();
+    altered_witness.txdata[1].input[0].witness = Witness::from_slice(&[[1u8; 64]]);
+    for (i, tampered) in [altered_tx.clone(), altered_witness, altered_tx]
+        .into_iter()
+        .enumerate()
+    {
+        assert_eq!(tampered.block_hash(), hash);
+        sync.block(&indexer, tampered).unwrap();
+        assert!(matches!(
+            next_event(&events),
+            Ok(Event::BlockRejected { hash: rejected, .. }) if rejected == hash
+        ));
+        if i + 1 < MAX_BLOCK_REJECTIONS as usize {
+            // The block is requested again and nothing is indexed
+            assert_eq!(expect_get_data(&sync.receiver), vec![hash]);
+        } else {
+            // The peer keeps sending the broken block, it is dropped
+            assert!(matches!(
+                sync.receiver.try_recv(),
+                Ok(Event::DropPeer { .. })
+            ));
+            assert!(sync.receiver.try_recv().is_err());
+        }
+        assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(0));
+        assert!(next_event(&events).is_err());
+    }
+
+    // The next peer is asked for the block again
+    sync.expected_blocks.insert(hash);
+
+    sync.block(&indexer, block).unwrap();
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(1));
+    // The vault transaction is processed (its UNIT parent isn't indexed in the test)
+    let event = next_event(&events);
+    assert!(
+        matches!(event, Ok(Event::ProcessingError { txid, .. }) if txid == vault_txid),
+        "{event:?}"
+    );
+}
+
+#[test]
+#[serial]
+fn indexer_rejects_block_tx_order() {
+    init_parser();
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        // The order is checked regardless of the merkle root and witness commitment
+        .verify_blocks(false)
+        .build()
+        .unwrap();
+    let events = indexer.add_event_reader().unwrap();
+
+    let unit_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX_PHASE1).unwrap()).expect("valid transaction");
+    let mut open_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    open_tx.input[1].previous_output = OutPoint::new(unit_tx.compute_txid(), 0);
+    // The child is placed before its parent
+    let block = Block {
+        header: mk_header(HEADER_HEIGHT_1),
+        txdata: vec![open_tx, unit_tx],
+    };
+    let hash = block.block_hash();
+
+    let mut sync = feed_headers(&indexer, &[block.header]);
+    assert_eq!(expect_get_data(&sync.receiver), vec![hash]);
+
+    for i in 0..MAX_BLOCK_REJECTIONS as usize {
+        // The indexer keeps running
+        sync.block(&indexer, block.clone()).unwrap();
+        let event = next_event(&events);
+        assert!(
+            matches!(&event, Ok(Event::BlockRejected { hash: rejected, reason })
+                if *rejected == hash && reason.contains("spends outputs of later transaction")),
+            "{event:?}"
+        );
+        if i + 1 < MAX_BLOCK_REJECTIONS as usize {
+            assert_eq!(expect_get_data(&sync.receiver), vec![hash]);
+        } else {
+            assert!(matches!(
+                sync.receiver.try_recv(),
+                Ok(Event::DropPeer { .. })
+            ));
+        }
+        assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(0));
+    }
+    let db = indexer.get_database();
+    let conn = db.lock().unwrap();
+    assert_eq!(conn.count_unit_txs().unwrap(), 0);
+    assert_eq!(conn.count_vault_txs().unwrap(), 0);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+#[serial]
+fn indexer_run_async() {
+    init_parser();
+    let indexer = Arc::new(
+        Indexer::builder()
+            .network(Network::Mutinynet)
+            .start_height(ScannedHeight(0))
+            .node("127.0.0.1:1")
+            .max_reconnect_attempts(Some(1))
+            .build()
+            .unwrap(),
+    );
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let mut events = indexer.add_async_event_reader().unwrap();
+        assert!(indexer.clone().run_async().await.is_err());
+        assert!(matches!(events.recv().await, Some(Event::Disconnected)));
+    });
+}
+
+#[test]
+#[serial]
+fn indexer_readers_public_events() {
+    use crate::indexer::event::EventReaders;
+
+    let mut readers = EventReaders::default();
+    let reader = readers.add_reader(EVENTS_CAPACITY);
+    readers.publish(Event::IncomingMessage(NetworkMessage::Verack));
+    readers.publish(Event::OutcomingMessage(NetworkMessage::GetAddr));
+    readers.publish(Event::DropPeer {
+        reason: "test".to_owned(),
+    });
+    readers.publish(Event::Disconnected);
+    assert!(matches!(reader.try_recv(), Ok(Event::Disconnected)));
+    assert_eq!(reader.try_recv().err(), Some(TryRecvError::Empty));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+#[serial]
+fn indexer_async_reader_dropped() {
+    use crate::indexer::asynchronous::{forward_events, ASYNC_READER_POLL};
+    use crate::indexer::event::EventReaders;
+
+    let mut readers = EventReaders::default();
+    let events = forward_events(readers.add_reader(EVENTS_CAPACITY));
+    drop(events);
+    // No events are published, the forwarding thread must notice the drop by itself
+    thread::sleep(ASYNC_READER_POLL * 3);
+    readers.publish(Event::Disconnected);
+    assert!(readers.is_empty());
+}
+
+#[test]
+#[serial]
+fn indexer_skip_to() {
+    init_parser();
+    let indexer = Indexer::builder()
+        .network(Network::Mutinynet)
+        .start_height(ScannedHeight(0))
+        .batch_size(1)
+        .verify_blocks(false)
+        .build()
+        .unwrap();
+    let headers = vec![
+        mk_header(HEADER_HEIGHT_1),
+        mk_header(HEADER_HEIGHT_2),
+        mk_header(HEADER_HEIGHT_3),
+    ];
+    let mut sync = feed_headers(&indexer, &headers);
+    assert_eq!(
+        expect_get_data(&sync.receiver),
+        vec![headers[0].block_hash()]
+    );
+
+    assert!(indexer.skip_to(ScannedHeight(2), false).is_err());
+    assert!(indexer.skip_to(ScannedHeight(4), true).is_err());
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(0));
+    assert_eq!(
+        indexer.skip_to(ScannedHeight(2), true).unwrap(),
+        ScannedHeight(2)
+    );
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(2));
+    // Going back is a rescan, not a skip
+    assert_eq!(
+        indexer.skip_to(ScannedHeight(1), true).unwrap(),
+        ScannedHeight(2)
+    );
+
+    // Already requested block arrives, the next batch continues after the skipped blocks
+    sync.deliver(&indexer, headers[0], vec![]);
+    assert_eq!(
+        expect_get_data(&sync.receiver),
+        vec![headers[2].block_hash()]
+    );
+    assert_eq!(indexer.scanned_height().unwrap(), ScannedHeight(2));
+}
+
+#[test]
+#[serial]
+fn indexer_dump_roundtrip() {
+    let indexer = init_offline_indexer();
+    let headers = [
+        mk_header(HEADER_HEIGHT_1),
+        mk_header(HEADER_HEIGHT_2),
+        mk_header(HEADER_HEIGHT_3),
+    ];
+    let meta = {
+        let cache = indexer.get_headers_cache();
+        let mut cache = cache.lock().unwrap();
+        let db = indexer.get_database();
+        let mut conn = db.lock().unwrap();
+        cache.update_longest_chain(&headers).unwrap();
+        cache.store(&mut conn).unwrap();
+        store_vault_fixture(&mut conn, UNKNOWN_VAULT_TX, 2, 1);
+        let meta = store_vault_fixture(&mut conn, OPEN_VAULT_TX, 3, 1);
+        conn.set_scanned_height(ScannedHeight(3)).unwrap();
+        meta
+    };
+    let collect = |indexer: &Indexer| {
+        let db = indexer.get_database();
+        let conn = db.lock().unwrap();
+        (
+            IndexerStats::collect(&conn).unwrap(),
+            conn.load_vault_state(meta.vault_id).unwrap(),
+            conn.get_raw_tx(meta.vault_tx.txid).unwrap(),
+        )
+    };
+    let expected = collect(&indexer);
+    assert_eq!(expected.0.vaults, 2);
+    assert!(expected.0.unit_transactions > 0);
+    assert!(expected.1.is_some());
+
+    let path = std::env::temp_dir().join("vault_indexer_dump.jsonl.gz");
+    let rows = indexer.export_dump(&path).unwrap();
+    assert!(rows > 0);
+
+    let imported = init_offline_indexer();
+    assert_eq!(imported.import_dump(&path).unwrap(), rows);
+    assert_eq!(collect(&imported), expected);
+    assert_eq!(imported.chain_height().unwrap(), ChainHeight(3));
+    assert_eq!(imported.scanned_height().unwrap(), ScannedHeight(3));
+
+    // Importing again replaces the rows instead of failing on duplicates
+    assert_eq!(imported.import_dump(&path).unwrap(), rows);
+    assert_eq!(collect(&imported), expected);
+
+    // Broken dump leaves the database untouched
+    std::fs::write(&path, b"not a dump").unwrap();
+    assert!(imported.import_dump(&path).is_err());
+    assert_eq!(collect(&imported), expected);
+    std::fs::remove_file(&path).unwrap();
+}
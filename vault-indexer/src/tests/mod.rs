@@ -1,7 +1,14 @@
 mod db;
 mod framework;
+mod health;
+mod indexer;
+#[cfg(unix)]
+mod peer;
+mod queries;
 mod runes;
+mod service;
 mod transaction;
+mod webhook;
 
 use framework::*;
 
@@ -29,7 +36,7 @@ fn node_sync_headers() {
     });
     // Wait until we have non zero height of downloaded headers
     wait_until(3, Duration::from_secs(1), || {
-        indexer.chain_height().unwrap() > 0
+        indexer.chain_height().unwrap().get() > 0
     });
 }
 
@@ -43,10 +50,10 @@ fn node_scan_process() {
     });
     // Wait until we have non zero height of downloaded headers
     wait_until(3, Duration::from_secs(1), || {
-        indexer.chain_height().unwrap() > 0
+        indexer.chain_height().unwrap().get() > 0
     });
     // Wait until we have scanned several blocks
     wait_until(3, Duration::from_secs(1), || {
-        indexer.scanned_height().unwrap() > 0
+        indexer.scanned_height().unwrap().get() > 0
     });
 }
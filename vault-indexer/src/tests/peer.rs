@@ -0,0 +1,328 @@
+use crate::indexer::event::Event;
+use crate::indexer::node::{
+    build_version_message, node_process, receive_message, send_message, PeerStream,
+    DEFAULT_MAX_DECODE_FAILURES,
+};
+use crate::indexer::seed::{parse_seed, PeerSource, SeedResolver};
+use crate::tests::framework::*;
+use crate::Network;
+use bitcoin::p2p::{message::NetworkMessage, ServiceFlags};
+use bus::Bus;
+use core::net::{IpAddr, SocketAddr};
+use core::time::Duration;
+use serial_test::serial;
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::sync::mpmc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+impl PeerStream for UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> std::io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+const PEER_HEIGHT: u32 = 3;
+
+fn peer_addr() -> SocketAddr {
+    "127.0.0.1:18444".parse().unwrap()
+}
+
+fn next_event(receiver: &Receiver<Event>) -> Event {
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("event from node worker")
+}
+
+#[test]
+#[serial]
+fn peer_scripted_session() {
+    init_parser();
+    let network = Network::Mutinynet;
+    let (local, mut remote) = UnixStream::pair().unwrap();
+
+    // Scripted remote node
+    let peer_handle = thread::spawn(move || {
+        match receive_message(&mut remote, network).unwrap() {
+            NetworkMessage::Version(_) => (),
+            other => panic!("Expected version message, got {other:?}"),
+        }
+        let mut version = build_version_message(&peer_addr(), "scripted peer", PEER_HEIGHT);
+        version.services = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
+        send_message(&mut remote, network, NetworkMessage::Version(version)).unwrap();
+        send_message(&mut remote, network, NetworkMessage::Verack).unwrap();
+        match receive_message(&mut remote, network).unwrap() {
+            NetworkMessage::Verack => (),
+            other => panic!("Expected verack message, got {other:?}"),
+        }
+
+        let headers = vec![mk_header(HEADER_HEIGHT_1)];
+        send_message(&mut remote, network, NetworkMessage::Headers(headers)).unwrap();
+        // Answer to the ping from our side
+        match receive_message(&mut remote, network).unwrap() {
+            NetworkMessage::Ping(nonce) => {
+                send_message(&mut remote, network, NetworkMessage::Pong(nonce)).unwrap()
+            }
+            other => panic!("Expected ping message, got {other:?}"),
+        }
+        remote
+    });
+
+    let mut bus = Bus::new(16);
+    let bus_receiver = bus.add_rx();
+    let (events_sender, events_receiver) = sync_channel(16);
+    let node_handle = thread::spawn(move || {
+        let mut attempt = 2;
+        let (res, _) = node_process(
+            local,
+            &peer_addr(),
+            network,
+            0,
+            DEFAULT_MAX_DECODE_FAILURES,
+            events_sender,
+            bus_receiver,
+            &mut attempt,
+        );
+        (res, attempt)
+    });
+
+    match next_event(&events_receiver) {
+        Event::Handshaked(peer) => {
+            assert_eq!(peer.start_height, PEER_HEIGHT);
+            assert_eq!(peer.user_agent, "scripted peer");
+            assert_eq!(peer.services, ServiceFlags::NETWORK | ServiceFlags::WITNESS);
+        }
+        other => panic!("Expected handshake event, got {other:?}"),
+    }
+    match next_event(&events_receiver) {
+        Event::IncomingMessage(NetworkMessage::Headers(headers)) => {
+            assert_eq!(headers, vec![mk_header(HEADER_HEIGHT_1)])
+        }
+        other => panic!("Expected headers message, got {other:?}"),
+    }
+    bus.broadcast(Event::OutcomingMessage(NetworkMessage::Ping(42)));
+    match next_event(&events_receiver) {
+        Event::IncomingMessage(NetworkMessage::Pong(nonce)) => assert_eq!(nonce, 42),
+        other => panic!("Expected pong message, got {other:?}"),
+    }
+
+    let _remote = peer_handle.join().unwrap();
+    bus.broadcast(Event::Termination);
+    let (res, attempt) = node_handle.join().unwrap();
+    res.expect("Normal termination");
+    // Successful handshake resets the reconnection counter
+    assert_eq!(attempt, 0);
+}
+
+#[test]
+#[serial]
+fn peer_no_version_message() {
+    init_parser();
+    let network = Network::Mutinynet;
+    let (local, mut remote) = UnixStream::pair().unwrap();
+
+    let peer_handle = thread::spawn(move || {
+        receive_message(&mut remote, network).unwrap();
+        // Misbehaving peer skips the version
+        send_message(&mut remote, network, NetworkMessage::Verack).unwrap();
+        remote
+    });
+
+    let mut bus = Bus::new(16);
+    let (events_sender, events_receiver) = sync_channel(16);
+    let mut attempt = 1;
+    let (res, _) = node_process(
+        local,
+        &peer_addr(),
+        network,
+        0,
+        DEFAULT_MAX_DECODE_FAILURES,
+        events_sender,
+        bus.add_rx(),
+        &mut attempt,
+    );
+    let _remote = peer_handle.join().unwrap();
+    let err = res.expect_err("Handshake should fail");
+    assert_eq!(
+        err.to_string(),
+        "First message from the peer is not version message"
+    );
+    assert_eq!(attempt, 1);
+    assert!(events_receiver.try_recv().is_err());
+}
+
+#[test]
+#[serial]
+fn peer_corrupt_stream() {
+    init_parser();
+    let network = Network::Mutinynet;
+    let (local, mut remote) = UnixStream::pair().unwrap();
+
+    let peer_handle = thread::spawn(move || {
+        receive_message(&mut remote, network).unwrap();
+        let version = build_version_message(&peer_addr(), "scripted peer", PEER_HEIGHT);
+        send_message(&mut remote, network, NetworkMessage::Version(version)).unwrap();
+        send_message(&mut remote, network, NetworkMessage::Verack).unwrap();
+        receive_message(&mut remote, network).unwrap();
+        send_message(&mut remote, network, NetworkMessage::Headers(vec![])).unwrap();
+
+        // Frames with valid magic, but garbage instead of checksum and payload
+        let mut garbage = vec![];
+        garbage.extend(network.magic().to_bytes());
+        garbage.extend(b"headers\0\0\0\0\0");
+        garbage.extend(4u32.to_le_bytes());
+        garbage.extend([0xff; 8]);
+        for _ in 0..3 {
+            remote.write_all(&garbage).unwrap();
+        }
+        remote
+    });
+
+    let mut bus = Bus::new(16);
+    let (events_sender, events_receiver) = sync_channel(16);
+    let mut attempt = 0;
+    let (res, _) = node_process(
+        local,
+        &peer_addr(),
+        network,
+        0,
+        3,
+        events_sender,
+        bus.add_rx(),
+        &mut attempt,
+    );
+    let _remote = peer_handle.join().unwrap();
+    let err = res.expect_err("Corrupted stream should reconnect");
+    assert_eq!(
+        err.to_string(),
+        "Stream looks corrupted, 3 messages in a row failed to decode"
+    );
+    assert!(matches!(next_event(&events_receiver), Event::Handshaked(_)));
+    assert!(matches!(
+        next_event(&events_receiver),
+        Event::IncomingMessage(NetworkMessage::Headers(_))
+    ));
+}
+
+/// Returns scripted answers of the seed, one per resolution
+struct MockResolver(Arc<Mutex<Vec<std::io::Result<Vec<IpAddr>>>>>);
+
+impl SeedResolver for MockResolver {
+    fn resolve(&self, host: &str) -> std::io::Result<Vec<IpAddr>> {
+        assert_eq!(host, "seed.example.com");
+        self.0.lock().unwrap().remove(0)
+    }
+}
+
+#[test]
+#[serial]
+fn peer_dns_seed_rotation() {
+    assert_eq!(
+        parse_seed("seed.example.com", 38333),
+        ("seed.example.com".to_owned(), 38333)
+    );
+    assert_eq!(
+        parse_seed("seed.example.com:18444", 38333),
+        ("seed.example.com".to_owned(), 18444)
+    );
+
+    let first = IpAddr::from([10, 0, 0, 1]);
+    let second = IpAddr::from([10, 0, 0, 2]);
+    let third = IpAddr::from([10, 0, 0, 3]);
+    let answers = Arc::new(Mutex::new(vec![
+        Ok(vec![first, second]),
+        Ok(vec![third]),
+        Ok(vec![]),
+        Err(std::io::Error::other("no network")),
+    ]));
+    let mut peers = PeerSource::new(
+        "45.79.52.207:38333".to_owned(),
+        Some(parse_seed("seed.example.com", 38333)),
+        Box::new(MockResolver(answers.clone())),
+    );
+    // Nodes are tried in turns, the seed is resolved again after the last one
+    assert_eq!(peers.next_address(), "10.0.0.1:38333");
+    assert_eq!(peers.next_address(), "10.0.0.2:38333");
+    assert_eq!(peers.next_address(), "10.0.0.3:38333");
+    // Empty answer and failed resolution fall back to the fixed node
+    assert_eq!(peers.next_address(), "45.79.52.207:38333");
+    assert_eq!(peers.next_address(), "45.79.52.207:38333");
+    assert!(answers.lock().unwrap().is_empty());
+
+    // Without the seed only the fixed node is used
+    let mut peers = PeerSource::new(
+        "127.0.0.1:18444".to_owned(),
+        None,
+        Box::new(MockResolver(Arc::default())),
+    );
+    assert_eq!(peers.next_address(), "127.0.0.1:18444");
+    assert_eq!(peers.next_address(), "127.0.0.1:18444");
+}
+
+#[test]
+#[serial]
+fn peer_outgoing_burst() {
+    init_parser();
+    let network = Network::Mutinynet;
+    let (local, mut remote) = UnixStream::pair().unwrap();
+    const BURST: u64 = 32;
+
+    // Remote node that records the order of pings
+    let peer_handle = thread::spawn(move || {
+        match receive_message(&mut remote, network).unwrap() {
+            NetworkMessage::Version(_) => (),
+            other => panic!("Expected version message, got {other:?}"),
+        }
+        let version = build_version_message(&peer_addr(), "burst peer", PEER_HEIGHT);
+        send_message(&mut remote, network, NetworkMessage::Version(version)).unwrap();
+        send_message(&mut remote, network, NetworkMessage::Verack).unwrap();
+        match receive_message(&mut remote, network).unwrap() {
+            NetworkMessage::Verack => (),
+            other => panic!("Expected verack message, got {other:?}"),
+        }
+        (0..BURST)
+            .map(|_| match receive_message(&mut remote, network).unwrap() {
+                NetworkMessage::Ping(nonce) => nonce,
+                other => panic!("Expected ping message, got {other:?}"),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut bus = Bus::new(BURST as usize * 2);
+    let bus_receiver = bus.add_rx();
+    let (events_sender, events_receiver) = sync_channel(16);
+    let node_handle = thread::spawn(move || {
+        let mut attempt = 0;
+        node_process(
+            local,
+            &peer_addr(),
+            network,
+            0,
+            DEFAULT_MAX_DECODE_FAILURES,
+            events_sender,
+            bus_receiver,
+            &mut attempt,
+        )
+        .0
+    });
+    assert!(matches!(next_event(&events_receiver), Event::Handshaked(_)));
+
+    // Burst with other events in between, the termination comes after all messages
+    for nonce in 0..BURST {
+        bus.broadcast(Event::OutcomingMessage(NetworkMessage::Ping(nonce)));
+        if nonce % 8 == 0 {
+            bus.broadcast(Event::Disconnected);
+        }
+    }
+    bus.broadcast(Event::Termination);
+
+    let nonces = peer_handle.join().unwrap();
+    assert_eq!(nonces, (0..BURST).collect::<Vec<_>>());
+    node_handle.join().unwrap().expect("Normal termination");
+}
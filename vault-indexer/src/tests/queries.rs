@@ -0,0 +1,937 @@
+use crate::db::vault::{
+    rebuild_volume_buckets, scan_action_aggregated, scan_overall_volume, DatabaseVault,
+    DatabaseVaultAdvance, HistoryCursor, SortOrder, VaultRanking, VaultState, VaultTxMeta,
+};
+use crate::db::Error;
+use crate::service::{
+    parse_liquidation_hash, recent_blocks_start, Error as ServiceError, TimeSpan, VaultTxInfo,
+};
+use crate::tests::framework::*;
+use crate::tests::transaction::{
+    BORROW_TX, DEPOSIT_TX, OPEN_VAULT_TX, REPAY_TX, UNKNOWN_VAULT_TX, WITHDRAW_TX,
+};
+use crate::vault::{LiquidationHash, LiquidationHashError, PayloadLocation, VaultAction, VaultTx};
+use crate::Network;
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::{Amount, BlockHash, OutPoint, ScriptBuf, Transaction, Txid};
+use rusqlite::Connection;
+use serial_test::serial;
+
+#[test]
+#[serial]
+fn query_action_history_by_vault() {
+    let mut db = init_db();
+    let first = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    let second = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 2, 1);
+    let span = TimeSpan::Day.time_width();
+
+    let all = db.action_aggregated(VaultAction::Open, span, None).unwrap();
+    assert_eq!(all.len(), 2);
+
+    let only_first = db
+        .action_aggregated(VaultAction::Open, span, Some(first.vault_id))
+        .unwrap();
+    assert_eq!(only_first.len(), 1);
+    assert_eq!(only_first[0].btc_volume, first.btc_volume as u64);
+    assert_eq!(only_first[0].unit_volume, FIXTURE_UNIT_AMOUNT);
+
+    let only_second = db
+        .action_aggregated(VaultAction::Open, span, Some(second.vault_id))
+        .unwrap();
+    assert_eq!(only_second.len(), 1);
+    assert_eq!(only_second[0].btc_volume, second.btc_volume as u64);
+
+    let no_borrows = db
+        .action_aggregated(VaultAction::Borrow, span, Some(first.vault_id))
+        .unwrap();
+    assert!(no_borrows.is_empty());
+}
+
+#[test]
+#[serial]
+fn query_history_roundtrip() {
+    let mut db = init_db();
+    // Open transaction without liquidation fields and with them
+    let first = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    let second = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 2, 3);
+    assert!(second.vault_tx.liquidation_hash.is_some());
+
+    let history = db
+        .range_history_all(None, None, SortOrder::Asc, false)
+        .unwrap();
+    assert_eq!(history, vec![first, second.clone()]);
+
+    let vault_history = db
+        .range_history_vault(second.vault_id, None, None, SortOrder::Asc, false)
+        .unwrap();
+    assert_eq!(vault_history, vec![second]);
+}
+
+#[test]
+#[serial]
+fn query_transactions_columns() {
+    let db = init_db();
+    let mut statement = db
+        .prepare("SELECT name FROM pragma_table_info('transactions') ORDER BY cid")
+        .unwrap();
+    let columns: Vec<String> = statement
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    // Positions that `load_vault_meta` reads and `INSERT INTO transactions VALUES` writes
+    let expected = [
+        "txid",
+        "output",
+        "block_pos",
+        "vault_txid",
+        "version",
+        "action",
+        "balance",
+        "oracle_price",
+        "oracle_timestamp",
+        "liquidation_price",
+        "liquidation_hash",
+        "block_hash",
+        "height",
+        "in_longest",
+        "raw_tx",
+        "btc_custody",
+        "unit_volume",
+        "btc_volume",
+        "prev_tx",
+        "num_inputs",
+        "num_outputs",
+        "payload_in_annex",
+    ];
+    assert_eq!(columns, expected);
+}
+
+#[test]
+#[serial]
+fn query_history_sort_order() {
+    let mut db = init_db();
+    // Insertion order differs from the chain order
+    let later = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 5, 0);
+    let earlier = store_vault_fixture(&mut db, OPEN_VAULT_TX, 4, 2);
+
+    let asc = db
+        .range_history_all(None, None, SortOrder::Asc, false)
+        .unwrap();
+    assert_eq!(asc, vec![earlier.clone(), later.clone()]);
+    let desc = db
+        .range_history_all(None, None, SortOrder::Desc, false)
+        .unwrap();
+    assert_eq!(desc, vec![later, earlier]);
+}
+
+#[test]
+#[serial]
+fn query_raw_tx() {
+    let mut db = init_db();
+    let meta = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+
+    let raw_tx = db.get_raw_tx(meta.vault_tx.txid).unwrap();
+    assert_eq!(raw_tx, Some(hex::decode(OPEN_VAULT_TX).unwrap()));
+    assert_eq!(db.get_raw_tx(Txid::all_zeros()).unwrap(), None);
+}
+
+#[test]
+#[serial]
+fn query_history_include_forks() {
+    let mut db = init_db();
+    let main = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    let stale = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 2, 1);
+    // Pretend that the second block was reorged out
+    db.execute(
+        "UPDATE transactions SET in_longest = 0 WHERE height = 2",
+        [],
+    )
+    .unwrap();
+
+    let canonical = db
+        .range_history_all(None, None, SortOrder::Asc, false)
+        .unwrap();
+    assert_eq!(canonical, vec![main.clone()]);
+
+    let all = db
+        .range_history_all(None, None, SortOrder::Asc, true)
+        .unwrap();
+    let stale = VaultTxMeta {
+        in_longest: false,
+        ..stale
+    };
+    assert_eq!(all, vec![main, stale.clone()]);
+
+    let vault = db
+        .range_history_vault(stale.vault_id, None, None, SortOrder::Asc, false)
+        .unwrap();
+    assert!(vault.is_empty());
+}
+
+#[test]
+#[serial]
+fn query_history_from_height() {
+    let mut db = init_db();
+    let first = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 7, 0);
+    let second = store_vault_fixture(&mut db, OPEN_VAULT_TX, 5, 1);
+
+    let all = db.history_from_height(0, 10).unwrap();
+    assert_eq!(all, vec![second.clone(), first.clone()]);
+    assert_eq!(db.history_from_height(6, 10).unwrap(), vec![first]);
+    assert_eq!(db.history_from_height(0, 1).unwrap(), vec![second]);
+    assert!(db.history_from_height(8, 10).unwrap().is_empty());
+}
+
+#[test]
+#[serial]
+fn store_ambiguous_vault_parents() {
+    let mut db = init_db();
+    let first = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    let second = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 1, 2);
+    let block_hash = Network::Mutinynet.genesis_header().block_hash();
+
+    // Deposit that merges outputs of both vaults
+    let mut raw_tx: Transaction = deserialize(&hex::decode(DEPOSIT_TX).unwrap()).unwrap();
+    raw_tx.input[0].previous_output.txid = first.vault_tx.txid;
+    raw_tx.input[1].previous_output.txid = second.vault_tx.txid;
+    let vault_tx = VaultTx::from_tx(&raw_tx).unwrap();
+    let result = db.store_vault_tx(&vault_tx, block_hash, 1, 2, &raw_tx);
+    assert!(
+        matches!(result, Err(Error::AmbiguousVaultParents(txid)) if txid == vault_tx.txid),
+        "Expected ambiguous parents error, got {result:?}"
+    );
+
+    // The same deposit that spends only the first vault
+    raw_tx.input[1].previous_output.txid = Txid::all_zeros();
+    let vault_tx = VaultTx::from_tx(&raw_tx).unwrap();
+    let meta = db
+        .store_vault_tx(&vault_tx, block_hash, 1, 2, &raw_tx)
+        .unwrap();
+    assert_eq!(meta.vault_id, first.vault_id);
+}
+
+#[test]
+#[serial]
+fn store_reopen_vault_after_reorg() {
+    let mut db = init_db();
+    let open = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+
+    // Deposit changes the running state of the vault
+    let mut deposit_tx: Transaction = deserialize(&hex::decode(DEPOSIT_TX).unwrap()).unwrap();
+    deposit_tx.input[0].previous_output.txid = open.vault_tx.txid;
+    let deposit = VaultTx::from_tx(&deposit_tx).unwrap();
+    db.store_vault_tx(&deposit, open.block_hash, 2, 1, &deposit_tx)
+        .unwrap();
+
+    // Reorg moves the opening transaction to other block
+    let raw_tx: Transaction = deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).unwrap();
+    let new_block = mk_header(HEADER_HEIGHT_2).block_hash();
+    let reopened = db
+        .store_vault_tx(&open.vault_tx, new_block, 3, 2, &raw_tx)
+        .unwrap();
+    assert_eq!(reopened.vault_id, open.vault_id);
+    assert_eq!(reopened.btc_custody, open.btc_custody);
+
+    let (balance, custody, last_tx): (u32, u64, Vec<u8>) = db
+        .query_row(
+            "SELECT balance, custody, last_tx FROM vaults WHERE open_txid = ?1",
+            [open.vault_id.as_byte_array()],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(balance, open.vault_tx.balance);
+    assert_eq!(custody, open.btc_custody);
+    assert_eq!(last_tx, open.vault_tx.txid.as_byte_array().to_vec());
+
+    let history = db
+        .range_history_vault(open.vault_id, None, None, SortOrder::Asc, false)
+        .unwrap();
+    let stored_open = history
+        .iter()
+        .find(|meta| meta.vault_tx.txid == open.vault_tx.txid)
+        .unwrap();
+    assert_eq!(stored_open.block_hash, new_block);
+    assert_eq!(stored_open.height, 2);
+}
+
+#[test]
+#[serial]
+fn store_recompute_vault() {
+    let mut db = init_db();
+    // Chain deposit and withdraw to the opened vault
+    let open = store_vault_chain(
+        &mut db,
+        &[(1, OPEN_VAULT_TX), (2, DEPOSIT_TX), (3, WITHDRAW_TX)],
+    )
+    .remove(0);
+    let history = |db: &Connection| {
+        db.range_history_vault(open.vault_id, None, None, SortOrder::Asc, false)
+            .unwrap()
+    };
+    let vault_state = |db: &Connection| -> (u64, Vec<u8>) {
+        db.query_row(
+            "SELECT custody, last_tx FROM vaults WHERE open_txid = ?1",
+            [open.vault_id.as_byte_array()],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap()
+    };
+    let expected = history(&db);
+    let expected_state = vault_state(&db);
+    assert_eq!(expected.len(), 3);
+
+    // Corrupt the middle transaction and everything that depends on it
+    db.execute_batch(
+        r#"
+        UPDATE transactions SET btc_custody = 0, btc_volume = 0, unit_volume = 42, prev_tx = zeroblob(32)
+            WHERE height = 2;
+        UPDATE transactions SET btc_volume = 0, prev_tx = zeroblob(32) WHERE height = 3;
+        UPDATE vaults SET custody = 0;
+        "#,
+    )
+    .unwrap();
+    assert_ne!(history(&db), expected);
+
+    assert_eq!(db.recompute_vault(open.vault_id).unwrap(), 3);
+    assert_eq!(history(&db), expected);
+    assert_eq!(vault_state(&db), expected_state);
+
+    assert!(matches!(
+        db.recompute_vault(Txid::all_zeros()),
+        Err(Error::UnknownVaultId(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn store_vault_closed_lifecycle() {
+    let mut db = init_db();
+    let open = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    let vault_id = open.vault_id;
+    let state = db.load_vault_state(vault_id).unwrap().unwrap();
+    assert_eq!(state.custody, open.btc_custody);
+    assert!(!state.is_closed());
+
+    let mut parent = open.vault_tx.txid;
+    let mut chain = |db: &mut Connection, tx_hex, height, close: bool| {
+        let mut raw_tx: Transaction = deserialize(&hex::decode(tx_hex).unwrap()).unwrap();
+        raw_tx.input[0].previous_output.txid = parent;
+        if close {
+            // Withdraw all BTC and zero the balance in the payload
+            raw_tx.output[0].value = Amount::ZERO;
+            let payload = raw_tx.output.last_mut().unwrap();
+            let mut script = payload.script_pubkey.to_bytes();
+            let len = script.len();
+            script[len - 4..].fill(0);
+            payload.script_pubkey = ScriptBuf::from_bytes(script);
+        }
+        let meta = store_raw_vault_fixture(db, &raw_tx, height, 1);
+        assert_eq!(meta.vault_id, vault_id);
+        parent = meta.vault_tx.txid;
+        db.load_vault_state(vault_id).unwrap().unwrap()
+    };
+
+    let borrowed = chain(&mut db, BORROW_TX, 2, false);
+    assert!(borrowed.balance > 0);
+    assert_eq!(borrowed.closed_at_height, None);
+
+    // Debt is repaid, but BTC is still locked
+    let repaid = chain(&mut db, REPAY_TX, 3, false);
+    assert_eq!(repaid.balance, 0);
+    assert!(repaid.custody > 0);
+    assert_eq!(repaid.closed_at_height, None);
+
+    let withdrawn = chain(&mut db, WITHDRAW_TX, 4, true);
+    assert_eq!(withdrawn.balance, 0);
+    assert_eq!(withdrawn.custody, 0);
+    assert_eq!(withdrawn.closed_at_height, Some(4));
+    assert!(withdrawn.is_closed());
+
+    // New deposit reactivates the vault
+    let reactivated = chain(&mut db, DEPOSIT_TX, 5, false);
+    assert_eq!(reactivated.closed_at_height, None);
+}
+
+#[test]
+#[serial]
+fn query_vault_custody_outpoint() {
+    let mut db = init_db();
+    let open = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    let vault_id = open.vault_id;
+    let stored_output = |db: &Connection, txid: Txid, vout: u32| {
+        let raw_tx = db.get_raw_tx(txid).unwrap().expect("stored tx");
+        let tx: Transaction = deserialize(&raw_tx).unwrap();
+        tx.output[vout as usize].clone()
+    };
+
+    // Opening transaction keeps the custody in the third output
+    let custody = db
+        .load_vault_custody(vault_id)
+        .unwrap()
+        .expect("active vault");
+    assert_eq!(custody.outpoint, OutPoint::new(vault_id, 2));
+    let output = stored_output(&db, vault_id, 2);
+    assert_eq!(custody.script_pubkey, output.script_pubkey);
+    assert_eq!(custody.value, output.value.to_sat());
+    assert_eq!(custody.value, open.btc_custody);
+
+    // Following transactions move the custody to the first output
+    let mut raw_tx: Transaction = deserialize(&hex::decode(BORROW_TX).unwrap()).unwrap();
+    raw_tx.input[0].previous_output.txid = vault_id;
+    let borrow = store_raw_vault_fixture(&mut db, &raw_tx, 2, 1);
+    let custody = db
+        .load_vault_custody(vault_id)
+        .unwrap()
+        .expect("active vault");
+    assert_eq!(custody.outpoint, OutPoint::new(borrow.vault_tx.txid, 0));
+    assert_eq!(custody.script_pubkey, raw_tx.output[0].script_pubkey);
+    assert_eq!(
+        stored_output(&db, borrow.vault_tx.txid, 0).script_pubkey,
+        custody.script_pubkey
+    );
+    assert_eq!(custody.value, borrow.btc_custody);
+
+    assert_eq!(db.load_vault_custody(Txid::all_zeros()).unwrap(), None);
+}
+
+#[test]
+#[serial]
+fn query_top_vaults() {
+    let mut db = init_db();
+    let first = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    let second = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 1, 2);
+
+    let ranked = |by, limit| -> Vec<Txid> {
+        db.top_vaults(by, limit)
+            .unwrap()
+            .into_iter()
+            .map(|state| state.vault_id)
+            .collect()
+    };
+    let mut by_custody = vec![first.clone(), second.clone()];
+    by_custody.sort_by_key(|meta| std::cmp::Reverse(meta.btc_custody));
+    let by_custody: Vec<Txid> = by_custody.iter().map(|meta| meta.vault_id).collect();
+    assert_eq!(ranked(VaultRanking::Custody, 10), by_custody);
+    assert_eq!(ranked(VaultRanking::Custody, 1), by_custody[0..1]);
+
+    let mut by_balance = vec![first, second];
+    by_balance.sort_by_key(|meta| std::cmp::Reverse(meta.vault_tx.balance));
+    let by_balance: Vec<Txid> = by_balance.iter().map(|meta| meta.vault_id).collect();
+    assert_eq!(ranked(VaultRanking::Balance, 10), by_balance);
+    assert!(ranked(VaultRanking::Balance, 0).is_empty());
+}
+
+#[test]
+#[serial]
+fn query_recent_history() {
+    let mut db = init_db();
+    let old = store_vault_fixture(&mut db, OPEN_VAULT_TX, 3, 1);
+    let new = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 7, 1);
+    let tip = 10;
+
+    let recent = |blocks| {
+        db.history_since_height(recent_blocks_start(tip, blocks), SortOrder::Asc, 10)
+            .unwrap()
+    };
+    assert!(recent(0).is_empty());
+    assert!(recent(3).is_empty());
+    assert_eq!(recent(4), vec![new.clone()]);
+    assert_eq!(recent(8), vec![old.clone(), new.clone()]);
+    assert_eq!(recent(u32::MAX), vec![old.clone(), new.clone()]);
+    // The limit keeps the first transactions in the requested order
+    assert_eq!(
+        db.history_since_height(0, SortOrder::Asc, 1).unwrap(),
+        vec![old.clone()]
+    );
+    assert_eq!(
+        db.history_since_height(0, SortOrder::Desc, 1).unwrap(),
+        vec![new.clone()]
+    );
+
+    // Stale transactions are not included
+    db.execute(
+        "UPDATE transactions SET in_longest = 0 WHERE height = 7",
+        [],
+    )
+    .unwrap();
+    assert_eq!(
+        db.history_since_height(0, SortOrder::Desc, 10).unwrap(),
+        vec![old]
+    );
+}
+
+#[test]
+#[serial]
+fn query_by_liquidation_hash() {
+    let mut db = init_db();
+    store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    let with_hash = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 2, 3);
+    let hash = with_hash
+        .vault_tx
+        .liquidation_hash
+        .expect("fixture with hash");
+
+    let parsed = parse_liquidation_hash("d9ceb8f426ae2006a5224f263433e035430cfbad").unwrap();
+    assert_eq!(parsed, hash);
+    assert_eq!(
+        db.find_by_liquidation_hash(parsed).unwrap(),
+        vec![with_hash.vault_tx.txid]
+    );
+    assert_eq!(
+        db.find_vault_by_tx(with_hash.vault_tx.txid).unwrap(),
+        Some(with_hash.vault_id)
+    );
+    assert!(db
+        .find_by_liquidation_hash(LiquidationHash::new([0; 20]))
+        .unwrap()
+        .is_empty());
+
+    for invalid in [
+        "d9ceb8f426ae2006a5224f263433e035430cfb",
+        "d9ceb8f426ae2006a5224f263433e035430cfbad00",
+        "zz",
+    ] {
+        let err = parse_liquidation_hash(invalid).unwrap_err();
+        assert!(matches!(err, ServiceError::ValidateLiquidationHash(_)));
+        assert_eq!(err.code(), Some("INVALID_LIQUIDATION_HASH"));
+    }
+}
+
+#[test]
+#[serial]
+fn query_action_breakdown() {
+    let mut db = init_db();
+    let open = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    let mut borrow_tx: Transaction = deserialize(&hex::decode(BORROW_TX).unwrap()).unwrap();
+    borrow_tx.input[0].previous_output.txid = open.vault_tx.txid;
+    let borrow = store_raw_vault_fixture(&mut db, &borrow_tx, 2, 1);
+    let other = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 3, 1);
+
+    // All transactions fall into the single bucket
+    let span = u32::MAX;
+    let breakdown = db.action_breakdown(span, None, None).unwrap();
+    assert_eq!(breakdown.len(), 1);
+    assert_eq!(breakdown[0].timestamp_start, 0);
+    let actions: Vec<VaultAction> = breakdown[0].actions.iter().map(|v| v.action).collect();
+    assert_eq!(actions, vec![VaultAction::Borrow, VaultAction::Open]);
+
+    // Volumes match the per action aggregation
+    for volume in breakdown[0].actions.iter() {
+        let agg = db.action_aggregated(volume.action, span, None).unwrap();
+        assert_eq!(agg.len(), 1);
+        assert_eq!(volume.unit_volume, agg[0].unit_volume);
+        assert_eq!(volume.btc_volume, agg[0].btc_volume);
+    }
+    let opens = &breakdown[0].actions[1];
+    assert_eq!(
+        opens.btc_volume,
+        (open.btc_volume + other.btc_volume) as u64
+    );
+    assert_eq!(
+        breakdown[0].actions[0].btc_volume,
+        borrow.btc_volume.unsigned_abs()
+    );
+
+    // The timestamp range excludes the transactions
+    let start = open
+        .vault_tx
+        .oracle_timestamp
+        .max(borrow.vault_tx.oracle_timestamp)
+        .max(other.vault_tx.oracle_timestamp)
+        + 1;
+    assert!(db
+        .action_breakdown(span, Some(start), None)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+#[serial]
+fn query_tx_shape() {
+    let mut db = init_db();
+    let raw_tx: Transaction = deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).unwrap();
+    let meta = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    assert_eq!(meta.num_inputs, Some(raw_tx.input.len() as u32));
+    assert_eq!(meta.num_outputs, Some(raw_tx.output.len() as u32));
+    let history = db
+        .range_history_all(None, None, SortOrder::Asc, false)
+        .unwrap();
+    assert_eq!(history, vec![meta.clone()]);
+    let info = VaultTxInfo::from_db_metainfo(Network::Mutinynet, &meta);
+    assert_eq!(info.num_inputs, meta.num_inputs);
+    assert_eq!(info.num_outputs, meta.num_outputs);
+
+    // Transactions stored by older versions have no counts
+    db.execute(
+        "UPDATE transactions SET num_inputs = NULL, num_outputs = NULL",
+        [],
+    )
+    .unwrap();
+    let history = db
+        .range_history_all(None, None, SortOrder::Asc, false)
+        .unwrap();
+    assert_eq!(history[0].num_inputs, None);
+    assert_eq!(history[0].num_outputs, None);
+    let info = serde_json::to_value(VaultTxInfo::from_db_metainfo(
+        Network::Mutinynet,
+        &history[0],
+    ))
+    .unwrap();
+    assert!(info["num_inputs"].is_null());
+}
+
+#[test]
+#[serial]
+fn query_vault_state_roundtrip() {
+    let mut db = init_db();
+    let mut statement = db
+        .prepare("SELECT name FROM pragma_table_info('vaults') ORDER BY cid")
+        .unwrap();
+    let columns: Vec<String> = statement
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    drop(statement);
+    // Positions that `load_vault_state_row` reads, `in_longest` only filters the queries
+    let expected = [
+        "open_txid",
+        "output",
+        "balance",
+        "oracle_price",
+        "oracle_timestamp",
+        "liquidation_price",
+        "liquidation_hash",
+        "custody",
+        "last_tx",
+        "closed_at_height",
+        "payload_in_annex",
+        "in_longest",
+    ];
+    assert_eq!(columns, expected);
+
+    // Vault with liquidation fields
+    let meta = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 2, 3);
+    let tx = &meta.vault_tx;
+    assert!(tx.liquidation_price.is_some());
+    let expected = VaultState {
+        vault_id: meta.vault_id,
+        payload: tx.payload,
+        balance: tx.balance,
+        oracle_price: tx.oracle_price,
+        oracle_timestamp: tx.oracle_timestamp,
+        liquidation_price: tx.liquidation_price,
+        liquidation_hash: tx.liquidation_hash,
+        custody: meta.btc_custody,
+        last_tx: tx.txid,
+        closed_at_height: None,
+    };
+    assert_eq!(db.load_vault_state(meta.vault_id).unwrap(), Some(expected));
+    assert_eq!(db.load_vault_state(Txid::all_zeros()).unwrap(), None);
+}
+
+#[test]
+#[serial]
+fn query_annex_payload_roundtrip() {
+    let mut db = init_db();
+    let open_tx: Transaction = deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).unwrap();
+    let annexed = move_payload_to_annex(&open_tx);
+    let meta = store_raw_vault_fixture(&mut db, &annexed, 2, 1);
+    assert_eq!(meta.vault_tx.payload, PayloadLocation::Annex);
+
+    let history = db.history_from_height(0, 10).unwrap();
+    assert_eq!(history, vec![meta.clone()]);
+    let state = db.load_vault_state(meta.vault_id).unwrap().unwrap();
+    assert_eq!(state.payload, PayloadLocation::Annex);
+    let info = VaultTxInfo::new(
+        Network::Mutinynet,
+        meta.vault_id,
+        &meta.vault_tx,
+        meta.block_hash,
+        meta.height,
+        meta.in_longest,
+        meta.btc_custody,
+        meta.unit_volume,
+        meta.btc_volume,
+        meta.prev_tx,
+    );
+    assert_eq!(info.op_return_output, None);
+    // The custody is found in the same output as with the OP_RETURN payload
+    let custody = db.load_vault_custody(meta.vault_id).unwrap().unwrap();
+    assert_eq!(custody.outpoint, OutPoint::new(meta.vault_tx.txid, 2));
+    assert_eq!(custody.value, open_tx.output[2].value.to_sat());
+}
+
+#[test]
+#[serial]
+fn query_vault_summary() {
+    let mut db = init_db();
+    let metas = store_vault_chain(
+        &mut db,
+        &[
+            (1, OPEN_VAULT_TX),
+            (2, BORROW_TX),
+            (3, REPAY_TX),
+            (4, DEPOSIT_TX),
+            (5, WITHDRAW_TX),
+        ],
+    );
+    let vault_id = metas[0].vault_id;
+    assert!(metas.iter().all(|meta| meta.vault_id == vault_id));
+    // Every fixture moves UNIT and BTC in the direction of its action
+    let unit_volumes: Vec<i32> = metas.iter().map(|meta| meta.unit_volume).collect();
+    assert!(unit_volumes[1] > 0 && unit_volumes[2] < 0);
+
+    let summary = db.vault_summary(vault_id).unwrap();
+    let unit_borrowed = (unit_volumes[0] + unit_volumes[1]) as u64;
+    let unit_repaid = unit_volumes[2].unsigned_abs() as u64;
+    let btc_deposited: i64 = metas.iter().map(|meta| meta.btc_volume.max(0)).sum::<i64>();
+    let btc_withdrawn: i64 = metas
+        .iter()
+        .map(|meta| (-meta.btc_volume).max(0))
+        .sum::<i64>();
+    assert_eq!(summary.tx_count, 5);
+    assert_eq!(summary.unit_borrowed, unit_borrowed);
+    assert_eq!(summary.unit_repaid, unit_repaid);
+    assert_eq!(
+        summary.unit_net,
+        unit_volumes.iter().map(|v| *v as i64).sum::<i64>()
+    );
+    assert_eq!(summary.btc_deposited, btc_deposited as u64);
+    assert_eq!(summary.btc_withdrawn, btc_withdrawn as u64);
+    assert_eq!(summary.btc_net, metas.last().unwrap().btc_custody as i64);
+
+    // Transactions of forks are not counted
+    db.set_vault_txs_in_longest(&[metas[4].block_hash], false)
+        .unwrap();
+    let summary = db.vault_summary(vault_id).unwrap();
+    assert_eq!(summary.tx_count, 0);
+}
+
+#[test]
+#[serial]
+fn query_vault_price_history() {
+    let mut db = init_db();
+    let metas = store_vault_chain(
+        &mut db,
+        &[
+            (1, OPEN_VAULT_TX),
+            (2, BORROW_TX),
+            (3, REPAY_TX),
+            (4, DEPOSIT_TX),
+        ],
+    );
+    let vault_id = metas[0].vault_id;
+    // Other vaults don't leak into the history
+    store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 5, 1);
+
+    let mut expected: Vec<(u32, u32)> = metas
+        .iter()
+        .map(|meta| (meta.vault_tx.oracle_timestamp, meta.vault_tx.oracle_price))
+        .collect();
+    expected.sort();
+    let history: Vec<(u32, u32)> = db
+        .vault_price_history(vault_id)
+        .unwrap()
+        .into_iter()
+        .map(|point| (point.oracle_timestamp, point.oracle_price))
+        .collect();
+    assert_eq!(history, expected);
+
+    // Reorged out transaction is excluded
+    db.execute(
+        "UPDATE transactions SET in_longest = 0 WHERE height = 3",
+        [],
+    )
+    .unwrap();
+    let repay = &metas[2].vault_tx;
+    expected.retain(|point| *point != (repay.oracle_timestamp, repay.oracle_price));
+    let history = db.vault_price_history(vault_id).unwrap();
+    assert_eq!(history.len(), 3);
+    assert_eq!(
+        history
+            .iter()
+            .map(|point| (point.oracle_timestamp, point.oracle_price))
+            .collect::<Vec<_>>(),
+        expected
+    );
+}
+
+#[test]
+#[serial]
+fn query_history_pages() {
+    let mut db = init_db();
+    // Transactions at the same height and position, only the txid orders them
+    let first = store_vault_fixture(&mut db, OPEN_VAULT_TX, 1, 1);
+    let second = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 1, 1);
+    let (low, high) = if first.vault_tx.txid.to_byte_array() < second.vault_tx.txid.to_byte_array()
+    {
+        (first, second)
+    } else {
+        (second, first)
+    };
+
+    for (sort, expected) in [
+        (SortOrder::Asc, vec![low.clone(), high.clone()]),
+        (SortOrder::Desc, vec![high.clone(), low.clone()]),
+    ] {
+        let all = db
+            .range_history_all_page(None, None, sort, false, None, 10)
+            .unwrap();
+        assert_eq!(all, expected, "{sort:?}");
+        let mut paged = vec![];
+        let mut cursor = None;
+        loop {
+            let page = db
+                .range_history_all_page(None, None, sort, false, cursor, 1)
+                .unwrap();
+            assert!(page.len() <= 1);
+            let Some(last) = page.last() else {
+                break;
+            };
+            cursor = Some(HistoryCursor::from(last));
+            paged.extend(page);
+        }
+        assert_eq!(paged, expected, "{sort:?}");
+    }
+}
+
+#[test]
+#[serial]
+fn query_liquidation_hash_roundtrip() {
+    let hex = "d9ceb8f426ae2006a5224f263433e035430cfbad";
+    let mut db = init_db();
+    // Parsed from the transaction and stored
+    let stored = store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 1, 0);
+    let hash = stored.vault_tx.liquidation_hash.expect("fixture with hash");
+    assert_eq!(hash.to_string(), hex);
+    assert_eq!(hex.parse::<LiquidationHash>().unwrap(), hash);
+
+    // Loaded back from both transactions and vault state tables
+    let loaded = db
+        .range_history_all(None, None, SortOrder::Asc, false)
+        .unwrap();
+    assert_eq!(loaded[0].vault_tx.liquidation_hash, Some(hash));
+    let state = db.load_vault_state(stored.vault_id).unwrap().unwrap();
+    assert_eq!(state.liquidation_hash, Some(hash));
+
+    // Serialized as hex in the API
+    let info = VaultTxInfo::from_db_metainfo(Network::Mutinynet, &loaded[0]);
+    let json = serde_json::to_value(&info).unwrap();
+    assert_eq!(json["liquidation_hash"], hex);
+
+    assert_eq!(
+        LiquidationHash::from_slice(&[0; 19]),
+        Err(LiquidationHashError::InvalidLength(19))
+    );
+    assert!(matches!(
+        "zz".parse::<LiquidationHash>(),
+        Err(LiquidationHashError::Hex(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn query_volume_buckets_consistent() {
+    let mut db = init_db();
+    let check = |db: &Connection| {
+        for action in [
+            VaultAction::Open,
+            VaultAction::Deposit,
+            VaultAction::Withdraw,
+            VaultAction::Borrow,
+            VaultAction::Repay,
+        ] {
+            for span in [
+                TimeSpan::Hour,
+                TimeSpan::Day,
+                TimeSpan::Week,
+                TimeSpan::Month,
+            ] {
+                let span = span.time_width();
+                assert_eq!(
+                    db.action_aggregated(action, span, None).unwrap(),
+                    scan_action_aggregated(db, action, span, None).unwrap(),
+                    "{action} per {span} seconds"
+                );
+            }
+        }
+        assert_eq!(
+            db.overall_volume().unwrap(),
+            scan_overall_volume(db).unwrap()
+        );
+    };
+    check(&db);
+    assert_eq!(db.overall_volume().unwrap(), (0, 0));
+
+    store_vault_fixture(&mut db, UNKNOWN_VAULT_TX, 1, 2);
+    let chained = store_vault_chain(
+        &mut db,
+        &[
+            (1, OPEN_VAULT_TX),
+            (2, BORROW_TX),
+            (3, DEPOSIT_TX),
+            (4, WITHDRAW_TX),
+        ],
+    );
+    let open = &chained[0];
+    check(&db);
+    assert_ne!(db.overall_volume().unwrap(), (0, 0));
+
+    // Reorg disconnects the last blocks and the transaction is mined again in other block
+    let withdraw = &chained[3];
+    let raw_withdraw: Transaction =
+        deserialize(&db.get_raw_tx(withdraw.vault_tx.txid).unwrap().unwrap()).unwrap();
+    assert!(
+        db.set_vault_txs_in_longest(&[withdraw.block_hash], false)
+            .unwrap()
+            > 0
+    );
+    check(&db);
+    db.store_vault_tx(
+        &withdraw.vault_tx,
+        BlockHash::all_zeros(),
+        3,
+        5,
+        &raw_withdraw,
+    )
+    .unwrap();
+    check(&db);
+
+    // Volumes and timestamps rewritten in place
+    db.execute(
+        "UPDATE transactions SET btc_volume = 7, unit_volume = -3, oracle_timestamp = oracle_timestamp + 86400 WHERE height = 2",
+        [],
+    )
+    .unwrap();
+    check(&db);
+    db.recompute_vault(open.vault_id).unwrap();
+    check(&db);
+
+    // Buckets maintained by the triggers are the same as rebuilt from scratch
+    let buckets = |db: &Connection| -> Vec<(String, i64, i64, i64, i64)> {
+        db.prepare("SELECT * FROM volume_buckets ORDER BY action, bucket")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    };
+    let maintained = buckets(&db);
+    rebuild_volume_buckets(&db).unwrap();
+    assert_eq!(buckets(&db), maintained);
+
+    db.drop_vaults().unwrap();
+    check(&db);
+    assert!(buckets(&db).is_empty());
+}
@@ -0,0 +1,507 @@
+use crate::db::vault::DatabaseRune;
+use crate::db::HeaderRecord;
+use crate::service::{
+    check_range, encode_response, start_websocket_server, wants_compression, Error, EventFilter,
+    HeaderInfo, HeightOrHash, ParsedTxInfo, Request, Response, VaultTxInfo, BLOCK_NOT_FOUND,
+    COMPRESS_THRESHOLD, HEADER_NOT_FOUND, INVALID_RANGE, INVALID_TXID, TX_NOT_FOUND,
+    VAULT_NOT_FOUND,
+};
+use crate::tests::runes::BORROW_TX_PHASE1;
+use crate::tests::transaction::{BORROW_TX, OPEN_VAULT_TX, UNKNOWN_VAULT_TX};
+use crate::vault::VaultTx;
+use crate::Network;
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::{BlockHash, Transaction};
+use flate2::read::GzDecoder;
+use serial_test::serial;
+use std::io::Read;
+use std::sync::Arc;
+use websocket::{ClientBuilder, Message, OwnedMessage};
+
+use super::{init_offline_indexer, init_parser, store_vault_fixture};
+
+#[test]
+#[serial]
+fn service_parse_tx() {
+    init_parser();
+
+    let parsed = ParsedTxInfo::parse(OPEN_VAULT_TX).unwrap();
+    assert_eq!(
+        parsed.txid,
+        "226b43bad347e7efb1b3b74f42da790f6b9edd2122532be9f801c74bac6d353b"
+    );
+    let vault = parsed.vault.expect("parsed vault payload");
+    assert_eq!(vault.action, "open");
+    assert_eq!(vault.balance, 1392952);
+    assert!(parsed.vault_error.is_none());
+    assert!(parsed.unit_amount.is_none());
+    assert!(parsed.unit_error.is_some());
+
+    let parsed = ParsedTxInfo::parse(BORROW_TX_PHASE1).unwrap();
+    assert!(parsed.vault.is_none());
+    assert!(parsed.vault_error.is_some());
+    assert!(parsed.unit_amount.is_some());
+    assert!(parsed.unit_error.is_none());
+
+    assert!(matches!(ParsedTxInfo::parse("zz"), Err(Error::RawTxHex(_))));
+    assert!(matches!(
+        ParsedTxInfo::parse("0200"),
+        Err(Error::RawTxDecode(_))
+    ));
+}
+
+#[test]
+#[serial]
+fn service_decimal_amounts() {
+    init_parser();
+
+    let raw_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    let vault_tx = VaultTx::from_tx(&raw_tx).unwrap();
+    let info = VaultTxInfo::new(
+        Network::Mutinynet,
+        vault_tx.txid,
+        &vault_tx,
+        Network::Mutinynet.genesis_header().block_hash(),
+        1,
+        true,
+        0,
+        -2988,
+        0,
+        vault_tx.txid,
+    );
+    assert_eq!(info.balance, 1392952);
+    assert_eq!(info.balance_decimal, 13929.52);
+    assert_eq!(info.oracle_price, 99094);
+    assert_eq!(info.oracle_price_decimal, 99094.0);
+    assert_eq!(info.liquidation_price_decimal, None);
+    assert_eq!(info.unit_volume_decimal, -29.88);
+}
+
+#[test]
+#[serial]
+fn service_ephemeral_port() {
+    let indexer = Arc::new(init_offline_indexer());
+    let addr = start_websocket_server(indexer, "127.0.0.1:0").unwrap();
+    assert_ne!(addr.port(), 0);
+
+    let mut client = ClientBuilder::new(&format!("ws://{addr}"))
+        .unwrap()
+        .connect_insecure()
+        .unwrap();
+    client
+        .send_message(&Message::text(
+            r#"{"method": "block_hash_at", "height": 0}"#,
+        ))
+        .unwrap();
+    match client.recv_message().unwrap() {
+        OwnedMessage::Text(text) => {
+            let genesis = Network::Mutinynet.genesis_header().block_hash();
+            assert!(text.contains(&genesis.to_string()), "{text}");
+        }
+        other => panic!("Expected text message, got {other:?}"),
+    }
+}
+
+#[test]
+#[serial]
+fn service_vault_not_found() {
+    let indexer = Arc::new(init_offline_indexer());
+    let meta = {
+        let db = indexer.get_database();
+        let mut conn = db.lock().unwrap();
+        store_vault_fixture(&mut conn, OPEN_VAULT_TX, 1, 0)
+    };
+    let addr = start_websocket_server(indexer, "127.0.0.1:0").unwrap();
+    let mut client = ClientBuilder::new(&format!("ws://{addr}"))
+        .unwrap()
+        .connect_insecure()
+        .unwrap();
+    let mut request = |vault_id: String| {
+        let request = format!(
+            r#"{{"method": "vault_history_tx", "vault_open_txid": "{vault_id}", "timestamp_end": 1}}"#
+        );
+        client.send_message(&Message::text(request)).unwrap();
+        match client.recv_message().unwrap() {
+            OwnedMessage::Text(text) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            other => panic!("Expected text message, got {other:?}"),
+        }
+    };
+
+    // Known vault without transactions in the range
+    let response = request(meta.vault_tx.txid.to_string());
+    assert_eq!(response["VaultHistory"], serde_json::json!([]));
+
+    // Unknown vault id
+    let response = request("00".repeat(32));
+    assert_eq!(response["code"], VAULT_NOT_FOUND, "{response}");
+    assert!(response["error"].is_string());
+}
+
+#[test]
+#[serial]
+fn service_replay_vault_only() {
+    let indexer = Arc::new(init_offline_indexer());
+    let meta = {
+        let db = indexer.get_database();
+        let mut conn = db.lock().unwrap();
+        let meta = store_vault_fixture(&mut conn, OPEN_VAULT_TX, 1, 0);
+        let unit_tx: Transaction = deserialize(&hex::decode(BORROW_TX_PHASE1).unwrap()).unwrap();
+        let block_hash = Network::Mutinynet.genesis_header().block_hash();
+        conn.store_unit_tx(&unit_tx, 1000, block_hash, 2).unwrap();
+        meta
+    };
+    let addr = start_websocket_server(indexer, "127.0.0.1:0").unwrap();
+    let mut client = ClientBuilder::new(&format!("ws://{addr}"))
+        .unwrap()
+        .connect_insecure()
+        .unwrap();
+    client
+        .send_message(&Message::text(r#"{"method": "replay_from", "height": 0}"#))
+        .unwrap();
+    let mut next = || match client.recv_message().unwrap() {
+        OwnedMessage::Text(text) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+        other => panic!("Expected text message, got {other:?}"),
+    };
+
+    // The UNIT transaction above the height isn't replayed, live events don't carry them either
+    let replayed = next();
+    assert_eq!(
+        replayed["NewTranscation"]["txid"],
+        meta.vault_tx.txid.to_string()
+    );
+    let finished = next();
+    assert_eq!(finished["ReplayFinished"]["count"], 1, "{finished}");
+    assert_eq!(finished["ReplayFinished"]["truncated"], false);
+}
+
+#[test]
+#[serial]
+fn service_not_found_codes() {
+    let indexer = Arc::new(init_offline_indexer());
+    let addr = start_websocket_server(indexer, "127.0.0.1:0").unwrap();
+    let mut client = ClientBuilder::new(&format!("ws://{addr}"))
+        .unwrap()
+        .connect_insecure()
+        .unwrap();
+    let mut request = |request: &str| {
+        client.send_message(&Message::text(request)).unwrap();
+        match client.recv_message().unwrap() {
+            OwnedMessage::Text(text) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            other => panic!("Expected text message, got {other:?}"),
+        }
+    };
+    let unknown = "00".repeat(32);
+
+    let response = request(&format!(r#"{{"method": "raw_tx", "txid": "{unknown}"}}"#));
+    assert_eq!(response["code"], TX_NOT_FOUND, "{response}");
+    let response = request(r#"{"method": "raw_tx", "txid": "not a txid"}"#);
+    assert_eq!(response["code"], INVALID_TXID, "{response}");
+    let response = request(&format!(r#"{{"method": "block", "hash": "{unknown}"}}"#));
+    assert_eq!(response["code"], BLOCK_NOT_FOUND, "{response}");
+    let response = request(r#"{"method": "header", "height_or_hash": 1}"#);
+    assert_eq!(response["code"], HEADER_NOT_FOUND, "{response}");
+    let response = request(&format!(
+        r#"{{"method": "header", "height_or_hash": "{unknown}"}}"#
+    ));
+    assert_eq!(response["code"], HEADER_NOT_FOUND, "{response}");
+
+    let response = request(
+        r#"{"method": "action_history", "action": "Open", "vault_open_txid": "not a txid"}"#,
+    );
+    assert_eq!(response["code"], INVALID_TXID, "{response}");
+}
+
+#[test]
+#[serial]
+fn service_inverted_range() {
+    let indexer = Arc::new(init_offline_indexer());
+    let addr = start_websocket_server(indexer, "127.0.0.1:0").unwrap();
+    let mut client = ClientBuilder::new(&format!("ws://{addr}"))
+        .unwrap()
+        .connect_insecure()
+        .unwrap();
+    let mut request = |request: &str| {
+        client.send_message(&Message::text(request)).unwrap();
+        match client.recv_message().unwrap() {
+            OwnedMessage::Text(text) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            other => panic!("Expected text message, got {other:?}"),
+        }
+    };
+
+    for method in [
+        "range_history_all",
+        "stream_history_all",
+        "action_breakdown",
+    ] {
+        let response = request(&format!(
+            r#"{{"method": "{method}", "timestamp_start": 10, "timestamp_end": 5}}"#
+        ));
+        assert_eq!(response["code"], INVALID_RANGE, "{method}: {response}");
+        assert!(response["error"].is_string());
+    }
+    let response = request(&format!(
+        r#"{{"method": "vault_history_tx", "vault_open_txid": "{}", "timestamp_start": 10, "timestamp_end": 5}}"#,
+        "00".repeat(32)
+    ));
+    assert_eq!(response["code"], INVALID_RANGE, "{response}");
+
+    // Equal bounds and open ranges are fine
+    let response =
+        request(r#"{"method": "range_history_all", "timestamp_start": 5, "timestamp_end": 5}"#);
+    assert_eq!(response["AllHistory"], serde_json::json!([]));
+    let response = request(r#"{"method": "range_history_all", "timestamp_start": 10}"#);
+    assert_eq!(response["AllHistory"], serde_json::json!([]));
+
+    assert!(matches!(
+        check_range(Some(2), Some(1)),
+        Err(Error::InvalidRange { start: 2, end: 1 })
+    ));
+    assert!(check_range(Some(1), None).is_ok());
+}
+
+#[test]
+#[serial]
+fn service_compressed_response() {
+    assert!(wants_compression("/?compress=gzip"));
+    assert!(wants_compression("/?foo=bar&compress=gzip"));
+    assert!(!wants_compression("/"));
+    assert!(!wants_compression("/?compress=zstd"));
+
+    let json = format!(r#"{{"AllHistory":"{}"}}"#, "a".repeat(COMPRESS_THRESHOLD));
+    match OwnedMessage::from(encode_response(json.clone(), true).unwrap()) {
+        OwnedMessage::Binary(bytes) => {
+            assert!(bytes.len() < json.len());
+            let mut decoded = String::new();
+            GzDecoder::new(bytes.as_slice())
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, json);
+        }
+        other => panic!("Expected binary message, got {other:?}"),
+    }
+
+    // Small responses and clients without opt in get plain text
+    assert_eq!(
+        OwnedMessage::from(encode_response(json.clone(), false).unwrap()),
+        OwnedMessage::Text(json)
+    );
+    let small = r#"{"BlockHash":"00"}"#.to_owned();
+    assert_eq!(
+        OwnedMessage::from(encode_response(small.clone(), true).unwrap()),
+        OwnedMessage::Text(small)
+    );
+}
+
+#[test]
+#[serial]
+fn service_api_schema() {
+    let indexer = init_offline_indexer();
+    let schema = indexer.api_schema();
+
+    let raw_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    let vault_tx = VaultTx::from_tx(&raw_tx).unwrap();
+    let info = VaultTxInfo::new(
+        Network::Mutinynet,
+        vault_tx.txid,
+        &vault_tx,
+        Network::Mutinynet.genesis_header().block_hash(),
+        1,
+        true,
+        0,
+        0,
+        0,
+        vault_tx.txid,
+    );
+    let response = serde_json::to_value(Response::NewTranscation(info)).unwrap();
+
+    // Fields of the sample are exactly the fields in the schema
+    let sample_fields: Vec<&String> = response["NewTranscation"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .collect();
+    let schema_fields: Vec<&String> = schema["response"]["definitions"]["VaultTxInfo"]
+        ["properties"]
+        .as_object()
+        .expect("VaultTxInfo in the schema")
+        .keys()
+        .collect();
+    assert_eq!(sample_fields, schema_fields);
+    let variants = schema["response"]["oneOf"].as_array().unwrap();
+    assert!(variants
+        .iter()
+        .any(|v| v["required"][0] == "NewTranscation"));
+
+    // The schema request is described in the schema too
+    let request = serde_json::json!({"method": "schema"});
+    serde_json::from_value::<Request>(request).unwrap();
+    assert!(schema["request"].to_string().contains("\"schema\""));
+}
+
+#[test]
+#[serial]
+fn service_subscribe_filter() {
+    let raw_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    let mut vault_tx = VaultTx::from_tx(&raw_tx).unwrap();
+    let mut mk_info = |balance, btc_volume| {
+        vault_tx.balance = balance;
+        VaultTxInfo::new(
+            Network::Mutinynet,
+            vault_tx.txid,
+            &vault_tx,
+            Network::Mutinynet.genesis_header().block_hash(),
+            1,
+            true,
+            0,
+            0,
+            btc_volume,
+            vault_tx.txid,
+        )
+    };
+    let small = mk_info(100, -1_000);
+    let large = mk_info(1_000_000, -2_000_000);
+
+    // Everything passes by default
+    assert!(EventFilter::default().passes(&small));
+
+    let filter = EventFilter {
+        min_balance: Some(10_000),
+        min_btc_volume: Some(1_000_000),
+    };
+    assert!(!filter.passes(&small));
+    assert!(filter.passes(&large));
+
+    // Borrow and repay have no BTC volume, only the balance threshold applies to them
+    let borrow_tx: Transaction =
+        deserialize(&hex::decode(BORROW_TX).unwrap()).expect("valid transaction");
+    let borrow_tx = VaultTx::from_tx(&borrow_tx).unwrap();
+    let mk_borrow = |balance| {
+        let mut borrow_tx = borrow_tx.clone();
+        borrow_tx.balance = balance;
+        VaultTxInfo::new(
+            Network::Mutinynet,
+            vault_tx.txid,
+            &borrow_tx,
+            Network::Mutinynet.genesis_header().block_hash(),
+            1,
+            true,
+            0,
+            0,
+            0,
+            vault_tx.txid,
+        )
+    };
+    assert!(filter.passes(&mk_borrow(1_000_000)));
+    assert!(!filter.passes(&mk_borrow(100)));
+
+    // The filter is applied for the connection
+    let indexer = Arc::new(init_offline_indexer());
+    let addr = start_websocket_server(indexer, "127.0.0.1:0").unwrap();
+    let mut client = ClientBuilder::new(&format!("ws://{addr}"))
+        .unwrap()
+        .connect_insecure()
+        .unwrap();
+    let request = r#"{"method": "subscribe", "min_balance": 10000, "min_btc_volume": 1000000}"#;
+    client.send_message(&Message::text(request)).unwrap();
+    let response = match client.recv_message().unwrap() {
+        OwnedMessage::Text(text) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+        other => panic!("Expected text message, got {other:?}"),
+    };
+    assert_eq!(
+        response["Subscribed"],
+        serde_json::to_value(filter).unwrap(),
+        "{response}"
+    );
+}
+
+#[test]
+#[serial]
+fn service_tx_confirmations() {
+    let mut db = super::init_db();
+    let meta = store_vault_fixture(&mut db, OPEN_VAULT_TX, 10, 0);
+    let confirmations = |chain_height| {
+        VaultTxInfo::from_db_metainfo(Network::Mutinynet, &meta)
+            .with_confirmations(chain_height)
+            .confirmations
+    };
+    assert_eq!(confirmations(9), 0);
+    assert_eq!(confirmations(10), 1);
+    assert_eq!(confirmations(11), 2);
+    assert_eq!(confirmations(15), 6);
+
+    let stale = VaultTxInfo {
+        in_longest: false,
+        ..VaultTxInfo::from_db_metainfo(Network::Mutinynet, &meta)
+    };
+    assert_eq!(stale.with_confirmations(15).confirmations, 0);
+}
+
+#[test]
+#[serial]
+fn service_header_request() {
+    let hash = Network::Mutinynet.genesis_header().block_hash();
+    let by_height: Request =
+        serde_json::from_str(r#"{"method": "header", "height_or_hash": 0}"#).unwrap();
+    assert!(matches!(
+        by_height,
+        Request::Header {
+            height_or_hash: HeightOrHash::Height(0)
+        }
+    ));
+    let by_hash: Request = serde_json::from_str(&format!(
+        r#"{{"method": "header", "height_or_hash": "{hash}"}}"#
+    ))
+    .unwrap();
+    assert!(matches!(
+        by_hash,
+        Request::Header {
+            height_or_hash: HeightOrHash::Hash(h)
+        } if h == hash.to_string()
+    ));
+
+    let info = HeaderInfo::from(&HeaderRecord {
+        header: Network::Mutinynet.genesis_header(),
+        height: 0,
+        in_longest: true,
+    });
+    assert_eq!(info.block_hash, hash.to_string());
+    assert_eq!(info.prev_blockhash, BlockHash::all_zeros().to_string());
+    assert_eq!(info.raw_header_hex.len(), 160);
+}
+
+#[test]
+#[serial]
+fn service_stream_history() {
+    let indexer = Arc::new(init_offline_indexer());
+    let (first, second) = {
+        let db = indexer.get_database();
+        let mut conn = db.lock().unwrap();
+        (
+            store_vault_fixture(&mut conn, OPEN_VAULT_TX, 1, 0),
+            store_vault_fixture(&mut conn, UNKNOWN_VAULT_TX, 2, 0),
+        )
+    };
+    let addr = start_websocket_server(indexer, "127.0.0.1:0").unwrap();
+    let mut client = ClientBuilder::new(&format!("ws://{addr}"))
+        .unwrap()
+        .connect_insecure()
+        .unwrap();
+    client
+        .send_message(&Message::text(r#"{"method": "stream_history_all"}"#))
+        .unwrap();
+    let mut recv = || match client.recv_message().unwrap() {
+        OwnedMessage::Text(text) => serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+        other => panic!("Expected text message, got {other:?}"),
+    };
+
+    // Every transaction is a separate frame without the response wrapper
+    let frame = recv();
+    assert_eq!(frame["txid"], first.vault_tx.txid.to_string(), "{frame}");
+    let frame = recv();
+    assert_eq!(frame["txid"], second.vault_tx.txid.to_string(), "{frame}");
+    assert_eq!(recv(), serde_json::json!({"done": true, "count": 2}));
+}
@@ -0,0 +1,165 @@
+use crate::indexer::event::Event;
+use crate::service::VaultTxInfo;
+use crate::tests::transaction::OPEN_VAULT_TX;
+use crate::vault::VaultTx;
+use crate::webhook::{Error, Webhook, SIGNATURE_HEADER};
+use crate::Network;
+use bitcoin::consensus::deserialize;
+use bitcoin::Transaction;
+use core::time::Duration;
+use serial_test::serial;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{channel, sync_channel, Receiver};
+use std::sync::Mutex;
+use std::thread;
+
+use super::framework::{init_db, store_vault_fixture};
+use super::init_parser;
+
+/// Received request headers (lowercase names) and body
+type HttpRequest = (Vec<(String, String)>, String);
+
+/// Serve HTTP requests answering with the given statuses one by one
+fn mock_server(statuses: Vec<u16>) -> (String, Receiver<HttpRequest>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        for status in statuses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = vec![];
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            loop {
+                line.clear();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(": ") {
+                    Some((name, value)) => headers.push((name.to_lowercase(), value.to_owned())),
+                    None => break,
+                }
+            }
+            let length = headers
+                .iter()
+                .find(|(name, _)| name == "content-length")
+                .map_or(0, |(_, value)| value.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            sender
+                .send((headers, String::from_utf8(body).unwrap()))
+                .unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+            .unwrap();
+        }
+    });
+    (url, receiver)
+}
+
+fn open_vault_info() -> VaultTxInfo {
+    let raw_tx: Transaction =
+        deserialize(&hex::decode(OPEN_VAULT_TX).unwrap()).expect("valid transaction");
+    let vault_tx = VaultTx::from_tx(&raw_tx).unwrap();
+    VaultTxInfo::new(
+        Network::Mutinynet,
+        vault_tx.txid,
+        &vault_tx,
+        Network::Mutinynet.genesis_header().block_hash(),
+        1,
+        true,
+        0,
+        0,
+        0,
+        vault_tx.txid,
+    )
+}
+
+#[test]
+#[serial]
+fn webhook_signature() {
+    let webhook = Webhook::new("http://127.0.0.1".to_owned(), Some("key".to_owned())).unwrap();
+    assert_eq!(
+        webhook
+            .sign("The quick brown fox jumps over the lazy dog")
+            .unwrap(),
+        "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+    );
+    let unsigned = Webhook::new("http://127.0.0.1".to_owned(), None).unwrap();
+    assert!(unsigned.sign("body").is_none());
+}
+
+#[test]
+#[serial]
+fn webhook_retry_delivery() {
+    init_parser();
+    let (url, requests) = mock_server(vec![500, 200]);
+    let mut webhook = Webhook::new(url, Some("secret".to_owned())).unwrap();
+    webhook.backoff = Duration::from_millis(10);
+    let info = open_vault_info();
+    webhook.deliver(&info).unwrap();
+
+    // The failed request and the retry carry the same signed body
+    for _ in 0..2 {
+        let (headers, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(body, serde_json::to_string(&info).unwrap());
+        let signature = headers
+            .iter()
+            .find(|(name, _)| name == &SIGNATURE_HEADER.to_lowercase())
+            .map(|(_, value)| value.clone());
+        assert_eq!(signature, webhook.sign(&body));
+    }
+}
+
+#[test]
+#[serial]
+fn webhook_gives_up() {
+    init_parser();
+    let (url, requests) = mock_server(vec![503, 503]);
+    let mut webhook = Webhook::new(url, None).unwrap();
+    webhook.backoff = Duration::from_millis(10);
+    webhook.attempts = 2;
+    assert!(matches!(
+        webhook.deliver(&open_vault_info()),
+        Err(Error::Status(503))
+    ));
+    let (headers, _) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(headers
+        .iter()
+        .all(|(name, _)| name != &SIGNATURE_HEADER.to_lowercase()));
+}
+
+#[test]
+#[serial]
+fn webhook_resubscribes() {
+    init_parser();
+    let (url, requests) = mock_server(vec![200]);
+    let webhook = Webhook::new(url, None).unwrap();
+    let meta = store_vault_fixture(&mut init_db(), OPEN_VAULT_TX, 1, 0);
+
+    // Each subscription hands the sending side to the test
+    let (subscriptions, senders) = channel();
+    let subscriptions = Mutex::new(subscriptions);
+    let handle = webhook.spawn(
+        Network::Mutinynet,
+        move || {
+            let (sender, receiver) = sync_channel(1);
+            subscriptions.lock().unwrap().send(sender).ok()?;
+            Some(receiver)
+        },
+        || 1,
+    );
+
+    // The reader is disconnected, the webhook subscribes again and keeps delivering
+    drop(senders.recv_timeout(Duration::from_secs(5)).unwrap());
+    let sender = senders.recv_timeout(Duration::from_secs(5)).unwrap();
+    sender.send(Event::NewTransaction(meta.clone())).unwrap();
+    let (_, body) = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    let info = VaultTxInfo::from_db_metainfo(Network::Mutinynet, &meta).with_confirmations(1);
+    assert_eq!(body, serde_json::to_string(&info).unwrap());
+
+    sender.send(Event::Termination).unwrap();
+    handle.join().unwrap();
+}
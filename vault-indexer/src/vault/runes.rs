@@ -19,6 +19,7 @@ pub struct UnitTransaction {
     pub unit_amount: UnitAmount,
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("The {0} is not a rune transaction")]
@@ -27,25 +28,38 @@ pub enum Error {
     Cenotaph(Txid, Cenotaph),
     #[error("The {0} doesn't have edicts for UNIT, runestone: {1:#?}")]
     DontHaveUnitRune(Txid, Runestone),
+    #[error("The {0} moves {1} UNIT that doesn't fit the amount type")]
+    UnitAmountOverflow(Txid, u128),
 }
 
 impl Error {
     pub fn is_definetely_not_unit(&self) -> bool {
         matches!(self, Error::NotRuneTx(_))
     }
+
+    /// The runestone is about UNIT, but it can't be parsed: a cenotaph that mints UNIT (edicts
+    /// of cenotaphs are lost) or UNIT edicts with too large amount
+    pub fn touches_unit(&self) -> bool {
+        match self {
+            Error::Cenotaph(_, cenotaph) => cenotaph.mint == Some(UNIT_RUNE_ID),
+            Error::UnitAmountOverflow(..) => true,
+            Error::NotRuneTx(_) | Error::DontHaveUnitRune(..) => false,
+        }
+    }
 }
 
 impl UnitTransaction {
+    #[allow(clippy::result_large_err)]
     pub fn from_tx(tx: &Transaction) -> Result<Self, Error> {
         let txid = tx.compute_txid();
-        let artifact = Runestone::decipher(&tx).ok_or(Error::NotRuneTx(txid))?;
+        let artifact = Runestone::decipher(tx).ok_or(Error::NotRuneTx(txid))?;
         match artifact {
             Artifact::Runestone(runestone) => {
                 let mut unit_amount = 0;
                 let mut units_encoutered = false;
                 for edict in runestone.edicts.iter() {
                     if edict.id == UNIT_RUNE_ID {
-                        unit_amount += edict.amount;
+                        unit_amount = edict.amount.saturating_add(unit_amount);
                         units_encoutered = true;
                     }
                 }
@@ -54,7 +68,8 @@ impl UnitTransaction {
                 } else {
                     Ok(UnitTransaction {
                         txid,
-                        unit_amount: unit_amount as u32,
+                        unit_amount: UnitAmount::try_from(unit_amount)
+                            .map_err(|_| Error::UnitAmountOverflow(txid, unit_amount))?,
                     })
                 }
             }
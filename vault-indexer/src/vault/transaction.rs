@@ -1,17 +1,20 @@
 pub use bitcoin::Txid;
 use bitcoin::{
     consensus::Decodable,
-    opcodes::all::{OP_PUSHBYTES_14, OP_PUSHBYTES_38, OP_PUSHNUM_8, OP_RETURN},
-    Script, Transaction, TxIn, TxOut,
+    opcodes::all::{OP_PUSHBYTES_75, OP_PUSHDATA1, OP_PUSHDATA2, OP_PUSHNUM_8, OP_RETURN},
+    Transaction, TxIn, TxOut,
 };
 use core::{assert_eq, fmt::Display, matches, str::FromStr};
 use log::*;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 use thiserror::Error;
 
 /// Action inside the vault tx
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema,
+)]
 #[repr(u8)]
 pub enum VaultAction {
     // Open new vault
@@ -85,6 +88,14 @@ impl VaultAction {
             _ => 1,
         }
     }
+
+    // Operations that lock or unlock BTC of the vault
+    pub fn moves_btc(self) -> bool {
+        matches!(
+            self,
+            VaultAction::Open | VaultAction::Deposit | VaultAction::Withdraw
+        )
+    }
 }
 
 /// Known versions of vault transaction
@@ -144,14 +155,103 @@ impl FromStr for VaultVersion {
 /// Fixed point (2 decimals) amount of stable units
 pub type UnitAmount = u32;
 
-/// TODO
+/// Oracle price of 1 BTC in whole USD, the payload has no decimals for the price. Vaults
+/// on the chain confirm that: opening with about 0.5 BTC at price 99094 borrows 13929.52 UNIT.
 pub type OraclePrice = u32;
 
+/// Amount of decimals in [UnitAmount]
+pub const UNIT_DECIMALS: u32 = 2;
+
+/// Amount of decimals in [OraclePrice]
+pub const ORACLE_PRICE_DECIMALS: u32 = 0;
+
+/// Convert fixed point UNIT amount to the decimal one
+pub fn unit_amount_decimal(amount: UnitAmount) -> f64 {
+    amount as f64 / 10u32.pow(UNIT_DECIMALS) as f64
+}
+
+/// Convert the signed UNIT volume (in minimal units) to the decimal one
+pub fn unit_volume_decimal(volume: i32) -> f64 {
+    volume as f64 / 10u32.pow(UNIT_DECIMALS) as f64
+}
+
+/// Convert oracle price to USD per BTC
+pub fn oracle_price_decimal(price: OraclePrice) -> f64 {
+    price as f64 / 10u32.pow(ORACLE_PRICE_DECIMALS) as f64
+}
+
 /// Length of liquidation hash in bytes
 pub const LIQUIDATION_HASH_LEN: usize = 20;
 
-/// Liquidation hash stored in byte array
-pub type LiquidationHash = [u8; LIQUIDATION_HASH_LEN];
+/// Length of the legacy payload (version, action, price, timestamp and balance). Payloads
+/// of other lengths are in the new format.
+pub const LEGACY_PAYLOAD_LEN: usize = 14;
+
+/// Opcodes that may follow OP_RETURN in a vault output by default
+pub const DEFAULT_PROTOCOL_PREFIXES: &[u8] = &[OP_PUSHNUM_8.to_u8()];
+
+/// Where the vault transaction carries its payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PayloadLocation {
+    /// OP_RETURN output with the given number
+    Output(u32),
+    /// Taproot annex of the first input, the transaction has no OP_RETURN output
+    Annex,
+}
+
+impl PayloadLocation {
+    /// Number of the OP_RETURN output, `None` for payloads in the annex
+    pub fn output(self) -> Option<u32> {
+        match self {
+            PayloadLocation::Output(output) => Some(output),
+            PayloadLocation::Annex => None,
+        }
+    }
+}
+
+/// Liquidation hash of exactly [LIQUIDATION_HASH_LEN] bytes, displayed and parsed as hex
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LiquidationHash([u8; LIQUIDATION_HASH_LEN]);
+
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum LiquidationHashError {
+    #[error("Liquidation hash must be {LIQUIDATION_HASH_LEN} bytes, got {0}")]
+    InvalidLength(usize),
+    #[error("Liquidation hash is not valid hex: {0}")]
+    Hex(#[from] hex::FromHexError),
+}
+
+impl LiquidationHash {
+    pub const fn new(bytes: [u8; LIQUIDATION_HASH_LEN]) -> Self {
+        LiquidationHash(bytes)
+    }
+
+    /// Checked constructor that fails unless the slice is exactly [LIQUIDATION_HASH_LEN] bytes
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, LiquidationHashError> {
+        bytes
+            .try_into()
+            .map(LiquidationHash)
+            .map_err(|_| LiquidationHashError::InvalidLength(bytes.len()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; LIQUIDATION_HASH_LEN] {
+        &self.0
+    }
+}
+
+impl Display for LiquidationHash {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl FromStr for LiquidationHash {
+    type Err = LiquidationHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LiquidationHash::from_slice(&hex::decode(s)?)
+    }
+}
 
 /// Vault id is a opening transaction ID
 pub type VaultId = Txid;
@@ -161,8 +261,8 @@ pub type VaultId = Txid;
 pub struct VaultTx {
     /// Hash of transaction where we found the vault tx
     pub txid: Txid,
-    /// The output number with the op_return
-    pub output: u32,
+    /// The OP_RETURN output or the annex with the payload
+    pub payload: PayloadLocation,
     /// Version of the transaction
     pub version: VaultVersion,
     /// The encoded action
@@ -196,16 +296,18 @@ pub enum MissingVaultField {
 
 #[derive(Debug, Error, PartialEq)]
 pub enum VaultParseError {
-    #[error("No OP_RETURN output detected")]
+    #[error("No OP_RETURN output or vault annex detected")]
     NoOpReturn,
-    #[error("No OP_PUSHNUM_8 after OP_RETURN")]
+    #[error("No protocol prefix (OP_PUSHNUM_8 by default) after OP_RETURN")]
     NoOpPush8,
-    #[error("Expected OP_PUSHNUM_8 but got opcode {0}")]
+    #[error("Expected protocol prefix (OP_PUSHNUM_8 by default) but got opcode {0}")]
     MismatchOpPush8(u8),
-    #[error("No OP_PUSHBYTES_14 after OP_RETURN")]
+    #[error("No data push after protocol prefix")]
     NoOpPushbytes14,
-    #[error("Expected OP_PUSHBYTES_14 or OP_PUSHBYTES_38 but got opcode {0}")]
+    #[error("Expected OP_PUSHBYTES_N, OP_PUSHDATA1 or OP_PUSHDATA2 but got opcode {0}")]
     MismatchOpPushbytes(u8),
+    #[error("Data push declares {declared} bytes, but the script has only {actual}")]
+    TruncatedPush { declared: usize, actual: usize },
     #[error("Missing {0} field")]
     MissingField(MissingVaultField),
     #[error("Not expected version {0}")]
@@ -214,6 +316,8 @@ pub enum VaultParseError {
     WrongAction(u8),
     #[error("Liquidation hash has unexpected length (not 20): {0}")]
     LiquidationHashInvalidLength(usize),
+    #[error("Annex has {0} bytes after the vault payload")]
+    AnnexTrailingBytes(usize),
 }
 
 impl VaultParseError {
@@ -221,7 +325,11 @@ impl VaultParseError {
     pub fn is_definetely_not_vault(&self) -> bool {
         matches!(
             *self,
-            Self::NoOpReturn | Self::NoOpPush8 | Self::MismatchOpPush8(_) | Self::NoOpPushbytes14
+            Self::NoOpReturn
+                | Self::NoOpPush8
+                | Self::MismatchOpPush8(_)
+                | Self::NoOpPushbytes14
+                | Self::AnnexTrailingBytes(_)
         )
     }
 }
@@ -244,38 +352,109 @@ impl VaultTx {
 
     /// Detect and parse the vault transaction from the given Bitcoin vessel transaction
     pub fn from_tx(tx: &Transaction) -> Result<Self, VaultParseError> {
+        VaultTx::from_tx_with_prefixes(tx, DEFAULT_PROTOCOL_PREFIXES)
+    }
+
+    /// Same as [`VaultTx::from_tx`], but the opcode after OP_RETURN may be any of the `prefixes`.
+    /// Allows to index deployments that mark their outputs differently.
+    pub fn from_tx_with_prefixes(
+        tx: &Transaction,
+        prefixes: &[u8],
+    ) -> Result<Self, VaultParseError> {
         // Find first op_return
-        let (out_i, op_return_out): (usize, &Script) = tx
+        let Some((out_i, op_return_out)) = tx
             .output
             .iter()
             .enumerate()
             .map(|(i, out)| (i, out.script_pubkey.as_script()))
             .find(|(_, out)| out.is_op_return())
-            .ok_or(VaultParseError::NoOpReturn)?;
+        else {
+            return VaultTx::from_annex(tx, prefixes);
+        };
 
         // Now let parse instructions one by one
         let mut instructions = op_return_out.bytes();
         // Skip op_return
         let op_return: u8 = instructions.next().ok_or(VaultParseError::NoOpReturn)?;
         assert_eq!(op_return, OP_RETURN.to_u8()); // if fires, the is_op_return fn is a lyier
+        VaultTx::parse_script_payload(
+            tx,
+            PayloadLocation::Output(out_i as u32),
+            &mut instructions,
+            prefixes,
+        )
+    }
+
+    /// Fallback for transactions without OP_RETURN output. The payload is taken from the Taproot
+    /// annex of the first input that is laid out as the OP_RETURN script after the opcode:
+    /// `0x50 <protocol prefix> <data push>`. Annexes of other protocols don't start with the
+    /// prefix or have bytes after the push, so they are not mistaken for vault payloads.
+    fn from_annex(tx: &Transaction, prefixes: &[u8]) -> Result<Self, VaultParseError> {
+        let annex = tx
+            .input
+            .first()
+            .and_then(|input| input.witness.taproot_annex())
+            .ok_or(VaultParseError::NoOpReturn)?;
+        if !annex.get(1).is_some_and(|prefix| prefixes.contains(prefix)) {
+            return Err(VaultParseError::NoOpReturn);
+        }
+        let mut instructions = annex[1..].iter().copied();
+        let vault_tx =
+            VaultTx::parse_script_payload(tx, PayloadLocation::Annex, &mut instructions, prefixes)?;
+        let trailing = instructions.count();
+        if trailing != 0 {
+            return Err(VaultParseError::AnnexTrailingBytes(trailing));
+        }
+        Ok(vault_tx)
+    }
 
-        // Skip OP_PUSHNUM_8
-        let op_pushnum_8: u8 = instructions.next().ok_or(VaultParseError::NoOpPush8)?;
-        if op_pushnum_8 != OP_PUSHNUM_8.to_u8() {
-            return Err(VaultParseError::MismatchOpPush8(op_pushnum_8));
+    /// Parse the protocol prefix and the pushed payload that follow OP_RETURN (or the annex tag)
+    fn parse_script_payload<I: Iterator<Item = u8>>(
+        tx: &Transaction,
+        location: PayloadLocation,
+        instructions: &mut I,
+        prefixes: &[u8],
+    ) -> Result<Self, VaultParseError> {
+        // Skip protocol prefix (OP_PUSHNUM_8 by default)
+        let prefix: u8 = instructions.next().ok_or(VaultParseError::NoOpPush8)?;
+        if !prefixes.contains(&prefix) {
+            return Err(VaultParseError::MismatchOpPush8(prefix));
         }
 
-        // Skip op_push bytes 14 or 38 bytes
+        // Decode length of the pushed payload (14 or 38 bytes are known so far)
         let op_pushbytes: u8 = instructions
             .next()
             .ok_or(VaultParseError::NoOpPushbytes14)?;
-        if op_pushbytes != OP_PUSHBYTES_14.to_u8() && op_pushbytes != OP_PUSHBYTES_38.to_u8() {
-            return Err(VaultParseError::MismatchOpPushbytes(op_pushbytes));
+        let push_len = match op_pushbytes {
+            _ if (1..=OP_PUSHBYTES_75.to_u8()).contains(&op_pushbytes) => op_pushbytes as usize,
+            _ if op_pushbytes == OP_PUSHDATA1.to_u8() => instructions
+                .next()
+                .ok_or(VaultParseError::NoOpPushbytes14)?
+                as usize,
+            _ if op_pushbytes == OP_PUSHDATA2.to_u8() => {
+                let low = instructions
+                    .next()
+                    .ok_or(VaultParseError::NoOpPushbytes14)?;
+                let high = instructions
+                    .next()
+                    .ok_or(VaultParseError::NoOpPushbytes14)?;
+                u16::from_le_bytes([low, high]) as usize
+            }
+            _ => return Err(VaultParseError::MismatchOpPushbytes(op_pushbytes)),
+        };
+        let payload: Vec<u8> = instructions.by_ref().take(push_len).collect();
+        if payload.len() != push_len {
+            return Err(VaultParseError::TruncatedPush {
+                declared: push_len,
+                actual: payload.len(),
+            });
         }
+        let mut instructions = payload.into_iter();
         // We distinguish the new format from legacy by length of the payload
-        let is_new_format = op_pushbytes == OP_PUSHBYTES_38.to_u8();
+        let is_new_format = instructions.len() != LEGACY_PAYLOAD_LEN;
 
-        // Parse version field
+        // Parse version field. Unknown version might be a newer protocol, so it is reported
+        // (and counted as unparsed by the indexer) instead of being silently skipped.
         let version_code: u8 = instructions
             .next()
             .ok_or(VaultParseError::MissingField(MissingVaultField::Version))?;
@@ -338,15 +517,18 @@ impl VaultTx {
         let liquidation_price = instructions.next_u32_be();
 
         // Take remaining bytes as hash
-        let bytes_left = instructions.len();
-        if bytes_left != 0 && bytes_left != LIQUIDATION_HASH_LEN {
-            return Err(VaultParseError::LiquidationHashInvalidLength(bytes_left));
-        }
-        let liquidation_hash = instructions.next20();
+        let bytes_left = instructions.as_slice();
+        let liquidation_hash = if bytes_left.is_empty() {
+            None
+        } else {
+            let hash = LiquidationHash::from_slice(bytes_left)
+                .map_err(|_| VaultParseError::LiquidationHashInvalidLength(bytes_left.len()))?;
+            Some(hash)
+        };
 
         Ok(VaultTx {
             txid: tx.compute_txid(),
-            output: out_i as u32,
+            payload: location,
             version: match version {
                 VaultVersion::Vault1 if !is_new_format => VaultVersion::Vault1Legacy,
                 _ => version,
@@ -373,29 +555,39 @@ pub enum AssumeCustodyErr {
 
 impl VaultTx {
     /// Try assume BTC amount held inside the custody.
+    ///
+    /// The OP_RETURN output is never a custody, so transactions without a spendable output
+    /// at the expected position are rejected instead of reporting zero custody.
     pub fn assume_custody_value(&self, tx: &Transaction) -> Result<u64, AssumeCustodyErr> {
-        match self.action {
-            VaultAction::Open => {
-                // First output and second outputs look like a UTXO connectors or inscriptions, so assume 3rd one is usually a custody
-                let custody_output: &TxOut = tx
-                    .output
-                    .get(2)
-                    .ok_or(AssumeCustodyErr::Open(tx.compute_txid()))?;
-                Ok(custody_output.value.to_sat())
-            }
-            VaultAction::Deposit
-            | VaultAction::Withdraw
-            | VaultAction::Borrow
-            | VaultAction::Repay => {
-                // First output looks like volume of custody (same script)
-                let cur_custody: &TxOut = tx
-                    .output
-                    .first()
-                    .ok_or(AssumeCustodyErr::Deposit(tx.compute_txid()))?;
-
-                Ok(cur_custody.value.to_sat())
+        let (_, custody_output) = self.assume_custody_output(tx)?;
+        Ok(custody_output.value.to_sat())
+    }
+
+    /// Try assume the output of the transaction that holds the custody, returns its index
+    /// together with the output. Uses the same heuristic as [VaultTx::assume_custody_value].
+    pub fn assume_custody_output<'a>(
+        &self,
+        tx: &'a Transaction,
+    ) -> Result<(u32, &'a TxOut), AssumeCustodyErr> {
+        let (pos, err): (usize, fn(Txid) -> AssumeCustodyErr) = match self.action {
+            // First output and second outputs look like a UTXO connectors or inscriptions, so assume 3rd one is usually a custody
+            VaultAction::Open => (2, AssumeCustodyErr::Open),
+            // First output looks like volume of custody (same script)
+            VaultAction::Withdraw => (0, AssumeCustodyErr::Withdraw),
+            VaultAction::Deposit | VaultAction::Borrow | VaultAction::Repay => {
+                (0, AssumeCustodyErr::Deposit)
             }
-        }
+        };
+        let custody_output: &TxOut = tx
+            .output
+            .get(pos)
+            // Payloads in the annex don't take an output, the custody stays at the same position
+            .filter(|out| {
+                self.payload != PayloadLocation::Output(pos as u32)
+                    && !out.script_pubkey.is_op_return()
+            })
+            .ok_or_else(|| err(tx.compute_txid()))?;
+        Ok((pos as u32, custody_output))
     }
 }
 
@@ -427,8 +619,6 @@ impl VaultTx {
 trait BytesParser {
     fn next4(&mut self) -> Option<[u8; 4]>;
 
-    fn next20(&mut self) -> Option<[u8; 20]>;
-
     fn next_u32_be(&mut self) -> Option<u32> {
         self.next4().map(u32::from_be_bytes)
     }
@@ -442,12 +632,4 @@ impl<T: Iterator<Item = u8>> BytesParser for T {
         }
         Some(buf)
     }
-
-    fn next20(&mut self) -> Option<[u8; 20]> {
-        let mut buf = [0u8; 20];
-        for item in &mut buf {
-            *item = self.next()?;
-        }
-        Some(buf)
-    }
 }
@@ -0,0 +1,172 @@
+use crate::indexer::event::Event;
+use crate::service::VaultTxInfo;
+use crate::Network;
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use core::time::Duration;
+use log::*;
+use std::sync::mpsc::{sync_channel, Receiver, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use thiserror::Error;
+
+/// Header with HMAC-SHA256 of the request body, set when the webhook has a secret
+pub const SIGNATURE_HEADER: &str = "X-Vault-Signature-256";
+
+/// How many times the delivery of single transaction is tried
+pub const WEBHOOK_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled for each next one
+pub const WEBHOOK_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Limit to establish connection to the webhook endpoint
+pub const WEBHOOK_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Limit to wait for the response of the webhook endpoint
+pub const WEBHOOK_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many transactions can wait for delivery. When the endpoint is slower than the
+/// indexing, new transactions are dropped (and logged) instead of stalling the events reader.
+pub const WEBHOOK_QUEUE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot encode JSON for webhook: {0}")]
+    Encoding(#[from] serde_json::Error),
+    #[error("Webhook responded with status {0}")]
+    Status(u16),
+    #[error("Failed to send webhook request: {0}")]
+    Transport(Box<ureq::Transport>),
+    #[error("Failed to initialize TLS for webhook: {0}")]
+    Tls(#[from] native_tls::Error),
+}
+
+/// Posts new vault transactions as JSON to the configured URL
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    secret: Option<String>,
+    agent: ureq::Agent,
+    pub(crate) attempts: u32,
+    pub(crate) backoff: Duration,
+}
+
+impl Webhook {
+    pub fn new(url: String, secret: Option<String>) -> Result<Self, Error> {
+        let agent = ureq::AgentBuilder::new()
+            .tls_connector(Arc::new(native_tls::TlsConnector::new()?))
+            .timeout_connect(WEBHOOK_CONNECT_TIMEOUT)
+            .timeout_read(WEBHOOK_READ_TIMEOUT)
+            .build();
+        Ok(Webhook {
+            url,
+            secret,
+            agent,
+            attempts: WEBHOOK_ATTEMPTS,
+            backoff: WEBHOOK_BACKOFF,
+        })
+    }
+
+    /// Hex encoded HMAC-SHA256 of the body with the secret in the format `sha256=<hex>`
+    pub fn sign(&self, body: &str) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+        engine.input(body.as_bytes());
+        Some(format!("sha256={}", hmac::Hmac::from_engine(engine)))
+    }
+
+    /// Post the transaction, retrying with exponential backoff on failures and non-2xx responses
+    pub fn deliver(&self, info: &VaultTxInfo) -> Result<(), Error> {
+        let body = serde_json::to_string(info)?;
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match self.post(&body) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= self.attempts => return Err(e),
+                Err(e) => {
+                    warn!(
+                        "Webhook delivery of {} failed (attempt {attempt}): {e}, retrying in {backoff:?}",
+                        info.txid
+                    );
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    fn post(&self, body: &str) -> Result<(), Error> {
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json");
+        if let Some(signature) = self.sign(body) {
+            request = request.set(SIGNATURE_HEADER, &signature);
+        }
+        match request.send_string(body) {
+            Ok(response) if (200..300).contains(&response.status()) => Ok(()),
+            Ok(response) => Err(Error::Status(response.status())),
+            Err(ureq::Error::Status(code, _)) => Err(Error::Status(code)),
+            Err(ureq::Error::Transport(e)) => Err(Error::Transport(Box::new(e))),
+        }
+    }
+
+    /// Deliver new vault transactions from the events until the indexer terminates. The
+    /// `subscribe` makes a new events reader, it is called on start and again when the reader
+    /// is disconnected for not keeping up. The `chain_height` gives the current tip to count
+    /// confirmations from.
+    ///
+    /// Requests are posted from a separate worker through a queue of [WEBHOOK_QUEUE_CAPACITY],
+    /// so a slow endpoint doesn't stop the draining of the events.
+    pub fn spawn<S, F>(self, network: Network, subscribe: S, chain_height: F) -> JoinHandle<()>
+    where
+        S: Fn() -> Option<Receiver<Event>> + Send + 'static,
+        F: Fn() -> u32 + Send + 'static,
+    {
+        let (queue, deliveries) = sync_channel::<VaultTxInfo>(WEBHOOK_QUEUE_CAPACITY);
+        let url = self.url.clone();
+        thread::spawn(move || {
+            trace!("Spawn webhook delivery thread for {}", self.url);
+            // Ends when the events thread drops the queue
+            for info in deliveries {
+                if let Err(e) = self.deliver(&info) {
+                    error!("Failed to deliver tx {} to the webhook: {e}", info.txid);
+                }
+            }
+        });
+        thread::spawn(move || {
+            trace!("Spawn webhook events thread for {url}");
+            loop {
+                let Some(events) = subscribe() else {
+                    error!("Cannot subscribe webhook to the events, deliveries are stopped");
+                    return;
+                };
+                for event in events {
+                    match event {
+                        Event::NewTransaction(meta) => {
+                            let info = VaultTxInfo::from_db_metainfo(network, &meta)
+                                .with_confirmations(chain_height());
+                            match queue.try_send(info) {
+                                Ok(()) => (),
+                                Err(TrySendError::Full(info)) => error!(
+                                    "Webhook queue is full, tx {} is not delivered",
+                                    info.txid
+                                ),
+                                Err(TrySendError::Disconnected(_)) => {
+                                    error!("Webhook delivery thread is gone");
+                                    return;
+                                }
+                            }
+                        }
+                        Event::Termination => return,
+                        _ => (),
+                    }
+                }
+                error!(
+                    "Webhook events reader is disconnected, transactions since then are not delivered. Subscribing again"
+                );
+            }
+        })
+    }
+}